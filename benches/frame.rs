@@ -0,0 +1,152 @@
+//! Benchmarks of the paths that run every frame.
+//!
+//! Of `DrawLaser::prepare`, only the instances built on the CPU are covered: the rest needs a
+//! rendy `Factory`, which can't be created without a GPU.
+use amethyst::core::ecs::{Builder, RunNow, World, WorldExt};
+use amethyst::core::math::{Matrix4, Point2, Vector2};
+use amethyst::core::timing::Time;
+use amethyst::core::transform::{HierarchySystemDesc, Parent, Transform};
+use amethyst::core::SystemDesc;
+use amethyst::renderer::palette::rgb::LinSrgb;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use iris::chart::timing::position_for_time;
+use iris::chart::{BpmCommand, ChartState, LaserId, NoteKind, PlaySettings, Timed};
+use iris::judge::{pick_note, JudgeWindows};
+use iris::laser::{build_instances, Laser, LaserBlend, LaserOptions, Note, NoteStyle};
+use iris::modifiers::ActiveModifiers;
+use iris::settings::Settings;
+use iris::theme::Theme;
+
+/// A BPM track with a change every beat, as in charts with gimmicks.
+fn bpm_track(changes: usize) -> Vec<Timed<BpmCommand>> {
+    let mut time = 0.;
+    let mut position = 0.;
+    (0..changes)
+        .map(|i| {
+            let bpm = 120. + (i % 7) as f32 * 10.;
+            let command = Timed {
+                time,
+                inner: BpmCommand { bpm, position },
+            };
+            time += 60. / bpm;
+            position += 1.;
+            command
+        })
+        .collect()
+}
+
+fn position_for_time_bench(c: &mut Criterion) {
+    let bpms = bpm_track(10_000);
+    let end = bpms.last().unwrap().time;
+    c.bench_function("position_for_time 10k changes", |b| {
+        let mut time = 0.;
+        b.iter(|| {
+            time = (time + 0.016) % end;
+            position_for_time(black_box(&bpms), black_box(time))
+        })
+    });
+}
+
+fn pick_note_bench(c: &mut Criterion) {
+    // Notes spread over 16 lanes and a few seconds around the press, like the spawned notes the
+    // judge system searches.
+    let notes: Vec<_> = (0..5_000)
+        .map(|i| {
+            let diff = (i as f32 / 5_000. - 0.5) * 4.;
+            let position = Point2::new((i % 16) as f32 / 16., 0.5);
+            (i, diff, position)
+        })
+        .collect();
+    let windows = JudgeWindows::default();
+    let input = Vector2::new(0.5, 0.5);
+    c.bench_function("pick_note 5k notes", |b| {
+        b.iter(|| {
+            pick_note(
+                black_box(&notes).iter().cloned(),
+                input,
+                &windows,
+                false,
+                0.01,
+            )
+        })
+    });
+}
+
+/// A world with `lasers` lasers, each with `notes` notes spread over the visible window.
+fn laser_world(lasers: u32, notes: u32) -> World {
+    let mut world = World::new();
+    world.register::<Laser>();
+    world.register::<Note>();
+    world.register::<Transform>();
+    world.register::<Parent>();
+    world.insert(Time::default());
+    world.insert(Theme::default());
+    world.insert(None::<PlaySettings>);
+    world.insert(Settings::default());
+    world.insert(LaserOptions::default());
+    world.insert(ActiveModifiers::default());
+    world.insert(ChartState {
+        draw_window: 0. ..4.,
+        ..Default::default()
+    });
+    let mut hierarchy = HierarchySystemDesc::<Parent>::default().build(&mut world);
+    for id in 0..lasers {
+        let mut transform = Transform::default();
+        transform.set_translation_y(id as f32 * 0.1);
+        let laser = world
+            .create_entity()
+            .with(Laser {
+                id: LaserId(id),
+                color: LinSrgb::new(0., 0.1, 0.8),
+                far_color: LinSrgb::new(0., 0.1, 0.8),
+                texture: None,
+                scroll: 0.,
+                lanes: 8,
+                player: 0,
+                note_style: NoteStyle::default(),
+                blend: LaserBlend::default(),
+                stencil: 1,
+                animation: None,
+            })
+            .with(transform)
+            .build();
+        for i in 0..notes {
+            let position = i as f32 / notes as f32 * 4.;
+            let mut transform = Transform::default();
+            transform.set_translation_xyz((i % 8) as f32 / 8., 0., position);
+            world
+                .create_entity()
+                .with(Note {
+                    time: position / 2.,
+                    player: 0,
+                    chord: None,
+                    kind: NoteKind::Tap,
+                    end_position: position,
+                    ticks: Vec::new(),
+                    tick_positions: Vec::new(),
+                })
+                .with(transform)
+                .with(Parent::new(laser))
+                .build();
+        }
+    }
+    hierarchy.run_now(&world);
+    world.maintain();
+    world
+}
+
+fn build_instances_bench(c: &mut Criterion) {
+    let world = laser_world(8, 500);
+    let post_transforms = [Matrix4::identity()];
+    c.bench_function("build_instances 8 lasers 4k notes", |b| {
+        b.iter(|| build_instances(black_box(&world), &post_transforms))
+    });
+}
+
+criterion_group!(
+    benches,
+    position_for_time_bench,
+    pick_note_bench,
+    build_instances_bench
+);
+criterion_main!(benches);
@@ -0,0 +1,14 @@
+#version 450
+
+layout(set = 1, binding = 0) uniform sampler2D image;
+
+layout(location = 0) in VertexData {
+    vec2 tex_coord;
+    vec4 color;
+} vertex;
+
+layout(location = 0) out vec4 color;
+
+void main() {
+    color = vertex.color * texture(image, vertex.tex_coord);
+}
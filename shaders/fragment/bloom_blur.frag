@@ -0,0 +1,21 @@
+#version 450
+
+const float weights[5] = float[](0.227027, 0.1945946, 0.1216216, 0.054054, 0.016216);
+
+layout(push_constant) uniform Params {
+    vec2 direction;
+};
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+
+layout(location = 0) in vec2 tex_coord;
+layout(location = 0) out vec4 color;
+
+void main() {
+    vec3 c = texture(source, tex_coord).rgb * weights[0];
+    for (int i = 1; i < 5; i++) {
+        c += texture(source, tex_coord + direction * i).rgb * weights[i];
+        c += texture(source, tex_coord - direction * i).rgb * weights[i];
+    }
+    color = vec4(c, 1.);
+}
@@ -0,0 +1,16 @@
+#version 450
+
+layout(push_constant) uniform Params {
+    float threshold;
+};
+
+layout(set = 0, binding = 0) uniform sampler2D scene;
+
+layout(location = 0) in vec2 tex_coord;
+layout(location = 0) out vec4 color;
+
+void main() {
+    vec3 c = texture(scene, tex_coord).rgb;
+    float luminance = dot(c, vec3(0.2126, 0.7152, 0.0722));
+    color = vec4(c * smoothstep(threshold, threshold + 0.2, luminance), 1.);
+}
@@ -0,0 +1,17 @@
+#version 450
+
+layout(push_constant) uniform Params {
+    float intensity;
+};
+
+layout(set = 0, binding = 0) uniform sampler2D scene;
+layout(set = 0, binding = 1) uniform sampler2D bloom;
+
+layout(location = 0) in vec2 tex_coord;
+layout(location = 0) out vec4 color;
+
+void main() {
+    vec3 hdr = texture(scene, tex_coord).rgb + texture(bloom, tex_coord).rgb * intensity;
+    // Reinhard tone mapping.
+    color = vec4(hdr / (1. + hdr), 1.);
+}
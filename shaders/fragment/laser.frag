@@ -7,19 +7,34 @@ layout(set = 1, binding = 0) uniform LaserArgs {
     vec3 basis;
     mat4 pre_transform;
     mat4 post_transform;
+    float time;
+    vec2 scroll;
+    float edge_aa;
 };
 
+layout(set = 2, binding = 0) uniform sampler2D surface;
+
 layout(location = 0) in VertexData {
     vec3 position;
     vec2 tex_coord;
     vec4 color;
+    vec4 far_color;
+    float scroll;
 } vertex;
 
 layout(location = 0) out vec4 color;
 
 void main() {
-    color = vertex.color;
+    color = mix(vertex.color, vertex.far_color, vertex.tex_coord.y);
+    color *= texture(surface, vertex.tex_coord + vec2(0., vertex.scroll));
     color.rgb *= pow(attenuation, distance(vertex.position, basis));
     float x_scan = vertex.tex_coord.x * 2. - 1.;
     color.rgb *= 1. / max(0.16, sqrt(1. - x_scan * x_scan)) / PI;
+    if (edge_aa > 0.5) {
+        // Fade out over the last pixel at each edge of the quad, which also works at steep
+        // angles where MSAA samples are sparse. Blending is additive, so the color is the coverage.
+        vec2 edge = min(vertex.tex_coord, 1. - vertex.tex_coord);
+        vec2 coverage = smoothstep(vec2(0.), fwidth(vertex.tex_coord), edge);
+        color.rgb *= coverage.x * coverage.y;
+    }
 }
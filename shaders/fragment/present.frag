@@ -0,0 +1,10 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D scene;
+
+layout(location = 0) in vec2 tex_coord;
+layout(location = 0) out vec4 color;
+
+void main() {
+    color = vec4(texture(scene, tex_coord).rgb, 1.);
+}
@@ -0,0 +1,25 @@
+#version 450
+
+layout(std140, set = 0, binding = 0) uniform Projview {
+    mat4 proj;
+    mat4 view;
+};
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec2 tex_coord;
+layout(location = 2) in vec4 center; // instance rate
+layout(location = 3) in vec4 tint; // instance rate
+
+layout(location = 0) out VertexData {
+    vec2 tex_coord;
+    vec4 color;
+} vertex;
+
+void main() {
+    // Expand the quad in view space so that it always faces the camera.
+    vec4 view_position = view * vec4(center.xyz, 1.0);
+    view_position.xy += position.xy * center.w;
+    vertex.tex_coord = tex_coord;
+    vertex.color = tint;
+    gl_Position = proj * view_position;
+}
@@ -0,0 +1,9 @@
+#version 450
+
+layout(location = 0) out vec2 tex_coord;
+
+void main() {
+    // A single triangle covering the whole viewport.
+    tex_coord = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    gl_Position = vec4(tex_coord * 2. - 1., 0., 1.);
+}
@@ -9,23 +9,35 @@ layout(set = 1, binding = 0) uniform LaserArgs {
     vec3 basis;
     mat4 pre_transform;
     mat4 post_transform;
+    float time;
+    vec2 scroll;
+    float edge_aa;
 };
 
 layout(location = 0) in vec3 position;
 layout(location = 1) in vec2 tex_coord;
 layout(location = 2) in mat4 model; // instance rate
 layout(location = 6) in vec4 tint; // instance rate
+layout(location = 7) in vec4 far_tint; // instance rate
+layout(location = 8) in vec4 surface; // instance rate
 
 layout(location = 0) out VertexData {
     vec3 position;
     vec2 tex_coord;
     vec4 color;
+    vec4 far_color;
+    float scroll;
 } vertex;
 
 void main() {
-    vec4 vertex_position = post_transform * model * pre_transform * vec4(position, 1.0);
+    vec4 local_position = model * pre_transform * vec4(position, 1.0);
+    // Map the visible part of the highway to 0..1 before projecting it.
+    local_position.z = (local_position.z - scroll.x) / (scroll.y - scroll.x);
+    vec4 vertex_position = post_transform * local_position;
     vertex.position = vertex_position.xyz / vertex_position.w;
     vertex.tex_coord = tex_coord;
     vertex.color = tint;
+    vertex.far_color = far_tint;
+    vertex.scroll = fract(surface.x * time);
     gl_Position = proj * view * vertex_position;
 }
@@ -0,0 +1,200 @@
+use crate::geometry;
+use crate::hud::HUD_FONT_SIZE;
+use crate::judge::{Judgement, JUDGE_FONT_SIZE};
+use crate::settings::Settings;
+use crate::ui::percent_transform;
+use crate::InterFont;
+use amethyst::{
+    assets::{AssetStorage, Handle, Loader, ProgressCounter},
+    ecs::Entity,
+    prelude::*,
+    renderer::{formats::texture::ImageFormat, Texture},
+    ui::{Anchor, TtfFormat, UiImage, UiText, UiTransform},
+    window::{ScreenDimensions, Window},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Assets loaded before gameplay starts, relative to the resources directory.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct AssetManifest {
+    pub font: String,
+    /// UI textures by name.
+    pub textures: BTreeMap<String, String>,
+}
+
+impl Default for AssetManifest {
+    fn default() -> Self {
+        Self {
+            font: "Inter-Regular.ttf".into(),
+            textures: BTreeMap::new(),
+        }
+    }
+}
+
+/// Frames the warmup text stays drawn for, so that its glyphs are in the glyph cache before
+/// gameplay needs them.
+const WARMUP_FRAMES: u32 = 2;
+/// Characters of the HUD: the score, the pacemaker difference and the BPM.
+const HUD_CHARACTERS: &str = "0123456789+-BPM>";
+/// Drawn, but too faint to see.
+const WARMUP_COLOR: [f32; 4] = [1., 1., 1., 0.01];
+
+/// UI textures from the [`AssetManifest`] by name.
+#[derive(Default)]
+pub struct UiTextures(pub BTreeMap<String, Handle<Texture>>);
+
+/// Loads the [`AssetManifest`] while showing a progress bar, then switches to the next state.
+///
+/// Missing assets are reported in the log instead of aborting; the game continues without them.
+///
+/// Once loaded, the judgements and the characters of the HUD are drawn faintly at their play sizes
+/// for a few frames, so that the first PERFECT of a session doesn't hitch on rasterizing glyphs.
+pub struct LoadingState {
+    manifest: AssetManifest,
+    next: Option<Box<dyn State<GameData<'static, 'static>, StateEvent>>>,
+    progress: ProgressCounter,
+    bar: Option<Entity>,
+    /// The warmup text, once loading is complete.
+    warmup: Vec<Entity>,
+    /// Frames left to draw the warmup text for.
+    warmup_frames: u32,
+}
+
+impl LoadingState {
+    pub fn new(
+        manifest: AssetManifest,
+        next: Box<dyn State<GameData<'static, 'static>, StateEvent>>,
+    ) -> Self {
+        Self {
+            manifest,
+            next: Some(next),
+            progress: ProgressCounter::new(),
+            bar: None,
+            warmup: Vec::new(),
+            warmup_frames: 0,
+        }
+    }
+
+    /// Draws the text of the judgement popups and the HUD to fill the glyph cache.
+    fn start_warmup(&mut self, world: &mut World) {
+        let font = world.read_resource::<InterFont>().0.clone();
+        let scale = {
+            let settings = world.read_resource::<Settings>();
+            let dimensions = world.read_resource::<ScreenDimensions>();
+            settings.ui.scale_factor(&dimensions)
+        };
+        let judgements: Vec<_> = [Judgement::Perfect, Judgement::Near, Judgement::Miss]
+            .iter()
+            .map(|judgement| judgement.text())
+            .collect();
+        let texts = [
+            (judgements.join(" "), JUDGE_FONT_SIZE),
+            (HUD_CHARACTERS.to_string(), HUD_FONT_SIZE),
+        ];
+        for (text, font_size) in texts.iter() {
+            let entity = world
+                .create_entity()
+                .with(percent_transform("Warmup", Anchor::Middle, 0., 0., 1., 0.1))
+                .with(UiText::new(
+                    font.clone(),
+                    text.clone(),
+                    WARMUP_COLOR,
+                    font_size * scale,
+                ))
+                .build();
+            self.warmup.push(entity);
+        }
+        self.warmup_frames = WARMUP_FRAMES;
+    }
+}
+
+impl SimpleState for LoadingState {
+    fn on_start(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        // The first state to run, so the window is moved before anything is shown in it.
+        geometry::restore_position(
+            &world.read_resource::<Settings>().window,
+            &world.read_resource::<Window>(),
+        );
+
+        let (font, textures) = {
+            let loader = world.read_resource::<Loader>();
+            let font = loader.load(
+                self.manifest.font.as_str(),
+                TtfFormat,
+                &mut self.progress,
+                &world.read_resource(),
+            );
+            let texture_storage = world.read_resource::<AssetStorage<Texture>>();
+            let progress = &mut self.progress;
+            let textures = self
+                .manifest
+                .textures
+                .iter()
+                .map(|(name, path)| {
+                    let handle = loader.load(
+                        path.as_str(),
+                        ImageFormat::default(),
+                        &mut *progress,
+                        &texture_storage,
+                    );
+                    (name.clone(), handle)
+                })
+                .collect();
+            (font, textures)
+        };
+        world.insert(InterFont(font));
+        world.insert(UiTextures(textures));
+
+        let mut transform = percent_transform("LoadingBar", Anchor::Middle, -0.3, 0., 0., 0.01);
+        transform.pivot = Anchor::MiddleLeft;
+        self.bar = Some(
+            world
+                .create_entity()
+                .with(transform)
+                .with(UiImage::SolidColor([1., 1., 1., 1.]))
+                .build(),
+        );
+    }
+
+    fn update(&mut self, data: &mut StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
+        let world = &mut data.world;
+        if let Some(bar) = self.bar {
+            let fraction = if self.progress.num_assets() == 0 {
+                1.
+            } else {
+                self.progress.num_finished() as f32 / self.progress.num_assets() as f32
+            };
+            if let Some(transform) = world.write_storage::<UiTransform>().get_mut(bar) {
+                transform.width = 0.6 * fraction;
+            }
+        }
+        if !self.progress.is_complete() {
+            return Trans::None;
+        }
+        if let Some(bar) = self.bar.take() {
+            for error in self.progress.errors() {
+                log::error!(
+                    "Failed to load {} `{}`: {}",
+                    error.asset_type_name,
+                    error.asset_name,
+                    error.error
+                );
+            }
+            world.delete_entity(bar).unwrap();
+            self.start_warmup(world);
+        }
+        if self.warmup_frames > 0 {
+            self.warmup_frames -= 1;
+            return Trans::None;
+        }
+        for entity in self.warmup.drain(..) {
+            world.delete_entity(entity).unwrap();
+        }
+        match self.next.take() {
+            Some(next) => Trans::Switch(next),
+            None => Trans::None,
+        }
+    }
+}
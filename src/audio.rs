@@ -0,0 +1,280 @@
+use crate::chart::PlaySettings;
+use crate::error::AudioError;
+use crate::settings::{AudioSettings, FramePacing, Settings};
+use amethyst::{
+    core::timing::Time,
+    ecs::{Read, ReadExpect, System, Write},
+};
+use rodio::{source::Zero, Device, Sample, Sink, Source};
+use std::sync::{
+    atomic::{AtomicU32, AtomicU64, Ordering},
+    Arc,
+};
+use std::time::Duration;
+
+const CHANNELS: u16 = 2;
+const SAMPLE_RATE: u32 = 48000;
+/// Fraction of the drift between the audio clock and the frame clock corrected each frame.
+const PACING_GAIN: f64 = 0.05;
+
+/// A clock advanced by the audio device's callback as it consumes samples.
+#[derive(Clone)]
+pub struct AudioClock {
+    samples: Arc<AtomicU64>,
+    /// Samples per second over all channels.
+    rate: u64,
+}
+
+impl AudioClock {
+    pub fn seconds(&self) -> f64 {
+        self.samples.load(Ordering::Relaxed) as f64 / self.rate as f64
+    }
+}
+
+/// Counts the samples pulled out of a source into an [`AudioClock`].
+struct Clocked<S> {
+    inner: S,
+    samples: Arc<AtomicU64>,
+}
+
+impl<S: Source> Iterator for Clocked<S>
+where
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        let sample = self.inner.next();
+        if sample.is_some() {
+            self.samples.fetch_add(1, Ordering::Relaxed);
+        }
+        sample
+    }
+}
+
+impl<S: Source> Source for Clocked<S>
+where
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+/// Mixer channels with separate volumes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Channel {
+    Music,
+    Keysound,
+    Effect,
+    Judge,
+}
+
+/// A volume shared with the sources playing on the audio thread, stored as `f32` bits.
+#[derive(Clone)]
+struct Volume(Arc<AtomicU32>);
+
+impl Volume {
+    fn new(volume: f32) -> Self {
+        Volume(Arc::new(AtomicU32::new(volume.to_bits())))
+    }
+
+    fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn set(&self, volume: f32) {
+        self.0.store(volume.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Applies the current volume of a mixer channel to a source while it plays.
+struct Mixed<S> {
+    inner: S,
+    volume: Volume,
+}
+
+impl<S: Source> Iterator for Mixed<S>
+where
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        self.inner
+            .next()
+            .map(|sample| sample.amplify(self.volume.get()))
+    }
+}
+
+impl<S: Source> Source for Mixed<S>
+where
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+/// The audio output stream. It always plays at least silence so that the clock keeps running.
+pub struct AudioOutput {
+    device: Device,
+    _sink: Sink,
+    pub clock: AudioClock,
+    music: Volume,
+    keysound: Volume,
+    effect: Volume,
+    judge: Volume,
+}
+
+impl AudioOutput {
+    /// Opens the configured output device, falling back to the default one if it is not found.
+    pub fn open(settings: &AudioSettings) -> Result<Self, AudioError> {
+        let configured = settings.device.as_ref().and_then(|name| {
+            let device = rodio::output_devices().find(|device| &device.name() == name);
+            if device.is_none() {
+                log::warn!(
+                    "Audio device `{}` not found, using the default device",
+                    name
+                );
+            }
+            device
+        });
+        let device = configured
+            .or_else(rodio::default_output_device)
+            .ok_or(AudioError::NoDevice)?;
+        let sink = Sink::new(&device);
+        let samples = Arc::new(AtomicU64::new(0));
+        sink.append(Clocked {
+            inner: Zero::<f32>::new(CHANNELS, SAMPLE_RATE),
+            samples: samples.clone(),
+        });
+        Ok(Self {
+            device,
+            _sink: sink,
+            clock: AudioClock {
+                samples,
+                rate: SAMPLE_RATE as u64 * CHANNELS as u64,
+            },
+            music: Volume::new(settings.music_volume),
+            keysound: Volume::new(settings.keysound_volume),
+            effect: Volume::new(settings.effect_volume),
+            judge: Volume::new(settings.judge_volume),
+        })
+    }
+
+    fn volume(&self, channel: Channel) -> &Volume {
+        match channel {
+            Channel::Music => &self.music,
+            Channel::Keysound => &self.keysound,
+            Channel::Effect => &self.effect,
+            Channel::Judge => &self.judge,
+        }
+    }
+
+    /// Plays a source on a mixer channel. Volume changes apply to sources that are already playing.
+    pub fn play<S>(&self, channel: Channel, source: S)
+    where
+        S: Source + Send + 'static,
+        S::Item: Sample + Send,
+    {
+        rodio::play_raw(
+            &self.device,
+            Mixed {
+                inner: source.convert_samples(),
+                volume: self.volume(channel).clone(),
+            },
+        );
+    }
+}
+
+/// Applies the volumes from the settings to the mixer. Silent practice mutes the music and
+/// keysounds.
+pub struct MixerSystem;
+
+impl<'s> System<'s> for MixerSystem {
+    type SystemData = (Read<'s, Settings>, Read<'s, Option<AudioOutput>>);
+
+    fn run(&mut self, (settings, output): Self::SystemData) {
+        if let Some(output) = &*output {
+            let silent = settings.gameplay.silent_practice;
+            let song_volume = |volume: f32| if silent { 0. } else { volume };
+            output.music.set(song_volume(settings.audio.music_volume));
+            output
+                .keysound
+                .set(song_volume(settings.audio.keysound_volume));
+            output.effect.set(settings.audio.effect_volume);
+            output.judge.set(settings.audio.judge_volume);
+        }
+    }
+}
+
+/// Slews the chart's base time so that chart time follows the audio clock in
+/// [`FramePacing::Audio`] mode, unless the playback rate was changed from the console.
+///
+/// The audio clock only advances once per device buffer, so it is followed with a small gain
+/// instead of being sampled directly; this removes drift without introducing buffer-sized steps.
+#[derive(Default)]
+pub struct FramePacingSystem {
+    /// The base time last written and the audio clock reading at chart time zero.
+    origin: Option<(f64, f64)>,
+}
+
+impl<'s> System<'s> for FramePacingSystem {
+    type SystemData = (
+        ReadExpect<'s, Time>,
+        Read<'s, Settings>,
+        Read<'s, Option<AudioOutput>>,
+        Write<'s, Option<PlaySettings>>,
+    );
+
+    fn run(&mut self, (time, settings, output, mut play_settings): Self::SystemData) {
+        let (output, play_settings) = match (&*output, &mut *play_settings) {
+            // A changed playback rate is meant to drift away from the audio clock.
+            (Some(output), Some(play_settings))
+                if settings.gameplay.frame_pacing == FramePacing::Audio
+                    && time.time_scale() == 1. =>
+            {
+                (output, play_settings)
+            }
+            _ => {
+                self.origin = None;
+                return;
+            }
+        };
+        let now = time.absolute_time_seconds();
+        let audio = output.clock.seconds();
+        // Re-anchor when the base time was reset by someone else, e.g. on restart.
+        let origin = match self.origin {
+            Some((base_time, origin)) if base_time == play_settings.base_time => origin,
+            _ => audio - (now - play_settings.base_time),
+        };
+        let drift = (audio - origin) - (now - play_settings.base_time);
+        play_settings.base_time -= drift * PACING_GAIN;
+        self.origin = Some((play_settings.base_time, origin));
+    }
+}
@@ -0,0 +1,402 @@
+//! Judgement popups drawn in world space above the judge quad, so that they follow the highway
+//! when the camera tilts or zooms. Used instead of the UI popups with `ui.world_popups`. The
+//! markers of the [`HeldKeys`] are drawn the same way.
+use crate::chart::PlaySettings;
+use crate::error::RenderError;
+use crate::judge::Judgement;
+use crate::key_markers::HeldKeys;
+use crate::laser::LaserOptions;
+use crate::settings::Settings;
+use crate::theme::Theme;
+use amethyst::assets::{AssetStorage, Handle, Loader};
+use amethyst::core::{
+    ecs::{Component, DenseVecStorage, Join, Read, ReadExpect, ReadStorage, SystemData, World},
+    math::Point2,
+    timing::Time,
+};
+use amethyst::renderer::{
+    bundle::{RenderOrder, RenderPlan, RenderPlugin, Target},
+    formats::texture::ImageFormat,
+    loaders::load_from_srgba,
+    palette::Srgba,
+    pass::validate_spirv,
+    pipeline::{PipelineDescBuilder, PipelinesBuilder},
+    rendy::{
+        command::{QueueId, RenderPassEncoder},
+        factory::Factory,
+        graph::{
+            render::{PrepareResult, RenderGroup, RenderGroupDesc},
+            GraphContext, NodeBuffer, NodeImage,
+        },
+        hal::{self, device::Device, pass::Subpass, pso},
+        mesh::{AsVertex, Color, Mesh, MeshBuilder, PosTex, VertexFormat},
+        shader::{ShaderSetBuilder, SpirvShader},
+    },
+    submodules::{DynamicVertexBuffer, EnvironmentSub, TextureId, TextureSub},
+    types::{Backend, Texture},
+};
+use glsl_layout::*;
+use std::marker::PhantomData;
+
+/// Seconds a popup is shown, matching the UI popups.
+pub const POPUP_SECONDS: f64 = 0.3;
+/// Size of a popup as a fraction of the width of its player's judge quad.
+const POPUP_SIZE: f32 = 0.12;
+/// Height of a popup's center above its note when it appears and when it leaves, as a fraction of
+/// the judge quad's height.
+const POPUP_RISE: (f32, f32) = (0.3, 0.6);
+const JUDGEMENTS: [Judgement; 3] = [Judgement::Perfect, Judgement::Near, Judgement::Miss];
+/// Size of a held key's marker as a fraction of the width of its player's judge quad.
+const KEY_MARKER_SIZE: f32 = 0.05;
+/// Faint, so that the notes stay readable through the markers.
+const KEY_MARKER_COLOR: [f32; 4] = [1., 1., 1., 0.2];
+
+/// A judgement popup, deleted by the judge system once it is older than [`POPUP_SECONDS`].
+pub struct Billboard {
+    pub judgement: Judgement,
+    pub player: u8,
+    /// Position of the judged note on the player's highway, across it in x and up it in y, both
+    /// from 0 to 1.
+    pub position: Point2<f32>,
+    /// Real time the popup appeared at.
+    pub shown_at: f64,
+}
+
+impl Component for Billboard {
+    type Storage = DenseVecStorage<Self>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, AsStd140)]
+#[repr(C, align(4))]
+struct BillboardInstance {
+    /// World position of the center in xyz and the size in w.
+    center: vec4,
+    tint: vec4,
+}
+
+impl AsVertex for BillboardInstance {
+    fn vertex() -> VertexFormat {
+        VertexFormat::new((Color::vertex(), Color::vertex()))
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref BILLBOARD_VERTEX: SpirvShader = SpirvShader::new(
+        validate_spirv(include_bytes!("../compiled/vertex/billboard.vert.spv")),
+        pso::ShaderStageFlags::VERTEX,
+        "main",
+    );
+
+    static ref BILLBOARD_FRAGMENT: SpirvShader = SpirvShader::new(
+        validate_spirv(include_bytes!("../compiled/fragment/billboard.frag.spv")),
+        pso::ShaderStageFlags::FRAGMENT,
+        "main",
+    );
+
+    static ref BILLBOARD_SHADERS: ShaderSetBuilder = ShaderSetBuilder::default()
+        .with_vertex(&*BILLBOARD_VERTEX).unwrap()
+        .with_fragment(&*BILLBOARD_FRAGMENT).unwrap();
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DrawBillboardDesc<B: Backend> {
+    marker: PhantomData<B>,
+}
+
+impl<B: Backend> DrawBillboardDesc<B> {
+    pub fn new() -> Self {
+        Self {
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<B: Backend> RenderGroupDesc<B, World> for DrawBillboardDesc<B> {
+    fn build(
+        self,
+        _: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        queue: QueueId,
+        world: &World,
+        framebuffer_width: u32,
+        framebuffer_height: u32,
+        subpass: Subpass<B>,
+        _: Vec<NodeBuffer>,
+        _: Vec<NodeImage>,
+    ) -> Result<Box<dyn RenderGroup<B, World>>, failure::Error> {
+        let env = EnvironmentSub::new(factory, [pso::ShaderStageFlags::VERTEX])
+            .map_err(|e| RenderError::Resources(e.to_string()))?;
+        let textures =
+            TextureSub::new(factory).map_err(|e| RenderError::Resources(e.to_string()))?;
+        let pipeline_layout = unsafe {
+            factory.device().create_pipeline_layout(
+                [env.raw_layout(), textures.raw_layout()].iter().cloned(),
+                None as Option<(_, _)>,
+            )
+        }
+        .map_err(|e| RenderError::Pipeline(e.to_string()))?;
+
+        let vertex_desc = vec![
+            (PosTex::vertex(), pso::VertexInputRate::Vertex),
+            (
+                BillboardInstance::vertex(),
+                pso::VertexInputRate::Instance(1),
+            ),
+        ];
+
+        let mut shaders = BILLBOARD_SHADERS
+            .build(factory, Default::default())
+            .map_err(|e| RenderError::Shaders(e.to_string()))?;
+
+        // No depth test: popups stay in front of the highway they are floating above.
+        let pipe_desc = PipelineDescBuilder::new()
+            .with_vertex_desc(&vertex_desc)
+            .with_shaders(
+                shaders
+                    .raw()
+                    .map_err(|e| RenderError::Shaders(e.to_string()))?,
+            )
+            .with_layout(&pipeline_layout)
+            .with_subpass(subpass)
+            .with_framebuffer_size(framebuffer_width, framebuffer_height)
+            .with_blend_targets(vec![pso::ColorBlendDesc {
+                mask: pso::ColorMask::ALL,
+                blend: Some(pso::BlendState::ALPHA),
+            }]);
+
+        let mut pipelines = PipelinesBuilder::new()
+            .with_pipeline(pipe_desc)
+            .build(factory, None)
+            .map_err(|e| RenderError::Pipeline(e.to_string()))?;
+
+        shaders.dispose(factory);
+
+        // Centered on the origin, with the image upright.
+        let mesh = MeshBuilder::new()
+            .with_vertices(
+                [
+                    ([-0.5, -0.5, 0.], [0., 1.]),
+                    ([0.5, -0.5, 0.], [1., 1.]),
+                    ([0.5, 0.5, 0.], [1., 0.]),
+                    ([-0.5, 0.5, 0.], [0., 0.]),
+                ]
+                .iter()
+                .cloned()
+                .map(|(p, t)| PosTex {
+                    position: p.into(),
+                    tex_coord: t.into(),
+                })
+                .collect::<Vec<_>>(),
+            )
+            .with_indices(&[0u32, 1, 2, 0, 2, 3][..])
+            .build(queue, factory)
+            .map_err(|e| RenderError::Mesh(e.to_string()))?;
+
+        let loader = world.read_resource::<Loader>();
+        let storage = world.read_resource::<AssetStorage<Texture>>();
+        let white_texture = loader.load_from_data(
+            load_from_srgba(Srgba::new(1., 1., 1., 1.)).into(),
+            (),
+            &storage,
+        );
+        let theme = world.read_resource::<Theme>();
+        let load = |path: &Option<String>| {
+            path.as_ref()
+                .map(|path| loader.load(path.as_str(), ImageFormat::default(), (), &storage))
+        };
+        let images = &theme.judge_images;
+        let judge_textures = [
+            load(&images.perfect),
+            load(&images.near),
+            load(&images.miss),
+        ];
+
+        Ok(Box::new(DrawBillboard::<B> {
+            pipeline: pipelines.pop().unwrap(),
+            pipeline_layout,
+            env,
+            textures,
+            white_texture,
+            judge_textures,
+            texture_ids: Vec::new(),
+            buffer: DynamicVertexBuffer::new(),
+            ranges: Vec::new(),
+            mesh,
+        }))
+    }
+}
+
+#[derive(Debug)]
+pub struct DrawBillboard<B: Backend> {
+    pipeline: B::GraphicsPipeline,
+    pipeline_layout: B::PipelineLayout,
+    env: EnvironmentSub<B>,
+    textures: TextureSub<B>,
+    white_texture: Handle<Texture>,
+    /// Images of each judgement from the theme, in the order of [`JUDGEMENTS`].
+    judge_textures: [Option<Handle<Texture>>; 3],
+    /// Texture bound for each judgement this frame, then for the key markers.
+    texture_ids: Vec<TextureId>,
+    buffer: DynamicVertexBuffer<B, BillboardInstance>,
+    /// Instances of each judgement this frame, in the order of [`JUDGEMENTS`], then of the key
+    /// markers.
+    ranges: Vec<std::ops::Range<u32>>,
+    mesh: Mesh<B>,
+}
+
+impl<B: Backend> RenderGroup<B, World> for DrawBillboard<B> {
+    fn prepare(
+        &mut self,
+        factory: &Factory<B>,
+        _: QueueId,
+        index: usize,
+        _: Subpass<B>,
+        world: &World,
+    ) -> PrepareResult {
+        let (time, settings, user_settings, options, held_keys, billboards) =
+            <(
+                ReadExpect<Time>,
+                Read<Option<PlaySettings>>,
+                Read<Settings>,
+                ReadExpect<LaserOptions>,
+                Read<HeldKeys>,
+                ReadStorage<Billboard>,
+            )>::fetch(world);
+        self.ranges.clear();
+        self.texture_ids.clear();
+        if options.judge_quad.len() < 4 {
+            return PrepareResult::DrawRecord;
+        }
+        self.env.process(factory, index, world);
+        self.textures.maintain(factory, world);
+
+        let players = settings.as_ref().map_or(1, |s| s.players.max(1));
+        let now = time.absolute_real_time_seconds();
+        let mut instances = Vec::new();
+        for (i, &judgement) in JUDGEMENTS.iter().enumerate() {
+            let texture = self.judge_textures[i].as_ref();
+            // Judgements without an image are plain quads in their color.
+            let (handle, [r, g, b, a]) = match texture {
+                Some(handle) => (handle, [1.; 4]),
+                None => (&self.white_texture, judgement.color()),
+            };
+            let id = match self.textures.insert(
+                factory,
+                world,
+                handle,
+                hal::image::Layout::ShaderReadOnlyOptimal,
+            ) {
+                Some((id, _)) => id,
+                None => continue,
+            };
+            let start = instances.len() as u32;
+            for billboard in billboards.join().filter(|b| b.judgement == judgement) {
+                let (quad, _) = options.player_quad(billboard.player, players);
+                let age = ((now - billboard.shown_at) / POPUP_SECONDS).min(1.) as f32;
+                let up = quad[3] - quad[0];
+                let rise = if user_settings.accessibility.reduced_motion {
+                    POPUP_RISE.0
+                } else {
+                    POPUP_RISE.0 + (POPUP_RISE.1 - POPUP_RISE.0) * age
+                };
+                let center = quad[0]
+                    + (quad[1] - quad[0]) * billboard.position.x
+                    + up * (billboard.position.y + rise);
+                let size = (quad[1] - quad[0]).norm() * POPUP_SIZE;
+                // Fades out over the second half.
+                let alpha = a * (2. - 2. * age).min(1.);
+                instances.push(BillboardInstance {
+                    center: [center.x, center.y, center.z, size].into(),
+                    tint: [r, g, b, alpha].into(),
+                });
+            }
+            self.texture_ids.push(id);
+            self.ranges.push(start..instances.len() as u32);
+        }
+        if let Some((id, _)) = self.textures.insert(
+            factory,
+            world,
+            &self.white_texture,
+            hal::image::Layout::ShaderReadOnlyOptimal,
+        ) {
+            let start = instances.len() as u32;
+            for &(player, position) in &held_keys.0 {
+                let (quad, _) = options.player_quad(player, players);
+                let center =
+                    quad[0] + (quad[1] - quad[0]) * position.x + (quad[3] - quad[0]) * position.y;
+                let size = (quad[1] - quad[0]).norm() * KEY_MARKER_SIZE;
+                instances.push(BillboardInstance {
+                    center: [center.x, center.y, center.z, size].into(),
+                    tint: KEY_MARKER_COLOR.into(),
+                });
+            }
+            self.texture_ids.push(id);
+            self.ranges.push(start..instances.len() as u32);
+        }
+        self.buffer.write(
+            factory,
+            index,
+            std::cmp::max(instances.len() as u64, 1),
+            &[&instances],
+        );
+        PrepareResult::DrawRecord
+    }
+
+    fn draw_inline(
+        &mut self,
+        mut encoder: RenderPassEncoder<B>,
+        index: usize,
+        _: Subpass<B>,
+        _: &World,
+    ) {
+        if self.ranges.iter().all(|range| range.start == range.end) {
+            return;
+        }
+        encoder.bind_graphics_pipeline(&self.pipeline);
+        self.env.bind(index, &self.pipeline_layout, 0, &mut encoder);
+        self.buffer.bind(index, 1, 0, &mut encoder);
+        for (range, &texture) in self.ranges.iter().zip(&self.texture_ids) {
+            if range.start == range.end {
+                continue;
+            }
+            self.textures
+                .bind(&self.pipeline_layout, 1, texture, &mut encoder);
+            self.mesh
+                .bind_and_draw(0, &[PosTex::vertex()], range.clone(), &mut encoder)
+                .unwrap();
+        }
+    }
+
+    fn dispose(self: Box<Self>, factory: &mut Factory<B>, _: &World) {
+        unsafe {
+            factory.device().destroy_graphics_pipeline(self.pipeline);
+            factory
+                .device()
+                .destroy_pipeline_layout(self.pipeline_layout);
+        }
+    }
+}
+
+/// Draws [`Billboard`]s, by default after the lasers. The theme sets where among the other
+/// plugins.
+#[derive(Debug)]
+pub struct RenderBillboard;
+
+impl<B: Backend> RenderPlugin<B> for RenderBillboard {
+    fn on_plan(
+        &mut self,
+        plan: &mut RenderPlan<B>,
+        _: &mut Factory<B>,
+        world: &World,
+    ) -> Result<(), amethyst::Error> {
+        let order: RenderOrder = world
+            .try_fetch::<Theme>()
+            .map_or(RenderOrder::AfterTransparent, |theme| {
+                theme.render_order.popups.into()
+            });
+        plan.extend_target(Target::default(), move |ctx| {
+            ctx.add(order, DrawBillboardDesc::<B>::new().builder())
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+//! Checks a tournament play bundle exported with the `bundle` console command.
+//!
+//! ```text
+//! iris-verify <bundle.json> <public key> [chart.ron]
+//! iris-verify --public-key <signing key>
+//! ```
+//!
+//! Verifies the signature with the organizer's public key, compares the chart hash with the
+//! organizer's copy of the chart if one is given and judges the replay again with the game's
+//! judge logic. Exits with 1 if anything doesn't match.
+//!
+//! The signing key is 32 random bytes in hex, e.g. from `openssl rand -hex 32`, and
+//! `--public-key` prints the public key to hand out with it.
+use iris::tournament::{self, SignedBundle};
+use std::path::Path;
+use std::process;
+
+fn main() {
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    if let [flag, key] = args.as_slice() {
+        if flag == "--public-key" {
+            match tournament::public_key(key) {
+                Ok(public) => println!("{}", public),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(2);
+                }
+            }
+            return;
+        }
+    }
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!("usage: iris-verify <bundle.json> <public key> [chart.ron]");
+        eprintln!("       iris-verify --public-key <signing key>");
+        process::exit(2);
+    }
+    let bundle = match SignedBundle::load(Path::new(&args[0])) {
+        Ok(bundle) => bundle,
+        Err(e) => {
+            eprintln!("{}: {}", args[0], e);
+            process::exit(2);
+        }
+    };
+    let mut ok = true;
+    match bundle.check_signature(&args[1]) {
+        Ok(()) => println!("Signature: ok"),
+        Err(e) => {
+            println!("Signature: {}", e);
+            ok = false;
+        }
+    }
+    if let Some(chart) = args.get(2) {
+        match std::fs::read(chart) {
+            Ok(bytes) => {
+                let hash = tournament::chart_hash(&bytes);
+                if bundle.bundle.chart_hash.as_ref() == Some(&hash) {
+                    println!("Chart: ok");
+                } else {
+                    println!(
+                        "Chart: hash {} does not match {}",
+                        bundle
+                            .bundle
+                            .chart_hash
+                            .as_ref()
+                            .map_or("none", String::as_str),
+                        hash
+                    );
+                    ok = false;
+                }
+            }
+            Err(e) => {
+                eprintln!("{}: {}", chart, e);
+                process::exit(2);
+            }
+        }
+    }
+
+    if bundle.bundle.constant_scroll {
+        println!("Scroll: constant, BPM changes were not shown");
+    }
+    let verification = bundle.bundle.verify();
+    for (player, (replayed, claimed)) in verification
+        .replayed
+        .iter()
+        .zip(&verification.claimed)
+        .enumerate()
+    {
+        println!(
+            "Player {}: claimed {} (max combo {}), replayed {} (max combo {})",
+            player + 1,
+            claimed.score,
+            claimed.max_combo,
+            replayed.score,
+            replayed.max_combo
+        );
+    }
+    for mismatch in &verification.mismatches {
+        let text = |judgement: Option<iris::judge::Judgement>| {
+            judgement.map_or("none", |judgement| judgement.text())
+        };
+        println!(
+            "Player {} note at {:.3}s: recorded {}, replayed {}",
+            mismatch.player + 1,
+            mismatch.time,
+            text(mismatch.recorded),
+            text(mismatch.replayed)
+        );
+    }
+    if !verification.is_consistent() {
+        ok = false;
+    }
+    println!("{}", if ok { "Verified" } else { "NOT verified" });
+    process::exit(if ok { 0 } else { 1 });
+}
@@ -0,0 +1,413 @@
+use crate::settings::Settings;
+use amethyst::core::ecs::World;
+use amethyst::renderer::{
+    bundle::{
+        ImageOptions, OutputColor, RenderOrder, RenderPlan, RenderPlugin, Target, TargetImage,
+        TargetPlanOutputs,
+    },
+    pass::validate_spirv,
+    pipeline::{PipelineDescBuilder, PipelinesBuilder},
+    rendy::{
+        command::{QueueId, RenderPassEncoder},
+        factory::Factory,
+        graph::{
+            render::{PrepareResult, RenderGroup, RenderGroupDesc},
+            GraphContext, ImageAccess, NodeBuffer, NodeImage,
+        },
+        hal::{
+            self,
+            device::Device,
+            format::{Aspects, Format, Swizzle},
+            image::{Filter, SamplerInfo, SubresourceRange, ViewKind, WrapMode},
+            pass::Subpass,
+            pso,
+        },
+        resource::{
+            DescriptorSet, DescriptorSetLayout, Escape, Handle, ImageView, ImageViewInfo, Sampler,
+        },
+        shader::{ShaderSetBuilder, SpirvShader},
+    },
+    types::Backend,
+    Kind,
+};
+use amethyst::window::ScreenDimensions;
+use failure::Error;
+use std::iter;
+use std::marker::PhantomData;
+
+/// The target presented to the window when the scene is rendered offscreen into `Target::Main`,
+/// see [`offscreen_enabled`].
+pub const PRESENT: Target = Target::Custom("present");
+const BRIGHT: Target = Target::Custom("bloom_bright");
+const BLUR_H: Target = Target::Custom("bloom_blur_h");
+const BLUR_V: Target = Target::Custom("bloom_blur_v");
+
+/// Format of the offscreen scene and bloom targets. Needs headroom above 1.0 for additive lasers.
+pub const HDR_FORMAT: Format = Format::Rgba16Sfloat;
+
+pub fn bloom_enabled(world: &World) -> bool {
+    world
+        .try_fetch::<Settings>()
+        .map_or(false, |s| s.graphics.bloom_intensity > 0.)
+}
+
+/// Whether the scene needs to be rendered offscreen, either for bloom or for a resolution scale.
+pub fn offscreen_enabled(world: &World) -> bool {
+    bloom_enabled(world)
+        || world
+            .try_fetch::<Settings>()
+            .map_or(false, |s| s.graphics.resolution_scale != 1.)
+}
+
+/// Size of the offscreen scene target in pixels.
+pub fn scene_size(world: &World) -> (u32, u32) {
+    let dimensions = world.read_resource::<ScreenDimensions>();
+    let scale = world
+        .try_fetch::<Settings>()
+        .map_or(1., |s| s.graphics.resolution_scale);
+    (
+        std::cmp::max((dimensions.width() * scale) as u32, 1),
+        std::cmp::max((dimensions.height() * scale) as u32, 1),
+    )
+}
+
+lazy_static::lazy_static! {
+    static ref FULLSCREEN_VERTEX: SpirvShader = SpirvShader::new(
+        validate_spirv(include_bytes!("../compiled/vertex/fullscreen.vert.spv")),
+        pso::ShaderStageFlags::VERTEX,
+        "main",
+    );
+
+    static ref BRIGHT_FRAGMENT: SpirvShader = SpirvShader::new(
+        validate_spirv(include_bytes!("../compiled/fragment/bloom_bright.frag.spv")),
+        pso::ShaderStageFlags::FRAGMENT,
+        "main",
+    );
+
+    static ref BLUR_FRAGMENT: SpirvShader = SpirvShader::new(
+        validate_spirv(include_bytes!("../compiled/fragment/bloom_blur.frag.spv")),
+        pso::ShaderStageFlags::FRAGMENT,
+        "main",
+    );
+
+    static ref PRESENT_FRAGMENT: SpirvShader = SpirvShader::new(
+        validate_spirv(include_bytes!("../compiled/fragment/present.frag.spv")),
+        pso::ShaderStageFlags::FRAGMENT,
+        "main",
+    );
+
+    static ref COMPOSITE_FRAGMENT: SpirvShader = SpirvShader::new(
+        validate_spirv(include_bytes!("../compiled/fragment/bloom_composite.frag.spv")),
+        pso::ShaderStageFlags::FRAGMENT,
+        "main",
+    );
+
+    static ref BRIGHT_SHADERS: ShaderSetBuilder = ShaderSetBuilder::default()
+        .with_vertex(&*FULLSCREEN_VERTEX).unwrap()
+        .with_fragment(&*BRIGHT_FRAGMENT).unwrap();
+
+    static ref BLUR_SHADERS: ShaderSetBuilder = ShaderSetBuilder::default()
+        .with_vertex(&*FULLSCREEN_VERTEX).unwrap()
+        .with_fragment(&*BLUR_FRAGMENT).unwrap();
+
+    static ref COMPOSITE_SHADERS: ShaderSetBuilder = ShaderSetBuilder::default()
+        .with_vertex(&*FULLSCREEN_VERTEX).unwrap()
+        .with_fragment(&*COMPOSITE_FRAGMENT).unwrap();
+
+    static ref PRESENT_SHADERS: ShaderSetBuilder = ShaderSetBuilder::default()
+        .with_vertex(&*FULLSCREEN_VERTEX).unwrap()
+        .with_fragment(&*PRESENT_FRAGMENT).unwrap();
+}
+
+/// A fullscreen pass sampling `inputs` images of the graph, parameterized by push constants.
+#[derive(Debug)]
+pub struct DrawPostProcessDesc<B: Backend> {
+    shaders: &'static ShaderSetBuilder,
+    inputs: usize,
+    params: [f32; 4],
+    marker: PhantomData<B>,
+}
+
+impl<B: Backend> DrawPostProcessDesc<B> {
+    pub fn new(shaders: &'static ShaderSetBuilder, inputs: usize, params: [f32; 4]) -> Self {
+        Self {
+            shaders,
+            inputs,
+            params,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<B: Backend> RenderGroupDesc<B, World> for DrawPostProcessDesc<B> {
+    fn images(&self) -> Vec<ImageAccess> {
+        vec![
+            ImageAccess {
+                access: hal::image::Access::SHADER_READ,
+                usage: hal::image::Usage::SAMPLED,
+                layout: hal::image::Layout::ShaderReadOnlyOptimal,
+                stages: pso::PipelineStage::FRAGMENT_SHADER,
+            };
+            self.inputs
+        ]
+    }
+
+    fn build(
+        self,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        _: QueueId,
+        _: &World,
+        framebuffer_width: u32,
+        framebuffer_height: u32,
+        subpass: Subpass<B>,
+        _: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+    ) -> Result<Box<dyn RenderGroup<B, World>>, Error> {
+        let set_layout: Handle<DescriptorSetLayout<B>> = factory
+            .create_descriptor_set_layout(
+                (0..self.inputs as u32)
+                    .map(|binding| pso::DescriptorSetLayoutBinding {
+                        binding,
+                        ty: pso::DescriptorType::CombinedImageSampler,
+                        count: 1,
+                        stage_flags: pso::ShaderStageFlags::FRAGMENT,
+                        immutable_samplers: false,
+                    })
+                    .collect(),
+            )?
+            .into();
+        let set = factory.create_descriptor_set(set_layout.clone())?;
+        let sampler = factory.get_sampler(SamplerInfo::new(Filter::Linear, WrapMode::Clamp))?;
+        let views = images
+            .iter()
+            .map(|node_image| {
+                let image = ctx.get_image(node_image.id).unwrap();
+                factory.create_image_view(
+                    image.clone(),
+                    ImageViewInfo {
+                        view_kind: ViewKind::D2,
+                        format: image.format(),
+                        swizzle: Swizzle::NO,
+                        range: SubresourceRange {
+                            aspects: Aspects::COLOR,
+                            levels: 0..1,
+                            layers: 0..1,
+                        },
+                    },
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        unsafe {
+            factory
+                .device()
+                .write_descriptor_sets(views.iter().enumerate().map(|(binding, view)| {
+                    pso::DescriptorSetWrite {
+                        set: set.raw(),
+                        binding: binding as u32,
+                        array_offset: 0,
+                        descriptors: Some(pso::Descriptor::CombinedImageSampler(
+                            view.raw(),
+                            hal::image::Layout::ShaderReadOnlyOptimal,
+                            sampler.raw(),
+                        )),
+                    }
+                }));
+        }
+
+        let pipeline_layout = unsafe {
+            factory.device().create_pipeline_layout(
+                iter::once(set_layout.raw()),
+                iter::once((pso::ShaderStageFlags::FRAGMENT, 0..16)),
+            )
+        }?;
+
+        let mut shaders = self.shaders.build(factory, Default::default())?;
+
+        let pipe_desc = PipelineDescBuilder::new()
+            .with_vertex_desc(&[])
+            .with_shaders(shaders.raw()?)
+            .with_layout(&pipeline_layout)
+            .with_subpass(subpass)
+            .with_framebuffer_size(framebuffer_width, framebuffer_height)
+            .with_blend_targets(vec![pso::ColorBlendDesc {
+                mask: pso::ColorMask::ALL,
+                blend: None,
+            }]);
+
+        let mut pipelines = PipelinesBuilder::new()
+            .with_pipeline(pipe_desc)
+            .build(factory, None)?;
+
+        shaders.dispose(factory);
+
+        Ok(Box::new(DrawPostProcess::<B> {
+            pipeline: pipelines.pop().unwrap(),
+            pipeline_layout,
+            set,
+            _views: views,
+            _sampler: sampler,
+            params: self.params,
+        }))
+    }
+}
+
+#[derive(Debug)]
+pub struct DrawPostProcess<B: Backend> {
+    pipeline: B::GraphicsPipeline,
+    pipeline_layout: B::PipelineLayout,
+    set: Escape<DescriptorSet<B>>,
+    _views: Vec<Escape<ImageView<B>>>,
+    _sampler: Handle<Sampler<B>>,
+    params: [f32; 4],
+}
+
+impl<B: Backend> RenderGroup<B, World> for DrawPostProcess<B> {
+    fn prepare(
+        &mut self,
+        _: &Factory<B>,
+        _: QueueId,
+        _: usize,
+        _: Subpass<B>,
+        _: &World,
+    ) -> PrepareResult {
+        PrepareResult::DrawReuse
+    }
+
+    fn draw_inline(
+        &mut self,
+        mut encoder: RenderPassEncoder<B>,
+        _: usize,
+        _: Subpass<B>,
+        _: &World,
+    ) {
+        let params: Vec<u32> = self.params.iter().map(|x| x.to_bits()).collect();
+        encoder.bind_graphics_pipeline(&self.pipeline);
+        unsafe {
+            encoder.bind_graphics_descriptor_sets(
+                &self.pipeline_layout,
+                0,
+                iter::once(self.set.raw()),
+                iter::empty(),
+            );
+            encoder.push_constants(
+                &self.pipeline_layout,
+                pso::ShaderStageFlags::FRAGMENT,
+                0,
+                &params,
+            );
+            encoder.draw(0..3, 0..1);
+        }
+    }
+
+    fn dispose(self: Box<Self>, factory: &mut Factory<B>, _: &World) {
+        unsafe {
+            factory.device().destroy_graphics_pipeline(self.pipeline);
+            factory
+                .device()
+                .destroy_pipeline_layout(self.pipeline_layout);
+        }
+    }
+}
+
+/// Bright-pass, separable blur and tone-mapping composite over the offscreen scene. With bloom
+/// disabled the offscreen scene is only scaled to the window.
+///
+/// Requires the window plugin to render the scene into an offscreen `Target::Main` and to present
+/// [`PRESENT`] instead; see [`offscreen_enabled`].
+#[derive(Debug, Default)]
+pub struct RenderBloom;
+
+impl<B: Backend> RenderPlugin<B> for RenderBloom {
+    fn on_plan(
+        &mut self,
+        plan: &mut RenderPlan<B>,
+        _: &mut Factory<B>,
+        world: &World,
+    ) -> Result<(), amethyst::Error> {
+        if !offscreen_enabled(world) {
+            return Ok(());
+        }
+        if !bloom_enabled(world) {
+            plan.extend_target(PRESENT, move |ctx| {
+                let scene = ctx.get_image(TargetImage::Color(Target::Main, 0))?;
+                ctx.add(
+                    RenderOrder::Opaque,
+                    DrawPostProcessDesc::<B>::new(&*PRESENT_SHADERS, 1, [0.; 4])
+                        .builder()
+                        .with_image(scene),
+                )?;
+                Ok(())
+            });
+            return Ok(());
+        }
+        let (intensity, threshold) = {
+            let settings = world.read_resource::<Settings>();
+            (
+                settings.graphics.bloom_intensity,
+                settings.graphics.bloom_threshold,
+            )
+        };
+        let (scene_width, scene_height) = scene_size(world);
+        let width = std::cmp::max(scene_width / 2, 1);
+        let height = std::cmp::max(scene_height / 2, 1);
+        let half_res = || TargetPlanOutputs {
+            colors: vec![OutputColor::Image(ImageOptions {
+                kind: Kind::D2(width, height, 1, 1),
+                levels: 1,
+                format: HDR_FORMAT,
+                clear: None,
+            })],
+            depth: None,
+        };
+        plan.define_pass(BRIGHT, half_res())?;
+        plan.define_pass(BLUR_H, half_res())?;
+        plan.define_pass(BLUR_V, half_res())?;
+
+        let passes = [
+            (
+                BRIGHT,
+                Target::Main,
+                &*BRIGHT_SHADERS,
+                [threshold, 0., 0., 0.],
+            ),
+            (
+                BLUR_H,
+                BRIGHT,
+                &*BLUR_SHADERS,
+                [1. / width as f32, 0., 0., 0.],
+            ),
+            (
+                BLUR_V,
+                BLUR_H,
+                &*BLUR_SHADERS,
+                [0., 1. / height as f32, 0., 0.],
+            ),
+        ];
+        for &(target, source, shaders, params) in passes.iter() {
+            plan.extend_target(target, move |ctx| {
+                let image = ctx.get_image(TargetImage::Color(source, 0))?;
+                ctx.add(
+                    RenderOrder::Opaque,
+                    DrawPostProcessDesc::<B>::new(shaders, 1, params)
+                        .builder()
+                        .with_image(image),
+                )?;
+                Ok(())
+            });
+        }
+        plan.extend_target(PRESENT, move |ctx| {
+            let scene = ctx.get_image(TargetImage::Color(Target::Main, 0))?;
+            let bloom = ctx.get_image(TargetImage::Color(BLUR_V, 0))?;
+            ctx.add(
+                RenderOrder::Opaque,
+                DrawPostProcessDesc::<B>::new(&*COMPOSITE_SHADERS, 2, [intensity, 0., 0., 0.])
+                    .builder()
+                    .with_image(scene)
+                    .with_image(bloom),
+            )?;
+            Ok(())
+        });
+        Ok(())
+    }
+}
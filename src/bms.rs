@@ -0,0 +1,216 @@
+//! The bodies of BMS charts: their notes and BPM changes, read for thumbnails and converted to
+//! native charts on import.
+//!
+//! Only the 1P lanes are read. Stops (channel 09) are ignored, and long notes become taps at
+//! their start.
+use crate::chart::timing::time_for_position;
+use crate::chart::{BpmCommand, Chart, LaserCommand, LaserId, Note, NoteKind, Timed};
+use std::collections::HashMap;
+
+/// Channels of the lanes from left to right: the scratch, then keys 1 to 7. Long notes use the
+/// same lanes on channels 5x.
+pub const LANE_CHANNELS: [u8; 8] = [0x16, 0x11, 0x12, 0x13, 0x14, 0x15, 0x18, 0x19];
+/// Offset of the long note channels from the lanes they are on.
+const LONG_NOTE_OFFSET: u8 = 0x40;
+const LENGTH_CHANNEL: u8 = 0x02;
+/// BPM changes written as hexadecimal integers.
+const BPM_CHANNEL: u8 = 0x03;
+/// BPM changes referring to `#BPMxx` definitions.
+const EXTENDED_BPM_CHANNEL: u8 = 0x08;
+/// BPM of charts without `#BPM`.
+pub const DEFAULT_BPM: f32 = 130.;
+/// Beats in a measure of length 1.
+const BEATS_PER_MEASURE: f32 = 4.;
+
+/// A note of a BMS chart in measures from the start.
+#[derive(Copy, Clone, Debug)]
+pub struct BmsNote {
+    pub position: f32,
+    pub lane: usize,
+    /// Whether the note starts or ends a long note.
+    pub long: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BmsChart {
+    /// Sorted by position.
+    pub notes: Vec<BmsNote>,
+    /// Positions in measures and the BPM from there on, sorted by position.
+    pub bpm_changes: Vec<(f32, f32)>,
+}
+
+enum Object {
+    Note { lane: usize, long: bool },
+    Bpm(f32),
+    ExtendedBpm(String),
+}
+
+/// Reads the notes of the 1P lanes and the BPM changes of a BMS chart. Measure lengths from
+/// channel 02 are applied to the positions.
+pub fn read(text: &str) -> BmsChart {
+    let mut lengths = Vec::new();
+    let mut definitions = HashMap::new();
+    let mut objects = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        let (command, data) = match line.find(':') {
+            Some(split) if line.starts_with('#') => (&line[1..split], line[split + 1..].trim()),
+            _ => {
+                // Extended BPM definitions, `#BPMxx value`, may follow the changes using them.
+                let mut words = line.split_whitespace();
+                if let (Some(command), Some(value)) = (words.next(), words.next()) {
+                    if command.len() == 6 && command.to_ascii_uppercase().starts_with("#BPM") {
+                        if let Ok(bpm) = value.parse::<f32>() {
+                            definitions.insert(command[4..].to_ascii_uppercase(), bpm);
+                        }
+                    }
+                }
+                continue;
+            }
+        };
+        let (measure, channel) = match (
+            command.get(..3).and_then(|m| m.parse::<usize>().ok()),
+            command
+                .get(3..5)
+                .and_then(|c| u8::from_str_radix(c, 16).ok()),
+        ) {
+            (Some(measure), Some(channel)) if command.len() == 5 => (measure, channel),
+            _ => continue,
+        };
+        if channel == LENGTH_CHANNEL {
+            if let Ok(length) = data.parse::<f32>() {
+                if lengths.len() <= measure {
+                    lengths.resize(measure + 1, 1.);
+                }
+                lengths[measure] = length.max(0.);
+            }
+            continue;
+        }
+        let lane = LANE_CHANNELS
+            .iter()
+            .position(|&c| c == channel || c + LONG_NOTE_OFFSET == channel);
+        if lane.is_none() && channel != BPM_CHANNEL && channel != EXTENDED_BPM_CHANNEL {
+            continue;
+        }
+        let count = data.len() / 2;
+        for i in 0..count {
+            let id = match data.get(i * 2..i * 2 + 2) {
+                Some(id) if id != "00" => id,
+                _ => continue,
+            };
+            let object = match lane {
+                Some(lane) => Object::Note {
+                    lane,
+                    long: channel > LONG_NOTE_OFFSET,
+                },
+                None if channel == BPM_CHANNEL => match u8::from_str_radix(id, 16) {
+                    Ok(bpm) => Object::Bpm(bpm as f32),
+                    Err(_) => continue,
+                },
+                None => Object::ExtendedBpm(id.to_ascii_uppercase()),
+            };
+            objects.push((measure, i as f32 / count as f32, object));
+        }
+    }
+    let last = objects.iter().map(|&(m, _, _)| m + 1).max().unwrap_or(0);
+    lengths.resize(last.max(lengths.len()), 1.);
+    let mut starts = Vec::with_capacity(lengths.len());
+    let mut start = 0.;
+    for length in &lengths {
+        starts.push(start);
+        start += length;
+    }
+    let mut chart = BmsChart::default();
+    for (measure, fraction, object) in objects {
+        let position = starts[measure] + fraction * lengths[measure];
+        match object {
+            Object::Note { lane, long } => chart.notes.push(BmsNote {
+                position,
+                lane,
+                long,
+            }),
+            Object::Bpm(bpm) => chart.bpm_changes.push((position, bpm)),
+            Object::ExtendedBpm(id) => match definitions.get(&id) {
+                Some(&bpm) => chart.bpm_changes.push((position, bpm)),
+                None => log::warn!("BPM change to undefined #BPM{}", id),
+            },
+        }
+    }
+    chart
+        .notes
+        .sort_by(|a, b| a.position.partial_cmp(&b.position).unwrap());
+    chart
+        .bpm_changes
+        .sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    chart
+}
+
+/// Converts `bms` to a native chart with one laser holding all lanes, starting at `base_bpm`.
+pub fn to_chart(bms: &BmsChart, base_bpm: f32) -> Chart {
+    let mut bpm = vec![Timed {
+        time: 0.0,
+        inner: BpmCommand {
+            bpm: base_bpm,
+            position: 0.0,
+        },
+    }];
+    // Stops are not supported, so changes to zero BPM are dropped with them.
+    for &(position, change) in bms.bpm_changes.iter().filter(|&&(_, bpm)| bpm > 0.) {
+        let position = position * BEATS_PER_MEASURE;
+        let last = bpm.last_mut().unwrap();
+        if position == last.position {
+            last.inner.bpm = change;
+            continue;
+        }
+        let time = last.time + (position - last.position) * 60. / last.bpm;
+        bpm.push(Timed {
+            time,
+            inner: BpmCommand {
+                bpm: change,
+                position,
+            },
+        });
+    }
+    // Long notes alternate between start and end on their lane.
+    let mut held = [false; LANE_CHANNELS.len()];
+    let notes = bms
+        .notes
+        .iter()
+        .filter(|note| {
+            if !note.long {
+                return true;
+            }
+            held[note.lane] = !held[note.lane];
+            held[note.lane]
+        })
+        .map(|note| Timed {
+            time: time_for_position(&bpm, note.position * BEATS_PER_MEASURE),
+            inner: Note {
+                laser: LaserId(0),
+                lane: note.lane as u32,
+                kind: NoteKind::Tap,
+            },
+        })
+        .collect();
+    Chart {
+        notes,
+        default_bpm: bpm[0].bpm,
+        bpm,
+        laser_bpm: Default::default(),
+        lasers: vec![Timed {
+            time: 0.0,
+            inner: (
+                LaserId(0),
+                LaserCommand::Enter {
+                    y: 0.1,
+                    lanes: LANE_CHANNELS.len() as u16,
+                    color: (0., 0.1, 0.8).into(),
+                    surface: Default::default(),
+                    note_style: None,
+                },
+            ),
+        }],
+        sections: Vec::new(),
+        zoom: Vec::new(),
+    }
+}
@@ -0,0 +1,150 @@
+use crate::audio::{FramePacingSystem, MixerSystem};
+use crate::chart::NoteSystem;
+use crate::combo_break::ComboBreakSystemDesc;
+use crate::hit_error::HitErrorSystemDesc;
+use crate::hud::HudSystem;
+use crate::judge::JudgeSystemDesc;
+use crate::judge_sound::JudgeSoundSystemDesc;
+use crate::key_markers::KeyMarkerSystemDesc;
+use crate::laser::LaserAnimationSystem;
+use crate::metronome::MetronomeSystemDesc;
+use crate::quality::AdaptiveQualitySystem;
+use crate::replay::{ReplayPlaybackSystem, ReplayRecordSystemDesc};
+use crate::score::ScoreSystemDesc;
+use crate::stream_overlay::StreamOverlaySystemDesc;
+use crate::LaserFovSystem;
+use amethyst::{
+    core::{SystemBundle, SystemDesc},
+    ecs::{DispatcherBuilder, World},
+};
+
+/// The systems that play a chart: timing, judgment, scoring, the HUD, the hit error bar, the
+/// metronome of silent practice, the stream overlay files, the markers of held keys and the laser
+/// camera.
+///
+/// The app embedding the playfield provides the rest:
+/// - `TransformBundle`, `UiBundle` and `AnimationBundle<(), UiTransform>` with the default
+///   system names, added before this bundle;
+/// - [`RenderLaser`](crate::laser::RenderLaser) in its `RenderingBundle`;
+/// - the [`Theme`](crate::theme::Theme), [`Settings`](crate::settings::Settings),
+///   [`Paths`](crate::paths::Paths), [`KeyMappings`](crate::judge::KeyMappings) and
+///   `Option<AudioOutput>` resources.
+///
+/// The chart is played by pushing [`MainStage`](crate::MainStage).
+pub struct IrisGameplayBundle {
+    players: u8,
+    camera_systems: Vec<String>,
+}
+
+impl IrisGameplayBundle {
+    /// Judges `players` players, one key map each.
+    pub fn new(players: u8) -> Self {
+        Self {
+            players,
+            camera_systems: Vec::new(),
+        }
+    }
+
+    /// Fits the highway to the camera only after the system `name` has moved or resized it.
+    pub fn with_camera_system(mut self, name: impl Into<String>) -> Self {
+        self.camera_systems.push(name.into());
+        self
+    }
+}
+
+impl<'a, 'b> SystemBundle<'a, 'b> for IrisGameplayBundle {
+    fn build(
+        self,
+        world: &mut World,
+        builder: &mut DispatcherBuilder<'a, 'b>,
+    ) -> Result<(), amethyst::Error> {
+        let mut camera_deps: Vec<_> = self.camera_systems.iter().map(String::as_str).collect();
+        camera_deps.push("transform_system");
+        builder.add(LaserFovSystem::new(), "laser_fov", &camera_deps);
+        builder.add(FramePacingSystem::default(), "frame_pacing_system", &[]);
+        builder.add(MixerSystem, "mixer_system", &[]);
+        builder.add(NoteSystem, "note_system", &["frame_pacing_system"]);
+        builder.add(
+            LaserAnimationSystem,
+            "laser_animation_system",
+            &["note_system"],
+        );
+        builder.add(
+            AdaptiveQualitySystem::default(),
+            "adaptive_quality_system",
+            &[],
+        );
+
+        builder.add(
+            ReplayPlaybackSystem,
+            "replay_playback_system",
+            &["frame_pacing_system"],
+        );
+        builder.add(
+            KeyMarkerSystemDesc.build(world),
+            "key_marker_system",
+            &["replay_playback_system"],
+        );
+        let judge_systems: Vec<_> = (0..self.players)
+            .map(|player| format!("judge_system_{}", player))
+            .collect();
+        let judge_system_refs: Vec<_> = judge_systems.iter().map(String::as_str).collect();
+        for (player, name) in judge_systems.iter().enumerate() {
+            builder.add(
+                JudgeSystemDesc {
+                    player: player as u8,
+                }
+                .build(world),
+                name,
+                &[
+                    "note_system",
+                    "animation_control_system",
+                    "replay_playback_system",
+                ],
+            );
+        }
+        builder.add(
+            ReplayRecordSystemDesc.build(world),
+            "replay_record_system",
+            &[],
+        );
+        builder.add(
+            ScoreSystemDesc.build(world),
+            "score_system",
+            &judge_system_refs,
+        );
+        builder.add(HudSystem::default(), "hud_system", &["score_system"]);
+        builder.add(
+            MetronomeSystemDesc.build(world),
+            "metronome_system",
+            &["frame_pacing_system"],
+        );
+        builder.add(
+            HitErrorSystemDesc.build(world),
+            "hit_error_system",
+            &judge_system_refs,
+        );
+        builder.add(
+            ComboBreakSystemDesc.build(world),
+            "combo_break_system",
+            &["score_system"],
+        );
+        builder.add(
+            JudgeSoundSystemDesc.build(world),
+            "judge_sound_system",
+            &judge_system_refs,
+        );
+        builder.add(
+            StreamOverlaySystemDesc.build(world),
+            "stream_overlay_system",
+            &["score_system"],
+        );
+        #[cfg(feature = "scripting")]
+        builder.add(
+            crate::scripting::ScriptJudgeSystemDesc.build(world),
+            "script_judge_system",
+            &["score_system"],
+        );
+        Ok(())
+    }
+}
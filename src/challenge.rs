@@ -0,0 +1,120 @@
+use crate::library::Song;
+use crate::migrate::{self, Versioned};
+use crate::modifiers::{Modifier, SeedRng};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Entries kept on each daily leaderboard.
+const LEADERBOARD_SIZE: usize = 10;
+/// Modifier sets a daily challenge picks from.
+const MODIFIER_SETS: &[&[Modifier]] = &[
+    &[],
+    &[Modifier::Mirror],
+    &[Modifier::Shuffle],
+    &[Modifier::Hidden],
+    &[Modifier::SuddenDeath],
+    &[Modifier::PerfectOnly],
+];
+
+/// The daily challenge being played, if any. Its scores go to a separate leaderboard instead of
+/// the personal best.
+#[derive(Clone, Debug)]
+pub struct Challenge {
+    /// Days since the Unix epoch in UTC.
+    pub day: u64,
+    pub modifiers: Vec<Modifier>,
+    /// Seed for randomized modifiers.
+    pub seed: u64,
+}
+
+impl Challenge {
+    pub fn today() -> Self {
+        let day = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() / (24 * 60 * 60));
+        let mut rng = SeedRng::new(day);
+        // The first number picks the chart, see `pick`.
+        rng.next_u64();
+        let modifiers = MODIFIER_SETS[rng.below(MODIFIER_SETS.len())].to_vec();
+        let seed = rng.next_u64();
+        Self {
+            day,
+            modifiers,
+            seed,
+        }
+    }
+
+    /// The song of the day. Songs are ordered by path so that the pick does not depend on the
+    /// order the library was scanned in.
+    pub fn pick<'a>(&self, songs: &'a [Song]) -> Option<&'a Song> {
+        if songs.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<_> = songs.iter().collect();
+        sorted.sort_by(|a, b| a.path.cmp(&b.path));
+        Some(sorted[SeedRng::new(self.day).below(sorted.len())])
+    }
+
+    pub fn leaderboard_path(&self, root: &Path) -> PathBuf {
+        root.join("scores")
+            .join("daily")
+            .join(format!("{}.ron", self.day))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LeaderboardEntry {
+    pub song: PathBuf,
+    /// The score multiplied by the modifiers.
+    pub score: u32,
+    pub max_combo: u32,
+    /// The modifiers the entry was played with, shown as their icons.
+    #[serde(default)]
+    pub modifiers: Vec<Modifier>,
+}
+
+/// The best local plays of one daily challenge.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Leaderboard {
+    /// Format version, see [`Versioned`].
+    #[serde(default = "migrate::unversioned")]
+    pub version: u32,
+    /// Sorted by descending score.
+    pub entries: Vec<LeaderboardEntry>,
+}
+
+impl Default for Leaderboard {
+    fn default() -> Self {
+        Self {
+            version: Self::current_version(),
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl Versioned for Leaderboard {
+    const MIGRATIONS: &'static [fn(&mut Self)] = &[migrate::no_changes];
+
+    fn version_mut(&mut self) -> &mut u32 {
+        &mut self.version
+    }
+}
+
+impl Leaderboard {
+    /// Inserts the entry and returns its rank, or `None` if it didn't make the board.
+    pub fn insert(&mut self, entry: LeaderboardEntry) -> Option<usize> {
+        let rank = self
+            .entries
+            .iter()
+            .position(|e| e.score < entry.score)
+            .unwrap_or_else(|| self.entries.len());
+        if rank >= LEADERBOARD_SIZE {
+            return None;
+        }
+        self.entries.insert(rank, entry);
+        self.entries.truncate(LEADERBOARD_SIZE);
+        Some(rank)
+    }
+}
@@ -1,59 +1,264 @@
+use crate::diagnostics::ErrorEvent;
+use crate::error::ChartError;
+use crate::judge;
 use crate::laser;
+use crate::settings::{HighwayConfig, Settings, MIN_FADE_SECONDS};
+use crate::theme::Theme;
 use amethyst::{
+    assets::{AssetStorage, Loader},
     core::{
         math::Vector3,
         timing::Time,
         transform::{Parent, Transform},
     },
     ecs::{Entities, Entity, Read, ReadExpect, System, Write, WriteStorage},
-    renderer::palette::rgb::LinSrgb,
+    renderer::{
+        formats::texture::ImageFormat,
+        palette::rgb::LinSrgb,
+        rendy::{
+            hal::image::{Filter, SamplerInfo, WrapMode},
+            texture::image::ImageTextureConfig,
+        },
+        Texture,
+    },
+    shrev::EventChannel,
 };
 use std::collections::BTreeMap;
 use std::ops::{Deref, Range};
 use superslice::Ext;
+use timing::{equal_range_by_time, position_for_time};
+
+pub mod timing;
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug)]
 pub struct LaserId(pub u32);
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Note {
     pub laser: LaserId,
     pub lane: u32,
+    pub kind: NoteKind,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NoteKind {
+    /// Hit once.
+    Tap,
+    /// Hit at its time, then kept alive by pressing again and again until `end`, with gaps no
+    /// longer than [`JudgeWindows::roll`](crate::judge::JudgeWindows::roll).
+    Roll { end: f32 },
+    /// Must not be pressed: a press near it within the near window damages the gauge. Mines get
+    /// no judgement and don't count as notes.
+    Mine,
+}
+
+impl Default for NoteKind {
+    fn default() -> Self {
+        NoteKind::Tap
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct BpmCommand {
     pub bpm: f32,
     pub position: f32,
 }
 
-#[derive(Debug)]
+/// Scales the whole highway around the center of the screen.
+#[derive(Debug, PartialEq)]
+pub struct ZoomCommand {
+    /// Size relative to the normal highway.
+    pub scale: f32,
+    /// Seconds to reach `scale` in.
+    pub duration: f32,
+}
+
+impl ZoomCommand {
+    /// The zoom at `time` of this command started at `start` from `from`.
+    fn at(&self, from: f32, start: f32, time: f32) -> f32 {
+        let t = if self.duration > 0. {
+            ((time - start) / self.duration).max(0.).min(1.)
+        } else {
+            1.
+        };
+        from + (self.scale - from) * t
+    }
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct LaserSurface {
+    /// Color at the far end of the highway. Falls back to the theme's fade of the near color.
+    pub far_color: Option<LinSrgb<f32>>,
+    /// Texture path relative to the resources directory. Falls back to the theme texture.
+    pub texture: Option<String>,
+    /// Texture scroll speed in texture units per second.
+    pub scroll: Option<f32>,
+    /// Falls back to the theme's blend mode.
+    pub blend: Option<laser::LaserBlend>,
+    /// Value the laser marks its area with in the lasers' stencil layer, up to
+    /// [`laser::MAX_LASER_STENCIL`]. Falls back to [`laser::LASER_STENCIL`].
+    pub stencil: Option<u8>,
+}
+
+#[derive(Debug, PartialEq)]
 pub enum LaserCommand {
     Enter {
         y: f32,
         lanes: u16,
         color: LinSrgb<f32>,
+        surface: LaserSurface,
+        /// Overrides the default note appearance on this laser.
+        note_style: Option<laser::NoteStyle>,
     },
     Leave,
     LineTo {
         time: Timed<()>,
         y: f32,
     },
+    /// Fades the laser to `color` over `duration` seconds. The far color follows with the theme's
+    /// fade of `color`.
+    ColorTo {
+        color: LinSrgb<f32>,
+        duration: f32,
+    },
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Chart {
     /// All notes sorted by time.
     pub notes: Vec<Timed<Note>>,
     /// BPM change sequences sorted by time.
     pub bpm: Vec<Timed<BpmCommand>>,
+    /// BPM tracks of lasers that scroll on their own instead of `bpm`, for polyrhythms. Each is
+    /// sorted by time and not empty.
+    pub laser_bpm: BTreeMap<LaserId, Vec<Timed<BpmCommand>>>,
     /// Laser sequences sorted by time.
     pub lasers: Vec<Timed<(LaserId, LaserCommand)>>,
+    /// Section labels such as verse or chorus, sorted by time.
+    pub sections: Vec<Timed<String>>,
+    /// Highway zoom changes sorted by time.
+    pub zoom: Vec<Timed<ZoomCommand>>,
     pub default_bpm: f32,
 }
 
+impl Chart {
+    /// Number of notes that get a judgement, which excludes mines.
+    pub fn judged_notes(&self) -> usize {
+        self.notes
+            .iter()
+            .filter(|note| note.kind != NoteKind::Mine)
+            .count()
+    }
+
+    /// The highway zoom at `time`. A zoom starting before the previous one finished continues
+    /// from where that one got to.
+    pub fn zoom_at(&self, time: f32) -> f32 {
+        let mut from = 1.;
+        let mut current: Option<&Timed<ZoomCommand>> = None;
+        for command in self.zoom.iter().take_while(|command| command.time <= time) {
+            if let Some(previous) = current {
+                from = previous.at(from, previous.time, command.time);
+            }
+            current = Some(command);
+        }
+        current.map_or(1., |command| command.at(from, command.time, time))
+    }
+
+    /// The section playing at `time`.
+    pub fn section_at(&self, time: f32) -> Option<&Timed<String>> {
+        let idx = self
+            .sections
+            .upper_bound_by(|x| x.time.partial_cmp(&time).unwrap());
+        idx.checked_sub(1).map(|i| &self.sections[i])
+    }
+
+    /// Index into `bpm` of the command in effect at `time`.
+    pub fn bpm_index_at(&self, time: f32) -> Option<usize> {
+        let idx = self
+            .bpm
+            .upper_bound_by(|x| x.time.partial_cmp(&time).unwrap());
+        idx.checked_sub(1)
+    }
+
+    /// The BPM commands `laser` scrolls on.
+    pub fn bpm_track(&self, laser: LaserId) -> &[Timed<BpmCommand>] {
+        self.laser_bpm.get(&laser).unwrap_or(&self.bpm)
+    }
+
+    /// Highway position in beats of each note in `notes` on the track of its laser, in one pass
+    /// over the main BPM commands.
+    pub fn note_positions(&self, scroll: ScrollMode) -> Vec<f32> {
+        if scroll == ScrollMode::Constant {
+            return self
+                .notes
+                .iter()
+                .map(|note| scroll.position(&self.bpm, self.default_bpm, note.time))
+                .collect();
+        }
+        let mut bpm = 0;
+        self.notes
+            .iter()
+            .map(|note| {
+                if let Some(track) = self.laser_bpm.get(&note.laser) {
+                    return position_for_time(track, note.time);
+                }
+                while bpm + 1 < self.bpm.len() && self.bpm[bpm + 1].time < note.time {
+                    bpm += 1;
+                }
+                let command = &self.bpm[bpm];
+                command.position + (note.time - command.time) * command.bpm / 60.0
+            })
+            .collect()
+    }
+
+    /// The first command after `time` that actually changes the BPM.
+    pub fn next_bpm_change(&self, time: f32) -> Option<&Timed<BpmCommand>> {
+        let current = self.bpm_index_at(time);
+        let bpm = current.map(|i| self.bpm[i].bpm);
+        self.bpm[current.map_or(0, |i| i + 1)..]
+            .iter()
+            .find(|x| Some(x.bpm) != bpm)
+    }
+
+    /// The nearest section start strictly after (or before, if not `forward`) `time`, used as a
+    /// snap point for seeking.
+    pub fn snap_to_section(&self, time: f32, forward: bool) -> Option<f32> {
+        if forward {
+            let idx = self
+                .sections
+                .upper_bound_by(|x| x.time.partial_cmp(&time).unwrap());
+            self.sections.get(idx).map(|x| x.time)
+        } else {
+            let idx = self
+                .sections
+                .lower_bound_by(|x| x.time.partial_cmp(&time).unwrap());
+            idx.checked_sub(1).map(|i| self.sections[i].time)
+        }
+    }
+}
+
 pub struct NoteSystem;
 
+/// How chart time maps to highway position.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScrollMode {
+    /// Follows the BPM commands of the chart, with their speed changes and stops.
+    Chart,
+    /// Scrolls at the chart's default BPM throughout, like a C-mod, so that notes come at a
+    /// constant speed.
+    Constant,
+}
+
+impl ScrollMode {
+    /// Highway position in beats at `time` on `track` of a chart with `default_bpm`.
+    pub fn position(self, track: &[Timed<BpmCommand>], default_bpm: f32, time: f32) -> f32 {
+        match self {
+            ScrollMode::Chart => position_for_time(track, time),
+            ScrollMode::Constant => time * default_bpm / 60.0,
+        }
+    }
+}
+
 pub struct PlaySettings {
     /// The margin between note appearance and judgement in seconds.
     pub speed: f32,
@@ -62,28 +267,67 @@ pub struct PlaySettings {
     pub base_time: f64,
     /// The degree to which keyboard positions are compensated.
     pub norm_threshold: f32,
+    /// Number of players sharing the screen, each playing their own copy of the chart.
+    pub players: u8,
+    /// Where notes are drawn; judgement always follows chart time.
+    pub scroll: ScrollMode,
 }
 pub struct ChartState {
-    /// The window of transforms z where we draw.
+    /// The window of transforms z where we draw, on the main BPM track.
     pub draw_window: Range<f32>,
+    /// The windows of lasers with their own [BPM track](Chart::laser_bpm).
+    pub laser_windows: BTreeMap<LaserId, Range<f32>>,
     /// Relative position to cut off the laser origin.
     pub cutoff: f32,
-    lasers: BTreeMap<LaserId, Entity>,
+    lasers: BTreeMap<(u8, LaserId), Entity>,
+    /// [`Chart::note_positions`], computed when the chart starts playing.
+    positions: Vec<f32>,
+    /// The scroll mode `positions` are for, or `None` if they are out of date.
+    positions_scroll: Option<ScrollMode>,
     /// The time up to which we have loaded.
     last_time: f32,
+    /// Notes before this time are not spawned, so that seeking skips them.
+    notes_from: f32,
 }
 impl Default for ChartState {
     fn default() -> Self {
         Self {
             draw_window: 0. ..0.,
-            cutoff: 0.7,
+            laser_windows: BTreeMap::new(),
+            cutoff: HighwayConfig::default().cutoff,
             lasers: BTreeMap::new(),
+            positions: Vec::new(),
+            positions_scroll: None,
             last_time: 0.,
+            notes_from: 0.,
+        }
+    }
+}
+impl ChartState {
+    /// The window of transforms z where the notes of `laser` are drawn.
+    pub fn window_of(&self, laser: LaserId) -> Range<f32> {
+        self.laser_windows
+            .get(&laser)
+            .cloned()
+            .unwrap_or_else(|| self.draw_window.clone())
+    }
+
+    /// Makes the note positions be computed again, after the chart changes.
+    pub fn invalidate_positions(&mut self) {
+        self.positions_scroll = None;
+    }
+
+    /// The state for playing from `time` on an empty highway. Lasers present at `time` are
+    /// spawned on the next update, notes only from `time` on.
+    pub fn starting_at(time: f32) -> Self {
+        Self {
+            notes_from: time,
+            ..Default::default()
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Timed<T> {
     pub time: f32,
     pub inner: T,
@@ -96,38 +340,59 @@ impl<T> Deref for Timed<T> {
     }
 }
 
-fn equal_range_by_time<T>(slice: &[Timed<T>], lo: f32, hi: f32) -> Range<usize> {
-    use std::cmp::Ordering::*;
-    assert!(lo <= hi);
-    slice.equal_range_by(|x| {
-        if x.time < lo {
-            Less
-        } else if x.time >= hi {
-            Greater
-        } else {
-            Equal
+/// If `notes[index]` is the leftmost of several notes at the same time on the same laser, the
+/// number of lanes from it up to the rightmost one.
+fn chord_span(notes: &[Timed<Note>], index: usize) -> Option<u32> {
+    let note = &notes[index];
+    if note.kind == NoteKind::Mine {
+        return None;
+    }
+    let same_time = |other: &&Timed<Note>| other.time == note.time;
+    let chord = notes[..index]
+        .iter()
+        .rev()
+        .take_while(same_time)
+        .chain(notes[index + 1..].iter().take_while(same_time))
+        .filter(|other| {
+            other.laser == note.laser && other.lane != note.lane && other.kind != NoteKind::Mine
+        });
+    let mut right = note.lane;
+    for other in chord {
+        if other.lane < note.lane {
+            return None;
         }
-    })
+        right = right.max(other.lane);
+    }
+    if right > note.lane {
+        Some(right - note.lane + 1)
+    } else {
+        None
+    }
 }
 
-fn position_for_time(bpms: &[Timed<BpmCommand>], time: f32) -> f32 {
-    let lower_bound = &bpms[bpms
-        .lower_bound_by(|x| x.time.partial_cmp(&time).unwrap())
-        .saturating_sub(1)];
-    lower_bound.position + (time - lower_bound.time) * lower_bound.bpm / 60.0
+/// Seconds a note stays visible at a constant `bpm`, mirroring how [`NoteSystem`] shortens the
+/// draw window once the cutoff reaches [`HighwayConfig::max_cutoff`].
+pub fn visible_time(speed: f32, bpm: f32, default_bpm: f32, highway: &HighwayConfig) -> f32 {
+    let cutoff = highway.cutoff * bpm / default_bpm;
+    speed * (highway.max_cutoff / cutoff).min(1.)
 }
 
 impl<'s> System<'s> for NoteSystem {
     type SystemData = (
         Entities<'s>,
         ReadExpect<'s, Time>,
+        ReadExpect<'s, Loader>,
+        ReadExpect<'s, Theme>,
+        Read<'s, AssetStorage<Texture>>,
         Read<'s, Option<Chart>>,
         Read<'s, Option<PlaySettings>>,
+        Read<'s, Settings>,
         Write<'s, ChartState>,
         WriteStorage<'s, Parent>,
         WriteStorage<'s, laser::Laser>,
         WriteStorage<'s, laser::Note>,
         WriteStorage<'s, Transform>,
+        Write<'s, EventChannel<ErrorEvent>>,
     );
 
     fn run(
@@ -135,68 +400,247 @@ impl<'s> System<'s> for NoteSystem {
         (
             entities,
             time,
+            loader,
+            theme,
+            textures,
             chart,
             settings,
+            user_settings,
             mut state,
             mut parents,
             mut laser_storage,
             mut note_storage,
             mut transforms,
+            mut errors,
         ): Self::SystemData,
     ) {
         if let Some(settings) = &*settings {
+            let highway = &user_settings.highway;
             let chart = chart.as_ref().unwrap();
             let notes = &chart.notes;
             let lasers = &chart.lasers;
 
             let now_rel = (time.absolute_time_seconds() - settings.base_time) as f32;
-            let start_pos = position_for_time(&chart.bpm, now_rel);
-            let end_pos = position_for_time(&chart.bpm, now_rel + settings.speed);
-            let cutoff = 0.7 * (end_pos - start_pos) / (chart.default_bpm / 60.) / settings.speed;
-            let clamped_cutoff = cutoff.min(0.95);
+            let scroll = settings.scroll;
+            let start_pos = scroll.position(&chart.bpm, chart.default_bpm, now_rel);
+            let end_pos = scroll.position(&chart.bpm, chart.default_bpm, now_rel + settings.speed);
+            let cutoff =
+                highway.cutoff * (end_pos - start_pos) / (chart.default_bpm / 60.) / settings.speed;
+            let clamped_cutoff = cutoff.min(highway.max_cutoff);
             let clamped_end_pos = start_pos + (end_pos - start_pos) * clamped_cutoff / cutoff;
 
             for to_load in &lasers[equal_range_by_time(lasers, state.last_time, now_rel)] {
-                match to_load.1 {
-                    LaserCommand::Enter { y, lanes, color } => {
-                        let eid = entities.create();
-                        laser_storage
-                            .insert(eid, laser::Laser { color, lanes })
-                            .unwrap();
-                        let mut transform = Transform::default();
-                        transform.set_translation_y(y);
-                        transforms.insert(eid, transform).unwrap();
-                        assert!(state.lasers.insert(to_load.0, eid).is_none());
+                match &to_load.1 {
+                    LaserCommand::Enter {
+                        y,
+                        lanes,
+                        color,
+                        surface,
+                        note_style,
+                    } => {
+                        let (y, lanes, color) = (*y, *lanes, *color);
+                        let far_color = surface
+                            .far_color
+                            .unwrap_or_else(|| color * theme.laser_far_fade);
+                        let texture = surface
+                            .texture
+                            .as_ref()
+                            .or_else(|| theme.laser_texture.as_ref())
+                            .map(|path| {
+                                loader.load(
+                                    path.as_str(),
+                                    ImageFormat(ImageTextureConfig {
+                                        sampler_info: SamplerInfo::new(
+                                            Filter::Linear,
+                                            WrapMode::Tile,
+                                        ),
+                                        ..Default::default()
+                                    }),
+                                    (),
+                                    &textures,
+                                )
+                            });
+                        let scroll = surface.scroll.unwrap_or(theme.laser_scroll);
+                        for player in 0..settings.players {
+                            let eid = entities.create();
+                            laser_storage
+                                .insert(
+                                    eid,
+                                    laser::Laser {
+                                        color,
+                                        far_color,
+                                        texture: texture.clone(),
+                                        scroll,
+                                        id: to_load.0,
+                                        lanes,
+                                        player,
+                                        note_style: note_style.clone().unwrap_or_default(),
+                                        blend: surface.blend.unwrap_or(theme.laser_blend),
+                                        stencil: surface.stencil.unwrap_or(laser::LASER_STENCIL),
+                                        animation: None,
+                                    },
+                                )
+                                .unwrap();
+                            let mut transform = Transform::default();
+                            transform.set_translation_y(y);
+                            transforms.insert(eid, transform).unwrap();
+                            if let Some(old) = state.lasers.insert((player, to_load.0), eid) {
+                                errors.single_write(ErrorEvent::new(
+                                    ChartError::LaserAlreadyPresent {
+                                        laser: to_load.0,
+                                        time: to_load.time,
+                                    }
+                                    .to_string(),
+                                ));
+                                entities.delete(old).unwrap();
+                            }
+                        }
                     }
                     LaserCommand::Leave => {
-                        let eid = state.lasers.remove(&to_load.0).unwrap();
-                        entities.delete(eid).unwrap();
+                        for player in 0..settings.players {
+                            match state.lasers.remove(&(player, to_load.0)) {
+                                Some(eid) => entities.delete(eid).unwrap(),
+                                None => errors.single_write(ErrorEvent::new(
+                                    ChartError::LaserNotEntered {
+                                        laser: to_load.0,
+                                        time: to_load.time,
+                                    }
+                                    .to_string(),
+                                )),
+                            }
+                        }
                     }
-                    LaserCommand::LineTo { .. } => unimplemented!(),
+                    LaserCommand::ColorTo { color, duration } => {
+                        let duration = if user_settings.accessibility.suppress_flashes {
+                            duration.max(MIN_FADE_SECONDS)
+                        } else {
+                            *duration
+                        };
+                        for player in 0..settings.players {
+                            let laser = state
+                                .lasers
+                                .get(&(player, to_load.0))
+                                .and_then(|&eid| laser_storage.get_mut(eid));
+                            match laser {
+                                Some(laser) => {
+                                    // A fade still running continues from where it is now.
+                                    let from = laser
+                                        .animation
+                                        .as_ref()
+                                        .map_or((laser.color, laser.far_color), |animation| {
+                                            animation.at(to_load.time)
+                                        });
+                                    laser.animation = Some(laser::ColorAnimation {
+                                        from,
+                                        to: (*color, *color * theme.laser_far_fade),
+                                        start: to_load.time,
+                                        duration,
+                                    });
+                                }
+                                None => errors.single_write(ErrorEvent::new(
+                                    ChartError::CommandWithoutLaser {
+                                        laser: to_load.0,
+                                        time: to_load.time,
+                                        command: "ColorTo",
+                                    }
+                                    .to_string(),
+                                )),
+                            }
+                        }
+                    }
+                    LaserCommand::LineTo { .. } => errors.single_write(ErrorEvent::new(
+                        ChartError::Unsupported {
+                            laser: to_load.0,
+                            time: to_load.time,
+                            command: "LineTo",
+                        }
+                        .to_string(),
+                    )),
                 }
             }
-            for to_load in &notes[equal_range_by_time(
+            let range = equal_range_by_time(
                 notes,
-                state.last_time + settings.speed,
+                (state.last_time + settings.speed).max(state.notes_from),
                 now_rel + settings.speed,
-            )] {
-                let eid = entities.create();
-                note_storage
-                    .insert(eid, laser::Note { time: to_load.time })
-                    .unwrap();
-                let laser_id = state.lasers[&to_load.laser];
-                let laser = laser_storage.get(laser_id).unwrap();
-                parents.insert(eid, Parent::new(laser_id)).unwrap();
-
-                let mut transform = Transform::default();
-                transform.set_translation_x(to_load.lane as f32 / laser.lanes as f32);
-                transform.set_scale(Vector3::new(1. / laser.lanes as f32, 1., 1.));
-                transform.set_translation_z(position_for_time(&chart.bpm, to_load.time));
-                transforms.insert(eid, transform).unwrap();
+            );
+            if state.positions_scroll != Some(scroll) {
+                state.positions = chart.note_positions(scroll);
+                state.positions_scroll = Some(scroll);
+            }
+            for index in range {
+                let to_load = &notes[index];
+                let chord = chord_span(notes, index);
+                let position = state.positions[index];
+                let track = chart.bpm_track(to_load.laser);
+                let ticks = match to_load.kind {
+                    NoteKind::Roll { end } => judge::roll_ticks(track, to_load.time, end),
+                    NoteKind::Tap | NoteKind::Mine => Vec::new(),
+                };
+                let tick_positions: Vec<_> = ticks
+                    .iter()
+                    .map(|&tick| scroll.position(track, chart.default_bpm, tick))
+                    .collect();
+                for player in 0..settings.players {
+                    let (laser_id, lanes) = match state
+                        .lasers
+                        .get(&(player, to_load.laser))
+                        .and_then(|&id| Some((id, laser_storage.get(id)?.lanes)))
+                    {
+                        Some(laser) => laser,
+                        None => {
+                            errors.single_write(ErrorEvent::new(
+                                ChartError::NoteWithoutLaser {
+                                    laser: to_load.laser,
+                                    time: to_load.time,
+                                }
+                                .to_string(),
+                            ));
+                            continue;
+                        }
+                    };
+                    let mut transform = Transform::default();
+                    transform.set_translation_xyz(to_load.lane as f32 / lanes as f32, 0., position);
+                    transform.set_scale(Vector3::new(1. / lanes as f32, 1., 1.));
+                    let note = laser::Note {
+                        time: to_load.time,
+                        player,
+                        chord,
+                        kind: to_load.kind,
+                        end_position: match to_load.kind {
+                            NoteKind::Roll { end } => {
+                                scroll.position(track, chart.default_bpm, end)
+                            }
+                            NoteKind::Tap | NoteKind::Mine => position,
+                        },
+                        ticks: ticks.clone(),
+                        tick_positions: tick_positions.clone(),
+                    };
+                    entities
+                        .build_entity()
+                        .with(note, &mut note_storage)
+                        .with(Parent::new(laser_id), &mut parents)
+                        .with(transform, &mut transforms)
+                        .build();
+                }
             }
 
             state.cutoff = clamped_cutoff;
             state.draw_window = start_pos..clamped_end_pos;
+            // Other tracks show the same stretch of time, cut off like the main one. At a constant
+            // scroll, they all share the main window.
+            state.laser_windows = chart
+                .laser_bpm
+                .iter()
+                .filter(|_| scroll == ScrollMode::Chart)
+                .map(|(&laser, track)| {
+                    let start = position_for_time(track, now_rel);
+                    let end = position_for_time(track, now_rel + settings.speed);
+                    (
+                        laser,
+                        start..start + (end - start) * clamped_cutoff / cutoff,
+                    )
+                })
+                .collect();
             state.last_time = now_rel;
         }
     }
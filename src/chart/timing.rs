@@ -0,0 +1,281 @@
+//! Conversions between chart time in seconds and highway position in beats, and the grids notes
+//! snap to.
+//!
+//! Everything that places or finds notes goes through these, so they are kept free of ECS state.
+//! BPM commands must be sorted by time and positions must not decrease between them; then the
+//! position is a continuous, non-decreasing function of time.
+use super::{BpmCommand, Timed};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use superslice::Ext;
+
+/// Indices of the elements of `slice`, which is sorted by time, with a time in `lo..hi`.
+///
+/// # Panics
+///
+/// If `lo > hi`.
+pub fn equal_range_by_time<T>(slice: &[Timed<T>], lo: f32, hi: f32) -> Range<usize> {
+    use std::cmp::Ordering::*;
+    assert!(lo <= hi);
+    slice.equal_range_by(|x| {
+        if x.time < lo {
+            Less
+        } else if x.time >= hi {
+            Greater
+        } else {
+            Equal
+        }
+    })
+}
+
+/// Highway position in beats at `time`.
+///
+/// The last command before `time` applies; times before the first command extrapolate it
+/// backwards. `bpms` must not be empty.
+pub fn position_for_time(bpms: &[Timed<BpmCommand>], time: f32) -> f32 {
+    let lower_bound = &bpms[bpms
+        .lower_bound_by(|x| x.time.partial_cmp(&time).unwrap())
+        .saturating_sub(1)];
+    lower_bound.position + (time - lower_bound.time) * lower_bound.bpm / 60.0
+}
+
+/// Chart time at which the highway reaches `position`, the inverse of [`position_for_time`].
+///
+/// A position held by a stop (a command with zero BPM) is reached when the stop starts, so
+/// `position_for_time(bpms, time_for_position(bpms, p)) == p` for every reachable `p`, while the
+/// other direction only holds outside of stops. Positions past a stop at the end of the chart are
+/// never reached and give infinity. `bpms` must not be empty.
+pub fn time_for_position(bpms: &[Timed<BpmCommand>], position: f32) -> f32 {
+    let index = bpms
+        .lower_bound_by(|x| x.position.partial_cmp(&position).unwrap())
+        .saturating_sub(1);
+    let segment = &bpms[index];
+    if segment.bpm == 0. {
+        return if position > segment.position {
+            bpms.get(index + 1)
+                .map_or(std::f32::INFINITY, |next| next.time)
+        } else {
+            segment.time
+        };
+    }
+    segment.time + (position - segment.position) * 60.0 / segment.bpm
+}
+
+/// Grid lines that notes snap to, in beats.
+///
+/// `division` counts lines per whole note (four beats) as in note names: 16 for sixteenths, 12
+/// and 24 for triplets. With `swing`, every second line is pushed later by that fraction of the
+/// spacing; a third makes straight eighths into triplet-swung ones.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SnapGrid {
+    pub division: u32,
+    #[serde(default)]
+    pub swing: f32,
+}
+
+impl SnapGrid {
+    /// A straight grid of `division` lines per whole note.
+    pub fn new(division: u32) -> Self {
+        Self {
+            division,
+            swing: 0.,
+        }
+    }
+
+    /// The same grid with every second line delayed by `swing`, which must be in `0..1`.
+    pub fn with_swing(self, swing: f32) -> Self {
+        Self { swing, ..self }
+    }
+
+    /// Spacing between straight lines in beats.
+    pub fn step(&self) -> f32 {
+        4. / self.division as f32
+    }
+
+    /// Lines around `position`, sorted: the swung line of the previous pair, then both lines of
+    /// the pair containing `position` and of the next pair.
+    fn lines_around(&self, position: f32) -> [f32; 5] {
+        let step = self.step();
+        let pair = (position / (2. * step)).floor() * 2. * step;
+        let swung = step * (1. + self.swing);
+        [
+            pair - 2. * step + swung,
+            pair,
+            pair + swung,
+            pair + 2. * step,
+            pair + 2. * step + swung,
+        ]
+    }
+
+    /// The line nearest to `position`.
+    pub fn snap(&self, position: f32) -> f32 {
+        let lines = self.lines_around(position);
+        lines[1..4]
+            .iter()
+            .cloned()
+            .min_by(|a, b| {
+                (a - position)
+                    .abs()
+                    .partial_cmp(&(b - position).abs())
+                    .unwrap()
+            })
+            .unwrap()
+    }
+
+    /// The first line after `position`, for stepping a cursor forward.
+    pub fn next(&self, position: f32) -> f32 {
+        let lines = self.lines_around(position);
+        lines.iter().cloned().find(|&l| l > position).unwrap()
+    }
+
+    /// The last line before `position`, for stepping a cursor backward.
+    pub fn previous(&self, position: f32) -> f32 {
+        let lines = self.lines_around(position);
+        lines.iter().rev().cloned().find(|&l| l < position).unwrap()
+    }
+}
+
+/// `time` moved to the nearest line of `grid`.
+pub fn snap_time(bpms: &[Timed<BpmCommand>], time: f32, grid: &SnapGrid) -> f32 {
+    time_for_position(bpms, grid.snap(position_for_time(bpms, time)))
+}
+
+/// `time` moved to the nearest line of `grid` if it is at most `tolerance` beats off, for
+/// cleaning up notes that were meant to be on the grid. Notes further off are kept as they are,
+/// since they are likely on purpose or on a finer grid.
+pub fn quantize_time(
+    bpms: &[Timed<BpmCommand>],
+    time: f32,
+    grid: &SnapGrid,
+    tolerance: f32,
+) -> f32 {
+    let position = position_for_time(bpms, time);
+    let snapped = grid.snap(position);
+    if (snapped - position).abs() <= tolerance {
+        time_for_position(bpms, snapped)
+    } else {
+        time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Valid BPM commands from segment lengths in seconds and BPMs, with some stops.
+    fn bpms() -> impl Strategy<Value = Vec<Timed<BpmCommand>>> {
+        let bpm = prop_oneof![1 => Just(0f32), 4 => 30f32..300.];
+        (30f32..300., prop::collection::vec((0.1f32..10., bpm), 0..8)).prop_map(
+            |(first, segments)| {
+                let mut bpms = vec![Timed {
+                    time: 0.,
+                    inner: BpmCommand {
+                        bpm: first,
+                        position: 0.,
+                    },
+                }];
+                for (length, bpm) in segments {
+                    let last = bpms.last().unwrap();
+                    let (time, position) =
+                        (last.time + length, last.position + length * last.bpm / 60.);
+                    bpms.push(Timed {
+                        time,
+                        inner: BpmCommand { bpm, position },
+                    });
+                }
+                bpms
+            },
+        )
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() <= 1e-3 * (1. + a.abs().max(b.abs()))
+    }
+
+    fn bpm(time: f32, bpm: f32, position: f32) -> Timed<BpmCommand> {
+        Timed {
+            time,
+            inner: BpmCommand { bpm, position },
+        }
+    }
+
+    #[test]
+    fn straight_grids() {
+        let sixteenths = SnapGrid::new(16);
+        assert_eq!(sixteenths.snap(0.3), 0.25);
+        assert_eq!(sixteenths.snap(0.4), 0.5);
+        assert_eq!(sixteenths.next(0.25), 0.5);
+        assert_eq!(sixteenths.previous(0.25), 0.);
+        let triplets = SnapGrid::new(12);
+        assert!(close(triplets.snap(0.3), 1. / 3.));
+        assert!(close(triplets.next(0.4), 2. / 3.));
+        assert!(close(SnapGrid::new(24).snap(1.2), 7. / 6.));
+    }
+
+    #[test]
+    fn swung_eighths_land_on_triplets() {
+        let grid = SnapGrid::new(8).with_swing(1. / 3.);
+        assert!(close(grid.snap(0.6), 2. / 3.));
+        assert!(close(grid.next(0.), 2. / 3.));
+        assert_eq!(grid.next(0.7), 1.);
+        assert!(close(grid.previous(1.), 2. / 3.));
+        assert_eq!(grid.previous(0.6), 0.);
+    }
+
+    #[test]
+    fn quantizes_only_notes_slightly_off() {
+        let bpms = [bpm(0., 120., 0.)];
+        let grid = SnapGrid::new(16);
+        // 0.52 beats, just after the sixteenth at 0.5.
+        assert!(close(quantize_time(&bpms, 0.26, &grid, 0.05), 0.25));
+        // 0.6 beats, on a finer grid on purpose.
+        assert_eq!(quantize_time(&bpms, 0.3, &grid, 0.05), 0.3);
+    }
+
+    #[test]
+    fn snaps_across_bpm_changes() {
+        let bpms = [bpm(0., 120., 0.), bpm(1., 240., 2.)];
+        // 2.52 beats, after the change to 240 BPM.
+        assert!(close(snap_time(&bpms, 1.13, &SnapGrid::new(16)), 1.125));
+    }
+
+    proptest! {
+        #[test]
+        fn position_does_not_decrease(bpms in bpms(), a in -10f32..100., b in -10f32..100.) {
+            let (a, b) = (a.min(b), a.max(b));
+            prop_assert!(position_for_time(&bpms, a) <= position_for_time(&bpms, b) + 1e-3);
+        }
+
+        #[test]
+        fn reachable_positions_round_trip(bpms in bpms(), time in 0f32..100.) {
+            let position = position_for_time(&bpms, time);
+            let back = position_for_time(&bpms, time_for_position(&bpms, position));
+            prop_assert!(close(back, position), "{} != {}", back, position);
+        }
+
+        #[test]
+        fn times_outside_of_stops_round_trip(bpms in bpms(), time in 0f32..100.) {
+            let segment = &bpms[bpms
+                .lower_bound_by(|x| x.time.partial_cmp(&time).unwrap())
+                .saturating_sub(1)];
+            prop_assume!(segment.bpm > 0.);
+            let back = time_for_position(&bpms, position_for_time(&bpms, time));
+            prop_assert!(close(back, time), "{} != {}", back, time);
+        }
+
+        #[test]
+        fn equal_range_holds_exactly_the_times_in_range(
+            mut times in prop::collection::vec(0f32..100., 0..32),
+            lo in 0f32..100.,
+            length in 0f32..50.,
+        ) {
+            times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let slice: Vec<_> = times.iter().map(|&time| Timed { time, inner: () }).collect();
+            let range = equal_range_by_time(&slice, lo, lo + length);
+            for (i, x) in slice.iter().enumerate() {
+                prop_assert_eq!(range.contains(&i), (lo..lo + length).contains(&x.time));
+            }
+        }
+    }
+}
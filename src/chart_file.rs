@@ -0,0 +1,644 @@
+use crate::chart::{
+    BpmCommand, Chart, LaserCommand, LaserId, LaserSurface, Note, NoteKind, Timed, ZoomCommand,
+};
+use crate::crash;
+use crate::error::ChartError;
+use crate::laser::{self, LaserBlend, NoteStyle};
+use crate::vfs::Vfs;
+use amethyst::renderer::palette::rgb::LinSrgb;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Name of the native chart in a song directory.
+pub const CHART_FILE: &str = "chart.ron";
+/// The newest chart format this build reads.
+///
+/// 2 added rolls, 3 mines, 4 laser color fades, 5 highway zoom, 6 per-laser BPM tracks and 7
+/// laser stencil values. Charts with rolls, mines or fades require that version; zoom, laser
+/// tracks and stencil values are skipped by older builds, which scroll every laser on the main
+/// track and mask every laser alike.
+pub const CHART_VERSION: u32 = 7;
+
+/// [`crash::chart_hash`] of the native chart of the song in `song`, which identifies the chart
+/// independently of where the song is.
+pub fn song_chart_hash(fs: &dyn Vfs, song: &Path) -> Option<u64> {
+    let bytes = fs.read(&song.join(CHART_FILE)).ok()?;
+    Some(crash::chart_hash(&bytes))
+}
+
+/// The native chart format.
+///
+/// Fields added in later versions are ignored by older builds, so a chart using them still plays,
+/// only without the new feature. Anything an older build can't skip, such as a new laser command,
+/// raises `required_version` so that it is rejected with a clear message instead.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChartFile {
+    /// Format version the chart was written with.
+    pub version: u32,
+    /// Oldest format version that can play the chart.
+    pub required_version: u32,
+    pub default_bpm: f32,
+    pub bpm: Vec<BpmEntry>,
+    pub laser_bpm: Vec<LaserBpmEntry>,
+    pub lasers: Vec<LaserEntry>,
+    pub notes: Vec<NoteEntry>,
+    pub sections: Vec<SectionEntry>,
+    pub zoom: Vec<ZoomEntry>,
+}
+
+impl Default for ChartFile {
+    fn default() -> Self {
+        Self {
+            version: CHART_VERSION,
+            required_version: 1,
+            default_bpm: 120.,
+            bpm: Vec::new(),
+            laser_bpm: Vec::new(),
+            lasers: Vec::new(),
+            notes: Vec::new(),
+            sections: Vec::new(),
+            zoom: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BpmEntry {
+    pub time: f32,
+    pub bpm: f32,
+    /// Highway position in beats at `time`.
+    pub position: f32,
+}
+
+/// A [`BpmEntry`] on the own track of a laser, see [`Chart::laser_bpm`].
+#[derive(Serialize, Deserialize)]
+pub struct LaserBpmEntry {
+    pub laser: u32,
+    pub time: f32,
+    pub bpm: f32,
+    pub position: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LaserEntry {
+    pub time: f32,
+    pub laser: u32,
+    pub command: LaserFileCommand,
+}
+
+/// [`LaserCommand`] with colors as linear RGB triples.
+#[derive(Serialize, Deserialize)]
+pub enum LaserFileCommand {
+    Enter {
+        y: f32,
+        lanes: u16,
+        color: [f32; 3],
+        #[serde(default)]
+        far_color: Option<[f32; 3]>,
+        #[serde(default)]
+        texture: Option<String>,
+        #[serde(default)]
+        scroll: Option<f32>,
+        #[serde(default)]
+        note_color: Option<[f32; 3]>,
+        #[serde(default)]
+        note_height: Option<f32>,
+        #[serde(default)]
+        blend: Option<LaserBlend>,
+        #[serde(default)]
+        stencil: Option<u8>,
+    },
+    Leave,
+    /// Moves the laser to `y`, arriving at `time`.
+    LineTo {
+        time: f32,
+        y: f32,
+    },
+    /// Fades the laser to `color` over `duration` seconds.
+    ColorTo {
+        color: [f32; 3],
+        duration: f32,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NoteEntry {
+    pub time: f32,
+    pub laser: u32,
+    pub lane: u32,
+    #[serde(default)]
+    pub kind: NoteFileKind,
+}
+
+/// [`NoteKind`].
+#[derive(Serialize, Deserialize)]
+pub enum NoteFileKind {
+    Tap,
+    Roll { end: f32 },
+    Mine,
+}
+
+impl Default for NoteFileKind {
+    fn default() -> Self {
+        NoteFileKind::Tap
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SectionEntry {
+    pub time: f32,
+    pub name: String,
+}
+
+/// [`ZoomCommand`].
+#[derive(Serialize, Deserialize)]
+pub struct ZoomEntry {
+    pub time: f32,
+    pub scale: f32,
+    #[serde(default)]
+    pub duration: f32,
+}
+
+fn color([r, g, b]: [f32; 3]) -> LinSrgb<f32> {
+    LinSrgb::new(r, g, b)
+}
+
+fn rgb(color: LinSrgb<f32>) -> [f32; 3] {
+    [color.red, color.green, color.blue]
+}
+
+impl ChartFile {
+    pub fn load(fs: &dyn Vfs, path: &Path) -> Result<Chart, ChartError> {
+        let text = fs.read_to_string(path)?;
+        let file: ChartFile = ron::de::from_str(&text)?;
+        if file.required_version > CHART_VERSION {
+            return Err(ChartError::NeedsNewerVersion {
+                required: file.required_version,
+                supported: CHART_VERSION,
+            });
+        }
+        if file.version > CHART_VERSION {
+            log::info!(
+                "{} uses chart format {}, features newer than {} are ignored",
+                path.display(),
+                file.version,
+                CHART_VERSION
+            );
+        }
+        for entry in &file.lasers {
+            if let LaserFileCommand::Enter {
+                stencil: Some(stencil),
+                ..
+            } = entry.command
+            {
+                if stencil > laser::MAX_LASER_STENCIL {
+                    return Err(ChartError::StencilOutOfRange {
+                        laser: LaserId(entry.laser),
+                        time: entry.time,
+                        stencil,
+                        max: laser::MAX_LASER_STENCIL,
+                    });
+                }
+            }
+        }
+        Ok(file.into_chart())
+    }
+
+    /// Writes `chart` to `path` in the native format. Entries keep the order of the chart, so
+    /// saving a loaded chart again gives the same file.
+    pub fn save(chart: &Chart, path: &Path) -> Result<(), ChartError> {
+        let text = ron::ser::to_string_pretty(&Self::from_chart(chart), Default::default())?;
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// The file that [`into_chart`](Self::into_chart) turns back into `chart`.
+    pub fn from_chart(chart: &Chart) -> Self {
+        let mut required_version = 1;
+        let notes = chart
+            .notes
+            .iter()
+            .map(|note| NoteEntry {
+                time: note.time,
+                laser: note.laser.0,
+                lane: note.lane,
+                kind: match note.kind {
+                    NoteKind::Tap => NoteFileKind::Tap,
+                    NoteKind::Roll { end } => {
+                        required_version = required_version.max(2);
+                        NoteFileKind::Roll { end }
+                    }
+                    NoteKind::Mine => {
+                        required_version = required_version.max(3);
+                        NoteFileKind::Mine
+                    }
+                },
+            })
+            .collect();
+        let bpm = chart
+            .bpm
+            .iter()
+            .map(|entry| BpmEntry {
+                time: entry.time,
+                bpm: entry.bpm,
+                position: entry.position,
+            })
+            .collect();
+        let lasers = chart
+            .lasers
+            .iter()
+            .map(|entry| {
+                let (laser, command) = &entry.inner;
+                let command = match command {
+                    LaserCommand::Enter {
+                        y,
+                        lanes,
+                        color,
+                        surface,
+                        note_style,
+                    } => LaserFileCommand::Enter {
+                        y: *y,
+                        lanes: *lanes,
+                        color: rgb(*color),
+                        far_color: surface.far_color.map(rgb),
+                        texture: surface.texture.clone(),
+                        scroll: surface.scroll,
+                        note_color: note_style.as_ref().map(|style| rgb(style.color)),
+                        note_height: note_style.as_ref().map(|style| style.height),
+                        blend: surface.blend,
+                        stencil: surface.stencil,
+                    },
+                    LaserCommand::Leave => LaserFileCommand::Leave,
+                    LaserCommand::LineTo { time, y } => LaserFileCommand::LineTo {
+                        time: time.time,
+                        y: *y,
+                    },
+                    LaserCommand::ColorTo { color, duration } => {
+                        required_version = required_version.max(4);
+                        LaserFileCommand::ColorTo {
+                            color: rgb(*color),
+                            duration: *duration,
+                        }
+                    }
+                };
+                LaserEntry {
+                    time: entry.time,
+                    laser: laser.0,
+                    command,
+                }
+            })
+            .collect();
+        let sections = chart
+            .sections
+            .iter()
+            .map(|section| SectionEntry {
+                time: section.time,
+                name: section.inner.clone(),
+            })
+            .collect();
+        let zoom = chart
+            .zoom
+            .iter()
+            .map(|entry| ZoomEntry {
+                time: entry.time,
+                scale: entry.scale,
+                duration: entry.duration,
+            })
+            .collect();
+        let laser_bpm = chart
+            .laser_bpm
+            .iter()
+            .flat_map(|(laser, track)| {
+                track.iter().map(move |entry| LaserBpmEntry {
+                    laser: laser.0,
+                    time: entry.time,
+                    bpm: entry.bpm,
+                    position: entry.position,
+                })
+            })
+            .collect();
+        Self {
+            version: CHART_VERSION,
+            required_version,
+            default_bpm: chart.default_bpm,
+            bpm,
+            laser_bpm,
+            lasers,
+            notes,
+            sections,
+            zoom,
+        }
+    }
+
+    pub fn into_chart(self) -> Chart {
+        let mut notes: Vec<_> = self
+            .notes
+            .into_iter()
+            .map(|note| Timed {
+                time: note.time,
+                inner: Note {
+                    laser: LaserId(note.laser),
+                    lane: note.lane,
+                    kind: match note.kind {
+                        NoteFileKind::Tap => NoteKind::Tap,
+                        NoteFileKind::Roll { end } => NoteKind::Roll { end },
+                        NoteFileKind::Mine => NoteKind::Mine,
+                    },
+                },
+            })
+            .collect();
+        let mut bpm: Vec<_> = self
+            .bpm
+            .into_iter()
+            .map(|entry| Timed {
+                time: entry.time,
+                inner: BpmCommand {
+                    bpm: entry.bpm,
+                    position: entry.position,
+                },
+            })
+            .collect();
+        let mut laser_bpm = BTreeMap::<_, Vec<_>>::new();
+        for entry in self.laser_bpm {
+            laser_bpm
+                .entry(LaserId(entry.laser))
+                .or_default()
+                .push(Timed {
+                    time: entry.time,
+                    inner: BpmCommand {
+                        bpm: entry.bpm,
+                        position: entry.position,
+                    },
+                });
+        }
+        let mut lasers: Vec<_> = self
+            .lasers
+            .into_iter()
+            .map(|entry| {
+                let command = match entry.command {
+                    LaserFileCommand::Enter {
+                        y,
+                        lanes,
+                        color: near,
+                        far_color,
+                        texture,
+                        scroll,
+                        note_color,
+                        note_height,
+                        blend,
+                        stencil,
+                    } => LaserCommand::Enter {
+                        y,
+                        lanes,
+                        color: color(near),
+                        surface: LaserSurface {
+                            far_color: far_color.map(color),
+                            texture,
+                            scroll,
+                            blend,
+                            stencil,
+                        },
+                        note_style: if note_color.is_some() || note_height.is_some() {
+                            let default = NoteStyle::default();
+                            Some(NoteStyle {
+                                color: note_color.map_or(default.color, color),
+                                height: note_height.unwrap_or(default.height),
+                            })
+                        } else {
+                            None
+                        },
+                    },
+                    LaserFileCommand::Leave => LaserCommand::Leave,
+                    LaserFileCommand::LineTo { time, y } => LaserCommand::LineTo {
+                        time: Timed { time, inner: () },
+                        y,
+                    },
+                    LaserFileCommand::ColorTo {
+                        color: to,
+                        duration,
+                    } => LaserCommand::ColorTo {
+                        color: color(to),
+                        duration,
+                    },
+                };
+                Timed {
+                    time: entry.time,
+                    inner: (LaserId(entry.laser), command),
+                }
+            })
+            .collect();
+        let mut sections: Vec<_> = self
+            .sections
+            .into_iter()
+            .map(|section| Timed {
+                time: section.time,
+                inner: section.name,
+            })
+            .collect();
+        let mut zoom: Vec<_> = self
+            .zoom
+            .into_iter()
+            .map(|entry| Timed {
+                time: entry.time,
+                inner: ZoomCommand {
+                    scale: entry.scale,
+                    duration: entry.duration,
+                },
+            })
+            .collect();
+        // Everything downstream searches by time. The sorts are stable, so commands at the same
+        // time keep their order.
+        let by_time = |a: f32, b: f32| a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal);
+        notes.sort_by(|a, b| by_time(a.time, b.time));
+        bpm.sort_by(|a, b| by_time(a.time, b.time));
+        for track in laser_bpm.values_mut() {
+            track.sort_by(|a, b| by_time(a.time, b.time));
+        }
+        lasers.sort_by(|a, b| by_time(a.time, b.time));
+        sections.sort_by(|a, b| by_time(a.time, b.time));
+        zoom.sort_by(|a, b| by_time(a.time, b.time));
+        if bpm.is_empty() {
+            bpm.push(Timed {
+                time: 0.,
+                inner: BpmCommand {
+                    bpm: self.default_bpm,
+                    position: 0.,
+                },
+            });
+        }
+        Chart {
+            notes,
+            bpm,
+            laser_bpm,
+            lasers,
+            sections,
+            zoom,
+            default_bpm: self.default_bpm,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::FileSystem;
+
+    fn timed<T>(time: f32, inner: T) -> Timed<T> {
+        Timed { time, inner }
+    }
+
+    fn note(time: f32, lane: u32, kind: NoteKind) -> Timed<Note> {
+        timed(
+            time,
+            Note {
+                laser: LaserId(0),
+                lane,
+                kind,
+            },
+        )
+    }
+
+    /// A chart with every kind of entry the format has.
+    fn chart() -> Chart {
+        let mut laser_bpm = BTreeMap::new();
+        laser_bpm.insert(
+            LaserId(1),
+            vec![timed(
+                0.,
+                BpmCommand {
+                    bpm: 90.,
+                    position: 0.,
+                },
+            )],
+        );
+        Chart {
+            notes: vec![
+                note(1., 0, NoteKind::Tap),
+                note(1., 2, NoteKind::Roll { end: 2. }),
+                note(1.5, 1, NoteKind::Mine),
+            ],
+            bpm: vec![
+                timed(
+                    0.,
+                    BpmCommand {
+                        bpm: 120.,
+                        position: 0.,
+                    },
+                ),
+                timed(
+                    2.,
+                    BpmCommand {
+                        bpm: 0.,
+                        position: 4.,
+                    },
+                ),
+            ],
+            laser_bpm,
+            lasers: vec![
+                timed(
+                    0.,
+                    (
+                        LaserId(0),
+                        LaserCommand::Enter {
+                            y: 0.1,
+                            lanes: 4,
+                            color: LinSrgb::new(0., 0.1, 0.8),
+                            surface: LaserSurface {
+                                far_color: Some(LinSrgb::new(0., 0., 0.2)),
+                                texture: Some("textures/laser.png".into()),
+                                scroll: Some(0.5),
+                                blend: Some(LaserBlend::Add),
+                                stencil: Some(2),
+                            },
+                            note_style: Some(NoteStyle {
+                                color: LinSrgb::new(1., 1., 1.),
+                                height: 2.,
+                            }),
+                        },
+                    ),
+                ),
+                timed(
+                    0.,
+                    (
+                        LaserId(1),
+                        LaserCommand::Enter {
+                            y: 0.5,
+                            lanes: 2,
+                            color: LinSrgb::new(0.8, 0.1, 0.),
+                            surface: LaserSurface::default(),
+                            note_style: None,
+                        },
+                    ),
+                ),
+                timed(
+                    1.,
+                    (
+                        LaserId(0),
+                        LaserCommand::LineTo {
+                            time: timed(2., ()),
+                            y: 0.3,
+                        },
+                    ),
+                ),
+                timed(
+                    1.5,
+                    (
+                        LaserId(1),
+                        LaserCommand::ColorTo {
+                            color: LinSrgb::new(0.2, 0.2, 0.2),
+                            duration: 0.5,
+                        },
+                    ),
+                ),
+                timed(3., (LaserId(1), LaserCommand::Leave)),
+            ],
+            sections: vec![timed(0., "Intro".into()), timed(1., "Verse".into())],
+            zoom: vec![timed(
+                1.,
+                ZoomCommand {
+                    scale: 1.5,
+                    duration: 0.25,
+                },
+            )],
+            default_bpm: 120.,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = std::env::temp_dir().join(format!("iris-chart-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(CHART_FILE);
+        let chart = chart();
+        ChartFile::save(&chart, &path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let loaded = ChartFile::load(&FileSystem::new(&dir), Path::new(CHART_FILE)).unwrap();
+        assert_eq!(loaded, chart);
+        // Saving the loaded chart again gives the same file.
+        ChartFile::save(&loaded, &path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), text);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_stencil_out_of_range() {
+        let dir = std::env::temp_dir().join(format!("iris-chart-stencil-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(CHART_FILE);
+        let mut chart = chart();
+        if let LaserCommand::Enter { surface, .. } = &mut chart.lasers[0].inner.1 {
+            surface.stencil = Some(laser::MAX_LASER_STENCIL + 1);
+        }
+        ChartFile::save(&chart, &path).unwrap();
+        let loaded = ChartFile::load(&FileSystem::new(&dir), Path::new(CHART_FILE));
+        std::fs::remove_dir_all(&dir).unwrap();
+        match loaded {
+            Err(ChartError::StencilOutOfRange { stencil, .. }) => {
+                assert_eq!(stencil, laser::MAX_LASER_STENCIL + 1)
+            }
+            other => panic!("expected a stencil error, got {:?}", other),
+        }
+    }
+}
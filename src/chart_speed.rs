@@ -0,0 +1,66 @@
+//! The scroll speed last used for each chart, recalled when the chart is selected or started
+//! unless [`normalize_speed`] is set.
+//!
+//! [`normalize_speed`]: crate::settings::GameplaySettings::normalize_speed
+use crate::library::Song;
+use crate::migrate::{self, Versioned};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Width of the base BPM ranges sharing a speed, for charts that weren't played before.
+const BPM_BUCKET: f32 = 20.;
+
+/// The speeds of a profile by chart and by base BPM.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChartSpeeds {
+    /// Format version, see [`Versioned`].
+    #[serde(default = "migrate::unversioned")]
+    version: u32,
+    /// By the path of the song, see [`Song::path`].
+    pub songs: BTreeMap<PathBuf, f32>,
+    /// By the base BPM divided by [`BPM_BUCKET`], rounded.
+    pub bpm_buckets: BTreeMap<u32, f32>,
+}
+
+impl Default for ChartSpeeds {
+    fn default() -> Self {
+        Self {
+            version: Self::current_version(),
+            songs: BTreeMap::new(),
+            bpm_buckets: BTreeMap::new(),
+        }
+    }
+}
+
+impl Versioned for ChartSpeeds {
+    const MIGRATIONS: &'static [fn(&mut Self)] = &[migrate::no_changes];
+
+    fn version_mut(&mut self) -> &mut u32 {
+        &mut self.version
+    }
+}
+
+fn bpm_bucket(song: &Song) -> Option<u32> {
+    song.meta
+        .bpm
+        .map(|bpm| (bpm.base / BPM_BUCKET).round().max(0.) as u32)
+}
+
+impl ChartSpeeds {
+    /// The speed last used for `song`, or else for a song of about the same base BPM.
+    pub fn recall(&self, song: &Song) -> Option<f32> {
+        self.songs
+            .get(&song.path)
+            .or_else(|| self.bpm_buckets.get(&bpm_bucket(song)?))
+            .copied()
+    }
+
+    pub fn remember(&mut self, song: &Song, speed: f32) {
+        self.songs.insert(song.path.clone(), speed);
+        if let Some(bucket) = bpm_bucket(song) {
+            self.bpm_buckets.insert(bucket, speed);
+        }
+    }
+}
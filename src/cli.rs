@@ -0,0 +1,197 @@
+use crate::error::CliError;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+pub const USAGE: &str = "\
+Usage: iris [--portable] [--chart <path> [--autoplay] [--rate <rate>] [--seek <seconds>] \
+[--record <file>]]
+
+  --portable        keep every file beside the executable
+  --chart <path>    play a song directory or chart file right away, then quit
+  --autoplay        play every note perfectly
+  --rate <rate>     play the chart at another rate, such as 1.2
+  --seek <seconds>  start playing at this chart time
+  --record <file>   also write the replay of the play to this file";
+
+/// The options given on the command line.
+#[derive(Debug, Default)]
+pub struct CliArgs {
+    /// Keep every file beside the executable, see [`Paths`](crate::paths::Paths).
+    pub portable: bool,
+    /// Print the usage and exit.
+    pub help: bool,
+    pub launch: Option<DirectLaunch>,
+}
+
+/// A chart launched from the command line, for testing, recording demos and scripting. It plays
+/// without going through song select, and the game quits once every note has been judged.
+#[derive(Clone, Debug, Default)]
+pub struct DirectLaunch {
+    /// Directory of the song, absolute so that it doesn't depend on the library.
+    pub song_dir: PathBuf,
+    pub autoplay: bool,
+    /// Playback rate, as set by the console's `speed` command.
+    pub rate: Option<f32>,
+    /// Chart time in seconds to start at.
+    pub seek: Option<f32>,
+    /// File to write the replay to, besides the profile's latest replay.
+    pub record: Option<PathBuf>,
+}
+
+fn value<T: FromStr>(
+    flag: &'static str,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<T, CliError> {
+    let value = args.next().ok_or(CliError::MissingValue(flag))?;
+    value
+        .parse()
+        .map_err(|_| CliError::InvalidValue { flag, value })
+}
+
+impl CliArgs {
+    pub fn from_env() -> Result<Self, CliError> {
+        Self::parse(std::env::args().skip(1))
+    }
+
+    /// Parses the arguments after the program name. Relative paths are taken from the working
+    /// directory.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, CliError> {
+        let mut parsed = CliArgs::default();
+        let mut chart: Option<PathBuf> = None;
+        let mut launch = DirectLaunch::default();
+        // Flags given that only apply to a chart.
+        let mut chart_flags = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--portable" => parsed.portable = true,
+                "-h" | "--help" => parsed.help = true,
+                "--chart" => chart = Some(value("--chart", &mut args)?),
+                "--autoplay" => {
+                    launch.autoplay = true;
+                    chart_flags.push("--autoplay");
+                }
+                "--rate" => {
+                    let rate: f32 = value("--rate", &mut args)?;
+                    if !rate.is_finite() || rate <= 0. {
+                        return Err(CliError::InvalidValue {
+                            flag: "--rate",
+                            value: rate.to_string(),
+                        });
+                    }
+                    launch.rate = Some(rate);
+                    chart_flags.push("--rate");
+                }
+                "--seek" => {
+                    let seek: f32 = value("--seek", &mut args)?;
+                    if !seek.is_finite() || seek < 0. {
+                        return Err(CliError::InvalidValue {
+                            flag: "--seek",
+                            value: seek.to_string(),
+                        });
+                    }
+                    launch.seek = Some(seek);
+                    chart_flags.push("--seek");
+                }
+                "--record" => {
+                    launch.record = Some(absolute(value("--record", &mut args)?));
+                    chart_flags.push("--record");
+                }
+                _ => return Err(CliError::UnknownArgument(arg)),
+            }
+        }
+        match chart {
+            Some(chart) => {
+                let chart = absolute(chart);
+                // A chart file plays the song it is in.
+                launch.song_dir = if chart.is_file() {
+                    chart.parent().map(PathBuf::from).unwrap_or_default()
+                } else {
+                    chart
+                };
+                parsed.launch = Some(launch);
+            }
+            None => {
+                if let Some(&flag) = chart_flags.first() {
+                    return Err(CliError::NeedsChart(flag));
+                }
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+fn absolute(path: PathBuf) -> PathBuf {
+    match std::env::current_dir() {
+        Ok(dir) => dir.join(path),
+        Err(_) => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<CliArgs, CliError> {
+        CliArgs::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn rate_and_seek_are_checked() {
+        let launch = parse(&["--chart", "song", "--rate", "1.5", "--seek", "30"])
+            .unwrap()
+            .launch
+            .unwrap();
+        assert_eq!(launch.rate, Some(1.5));
+        assert_eq!(launch.seek, Some(30.));
+        for args in &[
+            ["--chart", "song", "--rate", "0"],
+            ["--chart", "song", "--rate", "-1"],
+            ["--chart", "song", "--rate", "NaN"],
+            ["--chart", "song", "--rate", "fast"],
+            ["--chart", "song", "--seek", "-1"],
+            ["--chart", "song", "--seek", "inf"],
+        ] {
+            match parse(args) {
+                Err(CliError::InvalidValue { flag, .. }) => assert_eq!(flag, args[2]),
+                other => panic!("{:?} parsed as {:?}", args, other),
+            }
+        }
+        match parse(&["--chart", "song", "--rate"]) {
+            Err(CliError::MissingValue("--rate")) => {}
+            other => panic!("parsed as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn chart_flags_need_a_chart() {
+        let cases: &[&[&str]] = &[
+            &["--autoplay"],
+            &["--rate", "1"],
+            &["--seek", "1"],
+            &["--record", "replay"],
+        ];
+        for args in cases {
+            match parse(args) {
+                Err(CliError::NeedsChart(flag)) => assert_eq!(flag, args[0]),
+                other => panic!("{:?} parsed as {:?}", args, other),
+            }
+        }
+        let parsed = parse(&["--portable"]).unwrap();
+        assert!(parsed.portable);
+        assert!(parsed.launch.is_none());
+    }
+
+    #[test]
+    fn chart_files_play_their_song() {
+        let dir = std::env::temp_dir().join(format!("iris-cli-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let chart = dir.join("chart.ron");
+        std::fs::write(&chart, "").unwrap();
+        let from_file = parse(&["--chart", chart.to_str().unwrap()]).unwrap();
+        let from_dir = parse(&["--chart", dir.to_str().unwrap()]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(from_file.launch.unwrap().song_dir, dir);
+        assert_eq!(from_dir.launch.unwrap().song_dir, dir);
+    }
+}
@@ -0,0 +1,377 @@
+use crate::chart::{Chart, PlaySettings};
+use crate::judge::{JudgeEvent, Judgement, KeyMappings};
+use crate::score::Scores;
+use crate::settings::Settings;
+use crate::ui::percent_transform;
+use crate::InterFont;
+use amethyst::{
+    core::{timing::Time, Parent, SystemDesc},
+    ecs::{Entities, Entity, Read, ReadExpect, System, SystemData, World, Write, WriteStorage},
+    shrev::{EventChannel, ReaderId},
+    ui::{Anchor, UiImage, UiText, UiTransform},
+    window::ScreenDimensions,
+    winit::{ElementState, Event, KeyboardInput, WindowEvent},
+};
+use std::collections::VecDeque;
+
+/// Seconds of play kept before the break.
+const SNIPPET_SECONDS: f32 = 2.;
+/// Seconds still shown after the break before the playback loops.
+const TAIL_SECONDS: f32 = 0.3;
+/// Fraction of the notes that must be judged for a break to count as late.
+const LATE_FRACTION: f32 = 0.75;
+/// Speed of the playback relative to the original play.
+const PLAYBACK_RATE: f32 = 0.25;
+/// Seconds of chart time visible in the widget above its judge line.
+const VISIBLE_SECONDS: f32 = 0.5;
+/// Seconds a press stays lit on the judge line.
+const PRESS_SECONDS: f32 = 0.03;
+/// Height of the judge line from the bottom of the widget, as a fraction of it.
+const JUDGE_LINE: f32 = 0.1;
+const TITLE_FONT_SIZE: f32 = 18.;
+/// Keeps the overlay above the HUD.
+const OVERLAY_Z: f32 = 40.;
+
+#[derive(Clone, Debug)]
+enum SnippetEvent {
+    Judged {
+        time: f32,
+        judgement: Judgement,
+        /// Horizontal position of the note in percent of the screen.
+        x: f32,
+    },
+    Press {
+        time: f32,
+        /// Horizontal position of the key in percent of the screen.
+        x: f32,
+    },
+}
+
+impl SnippetEvent {
+    fn time(&self) -> f32 {
+        match self {
+            SnippetEvent::Judged { time, .. } | SnippetEvent::Press { time, .. } => *time,
+        }
+    }
+}
+
+/// The last moments before the first player lost a full combo late in the chart.
+pub struct ComboBreak {
+    /// Time of the missed note.
+    pub time: f32,
+    events: Vec<SnippetEvent>,
+}
+
+/// Records a [`ComboBreak`] while playing and replays it in slow motion once the chart is over,
+/// if enabled in the gameplay settings.
+///
+/// The notes and key presses of the last seconds are kept in a ring buffer until the first miss
+/// after most of the chart was played with a full combo.
+pub struct ComboBreakSystem {
+    judge_reader: ReaderId<JudgeEvent>,
+    event_reader: ReaderId<Event>,
+    recent: VecDeque<SnippetEvent>,
+    /// Whether the current play has missed a note yet.
+    broken: bool,
+    overlay: Option<Overlay>,
+}
+
+struct Overlay {
+    /// The background, with the title and judge line.
+    panel: [Entity; 3],
+    /// One marker per event of the snippet, in the same order.
+    markers: Vec<Entity>,
+    shown_at: f64,
+}
+
+pub struct ComboBreakSystemDesc;
+
+impl<'a, 'b> SystemDesc<'a, 'b, ComboBreakSystem> for ComboBreakSystemDesc {
+    fn build(self, world: &mut World) -> ComboBreakSystem {
+        <ComboBreakSystem as System<'_>>::SystemData::setup(world);
+
+        let judge_reader = world
+            .get_mut::<EventChannel<JudgeEvent>>()
+            .unwrap()
+            .register_reader();
+        let event_reader = world
+            .get_mut::<EventChannel<Event>>()
+            .unwrap()
+            .register_reader();
+
+        ComboBreakSystem {
+            judge_reader,
+            event_reader,
+            recent: VecDeque::new(),
+            broken: false,
+            overlay: None,
+        }
+    }
+}
+
+impl ComboBreakSystem {
+    fn record(
+        &mut self,
+        judge_events: &EventChannel<JudgeEvent>,
+        events: &EventChannel<Event>,
+        now: f32,
+        play_settings: &PlaySettings,
+        mappings: &KeyMappings,
+        late: bool,
+    ) -> Option<ComboBreak> {
+        let mapping = mappings.0.get(0).map_or(&[][..], Vec::as_slice);
+        for event in events.read(&mut self.event_reader) {
+            if let Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                scancode,
+                                state: ElementState::Pressed,
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } = event
+            {
+                if let Ok(i) = mapping.binary_search_by_key(&scancode, |(s, _)| s) {
+                    let (_, (x, _)) = mapping[i];
+                    self.recent.push_back(SnippetEvent::Press {
+                        time: now + play_settings.offset,
+                        x: x / play_settings.players.max(1) as f32,
+                    });
+                }
+            }
+        }
+        let mut combo_break = None;
+        for event in judge_events.read(&mut self.judge_reader) {
+            if event.player != 0 {
+                continue;
+            }
+            self.recent.push_back(SnippetEvent::Judged {
+                time: event.time,
+                judgement: event.judgement,
+                x: event.position.x,
+            });
+            if event.judgement == Judgement::Miss && !self.broken {
+                self.broken = true;
+                if late {
+                    combo_break = Some(ComboBreak {
+                        time: event.time,
+                        events: self.recent.iter().cloned().collect(),
+                    });
+                }
+            }
+        }
+        while self
+            .recent
+            .front()
+            .map_or(false, |e| e.time() < now - SNIPPET_SECONDS)
+        {
+            self.recent.pop_front();
+        }
+        combo_break
+    }
+
+    fn clear(&mut self, entities: &Entities<'_>) {
+        self.recent.clear();
+        self.broken = false;
+        if let Some(overlay) = self.overlay.take() {
+            for entity in overlay.panel.iter().chain(&overlay.markers) {
+                entities.delete(*entity).unwrap();
+            }
+        }
+    }
+}
+
+impl<'s> System<'s> for ComboBreakSystem {
+    type SystemData = (
+        Entities<'s>,
+        Read<'s, EventChannel<JudgeEvent>>,
+        Read<'s, EventChannel<Event>>,
+        ReadExpect<'s, Time>,
+        Read<'s, Option<PlaySettings>>,
+        Read<'s, Option<Chart>>,
+        Read<'s, Scores>,
+        Read<'s, KeyMappings>,
+        Read<'s, Settings>,
+        Write<'s, Option<ComboBreak>>,
+        ReadExpect<'s, ScreenDimensions>,
+        Option<ReadExpect<'s, InterFont>>,
+        WriteStorage<'s, Parent>,
+        WriteStorage<'s, UiText>,
+        WriteStorage<'s, UiTransform>,
+        WriteStorage<'s, UiImage>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            judge_events,
+            events,
+            time,
+            play_settings,
+            chart,
+            scores,
+            mappings,
+            settings,
+            mut combo_break,
+            dimensions,
+            inter_font,
+            mut parents,
+            mut texts,
+            mut transforms,
+            mut images,
+        ): Self::SystemData,
+    ) {
+        let (play_settings, chart) = match (&*play_settings, &*chart) {
+            (Some(play_settings), Some(chart)) if settings.gameplay.combo_break_replay => {
+                (play_settings, chart)
+            }
+            _ => {
+                // Drain events so they don't pile up until a chart starts.
+                judge_events.read(&mut self.judge_reader).for_each(drop);
+                events.read(&mut self.event_reader).for_each(drop);
+                self.clear(&entities);
+                return;
+            }
+        };
+        let now = (time.absolute_time_seconds() - play_settings.base_time) as f32;
+        let total_notes = chart.judged_notes();
+        let judged = scores.players.get(0).map_or(0, |s| s.records.len());
+        if let Some(captured) = self.record(
+            &judge_events,
+            &events,
+            now,
+            play_settings,
+            &mappings,
+            judged as f32 >= total_notes as f32 * LATE_FRACTION,
+        ) {
+            *combo_break = Some(captured);
+        }
+
+        let (combo_break, inter_font) = match (&*combo_break, &inter_font) {
+            (Some(combo_break), Some(inter_font)) if judged >= total_notes => {
+                (combo_break, inter_font)
+            }
+            _ => return,
+        };
+        let real_time = time.absolute_real_time_seconds();
+        let overlay = self.overlay.get_or_insert_with(|| {
+            let panel = entities.create();
+            let mut transform =
+                percent_transform("ComboBreak", Anchor::BottomRight, -0.02, 0.05, 0.2, 0.4);
+            transform.local_z = OVERLAY_Z;
+            transforms.insert(panel, transform).unwrap();
+            images
+                .insert(panel, UiImage::SolidColor([0., 0., 0., 0.7]))
+                .unwrap();
+            let mut child = || {
+                let entity = entities.create();
+                parents.insert(entity, Parent::new(panel)).unwrap();
+                entity
+            };
+            let title = child();
+            let font_size = TITLE_FONT_SIZE * settings.ui.scale_factor(&dimensions);
+            texts
+                .insert(
+                    title,
+                    UiText::new(
+                        inter_font.0.clone(),
+                        format!("Combo break at {:.1}s", combo_break.time),
+                        [1.; 4],
+                        font_size,
+                    ),
+                )
+                .unwrap();
+            transforms
+                .insert(
+                    title,
+                    percent_transform("ComboBreakTitle", Anchor::TopMiddle, 0., -0.02, 1., 0.1),
+                )
+                .unwrap();
+            let line = child();
+            transforms
+                .insert(
+                    line,
+                    percent_transform(
+                        "ComboBreakLine",
+                        Anchor::BottomMiddle,
+                        0.,
+                        JUDGE_LINE,
+                        1.,
+                        0.005,
+                    ),
+                )
+                .unwrap();
+            images
+                .insert(line, UiImage::SolidColor([1., 1., 1., 0.8]))
+                .unwrap();
+            let markers = combo_break
+                .events
+                .iter()
+                .map(|event| {
+                    let marker = child();
+                    let (color, height) = match event {
+                        SnippetEvent::Judged { judgement, .. } => (judgement.color(), 0.02),
+                        SnippetEvent::Press { .. } => ([1.; 4], 0.01),
+                    };
+                    transforms
+                        .insert(
+                            marker,
+                            percent_transform(
+                                "ComboBreakMarker",
+                                Anchor::BottomLeft,
+                                0.,
+                                0.,
+                                0.08,
+                                height,
+                            ),
+                        )
+                        .unwrap();
+                    images.insert(marker, UiImage::SolidColor(color)).unwrap();
+                    marker
+                })
+                .collect();
+            Overlay {
+                panel: [panel, title, line],
+                markers,
+                shown_at: real_time,
+            }
+        });
+
+        // Loop over the snippet in slow motion.
+        let start = combo_break.time - SNIPPET_SECONDS;
+        let length = SNIPPET_SECONDS + TAIL_SECONDS;
+        let playback = start + ((real_time - overlay.shown_at) as f32 * PLAYBACK_RATE) % length;
+        for (event, &marker) in combo_break.events.iter().zip(&overlay.markers) {
+            let transform = match transforms.get_mut(marker) {
+                Some(transform) => transform,
+                None => continue,
+            };
+            let (x, y, visible) = match *event {
+                SnippetEvent::Judged { time, judgement, x } => {
+                    let y = JUDGE_LINE + (time - playback) / VISIBLE_SECONDS * (1. - JUDGE_LINE);
+                    // Hit notes leave at the judge line, missed ones fall through it.
+                    let bottom = if judgement == Judgement::Miss {
+                        0.
+                    } else {
+                        JUDGE_LINE
+                    };
+                    (x, y, (bottom..=1.).contains(&y))
+                }
+                SnippetEvent::Press { time, x } => {
+                    (x, JUDGE_LINE, (playback - time).abs() < PRESS_SECONDS)
+                }
+            };
+            transform.local_x = x - 0.04;
+            transform.local_y = y;
+            // Hidden markers are moved out of the panel rather than deleted.
+            if !visible {
+                transform.local_y = -1.;
+            }
+        }
+    }
+}
@@ -0,0 +1,399 @@
+use crate::chart::{Chart, PlaySettings};
+use crate::chart_file::ChartFile;
+use crate::error::ConsoleError;
+use crate::gpu::GpuInfo;
+use crate::judge::{Assisted, Autoplay, KeyMappings};
+use crate::keycap::KeycapLabels;
+use crate::kiosk::Kiosk;
+use crate::library::Song;
+use crate::paths::Paths;
+use crate::score::Scores;
+use crate::settings::Settings;
+use crate::ui::percent_transform;
+use crate::vfs::FileSystem;
+use crate::InterFont;
+use amethyst::{
+    core::{timing::Time, SystemDesc},
+    ecs::{
+        Entities, Entity, LazyUpdate, Read, ReadExpect, System, SystemData, World, Write,
+        WriteStorage,
+    },
+    shrev::{EventChannel, ReaderId},
+    ui::{Anchor, LineMode, UiText, UiTransform},
+    window::ScreenDimensions,
+    winit::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
+};
+use std::collections::{BTreeMap, VecDeque};
+use std::path::{Component, Path, PathBuf};
+
+/// Font size of the console in logical pixels.
+const CONSOLE_FONT_SIZE: f32 = 16.;
+const CONSOLE_KEY: VirtualKeyCode = VirtualKeyCode::Grave;
+/// Output lines kept; older ones are dropped.
+const MAX_LINES: usize = 12;
+
+/// Runs a command with its arguments and returns the line to print.
+pub type CommandFn = fn(&mut World, &[&str]) -> Result<String, ConsoleError>;
+
+struct Command {
+    usage: &'static str,
+    run: CommandFn,
+}
+
+/// The developer console and its commands.
+///
+/// Commands run at the end of the frame with access to the whole world. Systems add their own
+/// with [`register`](Self::register) when they are built.
+#[derive(Default)]
+pub struct Console {
+    /// Whether the console is shown and takes the keyboard.
+    pub open: bool,
+    commands: BTreeMap<&'static str, Command>,
+    /// Output, oldest first.
+    lines: VecDeque<String>,
+    input: String,
+}
+
+impl Console {
+    /// Adds a command. `usage` is shown by `help` and when the command returns
+    /// [`ConsoleError::InvalidArguments`].
+    pub fn register(&mut self, name: &'static str, usage: &'static str, run: CommandFn) {
+        self.commands.insert(name, Command { usage, run });
+    }
+
+    /// The usage and function of a command.
+    pub fn command(&self, name: &str) -> Option<(&'static str, CommandFn)> {
+        self.commands
+            .get(name)
+            .map(|command| (command.usage, command.run))
+    }
+
+    pub fn print(&mut self, line: impl Into<String>) {
+        self.lines.push_back(line.into());
+        while self.lines.len() > MAX_LINES {
+            self.lines.pop_front();
+        }
+    }
+
+    fn text(&self) -> String {
+        let mut text = String::new();
+        for line in &self.lines {
+            text += line;
+            text += "\n";
+        }
+        text += &format!("> {}_", self.input);
+        text
+    }
+}
+
+/// Parses the only argument of a command.
+fn argument<T: std::str::FromStr>(args: &[&str]) -> Result<T, ConsoleError> {
+    match args {
+        [arg] => arg.parse().map_err(|_| ConsoleError::InvalidArguments),
+        _ => Err(ConsoleError::InvalidArguments),
+    }
+}
+
+fn play_settings(world: &mut World) -> Result<&mut PlaySettings, ConsoleError> {
+    world
+        .get_mut::<Option<PlaySettings>>()
+        .and_then(Option::as_mut)
+        .ok_or(ConsoleError::NotPlaying)
+}
+
+fn help(world: &mut World, _: &[&str]) -> Result<String, ConsoleError> {
+    let console = world.read_resource::<Console>();
+    let usages: Vec<_> = console.commands.values().map(|c| c.usage).collect();
+    Ok(usages.join("\n"))
+}
+
+fn seek(world: &mut World, args: &[&str]) -> Result<String, ConsoleError> {
+    let time: f32 = argument(args)?;
+    if time < 0. {
+        return Err(ConsoleError::InvalidArguments);
+    }
+    play_settings(world)?;
+    crate::seek(world, time);
+    world.insert(Assisted(true));
+    Ok(format!("Seeked to {:.2}s", time))
+}
+
+fn speed(world: &mut World, args: &[&str]) -> Result<String, ConsoleError> {
+    let rate: f32 = argument(args)?;
+    if rate <= 0. {
+        return Err(ConsoleError::InvalidArguments);
+    }
+    world.write_resource::<Time>().set_time_scale(rate);
+    world.insert(Assisted(true));
+    Ok(format!("Playback rate {:.2}x", rate))
+}
+
+fn autoplay(world: &mut World, args: &[&str]) -> Result<String, ConsoleError> {
+    let enabled = match args {
+        ["on"] => true,
+        ["off"] => false,
+        _ => return Err(ConsoleError::InvalidArguments),
+    };
+    world.insert(Autoplay(enabled));
+    if enabled {
+        world.insert(Assisted(true));
+    }
+    Ok(format!("Autoplay {}", args[0]))
+}
+
+/// `path` below `dir`. Absolute paths and `..` are refused, so that commands, which remote control
+/// can run too, only reach files inside the directories they are meant for.
+pub(crate) fn path_below(dir: &Path, path: &Path) -> Result<PathBuf, ConsoleError> {
+    let inside = path.components().all(|component| match component {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    });
+    if inside {
+        Ok(dir.join(path))
+    } else {
+        Err(ConsoleError::OutsidePath(path.to_path_buf()))
+    }
+}
+
+/// `path` below the selected song's directory, where chart paths are looked up, or below the data
+/// directory with no song selected.
+fn chart_path(world: &World, path: PathBuf) -> Result<PathBuf, ConsoleError> {
+    let paths = world.read_resource::<Paths>();
+    let dir = match &*world.read_resource::<Option<Song>>() {
+        Some(song) => world
+            .read_resource::<Settings>()
+            .library
+            .root(&paths)
+            .join(&song.path),
+        None => paths.data.clone(),
+    };
+    path_below(&dir, &path)
+}
+
+fn load(world: &mut World, args: &[&str]) -> Result<String, ConsoleError> {
+    let path = chart_path(world, argument(args)?)?;
+    let chart = ChartFile::load(&FileSystem::new(""), &path)?;
+    let now = world.read_resource::<Time>().absolute_time_seconds();
+    play_settings(world)?.base_time = now;
+    world.insert(Some(chart));
+    world.insert(Scores::default());
+    world.insert(Assisted(true));
+    crate::clear_highway(world, 0.);
+    Ok(format!("Loaded {}", path.display()))
+}
+
+fn export(world: &mut World, args: &[&str]) -> Result<String, ConsoleError> {
+    let path = chart_path(world, argument(args)?)?;
+    match &*world.read_resource::<Option<Chart>>() {
+        Some(chart) => ChartFile::save(chart, &path)?,
+        None => return Err(ConsoleError::NotPlaying),
+    }
+    Ok(format!("Exported to {}", path.display()))
+}
+
+fn gpu(world: &mut World, _: &[&str]) -> Result<String, ConsoleError> {
+    Ok(match &*world.read_resource::<Option<GpuInfo>>() {
+        Some(info) => info.summary(),
+        None => "The GPU has not been probed yet".into(),
+    })
+}
+
+fn keys(world: &mut World, _: &[&str]) -> Result<String, ConsoleError> {
+    let keycaps = world.read_resource::<KeycapLabels>();
+    let players: Vec<_> = world
+        .read_resource::<KeyMappings>()
+        .0
+        .iter()
+        .enumerate()
+        .map(|(player, mapping)| {
+            let keys: Vec<_> = mapping
+                .iter()
+                .map(|&(scancode, (x, y))| {
+                    format!("{} ({:.2}, {:.2})", keycaps.label(scancode), x, y)
+                })
+                .collect();
+            format!("Player {}: {}", player + 1, keys.join(", "))
+        })
+        .collect();
+    Ok(players.join("\n"))
+}
+
+fn offset(world: &mut World, args: &[&str]) -> Result<String, ConsoleError> {
+    let ms: f32 = argument(args)?;
+    let settings = play_settings(world)?;
+    // A sign adjusts the current offset.
+    if args[0].starts_with('+') || args[0].starts_with('-') {
+        settings.offset += ms / 1000.;
+    } else {
+        settings.offset = ms / 1000.;
+    }
+    Ok(format!("Offset {:+.0} ms", settings.offset * 1000.))
+}
+
+/// A console toggled with the backtick key for debugging charts and gameplay. It can't be opened
+/// while kiosk mode is locked.
+///
+/// The built-in commands are `help`, `seek`, `speed` (the playback rate), `autoplay`, `load`,
+/// `export` (the current chart in the native format), `offset`, `gpu` and `keys` (the bindings and
+/// their positions), and `bundle` with the `tournament` feature.
+pub struct ConsoleSystem {
+    reader_id: ReaderId<Event>,
+    text: Option<Entity>,
+}
+
+pub struct ConsoleSystemDesc;
+
+impl<'a, 'b> SystemDesc<'a, 'b, ConsoleSystem> for ConsoleSystemDesc {
+    fn build(self, world: &mut World) -> ConsoleSystem {
+        <ConsoleSystem as System<'_>>::SystemData::setup(world);
+
+        let reader_id = world
+            .get_mut::<EventChannel<Event>>()
+            .unwrap()
+            .register_reader();
+        let mut console = world.write_resource::<Console>();
+        console.register("help", "help", help);
+        console.register("seek", "seek <seconds>", seek);
+        console.register("speed", "speed <rate>", speed);
+        console.register("autoplay", "autoplay on|off", autoplay);
+        console.register("load", "load <chart>", load);
+        console.register("export", "export <chart>", export);
+        console.register("offset", "offset <ms>|+<ms>|-<ms>", offset);
+        console.register("gpu", "gpu", gpu);
+        console.register("keys", "keys", keys);
+        #[cfg(feature = "tournament")]
+        console.register("bundle", "bundle <file>", crate::tournament::export);
+        console.print("Type `help` for a list of commands.");
+
+        ConsoleSystem {
+            reader_id,
+            text: None,
+        }
+    }
+}
+
+impl ConsoleSystem {
+    fn execute(console: &mut Console, lazy: &LazyUpdate) {
+        let line = std::mem::replace(&mut console.input, String::new());
+        console.print(format!("> {}", line));
+        let mut words = line.split_whitespace();
+        let name = match words.next() {
+            Some(name) => name,
+            None => return,
+        };
+        let args: Vec<String> = words.map(String::from).collect();
+        let (usage, run) = match console.command(name) {
+            Some(command) => command,
+            None => {
+                console.print(ConsoleError::UnknownCommand(name.into()).to_string());
+                return;
+            }
+        };
+        lazy.exec_mut(move |world| {
+            let args: Vec<_> = args.iter().map(String::as_str).collect();
+            let output = match run(world, &args) {
+                Ok(output) => output,
+                Err(ConsoleError::InvalidArguments) => format!("usage: {}", usage),
+                Err(e) => e.to_string(),
+            };
+            log::info!("Console: {}", output);
+            world.write_resource::<Console>().print(output);
+        });
+    }
+}
+
+impl<'s> System<'s> for ConsoleSystem {
+    type SystemData = (
+        Entities<'s>,
+        Read<'s, EventChannel<Event>>,
+        Write<'s, Console>,
+        Read<'s, LazyUpdate>,
+        Read<'s, Settings>,
+        Read<'s, Kiosk>,
+        Write<'s, KeycapLabels>,
+        ReadExpect<'s, ScreenDimensions>,
+        Option<ReadExpect<'s, InterFont>>,
+        WriteStorage<'s, UiText>,
+        WriteStorage<'s, UiTransform>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            events,
+            mut console,
+            lazy,
+            settings,
+            kiosk,
+            mut keycaps,
+            dimensions,
+            inter_font,
+            mut texts,
+            mut transforms,
+        ): Self::SystemData,
+    ) {
+        for event in events.read(&mut self.reader_id) {
+            keycaps.learn(event);
+            let event = match event {
+                Event::WindowEvent { event, .. } => event,
+                _ => continue,
+            };
+            match event {
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(key),
+                            ..
+                        },
+                    ..
+                } => match *key {
+                    CONSOLE_KEY if kiosk.locked(&settings) => {}
+                    CONSOLE_KEY => console.open = !console.open,
+                    _ if !console.open => {}
+                    VirtualKeyCode::Return => Self::execute(&mut console, &lazy),
+                    VirtualKeyCode::Back => {
+                        console.input.pop();
+                    }
+                    _ => {}
+                },
+                // The toggle key types a backtick or tilde, which no command needs.
+                WindowEvent::ReceivedCharacter(c)
+                    if console.open && !c.is_control() && *c != '`' && *c != '~' =>
+                {
+                    console.input.push(*c)
+                }
+                _ => {}
+            }
+        }
+
+        // The font is only available once the loading state has finished.
+        let inter_font = match (console.open, &inter_font) {
+            (true, Some(inter_font)) => inter_font,
+            _ => {
+                if let Some(text) = self.text.take() {
+                    entities.delete(text).unwrap();
+                }
+                return;
+            }
+        };
+        let font_size = CONSOLE_FONT_SIZE * settings.ui.scale_factor(&dimensions);
+        let entity = *self.text.get_or_insert_with(|| {
+            let entity = entities.create();
+            let mut text = UiText::new(inter_font.0.clone(), String::new(), [1.; 4], font_size);
+            text.align = Anchor::BottomLeft;
+            text.line_mode = LineMode::Wrap;
+            texts.insert(entity, text).unwrap();
+            let mut transform =
+                percent_transform("Console", Anchor::TopLeft, 0.01, -0.01, 0.6, 0.4);
+            transform.local_z = 30.;
+            transforms.insert(entity, transform).unwrap();
+            entity
+        });
+        if let Some(text) = texts.get_mut(entity) {
+            text.text = console.text();
+            text.font_size = font_size;
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use crate::error::IrisError;
+use crate::kiosk;
+use crate::library::Song;
+use crate::migrate::{self, Versioned};
+use crate::profile::Profile;
+use crate::save_queue;
+use crate::settings::Settings;
+use crate::song_select::SongSelectState;
+use crate::ui::percent_transform;
+use crate::InterFont;
+use amethyst::{
+    config::Config,
+    core::timing::Time,
+    ecs::Entity,
+    input::is_key_down,
+    prelude::*,
+    ui::{Anchor, LineMode, UiText},
+    window::ScreenDimensions,
+    winit::VirtualKeyCode,
+};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Directory below the data directory holding course definitions.
+const COURSES_DIR: &str = "courses";
+/// Font size of the results screen in logical pixels.
+const RESULT_FONT_SIZE: f32 = 28.;
+
+/// A fixed sequence of charts played back-to-back with one gauge, like a dan course.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Course {
+    pub name: String,
+    /// Song directories relative to the library root, in play order.
+    pub songs: Vec<PathBuf>,
+}
+
+impl Course {
+    /// Reads every `.ron` file in the courses directory below `root`.
+    pub fn load_all(root: &Path) -> Vec<Course> {
+        let dir = root.join(COURSES_DIR);
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            // Having no courses is normal.
+            Err(_) => return Vec::new(),
+        };
+        let mut courses: Vec<_> = entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().map_or(false, |e| e == "ron"))
+            .filter_map(|path| match Course::load_no_fallback(&path) {
+                Ok(course) => Some(course),
+                Err(e) => {
+                    log::warn!("Failed to read {}: {}", path.display(), e);
+                    None
+                }
+            })
+            .collect();
+        courses.sort_by(|a, b| a.name.cmp(&b.name));
+        courses
+    }
+
+    /// Looks up the songs of the course, or returns the first one missing from `songs`.
+    pub fn resolve(&self, songs: &[Song]) -> Result<Vec<Song>, PathBuf> {
+        self.songs
+            .iter()
+            .map(|path| {
+                songs
+                    .iter()
+                    .find(|song| &song.path == path)
+                    .cloned()
+                    .ok_or_else(|| path.clone())
+            })
+            .collect()
+    }
+
+    pub fn record_path(&self, root: &Path) -> PathBuf {
+        root.join("scores")
+            .join("courses")
+            .join(format!("{}.ron", self.name))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StageResult {
+    pub title: String,
+    pub score: u32,
+    pub max_combo: u32,
+}
+
+/// The course being played, inserted as an `Option<CourseProgress>` resource.
+///
+/// The gauge starts full and carries over between charts; the course fails as soon as it is
+/// empty. There are no retries: every stage is played exactly once.
+pub struct CourseProgress {
+    pub course: Course,
+    pub songs: Vec<Song>,
+    /// Index into `songs` of the chart being played.
+    pub stage: usize,
+    /// Gauge at the start of the current stage.
+    pub gauge: f32,
+    pub results: Vec<StageResult>,
+    /// Whether a stage had [help](crate::judge::Assisted), which keeps the record from being
+    /// saved.
+    pub assisted: bool,
+}
+
+impl CourseProgress {
+    pub fn new(course: Course, songs: Vec<Song>) -> Self {
+        Self {
+            course,
+            songs,
+            stage: 0,
+            gauge: 1.,
+            results: Vec::new(),
+            assisted: false,
+        }
+    }
+
+    pub fn song(&self) -> &Song {
+        &self.songs[self.stage]
+    }
+
+    /// Records the stage that just ended. Returns the next song, or `None` if the course is over.
+    pub fn finish_stage(&mut self, result: StageResult, gauge: f32) -> Option<&Song> {
+        self.results.push(result);
+        self.gauge = gauge;
+        self.stage += 1;
+        if self.failed() {
+            None
+        } else {
+            self.songs.get(self.stage)
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        self.gauge <= 0.
+    }
+
+    pub fn cleared(&self) -> bool {
+        !self.failed() && self.results.len() == self.songs.len()
+    }
+
+    pub fn total_score(&self) -> u32 {
+        self.results.iter().map(|r| r.score).sum()
+    }
+}
+
+/// The best result of a course so far.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct CourseRecord {
+    /// Format version, see [`Versioned`].
+    #[serde(default = "migrate::unversioned")]
+    pub version: u32,
+    pub cleared: bool,
+    pub best_score: u32,
+    /// Most stages reached in one attempt.
+    pub best_stage: usize,
+    pub plays: u32,
+}
+
+impl Default for CourseRecord {
+    fn default() -> Self {
+        Self {
+            version: Self::current_version(),
+            cleared: false,
+            best_score: 0,
+            best_stage: 0,
+            plays: 0,
+        }
+    }
+}
+
+impl Versioned for CourseRecord {
+    const MIGRATIONS: &'static [fn(&mut Self)] = &[migrate::no_changes];
+
+    fn version_mut(&mut self) -> &mut u32 {
+        &mut self.version
+    }
+}
+
+impl CourseRecord {
+    pub fn update(&mut self, progress: &CourseProgress) {
+        self.cleared |= progress.cleared();
+        self.best_score = self.best_score.max(progress.total_score());
+        self.best_stage = self.best_stage.max(progress.results.len());
+        self.plays += 1;
+    }
+}
+
+/// Shows the outcome of each stage of a finished course and saves the clear record. Enter returns
+/// to song select, as does a timeout in kiosk mode.
+#[derive(Default)]
+pub struct CourseResultState {
+    text: Option<Entity>,
+    shown_at: f64,
+}
+
+impl CourseResultState {
+    fn save_record(
+        profile: &Profile,
+        progress: &CourseProgress,
+    ) -> Result<CourseRecord, IrisError> {
+        let path = progress.course.record_path(&profile.dir);
+        let mut record: CourseRecord = migrate::load(&path).unwrap_or_default();
+        record.update(progress);
+        save_queue::save(path, &record)?;
+        Ok(record)
+    }
+}
+
+impl SimpleState for CourseResultState {
+    fn on_start(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        let mut text = {
+            let progress = world.read_resource::<Option<CourseProgress>>();
+            let progress = match &*progress {
+                Some(progress) => progress,
+                None => return,
+            };
+            let mut text = format!(
+                "{}: {}\n\n",
+                progress.course.name,
+                if progress.cleared() {
+                    "CLEARED"
+                } else {
+                    "FAILED"
+                }
+            );
+            for (i, result) in progress.results.iter().enumerate() {
+                text += &format!(
+                    "Stage {}  {}  {:08}  {} combo\n",
+                    i + 1,
+                    result.title,
+                    result.score,
+                    result.max_combo
+                );
+            }
+            text += &format!("\nTotal {:09}", progress.total_score());
+            if progress.assisted {
+                text += "\nAssisted, not saved as a record";
+            } else {
+                match Self::save_record(&world.read_resource::<Profile>(), progress) {
+                    Ok(record) => {
+                        text += &format!(
+                            "\nBest {:09}{}",
+                            record.best_score,
+                            if record.cleared { "  (cleared)" } else { "" }
+                        )
+                    }
+                    Err(e) => log::warn!("Failed to save course record: {}", e),
+                }
+            }
+            text
+        };
+        text += "\n\nPress Enter to continue";
+
+        let font = world.read_resource::<InterFont>().0.clone();
+        let font_size = {
+            let settings = world.read_resource::<Settings>();
+            let dimensions = world.read_resource::<ScreenDimensions>();
+            RESULT_FONT_SIZE * settings.ui.scale_factor(&dimensions)
+        };
+        let transform = percent_transform("CourseResult", Anchor::Middle, 0., 0., 0.8, 0.8);
+        let mut ui_text = UiText::new(font, text, [1.; 4], font_size);
+        ui_text.line_mode = LineMode::Wrap;
+        self.text = Some(world.create_entity().with(transform).with(ui_text).build());
+        self.shown_at = world.read_resource::<Time>().absolute_real_time_seconds();
+    }
+
+    fn on_stop(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        if let Some(text) = self.text.take() {
+            world.delete_entity(text).unwrap();
+        }
+        world.insert(None::<CourseProgress>);
+    }
+
+    fn handle_event(
+        &mut self,
+        StateData { world, .. }: StateData<'_, GameData<'_, '_>>,
+        event: StateEvent,
+    ) -> SimpleTrans {
+        if let StateEvent::Window(event) = &event {
+            if kiosk::exit_requested(world, event) {
+                return Trans::Quit;
+            }
+            if is_key_down(event, VirtualKeyCode::Return) {
+                return Trans::Switch(Box::new(SongSelectState::default()));
+            }
+        }
+        Trans::None
+    }
+
+    fn update(
+        &mut self,
+        StateData { world, .. }: &mut StateData<'_, GameData<'_, '_>>,
+    ) -> SimpleTrans {
+        let now = world.read_resource::<Time>().absolute_real_time_seconds();
+        let settings = world.read_resource::<Settings>();
+        let kiosk = &settings.kiosk;
+        if kiosk.enabled && now - self.shown_at > kiosk.results_seconds {
+            return Trans::Switch(Box::new(SongSelectState::default()));
+        }
+        Trans::None
+    }
+}
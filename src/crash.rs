@@ -0,0 +1,199 @@
+//! Crash reports: a panic on the main thread writes a zip with everything needed to reproduce
+//! the crash, and the next launch tells the user where to find it.
+use crate::kiosk;
+use crate::settings::Settings;
+use crate::song_select::SongSelectState;
+use crate::ui::percent_transform;
+use crate::InterFont;
+use amethyst::{
+    ecs::Entity,
+    input::is_key_down,
+    prelude::*,
+    ui::{Anchor, LineMode, UiText},
+    window::ScreenDimensions,
+    winit::VirtualKeyCode,
+};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::panic::PanicInfo;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zip::{write::FileOptions, ZipWriter};
+
+/// Log lines kept for the report; older ones are dropped.
+const MAX_LOG_LINES: usize = 200;
+/// Directory of the reports, relative to the cache directory.
+const CRASH_DIR: &str = "crashes";
+/// Names the report that hasn't been shown to the user yet.
+const PENDING_FILE: &str = "pending";
+const DIALOG_FONT_SIZE: f32 = 20.;
+
+lazy_static::lazy_static! {
+    static ref RECENT_LOG: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+    /// Files of the report by name, kept up to date while the game runs as the world can't be
+    /// reached from the panic hook.
+    static ref CONTEXT: Mutex<BTreeMap<&'static str, String>> = Mutex::new(BTreeMap::new());
+}
+
+/// Keeps a formatted log line for the next report.
+pub fn record_log(line: &log::Record<'_>) {
+    let mut recent = RECENT_LOG.lock().unwrap_or_else(|e| e.into_inner());
+    recent.push_back(format!(
+        "[{}][{}] {}",
+        line.level(),
+        line.target(),
+        line.args()
+    ));
+    while recent.len() > MAX_LOG_LINES {
+        recent.pop_front();
+    }
+}
+
+/// Sets the file `name` of the next report, replacing what it held.
+pub fn set_context(name: &'static str, contents: String) {
+    CONTEXT
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(name, contents);
+}
+
+/// Removes the file `name` from the next report.
+pub fn clear_context(name: &'static str) {
+    CONTEXT
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(name);
+}
+
+/// Puts the current settings into the next report.
+pub fn snapshot_settings(settings: &Settings) {
+    match ron::ser::to_string_pretty(settings, Default::default()) {
+        Ok(text) => set_context("settings.ron", text),
+        Err(e) => log::warn!("Failed to snapshot settings for crash reports: {}", e),
+    }
+}
+
+/// FNV-1a of a chart file, which identifies the chart in reports independently of its path.
+pub fn chart_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Writes a report for panics on the main thread, after printing the panic as usual.
+///
+/// Panics on other threads, like the importer's, are caught and reported where they happen.
+pub fn install(root: &Path) {
+    let dir = root.join(CRASH_DIR);
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        if std::thread::current().name() != Some("main") {
+            return;
+        }
+        match write_report(&dir, info) {
+            Ok(path) => log::error!("Crash report written to {}", path.display()),
+            Err(e) => log::error!("Failed to write crash report: {}", e),
+        }
+    }));
+}
+
+fn write_report(dir: &Path, info: &PanicInfo<'_>) -> std::io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let name = format!("crash-{}.zip", timestamp);
+    let path = dir.join(&name);
+
+    let mut zip = ZipWriter::new(std::fs::File::create(&path)?);
+    let options = FileOptions::default();
+    zip.start_file("panic.txt", options)?;
+    write!(
+        zip,
+        "iris {}\n{}\n\n{:?}",
+        env!("CARGO_PKG_VERSION"),
+        info,
+        backtrace::Backtrace::new()
+    )?;
+    zip.start_file("log.txt", options)?;
+    for line in RECENT_LOG.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+        writeln!(zip, "{}", line)?;
+    }
+    for (name, contents) in CONTEXT.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+        zip.start_file(*name, options)?;
+        zip.write_all(contents.as_bytes())?;
+    }
+    zip.finish()?;
+
+    std::fs::write(dir.join(PENDING_FILE), name)?;
+    Ok(path)
+}
+
+/// The report of the last run if it crashed, which is only returned once.
+pub fn take_pending(root: &Path) -> Option<PathBuf> {
+    let dir = root.join(CRASH_DIR);
+    let pending = dir.join(PENDING_FILE);
+    let name = std::fs::read_to_string(&pending).ok()?;
+    if let Err(e) = std::fs::remove_file(&pending) {
+        log::warn!("Failed to clear pending crash report: {}", e);
+    }
+    Some(dir.join(name.trim()))
+}
+
+/// Tells the user that the last run crashed and where its report is, then goes to song select.
+pub struct CrashReportState {
+    report: PathBuf,
+    text: Option<Entity>,
+}
+
+impl CrashReportState {
+    pub fn new(report: PathBuf) -> Self {
+        Self { report, text: None }
+    }
+}
+
+impl SimpleState for CrashReportState {
+    fn on_start(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        let text = format!(
+            "Iris closed unexpectedly last time.\n\n\
+             A crash report was saved to\n{}\n\n\
+             Please attach it when reporting the problem.\n\n\
+             Press Enter to continue",
+            self.report.display()
+        );
+        let font = world.read_resource::<InterFont>().0.clone();
+        let font_size = {
+            let settings = world.read_resource::<Settings>();
+            let dimensions = world.read_resource::<ScreenDimensions>();
+            DIALOG_FONT_SIZE * settings.ui.scale_factor(&dimensions)
+        };
+        let transform = percent_transform("CrashReport", Anchor::Middle, 0., 0., 0.8, 0.8);
+        let mut ui_text = UiText::new(font, text, [1.; 4], font_size);
+        ui_text.line_mode = LineMode::Wrap;
+        self.text = Some(world.create_entity().with(transform).with(ui_text).build());
+    }
+
+    fn on_stop(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        if let Some(text) = self.text.take() {
+            world.delete_entity(text).unwrap();
+        }
+    }
+
+    fn handle_event(
+        &mut self,
+        StateData { world, .. }: StateData<'_, GameData<'_, '_>>,
+        event: StateEvent,
+    ) -> SimpleTrans {
+        if let StateEvent::Window(event) = &event {
+            if kiosk::exit_requested(world, event) {
+                return Trans::Quit;
+            }
+            if is_key_down(event, VirtualKeyCode::Return) {
+                return Trans::Switch(Box::new(SongSelectState::default()));
+            }
+        }
+        Trans::None
+    }
+}
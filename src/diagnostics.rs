@@ -0,0 +1,146 @@
+use crate::settings::Settings;
+use crate::ui::percent_transform;
+use crate::InterFont;
+use amethyst::{
+    core::{timing::Time, SystemDesc},
+    ecs::{Entities, Entity, Read, ReadExpect, System, SystemData, World, WriteStorage},
+    shrev::{EventChannel, ReaderId},
+    ui::{Anchor, UiText, UiTransform},
+    window::ScreenDimensions,
+};
+
+/// Seconds a toast stays on screen after the error last occurred.
+const TOAST_SECONDS: f64 = 5.;
+/// Toasts shown at once; older ones are dropped.
+const MAX_TOASTS: usize = 5;
+/// Font size of toasts in logical pixels.
+const TOAST_FONT_SIZE: f32 = 18.;
+
+/// A recoverable failure to report to the player, such as bad chart data.
+#[derive(Clone, Debug)]
+pub struct ErrorEvent {
+    pub message: String,
+}
+
+impl ErrorEvent {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+struct Toast {
+    message: String,
+    expires: f64,
+    /// Created once the font is available.
+    entity: Option<Entity>,
+}
+
+/// Logs [`ErrorEvent`]s and shows them as toasts in the top right corner.
+///
+/// Errors that repeat every frame are collapsed into one toast that stays while they occur.
+pub struct ErrorOverlaySystem {
+    reader_id: ReaderId<ErrorEvent>,
+    toasts: Vec<Toast>,
+}
+
+pub struct ErrorOverlaySystemDesc;
+
+impl<'a, 'b> SystemDesc<'a, 'b, ErrorOverlaySystem> for ErrorOverlaySystemDesc {
+    fn build(self, world: &mut World) -> ErrorOverlaySystem {
+        <ErrorOverlaySystem as System<'_>>::SystemData::setup(world);
+
+        let reader_id = world
+            .get_mut::<EventChannel<ErrorEvent>>()
+            .unwrap()
+            .register_reader();
+
+        ErrorOverlaySystem {
+            reader_id,
+            toasts: Vec::new(),
+        }
+    }
+}
+
+impl<'s> System<'s> for ErrorOverlaySystem {
+    type SystemData = (
+        Entities<'s>,
+        Read<'s, EventChannel<ErrorEvent>>,
+        ReadExpect<'s, Time>,
+        Read<'s, Settings>,
+        ReadExpect<'s, ScreenDimensions>,
+        Option<ReadExpect<'s, InterFont>>,
+        WriteStorage<'s, UiText>,
+        WriteStorage<'s, UiTransform>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            events,
+            time,
+            settings,
+            dimensions,
+            inter_font,
+            mut texts,
+            mut transforms,
+        ): Self::SystemData,
+    ) {
+        let now = time.absolute_real_time_seconds();
+        for event in events.read(&mut self.reader_id) {
+            match self.toasts.iter_mut().find(|t| t.message == event.message) {
+                Some(toast) => toast.expires = now + TOAST_SECONDS,
+                None => {
+                    log::error!("{}", event.message);
+                    self.toasts.push(Toast {
+                        message: event.message.clone(),
+                        expires: now + TOAST_SECONDS,
+                        entity: None,
+                    });
+                }
+            }
+        }
+
+        let overflow = self.toasts.len().saturating_sub(MAX_TOASTS);
+        let mut index = 0;
+        self.toasts.retain(|toast| {
+            index += 1;
+            let keep = index > overflow && toast.expires > now;
+            if !keep {
+                if let Some(entity) = toast.entity {
+                    entities.delete(entity).unwrap();
+                }
+            }
+            keep
+        });
+
+        let inter_font = match &inter_font {
+            Some(inter_font) => inter_font,
+            None => return,
+        };
+        let font_size = TOAST_FONT_SIZE * settings.ui.scale_factor(&dimensions);
+        for (i, toast) in self.toasts.iter_mut().enumerate() {
+            let entity = *toast.entity.get_or_insert_with(|| {
+                let entity = entities.create();
+                let mut text = UiText::new(
+                    inter_font.0.clone(),
+                    toast.message.clone(),
+                    [1., 0.3, 0.3, 1.],
+                    font_size,
+                );
+                text.align = Anchor::MiddleRight;
+                texts.insert(entity, text).unwrap();
+                let mut transform =
+                    percent_transform("ErrorToast", Anchor::TopRight, -0.01, 0., 0.6, 0.04);
+                transform.local_z = 20.;
+                transforms.insert(entity, transform).unwrap();
+                entity
+            });
+            if let Some(transform) = transforms.get_mut(entity) {
+                transform.local_y = -0.01 - 0.04 * i as f32;
+            }
+        }
+    }
+}
@@ -0,0 +1,406 @@
+//! Downloads songs from an online repository into the library, enabled with the `download`
+//! feature.
+//!
+//! A repository serves `index.json`, listing every song with the URL and SHA-256 of a zip archive
+//! of its song directory. Downloaded archives are verified and installed as [packs](crate::pack).
+use crate::error::DownloadError;
+use crate::kiosk;
+use crate::pack::{self, Pack, PackIndex};
+use crate::paths::Paths;
+use crate::settings::Settings;
+use crate::song_select::SongSelectState;
+use crate::ui::percent_transform;
+use crate::InterFont;
+use amethyst::{
+    ecs::Entity,
+    input::is_key_down,
+    prelude::*,
+    ui::{Anchor, LineMode, UiText},
+    window::ScreenDimensions,
+    winit::{Event, VirtualKeyCode, WindowEvent},
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+/// Directory below the library root that archives are downloaded to. It is hidden, so the
+/// library scan skips it.
+const DOWNLOAD_DIR: &str = ".downloads";
+/// Font size of the song list in logical pixels.
+const DOWNLOAD_FONT_SIZE: f32 = 28.;
+/// Matching songs listed at once.
+const LISTED_SONGS: usize = 12;
+/// Largest song list accepted from a repository.
+const MAX_INDEX_BYTES: u64 = 16 << 20;
+/// Largest song archive accepted from a repository, which is checked against its checksum only
+/// once it is complete.
+const MAX_ARCHIVE_BYTES: u64 = 1 << 30;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RemoteSong {
+    pub title: String,
+    pub artist: String,
+    #[serde(default)]
+    pub level: u32,
+    /// The zip archive of the song, relative to the repository URL unless absolute.
+    pub url: String,
+    /// Hex encoded SHA-256 of the archive.
+    pub sha256: String,
+}
+
+impl RemoteSong {
+    fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        self.title.to_lowercase().contains(&query) || self.artist.to_lowercase().contains(&query)
+    }
+
+    /// The file name to download the archive to, whose stem also names the pack directory.
+    fn archive_name(&self) -> Result<String, DownloadError> {
+        // The query and fragment are not part of the path on the server.
+        let path = self
+            .url
+            .split(|c| c == '?' || c == '#')
+            .next()
+            .unwrap_or_default();
+        let name = path.rsplit('/').next().unwrap_or_default();
+        let name = if name.is_empty() {
+            format!("{}.zip", self.title)
+        } else {
+            name.to_string()
+        };
+        safe_file_name(&name).ok_or(DownloadError::ArchiveName(name))
+    }
+}
+
+/// The last plain component of `name`, like zip's `ZipFile::sanitized_name` does for paths in
+/// archives, so that names such as `..` or `a\..\b.zip` can't leave the download directory.
+fn safe_file_name(name: &str) -> Option<String> {
+    let name = name
+        .split('\0')
+        .next()
+        .unwrap_or_default()
+        .replace('\\', "/");
+    Path::new(&name)
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .last()
+}
+
+#[derive(Deserialize)]
+struct RepositoryIndex {
+    songs: Vec<RemoteSong>,
+}
+
+fn url(repository: &str, path: &str) -> String {
+    if path.contains("://") {
+        path.into()
+    } else {
+        format!("{}/{}", repository.trim_end_matches('/'), path)
+    }
+}
+
+/// The body of `url`, failing once it grows beyond `limit` bytes.
+fn get(url: &str, limit: u64) -> Result<Vec<u8>, DownloadError> {
+    let response = ureq::get(url).call();
+    if let Some(e) = response.synthetic_error() {
+        return Err(DownloadError::Http(e.to_string()));
+    }
+    if !response.ok() {
+        return Err(DownloadError::Http(format!(
+            "{}: {} {}",
+            url,
+            response.status(),
+            response.status_text()
+        )));
+    }
+    let mut bytes = Vec::new();
+    response
+        .into_reader()
+        .take(limit + 1)
+        .read_to_end(&mut bytes)?;
+    if bytes.len() as u64 > limit {
+        return Err(DownloadError::TooLarge {
+            url: url.into(),
+            limit,
+        });
+    }
+    Ok(bytes)
+}
+
+/// The songs offered by `repository`.
+pub fn fetch_index(repository: &str) -> Result<Vec<RemoteSong>, DownloadError> {
+    let bytes = get(&url(repository, "index.json"), MAX_INDEX_BYTES)?;
+    let index: RepositoryIndex = serde_json::from_slice(&bytes)?;
+    Ok(index.songs)
+}
+
+/// Downloads `song`, checks it against its checksum and extracts it into the library as a pack.
+/// The pack still has to be added to the [`PackIndex`].
+pub fn download(
+    repository: &str,
+    song: &RemoteSong,
+    library_root: &Path,
+    replace: bool,
+) -> Result<Pack, DownloadError> {
+    let bytes = get(&url(repository, &song.url), MAX_ARCHIVE_BYTES)?;
+    let actual: String = Sha256::digest(&bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    if !actual.eq_ignore_ascii_case(song.sha256.trim()) {
+        return Err(DownloadError::Checksum {
+            expected: song.sha256.clone(),
+            actual,
+        });
+    }
+    let dir = library_root.join(DOWNLOAD_DIR);
+    std::fs::create_dir_all(&dir)?;
+    let archive = dir.join(song.archive_name()?);
+    std::fs::write(&archive, bytes)?;
+    let pack = pack::extract(library_root, &archive, replace);
+    let _ = std::fs::remove_file(&archive);
+    Ok(pack?)
+}
+
+/// Searches the repository in `download.repository` and downloads songs from it.
+///
+/// Typing filters the songs by title and artist, up and down select and Enter downloads the
+/// selected song in the background. Escape returns to song select, which lists the downloaded
+/// songs.
+#[derive(Default)]
+pub struct DownloadState {
+    library_root: PathBuf,
+    repository: String,
+    index: Option<Receiver<Result<Vec<RemoteSong>, DownloadError>>>,
+    songs: Vec<RemoteSong>,
+    query: String,
+    /// Indices into `songs` matching the query.
+    matches: Vec<usize>,
+    selected: usize,
+    /// Songs being downloaded, by title.
+    downloads: Vec<(String, Receiver<Result<Pack, DownloadError>>)>,
+    /// Outcome of the last download.
+    status: String,
+    text: Option<Entity>,
+}
+
+impl DownloadState {
+    fn filter(&mut self) {
+        let query = &self.query;
+        self.matches = (0..self.songs.len())
+            .filter(|&i| self.songs[i].matches(query))
+            .collect();
+        self.selected = self.selected.min(self.matches.len().saturating_sub(1));
+    }
+
+    fn start_download(&mut self) {
+        let song = match self.matches.get(self.selected) {
+            Some(&i) => self.songs[i].clone(),
+            None => return,
+        };
+        let archive = match song.archive_name() {
+            Ok(archive) => archive,
+            Err(e) => {
+                self.status = format!("Failed to download {}: {}", song.title, e);
+                return;
+            }
+        };
+        let replace = Path::new(&archive).file_stem().map_or(false, |stem| {
+            PackIndex::open(&self.library_root)
+                .get(&stem.to_string_lossy())
+                .is_some()
+        });
+        let repository = self.repository.clone();
+        let library_root = self.library_root.clone();
+        let (sender, receiver) = channel();
+        self.downloads.push((song.title.clone(), receiver));
+        thread::spawn(move || {
+            let _ = sender.send(download(&repository, &song, &library_root, replace));
+        });
+    }
+
+    fn label(&self) -> String {
+        let mut text = format!("Download songs\n\nSearch: {}_\n\n", self.query);
+        if self.index.is_some() {
+            text += "Loading the song list\n";
+        }
+        let first = self.selected.saturating_sub(LISTED_SONGS / 2);
+        for (i, &song) in self
+            .matches
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(LISTED_SONGS)
+        {
+            let song = &self.songs[song];
+            let marker = if i == self.selected { "> " } else { "  " };
+            text += &format!(
+                "{}{} / {}  Lv.{}\n",
+                marker, song.title, song.artist, song.level
+            );
+        }
+        for (title, _) in &self.downloads {
+            text += &format!("\nDownloading {}", title);
+        }
+        if !self.status.is_empty() {
+            text += &format!("\n{}", self.status);
+        }
+        text += "\nEnter: download  Escape: back";
+        text
+    }
+
+    fn refresh(&self, world: &World) {
+        if let Some(text) = self.text {
+            if let Some(text) = world.write_storage::<UiText>().get_mut(text) {
+                text.text = self.label();
+            }
+        }
+    }
+}
+
+impl SimpleState for DownloadState {
+    fn on_start(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        self.library_root = world
+            .read_resource::<Settings>()
+            .library
+            .root(&world.read_resource::<Paths>());
+        let repository = world
+            .read_resource::<Settings>()
+            .download
+            .repository
+            .clone();
+        match repository {
+            Some(repository) => {
+                self.repository = repository.clone();
+                let (sender, receiver) = channel();
+                thread::spawn(move || {
+                    let _ = sender.send(fetch_index(&repository));
+                });
+                self.index = Some(receiver);
+            }
+            None => self.status = DownloadError::NoRepository.to_string(),
+        }
+
+        let font = world.read_resource::<InterFont>().0.clone();
+        let font_size = {
+            let settings = world.read_resource::<Settings>();
+            let dimensions = world.read_resource::<ScreenDimensions>();
+            DOWNLOAD_FONT_SIZE * settings.ui.scale_factor(&dimensions)
+        };
+        let transform = percent_transform("Download", Anchor::Middle, 0., 0., 0.7, 0.9);
+        let mut ui_text = UiText::new(font, self.label(), [1.; 4], font_size);
+        ui_text.line_mode = LineMode::Wrap;
+        self.text = Some(world.create_entity().with(transform).with(ui_text).build());
+    }
+
+    fn on_stop(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        if let Some(text) = self.text.take() {
+            world.delete_entity(text).unwrap();
+        }
+    }
+
+    fn handle_event(
+        &mut self,
+        StateData { world, .. }: StateData<'_, GameData<'_, '_>>,
+        event: StateEvent,
+    ) -> SimpleTrans {
+        let event = match event {
+            StateEvent::Window(event) => event,
+            _ => return Trans::None,
+        };
+        if kiosk::exit_requested(world, &event) {
+            return Trans::Quit;
+        }
+        if is_key_down(&event, VirtualKeyCode::Up) {
+            self.selected = self.selected.saturating_sub(1);
+        } else if is_key_down(&event, VirtualKeyCode::Down) {
+            self.selected = (self.selected + 1).min(self.matches.len().saturating_sub(1));
+        } else if is_key_down(&event, VirtualKeyCode::Return) {
+            self.start_download();
+        } else if is_key_down(&event, VirtualKeyCode::Escape) {
+            return Trans::Switch(Box::new(SongSelectState::default()));
+        } else if is_key_down(&event, VirtualKeyCode::Back) {
+            self.query.pop();
+            self.filter();
+        } else if let Event::WindowEvent {
+            event: WindowEvent::ReceivedCharacter(c),
+            ..
+        } = event
+        {
+            if !c.is_control() {
+                self.query.push(c);
+                self.filter();
+            }
+        }
+        self.refresh(world);
+        Trans::None
+    }
+
+    fn update(&mut self, data: &mut StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
+        let mut changed = false;
+        let index = match &self.index {
+            Some(receiver) => match receiver.try_recv() {
+                Ok(result) => Some(result),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => Some(Err(DownloadError::Crashed)),
+            },
+            None => None,
+        };
+        if let Some(result) = index {
+            self.index = None;
+            match result {
+                Ok(songs) => self.songs = songs,
+                Err(e) => {
+                    log::warn!("Failed to fetch the song list: {}", e);
+                    self.status = format!("Failed to fetch the song list: {}", e);
+                }
+            }
+            self.filter();
+            changed = true;
+        }
+
+        let mut finished = Vec::new();
+        self.downloads
+            .retain(|(title, receiver)| match receiver.try_recv() {
+                Ok(result) => {
+                    finished.push((title.clone(), result));
+                    false
+                }
+                Err(TryRecvError::Empty) => true,
+                Err(TryRecvError::Disconnected) => {
+                    finished.push((title.clone(), Err(DownloadError::Crashed)));
+                    false
+                }
+            });
+        for (title, result) in finished {
+            changed = true;
+            match result {
+                Ok(pack) => {
+                    let mut index = PackIndex::open(&self.library_root);
+                    index.add(pack);
+                    match index.save(&self.library_root) {
+                        Ok(()) => self.status = format!("Downloaded {}", title),
+                        Err(e) => {
+                            log::warn!("Failed to save the pack list: {}", e);
+                            self.status = format!("Failed to save the pack list: {}", e);
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Failed to download {}: {}", title, e);
+                    self.status = format!("Failed to download {}: {}", title, e);
+                }
+            }
+        }
+        if changed {
+            self.refresh(data.world);
+        }
+        Trans::None
+    }
+}
@@ -0,0 +1,201 @@
+use crate::chart::LaserId;
+use amethyst::config::ConfigError;
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Any error of the game, for callers that handle errors of several modules alike.
+#[derive(Debug, Error)]
+pub enum IrisError {
+    #[error("{0}")]
+    Chart(#[from] ChartError),
+    #[error("{0}")]
+    Import(#[from] ImportError),
+    #[error("{0}")]
+    Render(#[from] RenderError),
+    #[error("{0}")]
+    Audio(#[from] AudioError),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    /// Reading or writing a settings, score or other RON file.
+    #[error("{0}")]
+    Config(#[from] ConfigError),
+    #[error("failed to start logging: {0}")]
+    Logger(#[from] log::SetLoggerError),
+}
+
+/// Chart data that can't be played as written. Errors during play skip the command and the chart
+/// plays on.
+#[derive(Debug, Error)]
+pub enum ChartError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("invalid chart: {0}")]
+    Parse(#[from] ron::de::Error),
+    #[error("failed to write the chart: {0}")]
+    Serialize(#[from] ron::ser::Error),
+    #[error("this chart needs a newer version of the game (format {required}, this build reads up to {supported})")]
+    NeedsNewerVersion { required: u32, supported: u32 },
+    #[error("Laser {laser:?} entered at {time} while already present")]
+    LaserAlreadyPresent { laser: LaserId, time: f32 },
+    #[error("Laser {laser:?} left at {time} without entering")]
+    LaserNotEntered { laser: LaserId, time: f32 },
+    #[error("Laser {laser:?} at {time}: {command} is not supported yet")]
+    Unsupported {
+        laser: LaserId,
+        time: f32,
+        command: &'static str,
+    },
+    #[error("Laser {laser:?} at {time}: stencil value {stencil} is above the maximum of {max}")]
+    StencilOutOfRange {
+        laser: LaserId,
+        time: f32,
+        stencil: u8,
+        max: u8,
+    },
+    #[error("Note at {time} is on laser {laser:?}, which is not present")]
+    NoteWithoutLaser { laser: LaserId, time: f32 },
+    #[error("Laser {laser:?} at {time}: {command} on a laser that is not present")]
+    CommandWithoutLaser {
+        laser: LaserId,
+        time: f32,
+        command: &'static str,
+    },
+}
+
+/// Why a chart could not be imported into the library.
+#[derive(Debug, Error)]
+pub enum ImportError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("missing #TITLE")]
+    MissingTitle,
+    #[error("failed to write the song metadata: {0}")]
+    Metadata(#[from] ConfigError),
+    #[error("failed to convert the chart: {0}")]
+    Chart(#[from] ChartError),
+    #[error("the importer crashed on this chart")]
+    Crashed,
+    #[error("the import was cancelled")]
+    Cancelled,
+    #[error("failed to start the import threads: {0}")]
+    Threads(#[from] rayon::ThreadPoolBuildError),
+}
+
+/// Failure to install a chart pack.
+#[derive(Debug, Error)]
+pub enum PackError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("invalid archive: {0}")]
+    Archive(#[from] zip::result::ZipError),
+    #[error("{0} is already in the library but was not installed as a pack")]
+    NotManaged(String),
+    #[error("the archive contains no songs")]
+    NoSongs,
+    #[error("the installer crashed on this archive")]
+    Crashed,
+}
+
+/// Failure to fetch the song list or a song from the download repository.
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    #[error("no song repository is configured")]
+    NoRepository,
+    #[error("{0}")]
+    Http(String),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("invalid song list: {0}")]
+    Index(#[from] serde_json::Error),
+    #[error("checksum mismatch, expected {expected} but got {actual}")]
+    Checksum { expected: String, actual: String },
+    #[error("no file name can be made from {0:?}")]
+    ArchiveName(String),
+    #[error("{url} is larger than {limit} bytes")]
+    TooLarge { url: String, limit: u64 },
+    #[error("{0}")]
+    Pack(#[from] PackError),
+    #[error("the download crashed")]
+    Crashed,
+}
+
+/// Failure to export or check a tournament play bundle.
+#[derive(Debug, Error)]
+pub enum TournamentError {
+    #[error("no tournament key is set")]
+    NoKey,
+    #[error("the key is not a hex encoded Ed25519 key")]
+    InvalidKey,
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("invalid bundle: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("the signature does not match, the bundle was changed or signed with another key")]
+    BadSignature,
+}
+
+/// Failure to set up a render group.
+#[derive(Debug, Error)]
+pub enum RenderError {
+    #[error("failed to create shader resources: {0}")]
+    Resources(String),
+    #[error("failed to compile shaders: {0}")]
+    Shaders(String),
+    #[error("failed to create the pipeline: {0}")]
+    Pipeline(String),
+    #[error("failed to upload a mesh: {0}")]
+    Mesh(String),
+    #[error("no stencil bits left for {0}")]
+    StencilExhausted(&'static str),
+}
+
+/// A console command that could not run. Shown as the command's output.
+#[derive(Debug, Error)]
+pub enum ConsoleError {
+    #[error("unknown command `{0}`, try `help`")]
+    UnknownCommand(String),
+    #[error("invalid arguments")]
+    InvalidArguments,
+    #[error("no chart is playing")]
+    NotPlaying,
+    #[error("{} is outside the directory it is looked up in", .0.display())]
+    OutsidePath(PathBuf),
+    #[error("{0}")]
+    Chart(#[from] ChartError),
+    #[error("{0}")]
+    Tournament(#[from] TournamentError),
+}
+
+#[derive(Debug, Error)]
+pub enum AudioError {
+    #[error("no audio output device found")]
+    NoDevice,
+    #[error("failed to decode {}: {message}", path.display())]
+    Decode { path: PathBuf, message: String },
+}
+
+/// A likely mistake in a key map. The map still loads; the warning is logged and shown in the key
+/// bindings.
+#[derive(Clone, Debug, PartialEq, Error)]
+pub enum KeyMapWarning {
+    #[error("{} is bound more than once", crate::keycap::label(*.0))]
+    Duplicate(u32),
+    #[error("row {} has no keys", .0 + 1)]
+    EmptyRow(usize),
+    #[error("row {} is offset by {offset} keys, outside the width of the map", .row + 1)]
+    OffsetOutOfRange { row: usize, offset: f32 },
+}
+
+/// Command line arguments that can't be used.
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error("unknown argument {0}")]
+    UnknownArgument(String),
+    #[error("{0} needs a value")]
+    MissingValue(&'static str),
+    #[error("invalid value {value} for {flag}")]
+    InvalidValue { flag: &'static str, value: String },
+    #[error("{0} only applies with --chart")]
+    NeedsChart(&'static str),
+}
@@ -0,0 +1,54 @@
+use crate::settings::WindowSettings;
+use amethyst::{
+    window::{DisplayConfig, Window},
+    winit::dpi::{LogicalPosition, PhysicalPosition},
+};
+
+/// Applies the saved window size to the display config before the window is created.
+pub fn apply_to_display_config(settings: &WindowSettings, config: &mut DisplayConfig) {
+    if let Some(dimensions) = settings.dimensions {
+        config.dimensions = Some(dimensions);
+    }
+}
+
+/// Moves the window to its saved position, unless the monitor it was on has been detached or no
+/// longer contains that position, in which case the window manager's placement is kept.
+pub fn restore_position(settings: &WindowSettings, window: &Window) {
+    let (x, y) = match settings.position {
+        Some(position) => position,
+        None => return,
+    };
+    let monitor = window.get_available_monitors().find(|monitor| {
+        let origin = monitor.get_position();
+        let size = monitor.get_dimensions();
+        let name_matches = settings.monitor.is_none() || monitor.get_name() == settings.monitor;
+        name_matches
+            && (origin.x..origin.x + size.width).contains(&(x as f64))
+            && (origin.y..origin.y + size.height).contains(&(y as f64))
+    });
+    match monitor {
+        Some(monitor) => window.set_position(LogicalPosition::from_physical(
+            PhysicalPosition::new(x as f64, y as f64),
+            monitor.get_hidpi_factor(),
+        )),
+        None => log::info!("Saved window monitor is not attached, using default placement"),
+    }
+}
+
+/// Reads the current window geometry to be saved into the settings.
+pub fn capture(window: &Window) -> WindowSettings {
+    let hidpi = window.get_hidpi_factor();
+    let dimensions = window.get_inner_size().map(|size| {
+        let size = size.to_physical(hidpi);
+        (size.width.round() as u32, size.height.round() as u32)
+    });
+    let position = window.get_outer_position().map(|position| {
+        let position = position.to_physical(hidpi);
+        (position.x.round() as i32, position.y.round() as i32)
+    });
+    WindowSettings {
+        dimensions,
+        position,
+        monitor: window.get_current_monitor().get_name(),
+    }
+}
@@ -0,0 +1,86 @@
+use crate::bloom;
+use crate::settings::GraphicsSettings;
+use amethyst::renderer::{
+    rendy::hal::{
+        format::{Format, ImageFeature},
+        Features, Limits, PhysicalDevice,
+    },
+    Backend, Factory,
+};
+
+/// Depth/stencil formats in order of preference.
+const DEPTH_STENCIL_FORMATS: [Format; 3] = [
+    Format::D24UnormS8Uint,
+    Format::D32SfloatS8Uint,
+    Format::D16UnormS8Uint,
+];
+
+/// What the graphics adapter supports, probed whenever the render graph is planned. The resource
+/// is `None` until the first plan.
+///
+/// rendy doesn't hand the adapter name to render plugins, so the adapter is described by its
+/// capabilities only.
+#[derive(Clone, Debug)]
+pub struct GpuInfo {
+    pub features: Features,
+    pub limits: Limits,
+    /// The most preferred depth/stencil format that can be attached, if any.
+    pub depth_stencil_format: Option<Format>,
+    /// Whether the offscreen scene format used for bloom and resolution scaling can be rendered
+    /// to and sampled.
+    pub hdr_target: bool,
+}
+
+impl GpuInfo {
+    pub fn probe<B: Backend>(factory: &Factory<B>) -> Self {
+        let physical = factory.physical();
+        let supports = |format, feature| {
+            physical
+                .format_properties(Some(format))
+                .optimal_tiling
+                .contains(feature)
+        };
+        Self {
+            features: physical.features(),
+            limits: physical.limits(),
+            depth_stencil_format: DEPTH_STENCIL_FORMATS
+                .iter()
+                .cloned()
+                .find(|&f| supports(f, ImageFeature::DEPTH_STENCIL_ATTACHMENT)),
+            hdr_target: supports(
+                bloom::HDR_FORMAT,
+                ImageFeature::COLOR_ATTACHMENT | ImageFeature::SAMPLED,
+            ),
+        }
+    }
+
+    /// One line for the console.
+    pub fn summary(&self) -> String {
+        format!(
+            "depth/stencil {:?}, HDR target {}, max texture {}",
+            self.depth_stencil_format,
+            if self.hdr_target { "yes" } else { "no" },
+            self.limits.max_texture_size
+        )
+    }
+
+    /// Lowers `graphics` to what the adapter handles, for a profile without saved settings.
+    /// `window` is the size of the window in pixels.
+    pub fn apply_safe_defaults(&self, graphics: &mut GraphicsSettings, window: (f32, f32)) {
+        if !self.hdr_target {
+            log::info!("The GPU can't render the offscreen scene; disabling bloom and scaling");
+            graphics.bloom_intensity = 0.;
+            graphics.resolution_scale = 1.;
+            return;
+        }
+        let largest = window.0.max(window.1);
+        let max_scale = self.limits.max_texture_size as f32 / largest;
+        if graphics.resolution_scale > max_scale {
+            log::info!(
+                "Limiting the resolution scale to {:.2} for the GPU",
+                max_scale
+            );
+            graphics.resolution_scale = max_scale;
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use crate::chart::PlaySettings;
+use crate::judge::{JudgeEvent, JudgeWindows, Judgement};
+use crate::settings::Settings;
+use crate::ui::percent_transform;
+use amethyst::{
+    core::{timing::Time, Parent, SystemDesc},
+    ecs::{Entities, Entity, Read, ReadExpect, System, SystemData, World, WriteStorage},
+    shrev::{EventChannel, ReaderId},
+    ui::{Anchor, UiImage, UiTransform},
+};
+use std::collections::VecDeque;
+
+/// Seconds a tick takes to fade out.
+const TICK_SECONDS: f64 = 2.;
+/// Width of the bar as a fraction of the screen with one player; each of several players gets
+/// their share of it.
+const BAR_WIDTH: f32 = 0.25;
+const BAR_HEIGHT: f32 = 0.015;
+/// Height of the bar's center above the bottom of the screen.
+const BAR_Y: f32 = 0.05;
+/// Width of a tick as a fraction of the bar.
+const TICK_WIDTH: f32 = 0.015;
+const BAND_ALPHA: f32 = 0.4;
+/// Keeps the bar above the HUD.
+const BAR_Z: f32 = 30.;
+
+struct Bar {
+    panel: Entity,
+    bands: [Entity; 3],
+    /// Ticks with the real time they were dropped at, oldest first.
+    ticks: VecDeque<(Entity, f64, [f32; 4])>,
+}
+
+/// Shows a bar below each player's highway where every hit drops a tick at its timing offset,
+/// early to the left, that fades out over a few seconds. Colored bands mark the perfect and near
+/// windows. Enabled by `ui.hit_error_bar`.
+pub struct HitErrorSystem {
+    judge_reader: ReaderId<JudgeEvent>,
+    bars: Vec<Bar>,
+}
+
+pub struct HitErrorSystemDesc;
+
+impl<'a, 'b> SystemDesc<'a, 'b, HitErrorSystem> for HitErrorSystemDesc {
+    fn build(self, world: &mut World) -> HitErrorSystem {
+        <HitErrorSystem as System<'_>>::SystemData::setup(world);
+
+        let judge_reader = world
+            .get_mut::<EventChannel<JudgeEvent>>()
+            .unwrap()
+            .register_reader();
+
+        HitErrorSystem {
+            judge_reader,
+            bars: Vec::new(),
+        }
+    }
+}
+
+/// Horizontal position on the bar, from 0 at the left to 1 at the right, of a hit `offset`
+/// seconds early. The bar spans the wider of the two near windows on both sides, so that its
+/// center is always a perfectly timed hit.
+fn bar_x(windows: &JudgeWindows, offset: f32) -> f32 {
+    let half = windows.near_early.max(windows.near_late);
+    (0.5 - offset / half * 0.5).max(0.).min(1.)
+}
+
+impl HitErrorSystem {
+    fn clear(&mut self, entities: &Entities<'_>) {
+        for bar in self.bars.drain(..) {
+            entities.delete(bar.panel).unwrap();
+            for entity in bar.bands.iter().chain(bar.ticks.iter().map(|(e, _, _)| e)) {
+                entities.delete(*entity).unwrap();
+            }
+        }
+    }
+}
+
+impl<'s> System<'s> for HitErrorSystem {
+    type SystemData = (
+        Entities<'s>,
+        Read<'s, EventChannel<JudgeEvent>>,
+        ReadExpect<'s, Time>,
+        Read<'s, Option<PlaySettings>>,
+        Read<'s, Settings>,
+        WriteStorage<'s, Parent>,
+        WriteStorage<'s, UiTransform>,
+        WriteStorage<'s, UiImage>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            judge_events,
+            time,
+            play_settings,
+            settings,
+            mut parents,
+            mut transforms,
+            mut images,
+        ): Self::SystemData,
+    ) {
+        let players = match &*play_settings {
+            Some(play_settings) if settings.ui.hit_error_bar => play_settings.players.max(1),
+            _ => {
+                judge_events.read(&mut self.judge_reader).for_each(drop);
+                self.clear(&entities);
+                return;
+            }
+        };
+        let windows = &settings.gameplay.windows(time.time_scale());
+        let width = BAR_WIDTH / f32::from(players);
+
+        while self.bars.len() < players as usize {
+            let x = (self.bars.len() as f32 + 0.5) / f32::from(players) - 0.5;
+            let panel = entities.create();
+            let mut transform = percent_transform(
+                "HitError",
+                Anchor::BottomMiddle,
+                x,
+                BAR_Y,
+                width,
+                BAR_HEIGHT,
+            );
+            transform.local_z = BAR_Z;
+            transforms.insert(panel, transform).unwrap();
+            images
+                .insert(panel, UiImage::SolidColor([0., 0., 0., 0.5]))
+                .unwrap();
+            let mut band = |id: &str, early: f32, late: f32, color: [f32; 4], height: f32| {
+                let entity = entities.create();
+                parents.insert(entity, Parent::new(panel)).unwrap();
+                let left = bar_x(windows, early);
+                let right = bar_x(windows, -late);
+                transforms
+                    .insert(
+                        entity,
+                        percent_transform(
+                            id,
+                            Anchor::MiddleLeft,
+                            (left + right) / 2.,
+                            0.,
+                            right - left,
+                            height,
+                        ),
+                    )
+                    .unwrap();
+                images.insert(entity, UiImage::SolidColor(color)).unwrap();
+                entity
+            };
+            let [r, g, b, _] = Judgement::Near.color();
+            let near = band(
+                "HitErrorNear",
+                windows.near_early,
+                windows.near_late,
+                [r, g, b, BAND_ALPHA],
+                0.4,
+            );
+            let [r, g, b, _] = Judgement::Perfect.color();
+            let perfect = band(
+                "HitErrorPerfect",
+                windows.perfect_early,
+                windows.perfect_late,
+                [r, g, b, BAND_ALPHA],
+                0.4,
+            );
+            let center = band("HitErrorCenter", 0.002, 0.002, [1.; 4], 1.);
+            self.bars.push(Bar {
+                panel,
+                bands: [near, perfect, center],
+                ticks: VecDeque::new(),
+            });
+        }
+
+        let now = time.absolute_real_time_seconds();
+        for event in judge_events.read(&mut self.judge_reader) {
+            // Notes that timed out have no offset to show.
+            let (offset, bar) = match (event.offset, self.bars.get_mut(event.player as usize)) {
+                (Some(offset), Some(bar)) => (offset, bar),
+                _ => continue,
+            };
+            let tick = entities.create();
+            parents.insert(tick, Parent::new(bar.panel)).unwrap();
+            transforms
+                .insert(
+                    tick,
+                    percent_transform(
+                        "HitErrorTick",
+                        Anchor::MiddleLeft,
+                        bar_x(windows, offset),
+                        0.,
+                        TICK_WIDTH,
+                        1.,
+                    ),
+                )
+                .unwrap();
+            let color = event.judgement.color();
+            images.insert(tick, UiImage::SolidColor(color)).unwrap();
+            bar.ticks.push_back((tick, now, color));
+        }
+
+        for bar in &mut self.bars {
+            while bar
+                .ticks
+                .front()
+                .map_or(false, |&(_, dropped, _)| now - dropped > TICK_SECONDS)
+            {
+                let (tick, _, _) = bar.ticks.pop_front().unwrap();
+                entities.delete(tick).unwrap();
+            }
+            for &(tick, dropped, [r, g, b, a]) in &bar.ticks {
+                let fade = 1. - ((now - dropped) / TICK_SECONDS) as f32;
+                if let Some(image) = images.get_mut(tick) {
+                    *image = UiImage::SolidColor([r, g, b, a * fade]);
+                }
+            }
+        }
+    }
+}
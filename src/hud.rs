@@ -0,0 +1,294 @@
+use crate::chart::{Chart, PlaySettings};
+use crate::modifiers::{self, ActiveModifiers};
+use crate::score::{Ghost, Pacemaker, Score, Scores};
+use crate::settings::Settings;
+use crate::InterFont;
+use amethyst::{
+    core::{timing::Time, Parent},
+    ecs::{Entities, Entity, Read, ReadExpect, System, WriteStorage},
+    ui::{Anchor, UiImage, UiText, UiTransform},
+    window::ScreenDimensions,
+};
+
+/// Font size of HUD text in logical pixels.
+pub(crate) const HUD_FONT_SIZE: f32 = 24.;
+/// Seconds the BPM stays highlighted after it changes.
+const BPM_FLASH_SECONDS: f32 = 0.5;
+const BPM_COLOR: [f32; 4] = [1., 1., 1., 1.];
+const BPM_FLASH_COLOR: [f32; 4] = [1., 0.9, 0.2, 1.];
+const BPM_WARNING_COLOR: [f32; 4] = [1., 0.5, 0.1, 1.];
+
+struct HudWidgets {
+    score: Entity,
+    ghost: Entity,
+    gauge_fill: Entity,
+    ghost_marker: Entity,
+}
+
+/// Draws the score and the gauge of each player, the comparison against the pacemaker, the
+/// current chart section, the current BPM along with upcoming BPM changes, and the icons of the
+/// modifiers with their score multiplier.
+#[derive(Default)]
+pub struct HudSystem {
+    widgets: Vec<HudWidgets>,
+    section: Option<Entity>,
+    bpm: Option<Entity>,
+    modifiers: Option<Entity>,
+}
+
+fn lerp_color(from: [f32; 4], to: [f32; 4], t: f32) -> [f32; 4] {
+    let mut color = from;
+    for (c, to) in color.iter_mut().zip(&to) {
+        *c += (to - *c) * t;
+    }
+    color
+}
+
+impl<'s> System<'s> for HudSystem {
+    type SystemData = (
+        Entities<'s>,
+        ReadExpect<'s, InterFont>,
+        ReadExpect<'s, Time>,
+        Read<'s, Option<Chart>>,
+        Read<'s, Option<PlaySettings>>,
+        Read<'s, Scores>,
+        Read<'s, Ghost>,
+        Read<'s, Option<Pacemaker>>,
+        Read<'s, ActiveModifiers>,
+        Read<'s, Settings>,
+        ReadExpect<'s, ScreenDimensions>,
+        WriteStorage<'s, Parent>,
+        WriteStorage<'s, UiText>,
+        WriteStorage<'s, UiTransform>,
+        WriteStorage<'s, UiImage>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            inter_font,
+            time,
+            chart,
+            play_settings,
+            scores,
+            ghost,
+            pacemaker,
+            active_modifiers,
+            settings,
+            dimensions,
+            mut parents,
+            mut texts,
+            mut transforms,
+            mut images,
+        ): Self::SystemData,
+    ) {
+        let font_size = HUD_FONT_SIZE * settings.ui.scale_factor(&dimensions);
+        let players = play_settings.as_ref().map_or(1, |s| s.players.max(1));
+        while self.widgets.len() < players as usize {
+            // The first player's HUD goes to the left when there is a second one.
+            let left = self.widgets.is_empty() && players > 1;
+            let (text_anchor, gauge_anchor, x) = if left {
+                (Anchor::TopLeft, Anchor::MiddleLeft, 1.)
+            } else {
+                (Anchor::TopRight, Anchor::MiddleRight, -1.)
+            };
+            let mut text = |id: &str, y: f32| {
+                let entity = entities.create();
+                texts
+                    .insert(
+                        entity,
+                        UiText::new(inter_font.0.clone(), String::new(), [1.; 4], font_size),
+                    )
+                    .unwrap();
+                transforms
+                    .insert(
+                        entity,
+                        percent_transform(id, text_anchor, 0.15 * x, y, 0.3, 0.05),
+                    )
+                    .unwrap();
+                entity
+            };
+            let score = text("Score", -0.05);
+            let ghost = text("Ghost", -0.1);
+
+            let gauge = entities.create();
+            transforms
+                .insert(
+                    gauge,
+                    percent_transform("Gauge", gauge_anchor, 0.03 * x, 0., 0.02, 0.6),
+                )
+                .unwrap();
+            images
+                .insert(gauge, UiImage::SolidColor([0.1, 0.1, 0.1, 1.]))
+                .unwrap();
+            let mut child = |id: &str, color: [f32; 4]| {
+                let entity = entities.create();
+                parents.insert(entity, Parent::new(gauge)).unwrap();
+                transforms
+                    .insert(
+                        entity,
+                        percent_transform(id, Anchor::BottomMiddle, 0., 0., 1., 0.),
+                    )
+                    .unwrap();
+                images.insert(entity, UiImage::SolidColor(color)).unwrap();
+                entity
+            };
+            let gauge_fill = child("GaugeFill", [0.8, 0., 0.8, 1.]);
+            let ghost_marker = child("GhostMarker", [1., 1., 1., 1.]);
+            self.widgets.push(HudWidgets {
+                score,
+                ghost,
+                gauge_fill,
+                ghost_marker,
+            });
+        }
+
+        let section = *self.section.get_or_insert_with(|| {
+            let entity = entities.create();
+            texts
+                .insert(
+                    entity,
+                    UiText::new(inter_font.0.clone(), String::new(), [1.; 4], font_size),
+                )
+                .unwrap();
+            transforms
+                .insert(
+                    entity,
+                    percent_transform("Section", Anchor::TopMiddle, 0., -0.05, 0.4, 0.05),
+                )
+                .unwrap();
+            entity
+        });
+        if let Some(text) = texts.get_mut(section) {
+            text.text = match (&*chart, &*play_settings) {
+                (Some(chart), Some(play_settings)) => {
+                    let now = (time.absolute_time_seconds() - play_settings.base_time) as f32;
+                    chart
+                        .section_at(now)
+                        .map_or_else(String::new, |s| s.inner.clone())
+                }
+                _ => String::new(),
+            };
+            text.font_size = font_size;
+        }
+
+        let bpm = *self.bpm.get_or_insert_with(|| {
+            let entity = entities.create();
+            texts
+                .insert(
+                    entity,
+                    UiText::new(inter_font.0.clone(), String::new(), BPM_COLOR, font_size),
+                )
+                .unwrap();
+            transforms
+                .insert(
+                    entity,
+                    percent_transform("Bpm", Anchor::TopMiddle, 0., -0.1, 0.4, 0.05),
+                )
+                .unwrap();
+            entity
+        });
+        if let Some(text) = texts.get_mut(bpm) {
+            let (label, color) = match (&*chart, &*play_settings) {
+                (Some(chart), Some(play_settings)) => {
+                    let now = (time.absolute_time_seconds() - play_settings.base_time) as f32;
+                    match chart.bpm_index_at(now) {
+                        Some(index) => {
+                            let current = &chart.bpm[index];
+                            let warning = settings.ui.bpm_warning_beats.and_then(|beats| {
+                                let next = chart.next_bpm_change(now)?;
+                                if next.time - now <= beats * 60. / current.bpm {
+                                    Some(next)
+                                } else {
+                                    None
+                                }
+                            });
+                            let since_change = now - current.time;
+                            match warning {
+                                Some(next) => (
+                                    format!("BPM {:.0} > {:.0}", current.bpm, next.bpm),
+                                    BPM_WARNING_COLOR,
+                                ),
+                                // The first command sets the initial BPM and isn't a change.
+                                None if index > 0 && since_change < BPM_FLASH_SECONDS => (
+                                    format!("BPM {:.0}", current.bpm),
+                                    lerp_color(
+                                        BPM_FLASH_COLOR,
+                                        BPM_COLOR,
+                                        since_change / BPM_FLASH_SECONDS,
+                                    ),
+                                ),
+                                None => (format!("BPM {:.0}", current.bpm), BPM_COLOR),
+                            }
+                        }
+                        None => (String::new(), BPM_COLOR),
+                    }
+                }
+                _ => (String::new(), BPM_COLOR),
+            };
+            text.text = label;
+            text.color = color;
+            text.font_size = font_size;
+        }
+
+        let modifier_icons = *self.modifiers.get_or_insert_with(|| {
+            let entity = entities.create();
+            texts
+                .insert(
+                    entity,
+                    UiText::new(inter_font.0.clone(), String::new(), [1.; 4], font_size),
+                )
+                .unwrap();
+            transforms
+                .insert(
+                    entity,
+                    percent_transform("Modifiers", Anchor::TopMiddle, 0., -0.15, 0.4, 0.05),
+                )
+                .unwrap();
+            entity
+        });
+        if let Some(text) = texts.get_mut(modifier_icons) {
+            let active = &active_modifiers.0;
+            let multiplier = modifiers::multiplier(active);
+            text.text = if multiplier != 1. {
+                format!("{} x{:.2}", modifiers::icons(active), multiplier)
+            } else {
+                modifiers::icons(active)
+            };
+            text.font_size = font_size;
+        }
+
+        let total_notes = chart.as_ref().map_or(0, Chart::judged_notes);
+        let default_score = Score::default();
+        for (player, widgets) in self.widgets.iter().enumerate() {
+            let score = scores.players.get(player).unwrap_or(&default_score);
+            // The pacemaker only races against the first player.
+            let racing = player == 0 && pacemaker.is_some();
+            if let Some(text) = texts.get_mut(widgets.score) {
+                text.text = format!("{:08}", score.value(total_notes));
+                text.font_size = font_size;
+            }
+            if let Some(text) = texts.get_mut(widgets.ghost) {
+                if racing {
+                    text.text = format!("{:+}", ghost.difference);
+                    text.color = if ghost.difference >= 0 {
+                        [0.2, 0.6, 1., 1.]
+                    } else {
+                        [0.9, 0., 0.2, 1.]
+                    };
+                } else {
+                    text.text.clear();
+                }
+                text.font_size = font_size;
+            }
+            if let Some(transform) = transforms.get_mut(widgets.gauge_fill) {
+                transform.height = score.gauge;
+            }
+            if let Some(transform) = transforms.get_mut(widgets.ghost_marker) {
+                transform.local_y = ghost.gauge;
+                transform.height = if racing { 0.005 } else { 0. };
+            }
+        }
+    }
+}
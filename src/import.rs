@@ -0,0 +1,388 @@
+use crate::bms;
+use crate::chart::timing::{quantize_time, SnapGrid};
+use crate::chart::Chart;
+use crate::chart_file::{ChartFile, CHART_FILE};
+use crate::error::{ImportError, IrisError};
+use crate::library::{BpmRange, Song, SongMeta, SONG_FILE};
+use crate::save_queue;
+use crate::thumbnail::{self, THUMBNAIL_FILE};
+use crate::vfs::{FileSystem, Vfs};
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::{channel, sync_channel, Receiver, Sender},
+    Arc,
+};
+use std::thread;
+
+/// Number of worker threads parsing charts.
+const IMPORT_THREADS: usize = 4;
+/// Charts found but not yet picked up by a worker. Finding charts pauses when this is full, so a
+/// huge pack doesn't queue up every path at once.
+const QUEUE_CAPACITY: usize = 64;
+/// Beats a note may be off the [import grid](crate::settings::LibrarySettings::import_quantize)
+/// to be moved onto it.
+const QUANTIZE_TOLERANCE: f32 = 0.02;
+const CHART_EXTENSIONS: [&str; 4] = ["bms", "bme", "bml", "pms"];
+/// Extensions tried for a `#WAVxx` file that doesn't exist as written, as packs often convert
+/// their audio without updating the charts.
+#[cfg(feature = "onset")]
+const AUDIO_EXTENSIONS: [&str; 3] = ["ogg", "wav", "flac"];
+/// Suggested offsets beyond this many seconds mean the chart is likely misaligned.
+#[cfg(feature = "onset")]
+const MISALIGNED_OFFSET: f32 = 0.03;
+
+struct ImportJob {
+    chart: PathBuf,
+    /// Song directory to create, relative to the library root.
+    destination: PathBuf,
+}
+
+type ImportResult = Result<Song, (PathBuf, ImportError)>;
+
+enum ImportEvent {
+    /// A chart was queued.
+    Found,
+    /// A pack has been searched completely.
+    Searched,
+    Imported(ImportResult),
+}
+
+/// Imports BMS packs into the library on background threads.
+///
+/// Each chart becomes a song directory in the library with its metadata, jacket, a
+/// [thumbnail](crate::thumbnail) of its densest part and the chart [converted](crate::bms) to
+/// the native format; the original stays in the pack and is referenced from
+/// [`SongMeta::source`]. Packs are searched on their own thread and the charts
+/// parsed on a dedicated rayon pool, so that the pool the game systems run on stays free. A chart that fails, even by panicking, is only reported and the
+/// rest of the import goes on.
+pub struct ImportQueue {
+    library_root: PathBuf,
+    /// Grid that notes slightly off it are moved onto.
+    quantize: Option<SnapGrid>,
+    pool: Arc<ThreadPool>,
+    events: Sender<ImportEvent>,
+    receiver: Receiver<ImportEvent>,
+    cancelled: Arc<AtomicBool>,
+    /// Packs still being searched for charts.
+    searching: usize,
+    /// Charts queued so far.
+    pub total: usize,
+    /// Charts imported, failed or skipped so far.
+    pub done: usize,
+    /// Charts skipped because the import was cancelled before they were picked up.
+    pub skipped: usize,
+    pub failures: Vec<(PathBuf, ImportError)>,
+}
+
+impl ImportQueue {
+    pub fn new(library_root: PathBuf, quantize: Option<SnapGrid>) -> Result<Self, IrisError> {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(IMPORT_THREADS)
+            .thread_name(|i| format!("import-{}", i))
+            .build()
+            .map_err(ImportError::Threads)?;
+        let (events, receiver) = channel();
+        Ok(Self {
+            library_root,
+            quantize,
+            pool: Arc::new(pool),
+            events,
+            receiver,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            searching: 0,
+            total: 0,
+            done: 0,
+            skipped: 0,
+            failures: Vec::new(),
+        })
+    }
+
+    /// Queues every chart below `pack` for import.
+    pub fn add_pack(&mut self, pack: &Path) {
+        let pack = pack.to_owned();
+        let pack_name = pack
+            .file_name()
+            .map_or_else(|| PathBuf::from("Imported"), PathBuf::from);
+        let (jobs, job_receiver) = sync_channel::<ImportJob>(QUEUE_CAPACITY);
+        self.searching += 1;
+
+        // Charts in archives are read from there; the paths saved as their source run through
+        // the archive.
+        let fs = Arc::new(FileSystem::new(""));
+
+        let events = self.events.clone();
+        let cancelled = self.cancelled.clone();
+        let search_fs = fs.clone();
+        thread::spawn(move || {
+            find_charts(&*search_fs, &pack, &mut |chart| {
+                if cancelled.load(Ordering::Relaxed) {
+                    return false;
+                }
+                let relative = chart.strip_prefix(&pack).unwrap();
+                let destination = pack_name.join(relative.with_extension(""));
+                // Blocks while the workers are behind.
+                jobs.send(ImportJob { chart, destination }).is_ok()
+                    && events.send(ImportEvent::Found).is_ok()
+            });
+            let _ = events.send(ImportEvent::Searched);
+        });
+
+        let events = self.events.clone();
+        let cancelled = self.cancelled.clone();
+        let library_root = self.library_root.clone();
+        let quantize = self.quantize;
+        let pool = self.pool.clone();
+        pool.spawn(move || {
+            // Ends once the search above hangs up.
+            job_receiver
+                .into_iter()
+                .par_bridge()
+                .for_each_with(events, |events, job| {
+                    // Skipped charts are still reported so that the progress adds up.
+                    let result = if cancelled.load(Ordering::Relaxed) {
+                        Err(ImportError::Cancelled)
+                    } else {
+                        panic::catch_unwind(AssertUnwindSafe(|| {
+                            import_chart(&*fs, &library_root, &job, quantize.as_ref())
+                        }))
+                        .unwrap_or_else(|_| Err(ImportError::Crashed))
+                    }
+                    .map_err(|e| (job.chart.clone(), e));
+                    let _ = events.send(ImportEvent::Imported(result));
+                });
+        });
+    }
+
+    /// Stops searching and importing. Charts being parsed right now still finish; those queued
+    /// are reported as skipped.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Collects the songs imported since the last call.
+    pub fn poll(&mut self) -> Vec<Song> {
+        let mut songs = Vec::new();
+        for event in self.receiver.try_iter() {
+            match event {
+                ImportEvent::Found => self.total += 1,
+                ImportEvent::Searched => self.searching -= 1,
+                ImportEvent::Imported(result) => {
+                    self.done += 1;
+                    match result {
+                        Ok(song) => songs.push(song),
+                        Err((_, ImportError::Cancelled)) => self.skipped += 1,
+                        Err((chart, error)) => {
+                            log::warn!("Failed to import {}: {}", chart.display(), error);
+                            self.failures.push((chart, error));
+                        }
+                    }
+                }
+            }
+        }
+        songs
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.searching == 0 && self.done == self.total
+    }
+}
+
+impl Drop for ImportQueue {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Calls `found` with every chart below `dir`, including those in zip archives, until it returns
+/// `false`.
+fn find_charts(fs: &dyn Vfs, dir: &Path, found: &mut dyn FnMut(PathBuf) -> bool) -> bool {
+    let entries = match fs.read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!("Failed to scan {}: {}", dir.display(), e);
+            return true;
+        }
+    };
+    for entry in entries {
+        let path = entry.path;
+        let go_on = if entry.is_dir {
+            find_charts(fs, &path, found)
+        } else if path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map_or(false, |e| {
+                CHART_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str())
+            })
+        {
+            found(path)
+        } else {
+            true
+        };
+        if !go_on {
+            return false;
+        }
+    }
+    true
+}
+
+/// Header commands of a BMS chart that the import needs besides the [`SongMeta`].
+#[derive(Default)]
+struct BmsHeader {
+    stagefile: Option<String>,
+    /// Files of the `#WAVxx` definitions.
+    #[cfg_attr(not(feature = "onset"), allow(dead_code))]
+    sounds: Vec<String>,
+}
+
+/// Reads the header commands of a BMS chart that song select needs.
+fn read_bms_meta(text: &str) -> Result<(SongMeta, BmsHeader), ImportError> {
+    let mut meta = SongMeta::default();
+    let mut header = BmsHeader::default();
+    let mut base_bpm = None;
+    let mut bpms = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        let (command, value) = match line.find(char::is_whitespace) {
+            Some(split) => (&line[..split], line[split..].trim()),
+            None => continue,
+        };
+        match command.to_ascii_uppercase().as_str() {
+            "#TITLE" => meta.title = value.into(),
+            "#ARTIST" => meta.artist = value.into(),
+            "#PLAYLEVEL" => meta.level = value.parse().unwrap_or(0),
+            "#STAGEFILE" => header.stagefile = Some(value.to_string()),
+            "#BANNER" if header.stagefile.is_none() => header.stagefile = Some(value.to_string()),
+            "#BPM" => base_bpm = value.parse::<f32>().ok(),
+            // Extended BPM definitions, `#BPMxx`, used by BPM changes in the chart body.
+            command if command.len() == 6 && command.starts_with("#BPM") => {
+                bpms.extend(value.parse::<f32>().ok())
+            }
+            command if command.len() == 6 && command.starts_with("#WAV") => {
+                header.sounds.push(value.to_string())
+            }
+            _ => {}
+        }
+    }
+    meta.bpm = base_bpm.filter(|bpm| *bpm > 0.).map(|base| {
+        bpms.retain(|bpm| *bpm > 0.);
+        BpmRange {
+            base,
+            min: bpms.iter().cloned().fold(base, f32::min),
+            max: bpms.iter().cloned().fold(base, f32::max),
+        }
+    });
+    if meta.title.is_empty() {
+        return Err(ImportError::MissingTitle);
+    }
+    Ok((meta, header))
+}
+
+/// The sound of the chart most likely to be the song itself: the largest one, as BMS has no
+/// separate song track and a full length background track dwarfs the keysounds.
+#[cfg(feature = "onset")]
+fn find_song_audio(fs: &dyn Vfs, chart: &Path, sounds: &[String]) -> Option<PathBuf> {
+    let dir = chart.parent()?;
+    sounds
+        .iter()
+        .filter_map(|sound| {
+            let path = dir.join(sound);
+            std::iter::once(path.clone())
+                .chain(AUDIO_EXTENSIONS.iter().map(|ext| path.with_extension(ext)))
+                .find_map(|path| Some((fs.size(&path).ok()?, path)))
+        })
+        .max_by_key(|(len, _)| *len)
+        .map(|(_, path)| path)
+}
+
+/// Suggests an offset for the chart from the onsets of its song audio, warning if the chart looks
+/// misaligned. BMS notes sit on a beat grid starting at zero, so the phase of the audio's beats
+/// against that grid is how far the notes are off.
+#[cfg(feature = "onset")]
+fn suggest_offset(fs: &dyn Vfs, chart: &Path, meta: &SongMeta, sounds: &[String]) -> Option<f32> {
+    let bpm = meta.bpm?.base;
+    let audio = find_song_audio(fs, chart, sounds)?;
+    let onsets = match crate::onset::detect(fs, &audio) {
+        Ok(onsets) => onsets,
+        Err(e) => {
+            log::warn!("Onset detection failed: {}", e);
+            return None;
+        }
+    };
+    let offset = crate::onset::beat_offset(&onsets, bpm)?;
+    if let Some(first) = crate::onset::first_beat(&onsets, bpm) {
+        log::debug!("{}: first beat at {:.3}s", audio.display(), first);
+    }
+    if offset.abs() > MISALIGNED_OFFSET {
+        log::warn!(
+            "{} looks misaligned with its audio by {:.0} ms",
+            chart.display(),
+            offset * 1000.
+        );
+    }
+    Some(offset)
+}
+
+/// Moves the notes of `chart` that are slightly off `grid` onto it.
+fn quantize_notes(chart: &mut Chart, grid: &SnapGrid) {
+    for note in &mut chart.notes {
+        note.time = quantize_time(&chart.bpm, note.time, grid, QUANTIZE_TOLERANCE);
+    }
+    chart
+        .notes
+        .sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+}
+
+fn import_chart(
+    fs: &dyn Vfs,
+    library_root: &Path,
+    job: &ImportJob,
+    quantize: Option<&SnapGrid>,
+) -> Result<Song, ImportError> {
+    let bytes = fs.read(&job.chart)?;
+    // Most charts are Shift_JIS, which is out of scope here; keep whatever decodes as UTF-8.
+    let text = String::from_utf8_lossy(&bytes);
+    let (mut meta, header) = read_bms_meta(&text)?;
+    let song_dir = library_root.join(&job.destination);
+    std::fs::create_dir_all(&song_dir)?;
+    if let Some(stagefile) = header.stagefile {
+        let image = job.chart.parent().unwrap().join(&stagefile);
+        if let Some(name) = Path::new(&stagefile).file_name() {
+            match fs
+                .read(&image)
+                .and_then(|bytes| std::fs::write(song_dir.join(name), bytes))
+            {
+                Ok(_) => meta.jacket = Some(name.to_string_lossy().into_owned()),
+                Err(e) => log::warn!("Failed to copy {}: {}", image.display(), e),
+            }
+        }
+    }
+    let bms = bms::read(&text);
+    let base_bpm = meta.bpm.map_or(bms::DEFAULT_BPM, |bpm| bpm.base);
+    let mut chart = bms::to_chart(&bms, base_bpm);
+    if let Some(grid) = quantize {
+        quantize_notes(&mut chart, grid);
+    }
+    ChartFile::save(&chart, &song_dir.join(CHART_FILE))?;
+    match thumbnail::write_bms_thumbnail(&bms.notes, &song_dir.join(THUMBNAIL_FILE)) {
+        Ok(true) => meta.thumbnail = Some(THUMBNAIL_FILE.into()),
+        Ok(false) => {}
+        Err(e) => log::warn!(
+            "Failed to render a thumbnail of {}: {}",
+            job.chart.display(),
+            e
+        ),
+    }
+    #[cfg(feature = "onset")]
+    {
+        meta.suggested_offset = suggest_offset(fs, &job.chart, &meta, &header.sounds);
+    }
+    meta.source = Some(job.chart.clone());
+    save_queue::save(song_dir.join(SONG_FILE), &meta)?;
+    Ok(Song {
+        meta,
+        path: job.destination.clone(),
+    })
+}
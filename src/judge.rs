@@ -1,4 +1,14 @@
-use crate::chart::PlaySettings;
+use crate::billboard::{Billboard, POPUP_SECONDS};
+use crate::chart::timing::{position_for_time, time_for_position};
+use crate::chart::{BpmCommand, NoteKind, PlaySettings, Timed};
+use crate::console::Console;
+use crate::error::KeyMapWarning;
+use crate::migrate::{self, Versioned};
+use crate::modifiers::{ActiveModifiers, Modifier};
+use crate::pause::Paused;
+use crate::replay::ReplayPlayback;
+use crate::settings::Settings;
+use crate::ui::percent_transform;
 use crate::{laser, InterFont};
 use amethyst::{
     animation::{
@@ -8,30 +18,339 @@ use amethyst::{
     },
     assets::{AssetStorage, Handle},
     core::{
-        math::{Matrix3, Point3, Vector2},
+        math::{Matrix3, Point2, Point3, Vector2},
         timing::Time,
         transform::Transform,
         Parent, SystemDesc,
     },
-    ecs::{Entities, Join, Read, ReadExpect, ReadStorage, System, SystemData, World, WriteStorage},
+    ecs::{
+        Entities, Entity, Join, Read, ReadExpect, ReadStorage, System, SystemData, World, Write,
+        WriteStorage,
+    },
     shrev::{EventChannel, ReaderId},
-    ui::{Anchor, ScaleMode, UiText, UiTransform},
+    ui::{Anchor, UiText, UiTransform},
+    window::ScreenDimensions,
     winit::{ElementState, Event, KeyboardInput, ScanCode, WindowEvent},
 };
 use serde::{Deserialize, Serialize};
 
-const PERFECT_WINDOW: f32 = 0.04;
-const NEAR_WINDOW: f32 = 0.08;
-const EARLY_MISS_WINDOW: f32 = 0.15;
+/// Font size of judgement popups in logical pixels.
+pub(crate) const JUDGE_FONT_SIZE: f32 = 40.;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Judgement {
+    Perfect,
+    Near,
+    Miss,
+}
+
+impl Judgement {
+    pub fn text(self) -> &'static str {
+        match self {
+            Judgement::Perfect => "PERFECT",
+            Judgement::Near => "NEAR",
+            Judgement::Miss => "MISS",
+        }
+    }
+
+    pub fn color(self) -> [f32; 4] {
+        match self {
+            Judgement::Perfect => [0.8, 0., 0.8, 1.],
+            Judgement::Near => [0., 0.1, 0.8, 1.],
+            Judgement::Miss => [0.9, 0., 0.2, 1.],
+        }
+    }
+}
+
+/// Part of the early miss window, next to the near window, that note lock still judges. Presses
+/// just too early for a near are meant for the note and take it as a miss; only those further
+/// out are mashing and ignored.
+const NOTE_LOCK_TOLERANCE: f32 = 0.5;
+
+/// Timing windows of each judgement in seconds, separately for early and late hits.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JudgeWindows {
+    pub perfect_early: f32,
+    pub perfect_late: f32,
+    pub near_early: f32,
+    /// Notes not hit within this time after their timing time out as misses.
+    pub near_late: f32,
+    /// Hits this early still take the note as a miss rather than being ignored.
+    pub early_miss: f32,
+    /// Longest gap between presses that keeps a roll alive.
+    pub roll: f32,
+}
+
+impl Default for JudgeWindows {
+    fn default() -> Self {
+        Self {
+            perfect_early: 0.04,
+            perfect_late: 0.04,
+            near_early: 0.08,
+            near_late: 0.08,
+            early_miss: 0.15,
+            roll: 0.5,
+        }
+    }
+}
+
+impl JudgeWindows {
+    /// Every window multiplied by `factor`.
+    pub fn scaled(&self, factor: f32) -> Self {
+        Self {
+            perfect_early: self.perfect_early * factor,
+            perfect_late: self.perfect_late * factor,
+            near_early: self.near_early * factor,
+            near_late: self.near_late * factor,
+            early_miss: self.early_miss * factor,
+            roll: self.roll * factor,
+        }
+    }
+
+    /// Whether a hit `diff` seconds before the note (negative if after) is judged at all. With
+    /// `note_lock`, hits that would be early misses are ignored instead unless they are within
+    /// [`NOTE_LOCK_TOLERANCE`] of the near window, so that mashing can't eat upcoming notes.
+    pub fn accepts(&self, diff: f32, note_lock: bool) -> bool {
+        let early = if note_lock {
+            self.near_early + (self.early_miss - self.near_early).max(0.) * NOTE_LOCK_TOLERANCE
+        } else {
+            self.early_miss
+        };
+        (-self.near_late..early).contains(&diff)
+    }
+
+    /// The judgement of a hit `diff` seconds before the note.
+    pub fn judge(&self, diff: f32) -> Judgement {
+        if (-self.perfect_late..self.perfect_early).contains(&diff) {
+            Judgement::Perfect
+        } else if (-self.near_late..self.near_early).contains(&diff) {
+            Judgement::Near
+        } else {
+            Judgement::Miss
+        }
+    }
+}
+
+/// How far a key at `input_pos` is from a note at `note_pos`, with vertical distance counting
+/// less, as keyboard rows are further apart than lanes.
+fn key_distance(input_pos: Vector2<f32>, note_pos: Point2<f32>) -> f32 {
+    Matrix3::new_nonuniform_scaling(&Vector2::new(1.0, 0.3))
+        .transform_vector(&(input_pos - note_pos.coords))
+        .norm_squared()
+}
+
+/// The key in `mapping` nearest to a note at `note_pos`, which its judgement is attributed to.
+pub fn nearest_key(mapping: &[(ScanCode, (f32, f32))], note_pos: Point2<f32>) -> Option<ScanCode> {
+    mapping
+        .iter()
+        .map(|&(scancode, (x, y))| (scancode, key_distance(Vector2::new(x, y), note_pos)))
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .map(|(scancode, _)| scancode)
+}
+
+/// The note taken by a press at `input_pos`, from `notes` given as an id, the note time minus the
+/// input time and the note position. Among the notes in the windows, the one nearest to the key
+/// wins, then the one nearest in time.
+pub fn pick_note<T>(
+    notes: impl Iterator<Item = (T, f32, Point2<f32>)>,
+    input_pos: Vector2<f32>,
+    windows: &JudgeWindows,
+    note_lock: bool,
+    norm_threshold: f32,
+) -> Option<(T, f32, Point2<f32>)> {
+    notes
+        .filter(|(_, diff, _)| windows.accepts(*diff, note_lock))
+        .map(|(id, diff, note_pos)| (id, diff, note_pos, key_distance(input_pos, note_pos)))
+        .filter(|(_, _, _, norm)| norm <= &norm_threshold)
+        .min_by(|(_, lhs_time, _, lhs), (_, rhs_time, _, rhs)| {
+            // TODO: relying on equality is not good
+            lhs.partial_cmp(rhs)
+                .unwrap()
+                .then_with(|| lhs_time.partial_cmp(&rhs_time).unwrap())
+        })
+        .map(|(id, diff, note_pos, _)| (id, diff, note_pos))
+}
+
+/// Whether a press at `input_pos` sets off a mine at `mine_pos`, `diff` seconds before the mine.
+pub fn sets_off_mine(
+    diff: f32,
+    input_pos: Vector2<f32>,
+    mine_pos: Point2<f32>,
+    windows: &JudgeWindows,
+    norm_threshold: f32,
+) -> bool {
+    (-windows.near_late..windows.near_early).contains(&diff)
+        && key_distance(input_pos, mine_pos) <= norm_threshold
+}
+
+/// Ticks of rolls per beat.
+pub const ROLL_TICKS_PER_BEAT: f32 = 2.;
+
+/// Times of the ticks of a roll from `time` to `end` on the BPM track `bpm`: every
+/// [`ROLL_TICKS_PER_BEAT`]th of a beat after the head, up to but excluding the end.
+pub fn roll_ticks(bpm: &[Timed<BpmCommand>], time: f32, end: f32) -> Vec<f32> {
+    let start = position_for_time(bpm, time) * ROLL_TICKS_PER_BEAT;
+    let end = position_for_time(bpm, end) * ROLL_TICKS_PER_BEAT;
+    (start.floor() as i64 + 1..)
+        .map(|tick| tick as f32)
+        .take_while(|&tick| tick < end)
+        .map(|tick| time_for_position(bpm, tick / ROLL_TICKS_PER_BEAT))
+        .collect()
+}
+
+/// A roll whose head was hit. Presses near it keep it alive until its end.
+pub struct ActiveRoll<T> {
+    /// The note, as the game or the [judge harness](crate::judge_harness) identifies it.
+    pub note: T,
+    pub time: f32,
+    pub end: f32,
+    /// Judgement and offset of the head, which the roll gets if it is kept up to its end.
+    pub judgement: Judgement,
+    pub offset: f32,
+    pub last_press: f32,
+    pub position: Point2<f32>,
+    /// Times of the ticks still to come, see [`roll_ticks`].
+    pub ticks: Vec<f32>,
+}
+
+/// The rolls held by a player, shared with the [judge harness](crate::judge_harness) so that
+/// both keep rolls alive alike.
+pub struct Rolls<T>(pub Vec<ActiveRoll<T>>);
+
+impl<T> Default for Rolls<T> {
+    fn default() -> Self {
+        Rolls(Vec::new())
+    }
+}
+
+impl<T: PartialEq> Rolls<T> {
+    pub fn contains(&self, note: &T) -> bool {
+        self.0.iter().any(|roll| roll.note == *note)
+    }
+
+    /// Keeps the roll near a press at `input_pos` alive. Returns `false` if there is none, in
+    /// which case the press goes on to hit notes.
+    pub fn press(&mut self, input_pos: Vector2<f32>, now: f32, norm_threshold: f32) -> bool {
+        match self
+            .0
+            .iter_mut()
+            .find(|roll| key_distance(input_pos, roll.position) <= norm_threshold)
+        {
+            Some(roll) => {
+                roll.last_press = now;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Passes the ticks before `now`, and ends the rolls that were kept up to their end, with the
+    /// judgement of their head, and those let go for longer than the roll window before it, as
+    /// misses. `ticked` gets each tick passed while its roll was kept alive, and `judged` each
+    /// ended roll with its judgement and offset.
+    pub fn update(
+        &mut self,
+        now: f32,
+        windows: &JudgeWindows,
+        mut ticked: impl FnMut(&ActiveRoll<T>, f32),
+        mut judged: impl FnMut(&ActiveRoll<T>, Judgement, Option<f32>),
+    ) {
+        for roll in &mut self.0 {
+            let passed = roll.ticks.iter().take_while(|&&tick| tick <= now).count();
+            for i in 0..passed {
+                let tick = roll.ticks[i];
+                if roll.last_press + windows.roll >= tick {
+                    ticked(roll, tick);
+                }
+            }
+            roll.ticks.drain(..passed);
+        }
+        self.0.retain(|roll| {
+            if roll.last_press + windows.roll < roll.end.min(now) {
+                judged(roll, Judgement::Miss, None);
+            } else if now >= roll.end {
+                judged(roll, roll.judgement, Some(roll.offset));
+            } else {
+                return true;
+            }
+            false
+        });
+    }
+}
+
+/// Where the hit area of a note is, in highway coordinates like the key positions.
+fn note_position(transform: &Transform) -> Point2<f32> {
+    transform
+        .global_matrix()
+        .transform_point(&Point3::new(0.5, 0., 0.))
+        .xy()
+}
+
+#[derive(Clone, Debug)]
+pub struct JudgeEvent {
+    pub player: u8,
+    pub judgement: Judgement,
+    /// The time of the judged note.
+    pub time: f32,
+    /// Note time minus input time, or `None` if the note timed out.
+    pub offset: Option<f32>,
+    /// Screen position of the note in percent.
+    pub position: Point2<f32>,
+    /// The key nearest to the note, whether or not it was the one pressed.
+    pub key: Option<ScanCode>,
+}
+
+/// A mine set off by a player.
+#[derive(Clone, Debug)]
+pub struct MineEvent {
+    pub player: u8,
+    /// The time of the mine.
+    pub time: f32,
+}
+
+/// A tick of a roll a player kept alive.
+#[derive(Clone, Debug)]
+pub struct TickEvent {
+    pub player: u8,
+    /// The time of the tick.
+    pub time: f32,
+}
 
 pub struct JudgeSystem {
+    player: u8,
     reader_id: ReaderId<Event>,
-    mapping: Vec<(ScanCode, (f32, f32))>,
     animation: Handle<Animation<UiTransform>>,
+    /// Keeps the popups in place with reduced motion. Popups are still animated so that they are
+    /// cleaned up when done.
+    still_animation: Handle<Animation<UiTransform>>,
+    rolls: Rolls<Entity>,
 }
 
 pub struct JudgeSystemDesc {
-    pub mapping: ScancodeMap,
+    /// The player whose notes this system judges.
+    pub player: u8,
+}
+
+/// Plays every note perfectly instead of reading the keyboard, e.g. for demos.
+#[derive(Default)]
+pub struct Autoplay(pub bool);
+
+/// Whether the play had help: autoplay, or seeking, changing the playback rate or loading another
+/// chart from the console or the command line. Assisted plays are not saved as scores, in the
+/// history or on leaderboards.
+#[derive(Default)]
+pub struct Assisted(pub bool);
+
+/// Key positions of each player, sorted by scancode. A resource so that bindings can change while
+/// running.
+#[derive(Default)]
+pub struct KeyMappings(pub Vec<Vec<(ScanCode, (f32, f32))>>);
+
+impl KeyMappings {
+    pub fn new(maps: Vec<ScancodeMap>) -> Self {
+        KeyMappings(maps.into_iter().map(ScancodeMap::into_mapping).collect())
+    }
 }
 
 impl<'a, 'b> SystemDesc<'a, 'b, JudgeSystem> for JudgeSystemDesc {
@@ -44,28 +363,33 @@ impl<'a, 'b> SystemDesc<'a, 'b, JudgeSystem> for JudgeSystemDesc {
             .register_reader();
 
         world.insert(AssetStorage::<Sampler<SamplerPrimitive<f32>>>::default());
-        use SamplerPrimitive::Vec2;
-        let sampler = world
-            .get_mut::<AssetStorage<Sampler<SamplerPrimitive<f32>>>>()
-            .unwrap()
-            .insert(Sampler {
-                input: vec![0., 0.3],
-                output: vec![Vec2([0., 0.]), Vec2([0., 0.1])],
-                function: InterpolationFunction::SphericalLinear,
-            });
-
         world.insert(AssetStorage::<Animation<UiTransform>>::default());
-        let animation = world
-            .get_mut::<AssetStorage<Animation<UiTransform>>>()
-            .unwrap()
-            .insert(Animation {
-                nodes: vec![(0, UiTransformChannel::Translation, sampler)],
-            });
+        let mut rise = |height: f32| {
+            use SamplerPrimitive::Vec2;
+            let sampler = world
+                .get_mut::<AssetStorage<Sampler<SamplerPrimitive<f32>>>>()
+                .unwrap()
+                .insert(Sampler {
+                    input: vec![0., 0.3],
+                    output: vec![Vec2([0., 0.]), Vec2([0., height])],
+                    function: InterpolationFunction::SphericalLinear,
+                });
+            world
+                .get_mut::<AssetStorage<Animation<UiTransform>>>()
+                .unwrap()
+                .insert(Animation {
+                    nodes: vec![(0, UiTransformChannel::Translation, sampler)],
+                })
+        };
+        let animation = rise(0.1);
+        let still_animation = rise(0.);
 
         JudgeSystem {
+            player: self.player,
             reader_id,
-            mapping: self.mapping.into_mapping(),
             animation,
+            still_animation,
+            rolls: Rolls::default(),
         }
     }
 }
@@ -76,6 +400,15 @@ impl<'s> System<'s> for JudgeSystem {
         Read<'s, EventChannel<Event>>,
         ReadExpect<'s, Time>,
         Read<'s, Option<PlaySettings>>,
+        Read<'s, Settings>,
+        Read<'s, KeyMappings>,
+        Read<'s, Autoplay>,
+        Read<'s, ActiveModifiers>,
+        Read<'s, Console>,
+        Read<'s, Paused>,
+        Read<'s, Option<ReplayPlayback>>,
+        Read<'s, laser::LaserOptions>,
+        ReadExpect<'s, ScreenDimensions>,
         WriteStorage<'s, laser::Note>,
         ReadStorage<'s, Transform>,
         ReadExpect<'s, InterFont>,
@@ -84,6 +417,10 @@ impl<'s> System<'s> for JudgeSystem {
         WriteStorage<'s, AnimationControlSet<(), UiTransform>>,
         WriteStorage<'s, SamplerControlSet<UiTransform>>,
         WriteStorage<'s, Parent>,
+        WriteStorage<'s, Billboard>,
+        Write<'s, EventChannel<JudgeEvent>>,
+        Write<'s, EventChannel<MineEvent>>,
+        Write<'s, EventChannel<TickEvent>>,
     );
 
     fn run(
@@ -93,6 +430,15 @@ impl<'s> System<'s> for JudgeSystem {
             events,
             time,
             settings,
+            user_settings,
+            mappings,
+            autoplay,
+            modifiers,
+            console,
+            paused,
+            playback,
+            laser_options,
+            dimensions,
             mut notes,
             transforms,
             inter_font,
@@ -101,10 +447,112 @@ impl<'s> System<'s> for JudgeSystem {
             mut anim,
             mut samp,
             mut parent,
+            mut billboards,
+            mut judge_events,
+            mut mine_events,
+            mut tick_events,
         ): Self::SystemData,
     ) {
-        for event in events.read(&mut self.reader_id) {
-            match event {
+        let player = self.player;
+        let mapping = mappings
+            .0
+            .get(player as usize)
+            .map_or(&[][..], Vec::as_slice);
+        let players = settings.as_ref().map_or(1, |s| s.players.max(1));
+        // Popups are shared between players, so only one system cleans them up.
+        if player == 0 {
+            for (eid, parent, samp) in (&entities, &parent, &mut samp).join() {
+                if let Some(ControlState::Done) = &samp.samplers.get(0).map(|x| &x.state) {
+                    entities.delete(parent.entity).unwrap();
+                }
+            }
+            let now = time.absolute_real_time_seconds();
+            for (eid, billboard) in (&entities, &billboards).join() {
+                if now - billboard.shown_at > POPUP_SECONDS {
+                    entities.delete(eid).unwrap();
+                }
+            }
+        }
+        let animation = if user_settings.accessibility.reduced_motion {
+            self.still_animation.clone()
+        } else {
+            self.animation.clone()
+        };
+        // Rolls are gone with their entities when the highway is cleared.
+        self.rolls.0.retain(|roll| entities.is_alive(roll.note));
+        let rolls = &mut self.rolls;
+        let font_size = JUDGE_FONT_SIZE * user_settings.ui.scale_factor(&dimensions);
+        let world_popups = user_settings.ui.world_popups;
+        let shown_at = time.absolute_real_time_seconds();
+        let zoom = laser_options.zoom;
+        let perfect_only = modifiers.contains(Modifier::PerfectOnly);
+        let mut judge = |judgement: Judgement, time: f32, offset: Option<f32>, pos: Point2<f32>| {
+            let judgement = match judgement {
+                Judgement::Near if perfect_only => Judgement::Miss,
+                judgement => judgement,
+            };
+            let highway_pos = pos;
+            // Notes are positioned relative to the player's own highway.
+            let pos = Point2::new((player as f32 + pos.x) / players as f32, pos.y);
+            judge_events.single_write(JudgeEvent {
+                player,
+                judgement,
+                time,
+                offset,
+                position: pos,
+                key: nearest_key(mapping, highway_pos),
+            });
+            if world_popups {
+                let entity = entities.create();
+                billboards
+                    .insert(
+                        entity,
+                        Billboard {
+                            judgement,
+                            player,
+                            position: highway_pos,
+                            shown_at,
+                        },
+                    )
+                    .unwrap();
+                return;
+            }
+            let (text, color) = (judgement.text(), judgement.color());
+            // The highway shrinks or grows around the center of the screen when zoomed.
+            let pos = Point2::new(0.5 + (pos.x - 0.5) * zoom, 0.5 + (pos.y - 0.5) * zoom);
+            let ui_entity = entities.create();
+            let ui_entity_parent = entities.create();
+            parent
+                .insert(ui_entity, Parent::new(ui_entity_parent))
+                .unwrap();
+            let text = UiText::new(inter_font.0.clone(), text.into(), color, font_size);
+            let mut ui_trans_parent =
+                percent_transform("JudgeParent", Anchor::BottomLeft, pos.x, pos.y, 0.3, 1.);
+            ui_trans_parent.pivot = Anchor::BottomMiddle;
+            ui_trans_parent.local_z = 0.;
+            let mut ui_trans = percent_transform("Judge", Anchor::BottomMiddle, 0., 0., 0.3, 0.1);
+            ui_trans.local_z = 0.;
+            ui_text.insert(ui_entity, text).unwrap();
+            ui_transform
+                .insert(ui_entity_parent, ui_trans_parent)
+                .unwrap();
+            ui_transform.insert(ui_entity, ui_trans).unwrap();
+            let mut control_set = AnimationControlSet::default();
+            control_set.insert(
+                (),
+                AnimationControl::new(
+                    animation.clone(),
+                    EndControl::Stay,
+                    ControlState::Requested,
+                    AnimationCommand::Start,
+                    1.0,
+                ),
+            );
+            anim.insert(ui_entity, control_set).unwrap();
+        };
+        let live_presses: Vec<_> = events
+            .read(&mut self.reader_id)
+            .filter_map(|event| match event {
                 Event::WindowEvent {
                     event:
                         WindowEvent::KeyboardInput {
@@ -117,114 +565,92 @@ impl<'s> System<'s> for JudgeSystem {
                             ..
                         },
                     ..
-                } => {
-                    if let Some(PlaySettings {
-                        base_time,
-                        offset,
-                        norm_threshold,
-                        ..
-                    }) = *settings
-                    {
-                        let rel = (time.absolute_time_seconds() - base_time) as f32 + offset;
-                        if let Ok(input_pos_idx) =
-                            self.mapping.binary_search_by_key(&scancode, |(s, _)| s)
+                } => Some(*scancode),
+                _ => None,
+            })
+            .collect();
+        let presses = match &*playback {
+            Some(playback) => playback
+                .due(player)
+                .iter()
+                .filter(|input| input.pressed)
+                .map(|input| input.scancode)
+                .collect(),
+            // Typing into the console or the pause menu doesn't hit notes.
+            None if autoplay.0 || console.open || paused.0 => Vec::new(),
+            None => live_presses,
+        };
+        for scancode in &presses {
+            if let Some(PlaySettings {
+                base_time,
+                offset,
+                norm_threshold,
+                ..
+            }) = *settings
+            {
+                let rel = (time.absolute_time_seconds() - base_time) as f32 + offset;
+                let windows = &user_settings.gameplay.windows(time.time_scale());
+                let note_lock = user_settings.gameplay.note_lock;
+                if let Ok(input_pos_idx) = mapping.binary_search_by_key(&scancode, |(s, _)| s) {
+                    let input_pos = {
+                        let (_, (x, y)) = mapping[input_pos_idx];
+                        Vector2::new(x, y)
+                    };
+                    for (entity, n, t) in (&entities, &notes, &transforms).join() {
+                        let diff = n.time - rel;
+                        if n.player == player
+                            && n.kind == NoteKind::Mine
+                            && sets_off_mine(
+                                diff,
+                                input_pos,
+                                note_position(t),
+                                windows,
+                                norm_threshold,
+                            )
                         {
-                            let input_pos = {
-                                let (_, (x, y)) = self.mapping[input_pos_idx];
-                                Vector2::new(x, y)
-                            };
-                            if let Some((entity, diff, pos, _)) =
-                                (&entities, &mut notes, &transforms)
-                                    .join()
-                                    .map(|(e, n, t)| (e, n.time - rel, t))
-                                    .filter(|(_, diff, _)| {
-                                        (-NEAR_WINDOW..EARLY_MISS_WINDOW).contains(&diff)
-                                    })
-                                    .map(|(e, n, t)| {
-                                        let note_pos = t
-                                            .global_matrix()
-                                            .transform_point(&Point3::new(0.5, 0., 0.))
-                                            .xy();
-                                        let norm = Matrix3::new_nonuniform_scaling(&Vector2::new(
-                                            1.0, 0.3,
-                                        ))
-                                        .transform_vector(&(input_pos - note_pos.coords))
-                                        .norm_squared();
-                                        (e, n, note_pos, norm)
-                                    })
-                                    .filter(|(_, _, _, norm)| norm <= &norm_threshold)
-                                    .min_by(|(_, lhs_time, _, lhs), (_, rhs_time, _, rhs)| {
-                                        // TODO: relying on equality is not good
-                                        lhs.partial_cmp(rhs)
-                                            .unwrap()
-                                            .then_with(|| lhs_time.partial_cmp(&rhs_time).unwrap())
-                                    })
-                            {
-                                let (text, color) =
-                                    if (-PERFECT_WINDOW..PERFECT_WINDOW).contains(&diff) {
-                                        ("PERFECT", [0.8, 0., 0.8, 1.])
-                                    } else if (-NEAR_WINDOW..NEAR_WINDOW).contains(&diff) {
-                                        ("NEAR", [0., 0.1, 0.8, 1.])
-                                    } else {
-                                        ("MISS", [0.9, 0., 0.2, 1.])
-                                    };
-                                let ui_entity = entities.create();
-                                let ui_entity_parent = entities.create();
-                                parent
-                                    .insert(ui_entity, Parent::new(ui_entity_parent))
-                                    .unwrap();
-                                let text =
-                                    UiText::new(inter_font.0.clone(), text.into(), color, 40.);
-                                let mut ui_trans_parent = UiTransform::new(
-                                    String::from("JudgeParent"),
-                                    Anchor::BottomLeft,
-                                    Anchor::BottomMiddle,
-                                    pos.x,
-                                    pos.y,
-                                    0.,
-                                    0.3,
-                                    1.,
-                                );
-                                ui_trans_parent.scale_mode = ScaleMode::Percent;
-                                let mut ui_trans = UiTransform::new(
-                                    String::from("Judge"),
-                                    Anchor::BottomMiddle,
-                                    Anchor::BottomMiddle,
-                                    0.,
-                                    0.,
-                                    0.,
-                                    0.3,
-                                    0.1,
-                                );
-                                ui_trans.scale_mode = ScaleMode::Percent;
-                                ui_text.insert(ui_entity, text).unwrap();
-                                ui_transform
-                                    .insert(ui_entity_parent, ui_trans_parent)
-                                    .unwrap();
-                                ui_transform.insert(ui_entity, ui_trans).unwrap();
-                                let mut control_set = AnimationControlSet::default();
-                                control_set.insert(
-                                    (),
-                                    AnimationControl::new(
-                                        self.animation.clone(),
-                                        EndControl::Stay,
-                                        ControlState::Requested,
-                                        AnimationCommand::Start,
-                                        1.0,
-                                    ),
-                                );
-                                anim.insert(ui_entity, control_set).unwrap();
+                            mine_events.single_write(MineEvent {
+                                player,
+                                time: n.time,
+                            });
+                            entities.delete(entity).unwrap();
+                        }
+                    }
+                    // Presses that keep a roll alive don't hit other notes.
+                    if rolls.press(input_pos, rel, norm_threshold) {
+                        continue;
+                    }
+                    let candidates = (&entities, &notes, &transforms)
+                        .join()
+                        .filter(|(e, n, _)| {
+                            n.player == player && n.kind != NoteKind::Mine && !rolls.contains(e)
+                        })
+                        .map(|(e, n, t)| (e, n.time - rel, note_position(t)));
+                    if let Some((entity, diff, pos)) =
+                        pick_note(candidates, input_pos, windows, note_lock, norm_threshold)
+                    {
+                        let judgement = windows.judge(diff);
+                        match notes.get(entity).map(|n| n.kind) {
+                            Some(NoteKind::Roll { end }) if judgement != Judgement::Miss => {
+                                rolls.0.push(ActiveRoll {
+                                    note: entity,
+                                    time: rel + diff,
+                                    end,
+                                    judgement,
+                                    offset: diff,
+                                    last_press: rel,
+                                    position: pos,
+                                    ticks: notes
+                                        .get(entity)
+                                        .map_or_else(Vec::new, |n| n.ticks.clone()),
+                                })
+                            }
+                            _ => {
+                                judge(judgement, rel + diff, Some(diff), pos);
                                 entities.delete(entity).unwrap();
                             }
                         }
                     }
                 }
-                _ => {}
-            }
-        }
-        for (eid, parent, samp) in (&entities, &parent, &mut samp).join() {
-            if let Some(ControlState::Done) = &samp.samplers.get(0).map(|x| &x.state) {
-                entities.delete(parent.entity).unwrap();
             }
         }
         if let Some(PlaySettings {
@@ -232,93 +658,154 @@ impl<'s> System<'s> for JudgeSystem {
         }) = *settings
         {
             let rel = (time.absolute_time_seconds() - base_time) as f32 + offset;
-            for (entity, _, t) in (&entities, &mut notes, &transforms)
+            let windows = &user_settings.gameplay.windows(time.time_scale());
+            rolls.update(
+                rel,
+                windows,
+                |_, tick| tick_events.single_write(TickEvent { player, time: tick }),
+                |roll, judgement, offset| {
+                    judge(judgement, roll.time, offset, roll.position);
+                    entities.delete(roll.note).unwrap();
+                },
+            );
+            let deadline = if autoplay.0 { 0. } else { windows.near_late };
+            // Autoplay holds rolls to their end.
+            let due = |n: &laser::Note| match n.kind {
+                NoteKind::Roll { end } if autoplay.0 => end,
+                _ => n.time + deadline,
+            };
+            for (entity, n, t) in (&entities, &mut notes, &transforms)
                 .join()
-                .filter(|(_, n, _)| n.time + NEAR_WINDOW < rel)
+                .filter(|(e, n, _)| n.player == player && due(n) < rel && !rolls.contains(e))
             {
-                let pos = t
-                    .global_matrix()
-                    .transform_point(&Point3::new(0.5, 0., 0.))
-                    .xy();
-                let (text, color) = ("MISS", [0.9, 0., 0.2, 1.]);
-                let ui_entity = entities.create();
-                let ui_entity_parent = entities.create();
-                parent
-                    .insert(ui_entity, Parent::new(ui_entity_parent))
-                    .unwrap();
-                let text = UiText::new(inter_font.0.clone(), text.into(), color, 40.);
-                let mut ui_trans_parent = UiTransform::new(
-                    String::from("JudgeParent"),
-                    Anchor::BottomLeft,
-                    Anchor::BottomMiddle,
-                    pos.x,
-                    pos.y,
-                    0.,
-                    0.3,
-                    1.,
-                );
-                ui_trans_parent.scale_mode = ScaleMode::Percent;
-                let mut ui_trans = UiTransform::new(
-                    String::from("Judge"),
-                    Anchor::BottomMiddle,
-                    Anchor::BottomMiddle,
-                    0.,
-                    0.,
-                    0.,
-                    0.3,
-                    0.1,
-                );
-                ui_trans.scale_mode = ScaleMode::Percent;
-                ui_text.insert(ui_entity, text).unwrap();
-                ui_transform
-                    .insert(ui_entity_parent, ui_trans_parent)
-                    .unwrap();
-                ui_transform.insert(ui_entity, ui_trans).unwrap();
-                let mut control_set = AnimationControlSet::default();
-                control_set.insert(
-                    (),
-                    AnimationControl::new(
-                        self.animation.clone(),
-                        EndControl::Stay,
-                        ControlState::Requested,
-                        AnimationCommand::Start,
-                        1.0,
-                    ),
-                );
-                anim.insert(ui_entity, control_set).unwrap();
+                // Mines that were avoided just go away.
+                if n.kind == NoteKind::Mine {
+                    entities.delete(entity).unwrap();
+                    continue;
+                }
+                let pos = note_position(t);
+                if autoplay.0 {
+                    for &tick in &n.ticks {
+                        tick_events.single_write(TickEvent { player, time: tick });
+                    }
+                    judge(Judgement::Perfect, n.time, Some(0.), pos);
+                } else {
+                    judge(Judgement::Miss, n.time, None, pos);
+                }
                 entities.delete(entity).unwrap();
             }
         }
     }
 }
 
-#[derive(Default, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct ScancodeRow {
     offset: f32,
     keys: Vec<ScanCode>,
 }
-#[derive(Default, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ScancodeMap {
+    /// Format version, see [`Versioned`].
+    #[serde(default = "migrate::unversioned")]
+    version: u32,
     width: f32,
     rows: Vec<ScancodeRow>,
 }
 
+impl Default for ScancodeMap {
+    fn default() -> Self {
+        Self {
+            version: Self::current_version(),
+            width: 0.,
+            rows: Vec::new(),
+        }
+    }
+}
+
+impl Versioned for ScancodeMap {
+    const MIGRATIONS: &'static [fn(&mut Self)] = &[migrate::no_changes];
+
+    fn version_mut(&mut self) -> &mut u32 {
+        &mut self.version
+    }
+}
+
 impl ScancodeMap {
-    fn into_mapping(self) -> Vec<(ScanCode, (f32, f32))> {
+    pub fn scancodes(&self) -> impl Iterator<Item = ScanCode> + '_ {
+        self.rows.iter().flat_map(|r| r.keys.iter().cloned())
+    }
+
+    /// The keys of each row, top row first.
+    pub fn rows(&self) -> impl Iterator<Item = &[ScanCode]> + '_ {
+        self.rows.iter().map(|r| r.keys.as_slice())
+    }
+
+    /// Binds the key at `index` in `row` to `scancode`.
+    pub fn set_key(&mut self, row: usize, index: usize, scancode: ScanCode) {
+        self.rows[row].keys[index] = scancode;
+    }
+
+    /// The position of each key on the judge quad, in row order.
+    pub fn positions(&self) -> impl Iterator<Item = (ScanCode, (f32, f32))> + '_ {
         let height = self.rows.len() as f32;
-        let mut ret: Vec<_> = self
-            .rows
-            .into_iter()
-            .enumerate()
-            .flat_map(|(i, r)| {
-                let width = r.keys.len() as f32;
-                let offset = r.offset;
-                r.keys
-                    .into_iter()
-                    .enumerate()
-                    .map(move |(j, k)| (k, (i as f32 / height, (offset + j as f32) / width)))
-            })
-            .collect();
+        self.rows.iter().enumerate().flat_map(move |(i, r)| {
+            let width = r.keys.len() as f32;
+            let offset = r.offset;
+            r.keys
+                .iter()
+                .enumerate()
+                .map(move |(j, &k)| (k, (i as f32 / height, (offset + j as f32) / width)))
+        })
+    }
+
+    /// Mistakes in a hand-written map. Row offsets are in key widths, so they are checked against
+    /// the width of the map.
+    pub fn validate(&self) -> Vec<KeyMapWarning> {
+        let mut warnings = Vec::new();
+        let mut seen = Vec::new();
+        for scancode in self.scancodes() {
+            if seen.contains(&scancode) {
+                if !warnings.contains(&KeyMapWarning::Duplicate(scancode)) {
+                    warnings.push(KeyMapWarning::Duplicate(scancode));
+                }
+            } else {
+                seen.push(scancode);
+            }
+        }
+        for (row, r) in self.rows.iter().enumerate() {
+            if r.keys.is_empty() {
+                warnings.push(KeyMapWarning::EmptyRow(row));
+            }
+            if self.width > 0. && !(0. ..1.).contains(&(r.offset / self.width)) {
+                warnings.push(KeyMapWarning::OffsetOutOfRange {
+                    row,
+                    offset: r.offset,
+                });
+            }
+        }
+        warnings
+    }
+
+    /// Where each key sits on the keyboard, in fractions of the width and height of the map, top
+    /// row first.
+    pub fn layout(&self) -> impl Iterator<Item = (ScanCode, (f32, f32))> + '_ {
+        let (width, height) = (self.width.max(1.), self.rows.len() as f32);
+        self.rows.iter().enumerate().flat_map(move |(i, r)| {
+            let offset = r.offset;
+            r.keys
+                .iter()
+                .enumerate()
+                .map(move |(j, &k)| (k, ((offset + j as f32) / width, i as f32 / height)))
+        })
+    }
+
+    /// The width of the map in keys.
+    pub fn width(&self) -> f32 {
+        self.width
+    }
+
+    fn into_mapping(self) -> Vec<(ScanCode, (f32, f32))> {
+        let mut ret: Vec<_> = self.positions().collect();
         ret.sort_unstable_by(|x, y| x.partial_cmp(y).unwrap());
         ret
     }
@@ -0,0 +1,430 @@
+//! Runs a chart and a scripted input timeline through the judgment rules without a window, for
+//! regression checks of the windows, chord handling and note lock.
+//!
+//! Presses are judged exactly at their time and notes time out as soon as their window closes,
+//! as if the game ran at an unlimited frame rate. Rolls and mines follow the rules of the game
+//! through [`Rolls`] and [`sets_off_mine`]. The result is compared as a snapshot:
+//!
+//! ```ignore
+//! let presses = [Press::on_lane(1.01, 0.1, 1, 4), Press::on_lane(1.2, 0.1, 2, 4)];
+//! let events = judge_chart(&chart, &presses, &JudgeOptions::default());
+//! assert_eq!(snapshot(&events), "1.000 0:1 PERFECT -10ms\n1.075 0:2 MISS\n");
+//! ```
+use crate::chart::{Chart, LaserCommand, LaserId, NoteKind};
+use crate::judge::{
+    pick_note, roll_ticks, sets_off_mine, ActiveRoll, JudgeWindows, Judgement, Rolls,
+};
+use amethyst::core::math::{Point2, Vector2};
+use std::collections::BTreeMap;
+
+/// A key press at a position on the highway, in chart time with the offset already applied.
+#[derive(Copy, Clone, Debug)]
+pub struct Press {
+    pub time: f32,
+    pub position: Vector2<f32>,
+}
+
+impl Press {
+    /// A press right on a lane of a laser at height `y` with `lanes` lanes.
+    pub fn on_lane(time: f32, y: f32, lane: u32, lanes: u16) -> Self {
+        Self {
+            time,
+            position: Vector2::new((lane as f32 + 0.5) / lanes as f32, y),
+        }
+    }
+}
+
+/// The rules in effect, as in the gameplay settings.
+#[derive(Copy, Clone, Debug)]
+pub struct JudgeOptions {
+    pub windows: JudgeWindows,
+    pub note_lock: bool,
+    pub norm_threshold: f32,
+}
+
+impl Default for JudgeOptions {
+    fn default() -> Self {
+        Self {
+            windows: JudgeWindows::default(),
+            note_lock: false,
+            norm_threshold: 0.1,
+        }
+    }
+}
+
+/// One judged note.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HarnessJudgement {
+    pub time: f32,
+    pub laser: LaserId,
+    pub lane: u32,
+    pub judgement: Judgement,
+    /// Note time minus press time, or `None` if the note timed out.
+    pub offset: Option<f32>,
+}
+
+/// What the game would have reported, in its order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HarnessEvent {
+    Judged(HarnessJudgement),
+    /// A mine was set off.
+    Mine {
+        time: f32,
+        laser: LaserId,
+        lane: u32,
+    },
+    /// A roll was kept alive through a tick at `time`.
+    Tick {
+        time: f32,
+        laser: LaserId,
+        lane: u32,
+    },
+}
+
+struct PendingNote {
+    index: usize,
+    position: Point2<f32>,
+}
+
+/// Judges every note of `chart` against `presses`, which must be sorted by time.
+///
+/// Notes on lasers that are not present are skipped, like the game does.
+pub fn judge_chart(chart: &Chart, presses: &[Press], options: &JudgeOptions) -> Vec<HarnessEvent> {
+    // Height and lanes of each laser at the time of each note.
+    let mut lasers = BTreeMap::new();
+    let mut command = 0;
+    let mut pending = Vec::new();
+    let mut mines = Vec::new();
+    for (index, note) in chart.notes.iter().enumerate() {
+        while command < chart.lasers.len() && chart.lasers[command].time <= note.time {
+            let (id, laser_command) = &chart.lasers[command].inner;
+            match laser_command {
+                LaserCommand::Enter { y, lanes, .. } => {
+                    lasers.insert(*id, (*y, *lanes));
+                }
+                LaserCommand::Leave => {
+                    lasers.remove(id);
+                }
+                LaserCommand::LineTo { .. } | LaserCommand::ColorTo { .. } => {}
+            }
+            command += 1;
+        }
+        if let Some(&(y, lanes)) = lasers.get(&note.laser) {
+            let note_pending = PendingNote {
+                index,
+                position: Point2::new((note.lane as f32 + 0.5) / lanes as f32, y),
+            };
+            if note.kind == NoteKind::Mine {
+                mines.push(note_pending);
+            } else {
+                pending.push(note_pending);
+            }
+        }
+    }
+
+    let windows = &options.windows;
+    let mut rolls = Rolls::default();
+    let mut events = Vec::new();
+    for press in presses {
+        time_out(
+            chart,
+            windows,
+            &mut pending,
+            &mut rolls,
+            press.time,
+            &mut events,
+        );
+        mines.retain(|mine| {
+            let diff = chart.notes[mine.index].time - press.time;
+            let set_off = sets_off_mine(
+                diff,
+                press.position,
+                mine.position,
+                windows,
+                options.norm_threshold,
+            );
+            if set_off {
+                let note = &chart.notes[mine.index];
+                events.push(HarnessEvent::Mine {
+                    time: note.time,
+                    laser: note.laser,
+                    lane: note.lane,
+                });
+            }
+            !set_off
+        });
+        // Presses that keep a roll alive don't hit other notes.
+        if rolls.press(press.position, press.time, options.norm_threshold) {
+            continue;
+        }
+        let candidates = pending
+            .iter()
+            .enumerate()
+            .map(|(i, note)| (i, chart.notes[note.index].time - press.time, note.position));
+        if let Some((i, diff, position)) = pick_note(
+            candidates,
+            press.position,
+            windows,
+            options.note_lock,
+            options.norm_threshold,
+        ) {
+            let note = pending.remove(i);
+            let judgement = windows.judge(diff);
+            match chart.notes[note.index].kind {
+                NoteKind::Roll { end } if judgement != Judgement::Miss => {
+                    rolls.0.push(ActiveRoll {
+                        note: note.index,
+                        time: press.time + diff,
+                        end,
+                        judgement,
+                        offset: diff,
+                        last_press: press.time,
+                        position,
+                        ticks: roll_ticks(
+                            chart.bpm_track(chart.notes[note.index].laser),
+                            chart.notes[note.index].time,
+                            end,
+                        ),
+                    })
+                }
+                _ => events.push(record(chart, note.index, judgement, Some(diff))),
+            }
+        }
+    }
+    time_out(
+        chart,
+        windows,
+        &mut pending,
+        &mut rolls,
+        std::f32::INFINITY,
+        &mut events,
+    );
+    events
+}
+
+/// Ends the rolls and misses the notes whose window closed before `now`.
+fn time_out(
+    chart: &Chart,
+    windows: &JudgeWindows,
+    pending: &mut Vec<PendingNote>,
+    rolls: &mut Rolls<usize>,
+    now: f32,
+    events: &mut Vec<HarnessEvent>,
+) {
+    // Ticks all come before the rolls ending, as the game passes them first too.
+    let mut ended = Vec::new();
+    rolls.update(
+        now,
+        windows,
+        |roll, time| {
+            let note = &chart.notes[roll.note];
+            events.push(HarnessEvent::Tick {
+                time,
+                laser: note.laser,
+                lane: note.lane,
+            })
+        },
+        |roll, judgement, offset| ended.push(record(chart, roll.note, judgement, offset)),
+    );
+    events.extend(ended);
+    pending.retain(|note| {
+        let timed_out = chart.notes[note.index].time + windows.near_late < now;
+        if timed_out {
+            events.push(record(chart, note.index, Judgement::Miss, None));
+        }
+        !timed_out
+    });
+}
+
+fn record(chart: &Chart, index: usize, judgement: Judgement, offset: Option<f32>) -> HarnessEvent {
+    let note = &chart.notes[index];
+    HarnessEvent::Judged(HarnessJudgement {
+        time: note.time,
+        laser: note.laser,
+        lane: note.lane,
+        judgement,
+        offset,
+    })
+}
+
+/// One line per event: note time, laser and lane, then the judgement and offset in milliseconds,
+/// `MINE` or, with the time of the tick, `TICK`.
+pub fn snapshot(events: &[HarnessEvent]) -> String {
+    let mut text = String::new();
+    for event in events {
+        match event {
+            HarnessEvent::Judged(j) => {
+                text += &format!(
+                    "{:.3} {}:{} {}",
+                    j.time,
+                    j.laser.0,
+                    j.lane,
+                    j.judgement.text()
+                );
+                if let Some(offset) = j.offset {
+                    text += &format!(" {:+.0}ms", offset * 1000.);
+                }
+            }
+            HarnessEvent::Mine { time, laser, lane } => {
+                text += &format!("{:.3} {}:{} MINE", time, laser.0, lane)
+            }
+            HarnessEvent::Tick { time, laser, lane } => {
+                text += &format!("{:.3} {}:{} TICK", time, laser.0, lane)
+            }
+        }
+        text += "\n";
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chart::{BpmCommand, Note, Timed};
+
+    const Y: f32 = 0.1;
+    const LANES: u16 = 4;
+
+    /// A chart of `notes` as time, lane and kind on one laser.
+    fn chart(notes: &[(f32, u32, NoteKind)]) -> Chart {
+        Chart {
+            notes: notes
+                .iter()
+                .map(|&(time, lane, kind)| Timed {
+                    time,
+                    inner: Note {
+                        laser: LaserId(0),
+                        lane,
+                        kind,
+                    },
+                })
+                .collect(),
+            bpm: vec![Timed {
+                time: 0.0,
+                inner: BpmCommand {
+                    bpm: 120.,
+                    position: 0.0,
+                },
+            }],
+            laser_bpm: Default::default(),
+            lasers: vec![Timed {
+                time: 0.0,
+                inner: (
+                    LaserId(0),
+                    LaserCommand::Enter {
+                        y: Y,
+                        lanes: LANES,
+                        color: (0., 0.1, 0.8).into(),
+                        surface: Default::default(),
+                        note_style: None,
+                    },
+                ),
+            }],
+            sections: Vec::new(),
+            zoom: Vec::new(),
+            default_bpm: 120.,
+        }
+    }
+
+    fn presses(presses: &[(f32, u32)]) -> Vec<Press> {
+        presses
+            .iter()
+            .map(|&(time, lane)| Press::on_lane(time, Y, lane, LANES))
+            .collect()
+    }
+
+    fn judge(chart: &Chart, presses: &[Press], options: &JudgeOptions) -> String {
+        snapshot(&judge_chart(chart, presses, options))
+    }
+
+    #[test]
+    fn windows() {
+        let chart = chart(&[
+            (1.0, 0, NoteKind::Tap),
+            (2.0, 1, NoteKind::Tap),
+            (3.0, 2, NoteKind::Tap),
+            (4.0, 3, NoteKind::Tap),
+        ]);
+        let presses = presses(&[(0.99, 0), (2.06, 1), (2.9, 2)]);
+        assert_eq!(
+            judge(&chart, &presses, &JudgeOptions::default()),
+            "1.000 0:0 PERFECT +10ms\n2.000 0:1 NEAR -60ms\n3.000 0:2 MISS +100ms\n4.000 0:3 MISS\n"
+        );
+    }
+
+    #[test]
+    fn presses_outside_the_windows_are_ignored() {
+        let chart = chart(&[(1.0, 0, NoteKind::Tap)]);
+        let presses = presses(&[(0.8, 0), (1.1, 0)]);
+        assert_eq!(
+            judge(&chart, &presses, &JudgeOptions::default()),
+            "1.000 0:0 MISS\n"
+        );
+    }
+
+    #[test]
+    fn note_lock_ignores_early_misses() {
+        let chart = chart(&[(1.0, 0, NoteKind::Tap)]);
+        let options = JudgeOptions {
+            note_lock: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            judge(&chart, &presses(&[(0.87, 0), (1.0, 0)]), &options),
+            "1.000 0:0 PERFECT +0ms\n"
+        );
+    }
+
+    #[test]
+    fn note_lock_keeps_misses_next_to_the_near_window() {
+        let chart = chart(&[(1.0, 0, NoteKind::Tap)]);
+        let options = JudgeOptions {
+            note_lock: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            judge(&chart, &presses(&[(0.9, 0), (1.0, 0)]), &options),
+            "1.000 0:0 MISS +100ms\n"
+        );
+    }
+
+    #[test]
+    fn rolls_kept_to_their_end_get_the_judgement_of_their_head() {
+        let chart = chart(&[(1.0, 0, NoteKind::Roll { end: 2.0 })]);
+        let presses = presses(&[(1.05, 0), (1.4, 0), (1.8, 0)]);
+        // Ticks every half beat at 120 BPM, up to but excluding the end.
+        assert_eq!(
+            judge(&chart, &presses, &JudgeOptions::default()),
+            "1.250 0:0 TICK\n1.500 0:0 TICK\n1.750 0:0 TICK\n1.000 0:0 NEAR -50ms\n"
+        );
+    }
+
+    #[test]
+    fn dropped_rolls_miss() {
+        let chart = chart(&[
+            (1.0, 0, NoteKind::Roll { end: 2.0 }),
+            (3.0, 0, NoteKind::Tap),
+        ]);
+        // The roll is dropped half a second after the last press, long before the next one.
+        // Only the ticks before that count.
+        let presses = presses(&[(1.0, 0), (1.2, 0), (3.0, 0)]);
+        assert_eq!(
+            judge(&chart, &presses, &JudgeOptions::default()),
+            "1.250 0:0 TICK\n1.500 0:0 TICK\n1.000 0:0 MISS\n3.000 0:0 PERFECT +0ms\n"
+        );
+    }
+
+    #[test]
+    fn mines_go_off_within_the_near_window() {
+        let chart = chart(&[
+            (1.0, 0, NoteKind::Mine),
+            (1.0, 3, NoteKind::Tap),
+            (2.0, 0, NoteKind::Mine),
+        ]);
+        let presses = presses(&[(1.0, 3), (1.02, 0), (2.5, 0)]);
+        assert_eq!(
+            judge(&chart, &presses, &JudgeOptions::default()),
+            "1.000 0:3 PERFECT +0ms\n1.000 0:0 MINE\n"
+        );
+    }
+}
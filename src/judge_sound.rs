@@ -0,0 +1,121 @@
+use crate::audio::{AudioOutput, Channel};
+use crate::judge::{JudgeEvent, Judgement};
+use crate::paths::Paths;
+use crate::score::Scores;
+use crate::theme::Theme;
+use crate::vfs::{FileSystem, Vfs};
+use amethyst::{
+    core::SystemDesc,
+    ecs::{Read, System, SystemData, World},
+    shrev::{EventChannel, ReaderId},
+};
+use rodio::{source::Buffered, Decoder, Source};
+use std::io::Cursor;
+use std::path::Path;
+
+pub(crate) type Sound = Buffered<Decoder<Cursor<Vec<u8>>>>;
+
+/// Loads a theme sound from the resources directory, where it may be inside a zip archive.
+pub(crate) fn load_sound(fs: &dyn Vfs, path: &Option<String>) -> Option<Sound> {
+    let path = Path::new(path.as_ref()?);
+    let result = fs
+        .read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| Decoder::new(Cursor::new(bytes)).map_err(|e| e.to_string()));
+    match result {
+        Ok(decoder) => Some(decoder.buffered()),
+        Err(e) => {
+            log::warn!("Failed to load {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Plays the theme's judgement and combo milestone sounds on the judge mixer channel.
+pub struct JudgeSoundSystem {
+    reader_id: ReaderId<JudgeEvent>,
+    perfect: Option<Sound>,
+    near: Option<Sound>,
+    miss: Option<Sound>,
+    combo: Option<Sound>,
+    combo_interval: u32,
+    /// Combo of each player, counted here so that milestones are heard on the note reaching them
+    /// even if several notes are judged in one frame.
+    combos: Vec<u32>,
+}
+
+pub struct JudgeSoundSystemDesc;
+
+impl<'a, 'b> SystemDesc<'a, 'b, JudgeSoundSystem> for JudgeSoundSystemDesc {
+    fn build(self, world: &mut World) -> JudgeSoundSystem {
+        <JudgeSoundSystem as System<'_>>::SystemData::setup(world);
+
+        let reader_id = world
+            .get_mut::<EventChannel<JudgeEvent>>()
+            .unwrap()
+            .register_reader();
+
+        let theme = world.read_resource::<Theme>();
+        let sounds = &theme.judge_sounds;
+        let fs = FileSystem::new(&world.read_resource::<Paths>().resources);
+        JudgeSoundSystem {
+            reader_id,
+            perfect: load_sound(&fs, &sounds.perfect),
+            near: load_sound(&fs, &sounds.near),
+            miss: load_sound(&fs, &sounds.miss),
+            combo: load_sound(&fs, &sounds.combo),
+            combo_interval: sounds.combo_interval,
+            combos: Vec::new(),
+        }
+    }
+}
+
+impl<'s> System<'s> for JudgeSoundSystem {
+    type SystemData = (
+        Read<'s, EventChannel<JudgeEvent>>,
+        Read<'s, Option<AudioOutput>>,
+        Read<'s, Scores>,
+    );
+
+    fn run(&mut self, (events, output, scores): Self::SystemData) {
+        // A new play has started.
+        for (player, combo) in self.combos.iter_mut().enumerate() {
+            if scores
+                .players
+                .get(player)
+                .map_or(true, |s| s.records.is_empty())
+            {
+                *combo = 0;
+            }
+        }
+        for event in events.read(&mut self.reader_id) {
+            let player = event.player as usize;
+            if self.combos.len() <= player {
+                self.combos.resize(player + 1, 0);
+            }
+            let combo = &mut self.combos[player];
+            let sound = match event.judgement {
+                Judgement::Miss => {
+                    *combo = 0;
+                    &self.miss
+                }
+                Judgement::Perfect | Judgement::Near => {
+                    *combo += 1;
+                    if self.combo.is_some()
+                        && self.combo_interval > 0
+                        && *combo % self.combo_interval == 0
+                    {
+                        &self.combo
+                    } else if event.judgement == Judgement::Perfect {
+                        &self.perfect
+                    } else {
+                        &self.near
+                    }
+                }
+            };
+            if let (Some(output), Some(sound)) = (&*output, sound) {
+                output.play(Channel::Judge, sound.clone());
+            }
+        }
+    }
+}
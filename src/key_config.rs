@@ -0,0 +1,317 @@
+use crate::error::KeyMapWarning;
+use crate::judge::{KeyMappings, ScancodeMap};
+use crate::keycap::KeycapLabels;
+use crate::kiosk;
+use crate::paths::Paths;
+use crate::profile::Profile;
+use crate::settings::Settings;
+use crate::ui::percent_transform;
+use crate::InterFont;
+use amethyst::{
+    core::Parent,
+    ecs::Entity,
+    input::is_key_down,
+    prelude::*,
+    ui::{Anchor, LineMode, UiImage, UiText},
+    window::ScreenDimensions,
+    winit::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
+};
+
+/// Font size of the key bindings in logical pixels.
+const KEY_CONFIG_FONT_SIZE: f32 = 24.;
+/// Size of a key in the preview of the judge quad, as a fraction of the preview.
+const MARKER_SIZE: f32 = 0.04;
+
+/// Edits the key bindings of the current profile. Arrow keys select a key and Enter rebinds it to
+/// the next key pressed; Tab switches players in versus. Changes apply to the play right away and
+/// are saved to the profile when Escape closes the screen.
+///
+/// Below the bindings, a preview of the judge quad shows where each key of the player presses, so
+/// that hand-written maps can be checked. Mistakes in the map are listed with the bindings.
+#[derive(Default)]
+pub struct KeyConfigState {
+    maps: Vec<ScancodeMap>,
+    player: usize,
+    row: usize,
+    index: usize,
+    /// Waiting for the key to bind the selection to.
+    binding: bool,
+    /// The last key pressed to bind, if it was refused because it is bound for another player,
+    /// and that player. The two players' maps can't overlap.
+    refused: Option<(u32, usize)>,
+    /// The maps don't match the number of players until restart, after changing the versus
+    /// setting.
+    needs_restart: bool,
+    text: Option<Entity>,
+    /// The preview of the judge quad and a marker for each key.
+    preview: Option<(Entity, Vec<Entity>)>,
+}
+
+impl KeyConfigState {
+    fn rows(&self) -> Vec<&[u32]> {
+        self.maps
+            .get(self.player)
+            .map_or_else(Vec::new, |map| map.rows().collect())
+    }
+
+    fn label(&self, keycaps: &KeycapLabels) -> String {
+        let mut text = format!("Key bindings, player {}\n\n", self.player + 1);
+        for (r, keys) in self.rows().into_iter().enumerate() {
+            let keys: Vec<_> = keys
+                .iter()
+                .enumerate()
+                .map(|(i, scancode)| {
+                    if (r, i) == (self.row, self.index) {
+                        format!("[{}]", keycaps.label(*scancode))
+                    } else {
+                        format!(" {} ", keycaps.label(*scancode))
+                    }
+                })
+                .collect();
+            text += &keys.join("");
+            text += "\n";
+        }
+        if let Some(map) = self.maps.get(self.player) {
+            for warning in map.validate() {
+                text += &format!("\nWarning: {}", warning);
+            }
+            for (other, other_map) in self.maps.iter().enumerate() {
+                if other == self.player {
+                    continue;
+                }
+                for scancode in map.scancodes() {
+                    if other_map.scancodes().any(|s| s == scancode) {
+                        text += &format!(
+                            "\nWarning: key {} is also bound for player {}",
+                            keycaps.label(scancode),
+                            other + 1
+                        );
+                    }
+                }
+            }
+        }
+        if let Some((scancode, other)) = self.refused {
+            text += &format!(
+                "\nKey {} is already bound for player {}",
+                keycaps.label(scancode),
+                other + 1
+            );
+        }
+        if self.needs_restart {
+            text += "\nRestart to apply these bindings to the play";
+        }
+        if self.binding {
+            text += "\nPress the key to bind, Escape to cancel";
+        } else {
+            text += "\nEnter: rebind  Tab: next player  Escape: save and close";
+        }
+        text
+    }
+
+    fn move_selection(&mut self, rows: i32, keys: i32) {
+        let row_count = self.rows().len();
+        if row_count == 0 {
+            return;
+        }
+        self.row = (self.row as i32 + rows).max(0).min(row_count as i32 - 1) as usize;
+        let key_count = self.rows()[self.row].len();
+        self.index = (self.index as i32 + keys)
+            .max(0)
+            .min(key_count as i32 - 1)
+            .max(0) as usize;
+    }
+
+    fn bind(&mut self, world: &mut World, scancode: u32) {
+        let player = self.player;
+        self.refused = self
+            .maps
+            .iter()
+            .enumerate()
+            .find(|&(other, map)| other != player && map.scancodes().any(|s| s == scancode))
+            .map(|(other, _)| (scancode, other));
+        if self.refused.is_some() {
+            return;
+        }
+        if let Some(map) = self.maps.get_mut(self.player) {
+            map.set_key(self.row, self.index, scancode);
+        }
+        if !self.needs_restart {
+            world.insert(KeyMappings::new(self.maps.clone()));
+        }
+    }
+
+    /// Recreates the markers of the preview for the current player.
+    fn show_positions(&mut self, world: &mut World) {
+        let panel = match &mut self.preview {
+            Some((panel, markers)) => {
+                for marker in markers.drain(..) {
+                    world.delete_entity(marker).unwrap();
+                }
+                *panel
+            }
+            None => return,
+        };
+        let map = match self.maps.get(self.player) {
+            Some(map) => map,
+            None => return,
+        };
+        let duplicates: Vec<_> = map
+            .validate()
+            .into_iter()
+            .filter_map(|warning| match warning {
+                KeyMapWarning::Duplicate(scancode) => Some(scancode),
+                _ => None,
+            })
+            .collect();
+        let keys = map
+            .rows()
+            .enumerate()
+            .flat_map(|(r, keys)| (0..keys.len()).map(move |i| (r, i)));
+        let mut markers = Vec::new();
+        for (key, (scancode, (x, y))) in keys.zip(map.positions()) {
+            let color = if key == (self.row, self.index) {
+                [1., 0.8, 0.2, 1.]
+            } else if duplicates.contains(&scancode) {
+                [1., 0.2, 0.2, 1.]
+            } else {
+                [1., 1., 1., 0.8]
+            };
+            let marker = world
+                .create_entity()
+                .with(percent_transform(
+                    "KeyConfigMarker",
+                    Anchor::BottomLeft,
+                    x - MARKER_SIZE / 2.,
+                    y - MARKER_SIZE / 2.,
+                    MARKER_SIZE,
+                    MARKER_SIZE,
+                ))
+                .with(UiImage::SolidColor(color))
+                .with(Parent::new(panel))
+                .build();
+            markers.push(marker);
+        }
+        if let Some((_, old)) = &mut self.preview {
+            *old = markers;
+        }
+    }
+
+    fn refresh(&mut self, world: &mut World) {
+        if let Some(text) = self.text {
+            if let Some(text) = world.write_storage::<UiText>().get_mut(text) {
+                text.text = self.label(&world.read_resource::<KeycapLabels>());
+            }
+        }
+        self.show_positions(world);
+    }
+}
+
+impl SimpleState for KeyConfigState {
+    fn on_start(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        let resources = world.read_resource::<Paths>().resources.clone();
+        self.maps = world
+            .read_resource::<Profile>()
+            .load_scancode_maps(&resources, &world.read_resource::<Settings>());
+        self.needs_restart = self.maps.len() != world.read_resource::<KeyMappings>().0.len();
+
+        let font = world.read_resource::<InterFont>().0.clone();
+        let font_size = {
+            let settings = world.read_resource::<Settings>();
+            let dimensions = world.read_resource::<ScreenDimensions>();
+            KEY_CONFIG_FONT_SIZE * settings.ui.scale_factor(&dimensions)
+        };
+        let transform = percent_transform("KeyConfig", Anchor::TopMiddle, 0., -0.05, 0.8, 0.55);
+        let mut ui_text = UiText::new(
+            font,
+            self.label(&world.read_resource::<KeycapLabels>()),
+            [1.; 4],
+            font_size,
+        );
+        ui_text.line_mode = LineMode::Wrap;
+        self.text = Some(world.create_entity().with(transform).with(ui_text).build());
+
+        let panel = world
+            .create_entity()
+            .with(percent_transform(
+                "KeyConfigPreview",
+                Anchor::BottomMiddle,
+                0.,
+                0.05,
+                0.5,
+                0.3,
+            ))
+            .with(UiImage::SolidColor([0., 0., 0., 0.7]))
+            .build();
+        self.preview = Some((panel, Vec::new()));
+        self.show_positions(world);
+    }
+
+    fn on_stop(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        if let Some(text) = self.text.take() {
+            world.delete_entity(text).unwrap();
+        }
+        if let Some((panel, markers)) = self.preview.take() {
+            world.delete_entities(&markers).unwrap();
+            world.delete_entity(panel).unwrap();
+        }
+        let profile = world.read_resource::<Profile>();
+        if let Err(e) = profile.save_scancode_maps(&world.read_resource::<Settings>(), &self.maps) {
+            log::warn!("Failed to save key bindings: {}", e);
+        }
+    }
+
+    fn handle_event(
+        &mut self,
+        StateData { world, .. }: StateData<'_, GameData<'_, '_>>,
+        event: StateEvent,
+    ) -> SimpleTrans {
+        let event = match event {
+            StateEvent::Window(event) => event,
+            _ => return Trans::None,
+        };
+        if kiosk::exit_requested(world, &event) {
+            return Trans::Quit;
+        }
+        // The console learns keys too, but only after the bound key is shown.
+        world.write_resource::<KeycapLabels>().learn(&event);
+        if self.binding {
+            if let Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                scancode,
+                                state: ElementState::Pressed,
+                                virtual_keycode,
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } = event
+            {
+                self.binding = false;
+                if virtual_keycode != Some(VirtualKeyCode::Escape) {
+                    self.bind(world, scancode);
+                }
+            }
+        } else if is_key_down(&event, VirtualKeyCode::Up) {
+            self.move_selection(-1, 0);
+        } else if is_key_down(&event, VirtualKeyCode::Down) {
+            self.move_selection(1, 0);
+        } else if is_key_down(&event, VirtualKeyCode::Left) {
+            self.move_selection(0, -1);
+        } else if is_key_down(&event, VirtualKeyCode::Right) {
+            self.move_selection(0, 1);
+        } else if is_key_down(&event, VirtualKeyCode::Tab) {
+            self.player = (self.player + 1) % self.maps.len().max(1);
+            self.move_selection(0, 0);
+        } else if is_key_down(&event, VirtualKeyCode::Return) {
+            self.binding = !self.rows().is_empty();
+        } else if is_key_down(&event, VirtualKeyCode::Escape) {
+            return Trans::Pop;
+        }
+        self.refresh(world);
+        Trans::None
+    }
+}
@@ -0,0 +1,118 @@
+use crate::chart::PlaySettings;
+use crate::judge::KeyMappings;
+use crate::replay::ReplayPlayback;
+use crate::settings::Settings;
+use amethyst::{
+    core::{math::Point2, SystemDesc},
+    ecs::{Read, System, SystemData, World, Write},
+    shrev::{EventChannel, ReaderId},
+    winit::{ElementState, Event, KeyboardInput, ScanCode, WindowEvent},
+};
+use std::collections::BTreeSet;
+
+/// The mapped keys held down, as the player and the key's position on their highway, in the
+/// coordinates of [`KeyMappings`]. Drawn as faint markers on the judge quad by
+/// [`RenderBillboard`](crate::billboard::RenderBillboard).
+#[derive(Default)]
+pub struct HeldKeys(pub Vec<(u8, Point2<f32>)>);
+
+/// Tracks the keys held while a chart plays, or those of the replay being watched, into
+/// [`HeldKeys`] with [`ui.key_markers`](crate::settings::UiSettings::key_markers). This shows how
+/// the physical keyboard maps onto the playfield.
+pub struct KeyMarkerSystem {
+    reader_id: ReaderId<Event>,
+    held: BTreeSet<ScanCode>,
+    /// Whether a replay was being watched, so that live and replayed keys don't mix.
+    watching: bool,
+}
+
+pub struct KeyMarkerSystemDesc;
+
+impl<'a, 'b> SystemDesc<'a, 'b, KeyMarkerSystem> for KeyMarkerSystemDesc {
+    fn build(self, world: &mut World) -> KeyMarkerSystem {
+        <KeyMarkerSystem as System<'_>>::SystemData::setup(world);
+
+        let reader_id = world
+            .get_mut::<EventChannel<Event>>()
+            .unwrap()
+            .register_reader();
+        KeyMarkerSystem {
+            reader_id,
+            held: BTreeSet::new(),
+            watching: false,
+        }
+    }
+}
+
+impl<'s> System<'s> for KeyMarkerSystem {
+    type SystemData = (
+        Read<'s, EventChannel<Event>>,
+        Read<'s, Option<PlaySettings>>,
+        Read<'s, Option<ReplayPlayback>>,
+        Read<'s, KeyMappings>,
+        Read<'s, Settings>,
+        Write<'s, HeldKeys>,
+    );
+
+    fn run(
+        &mut self,
+        (events, play_settings, playback, mappings, settings, mut held_keys): Self::SystemData,
+    ) {
+        if self.watching != playback.is_some() {
+            self.watching = playback.is_some();
+            self.held.clear();
+        }
+        for event in events.read(&mut self.reader_id) {
+            match event {
+                Event::WindowEvent {
+                    event:
+                        WindowEvent::KeyboardInput {
+                            input:
+                                KeyboardInput {
+                                    scancode, state, ..
+                                },
+                            ..
+                        },
+                    ..
+                } if !self.watching => match state {
+                    ElementState::Pressed => {
+                        self.held.insert(*scancode);
+                    }
+                    ElementState::Released => {
+                        self.held.remove(scancode);
+                    }
+                },
+                // Keys released while unfocused never report it.
+                Event::WindowEvent {
+                    event: WindowEvent::Focused(false),
+                    ..
+                } if !self.watching => self.held.clear(),
+                _ => {}
+            }
+        }
+        let players = play_settings.as_ref().map_or(1, |s| s.players.max(1));
+        if let Some(playback) = &*playback {
+            for player in 0..players {
+                for input in playback.due(player) {
+                    if input.pressed {
+                        self.held.insert(input.scancode);
+                    } else {
+                        self.held.remove(&input.scancode);
+                    }
+                }
+            }
+        }
+        held_keys.0.clear();
+        if play_settings.is_none() || !settings.ui.key_markers {
+            return;
+        }
+        for (player, mapping) in mappings.0.iter().enumerate().take(players as usize) {
+            held_keys.0.extend(
+                mapping
+                    .iter()
+                    .filter(|(scancode, _)| self.held.contains(scancode))
+                    .map(|&(_, (x, y))| (player as u8, Point2::new(x, y))),
+            );
+        }
+    }
+}
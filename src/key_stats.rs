@@ -0,0 +1,177 @@
+//! Judgements of a profile by key, to find weak fingers.
+//!
+//! Each judgement is attributed to the key nearest to its note, see [`JudgeEvent::key`], so
+//! misses count against the key that should have been pressed.
+//!
+//! [`JudgeEvent::key`]: crate::judge::JudgeEvent::key
+use crate::judge::{Judgement, ScancodeMap};
+use crate::keycap::KeycapLabels;
+use crate::migrate::{self, Versioned};
+use crate::score::NoteRecord;
+use crate::ui::percent_transform;
+use amethyst::{
+    core::Parent,
+    ecs::Entity,
+    prelude::*,
+    ui::{Anchor, FontHandle, LineMode, UiImage, UiText},
+    winit::ScanCode,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Color of keys without judgements.
+const NO_DATA_COLOR: [f32; 4] = [0.3, 0.3, 0.3, 0.8];
+/// Accuracy shown fully red; keys in between fade to green at full accuracy.
+const WORST_ACCURACY: f32 = 0.5;
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct KeyRecord {
+    pub perfect: u32,
+    pub near: u32,
+    pub miss: u32,
+}
+
+impl KeyRecord {
+    pub fn total(&self) -> u32 {
+        self.perfect + self.near + self.miss
+    }
+
+    /// Perfects count fully and nears half, as in the score.
+    pub fn accuracy(&self) -> Option<f32> {
+        match self.total() {
+            0 => None,
+            total => Some((self.perfect as f32 + self.near as f32 * 0.5) / total as f32),
+        }
+    }
+}
+
+/// Judgements of every play of a profile, by key.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyStats {
+    /// Format version, see [`Versioned`].
+    #[serde(default = "migrate::unversioned")]
+    version: u32,
+    pub keys: BTreeMap<ScanCode, KeyRecord>,
+}
+
+impl Default for KeyStats {
+    fn default() -> Self {
+        Self {
+            version: Self::current_version(),
+            keys: BTreeMap::new(),
+        }
+    }
+}
+
+impl Versioned for KeyStats {
+    const MIGRATIONS: &'static [fn(&mut Self)] = &[migrate::no_changes];
+
+    fn version_mut(&mut self) -> &mut u32 {
+        &mut self.version
+    }
+}
+
+impl KeyStats {
+    /// Counts the judgements of a play. Records without a key are skipped.
+    pub fn add(&mut self, records: &[NoteRecord]) {
+        for record in records {
+            let key = match record.key {
+                Some(key) => self.keys.entry(key).or_default(),
+                None => continue,
+            };
+            match record.judgement {
+                Judgement::Perfect => key.perfect += 1,
+                Judgement::Near => key.near += 1,
+                Judgement::Miss => key.miss += 1,
+            }
+        }
+    }
+}
+
+fn heat_color(accuracy: Option<f32>) -> [f32; 4] {
+    match accuracy {
+        Some(accuracy) => {
+            let t = ((accuracy - WORST_ACCURACY) / (1. - WORST_ACCURACY))
+                .max(0.)
+                .min(1.);
+            [1. - t, t, 0.2, 0.9]
+        }
+        None => NO_DATA_COLOR,
+    }
+}
+
+/// Draws `stats` as a keyboard laid out like `map` in the lower part of the screen, each key
+/// colored by its accuracy and labeled with its name and accuracy. Returns the created entities,
+/// to be deleted by the caller.
+pub fn show_heatmap(
+    world: &mut World,
+    stats: &KeyStats,
+    map: &ScancodeMap,
+    font: FontHandle,
+    font_size: f32,
+) -> Vec<Entity> {
+    let panel = world
+        .create_entity()
+        .with(percent_transform(
+            "KeyHeatmap",
+            Anchor::BottomMiddle,
+            0.,
+            0.05,
+            0.8,
+            0.35,
+        ))
+        .with(UiImage::SolidColor([0., 0., 0., 0.7]))
+        .build();
+    let mut entities = vec![panel];
+    let rows = map.rows().count().max(1) as f32;
+    let key_width = 1. / map.width().max(1.);
+    let labels: Vec<_> = {
+        let keycaps = world.read_resource::<KeycapLabels>();
+        map.layout()
+            .map(|(scancode, position)| {
+                let record = stats.keys.get(&scancode).cloned().unwrap_or_default();
+                let label = match record.accuracy() {
+                    Some(accuracy) => {
+                        format!("{}\n{:.0}%", keycaps.label(scancode), accuracy * 100.)
+                    }
+                    None => keycaps.label(scancode),
+                };
+                (position, record.accuracy(), label)
+            })
+            .collect()
+    };
+    for ((x, y), accuracy, label) in labels {
+        let cell = world
+            .create_entity()
+            .with(percent_transform(
+                "KeyHeatmapKey",
+                Anchor::TopLeft,
+                x + key_width * 0.05,
+                -y - 0.05 / rows,
+                key_width * 0.9,
+                0.9 / rows,
+            ))
+            .with(UiImage::SolidColor(heat_color(accuracy)))
+            .with(Parent::new(panel))
+            .build();
+        let mut text = UiText::new(font.clone(), label, [1.; 4], font_size);
+        text.line_mode = LineMode::Wrap;
+        let text = world
+            .create_entity()
+            .with(percent_transform(
+                "KeyHeatmapLabel",
+                Anchor::Middle,
+                0.,
+                0.,
+                1.,
+                1.,
+            ))
+            .with(text)
+            .with(Parent::new(cell))
+            .build();
+        entities.push(cell);
+        entities.push(text);
+    }
+    entities
+}
@@ -0,0 +1,264 @@
+//! Names of keys for display, since scancodes mean nothing to players.
+//!
+//! Scancodes name the physical position of a key, so the printed label depends on the keyboard
+//! layout. [`label`] assumes US QWERTY with the scancodes of the current OS. [`KeycapLabels`]
+//! learns the labels of the player's layout from the keys pressed, and falls back to [`label`].
+use amethyst::winit::{ElementState, Event, KeyboardInput, ScanCode, VirtualKeyCode, WindowEvent};
+use std::collections::HashMap;
+
+/// The label of the key at `scancode` on a US QWERTY keyboard, or the scancode in hex for keys
+/// without a known label.
+pub fn label(scancode: ScanCode) -> String {
+    match name(scancode) {
+        Some(name) => name.into(),
+        None => format!("0x{:02X}", scancode),
+    }
+}
+
+/// PC scancode set 1, which Windows reports and Linux evdev codes follow.
+#[cfg(not(target_os = "macos"))]
+fn name(scancode: ScanCode) -> Option<&'static str> {
+    const LABELS: [&str; 89] = [
+        "",
+        "Escape",
+        "1",
+        "2",
+        "3",
+        "4",
+        "5",
+        "6",
+        "7",
+        "8",
+        "9",
+        "0",
+        "-",
+        "=",
+        "Backspace",
+        "Tab",
+        "Q",
+        "W",
+        "E",
+        "R",
+        "T",
+        "Y",
+        "U",
+        "I",
+        "O",
+        "P",
+        "[",
+        "]",
+        "Enter",
+        "Left Ctrl",
+        "A",
+        "S",
+        "D",
+        "F",
+        "G",
+        "H",
+        "J",
+        "K",
+        "L",
+        ";",
+        "'",
+        "`",
+        "Left Shift",
+        "\\",
+        "Z",
+        "X",
+        "C",
+        "V",
+        "B",
+        "N",
+        "M",
+        ",",
+        ".",
+        "/",
+        "Right Shift",
+        "Num *",
+        "Left Alt",
+        "Space",
+        "Caps Lock",
+        "F1",
+        "F2",
+        "F3",
+        "F4",
+        "F5",
+        "F6",
+        "F7",
+        "F8",
+        "F9",
+        "F10",
+        "Num Lock",
+        "Scroll Lock",
+        "Num 7",
+        "Num 8",
+        "Num 9",
+        "Num -",
+        "Num 4",
+        "Num 5",
+        "Num 6",
+        "Num +",
+        "Num 1",
+        "Num 2",
+        "Num 3",
+        "Num 0",
+        "Num .",
+        "",
+        "",
+        "",
+        "F11",
+        "F12",
+    ];
+    match LABELS.get(scancode as usize) {
+        Some(&label) if !label.is_empty() => Some(label),
+        _ => extended_name(scancode),
+    }
+}
+
+/// Keys that set 1 sends with an `E0` prefix, which evdev numbers after the others. Windows
+/// reports them with the scancode of their numpad twin.
+#[cfg(target_os = "linux")]
+fn extended_name(scancode: ScanCode) -> Option<&'static str> {
+    Some(match scancode {
+        96 => "Num Enter",
+        97 => "Right Ctrl",
+        98 => "Num /",
+        100 => "Right Alt",
+        102 => "Home",
+        103 => "Up",
+        104 => "Page Up",
+        105 => "Left",
+        106 => "Right",
+        107 => "End",
+        108 => "Down",
+        109 => "Page Down",
+        110 => "Insert",
+        111 => "Delete",
+        _ => return None,
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn extended_name(_scancode: ScanCode) -> Option<&'static str> {
+    None
+}
+
+/// The virtual key codes of macOS, which number the keys in no particular order.
+#[cfg(target_os = "macos")]
+fn name(scancode: ScanCode) -> Option<&'static str> {
+    const LABELS: [&str; 59] = [
+        "A",
+        "S",
+        "D",
+        "F",
+        "H",
+        "G",
+        "Z",
+        "X",
+        "C",
+        "V",
+        "",
+        "B",
+        "Q",
+        "W",
+        "E",
+        "R",
+        "Y",
+        "T",
+        "1",
+        "2",
+        "3",
+        "4",
+        "6",
+        "5",
+        "=",
+        "9",
+        "7",
+        "-",
+        "8",
+        "0",
+        "]",
+        "O",
+        "U",
+        "[",
+        "I",
+        "P",
+        "Return",
+        "L",
+        "J",
+        "'",
+        "K",
+        ";",
+        "\\",
+        ",",
+        "/",
+        "N",
+        "M",
+        ".",
+        "Tab",
+        "Space",
+        "`",
+        "Delete",
+        "",
+        "Escape",
+        "",
+        "Command",
+        "Shift",
+        "Caps Lock",
+        "Option",
+    ];
+    match scancode {
+        0x3B => Some("Control"),
+        0x7B => Some("Left"),
+        0x7C => Some("Right"),
+        0x7D => Some("Down"),
+        0x7E => Some("Up"),
+        _ => LABELS
+            .get(scancode as usize)
+            .cloned()
+            .filter(|label| !label.is_empty()),
+    }
+}
+
+/// The label of a key as the layout maps it, e.g. `A` rather than `Key1` for digits.
+fn virtual_key_label(key: VirtualKeyCode) -> String {
+    let name = format!("{:?}", key);
+    match name.get(3..) {
+        Some(digit) if name.starts_with("Key") => digit.into(),
+        _ => name,
+    }
+}
+
+/// Labels of the keys pressed so far in the player's layout, by scancode.
+#[derive(Default)]
+pub struct KeycapLabels(HashMap<ScanCode, VirtualKeyCode>);
+
+impl KeycapLabels {
+    /// Remembers the key of a press in `event`.
+    pub fn learn(&mut self, event: &Event) {
+        if let Event::WindowEvent {
+            event:
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            scancode,
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(key),
+                            ..
+                        },
+                    ..
+                },
+            ..
+        } = event
+        {
+            self.0.insert(*scancode, *key);
+        }
+    }
+
+    pub fn label(&self, scancode: ScanCode) -> String {
+        match self.0.get(&scancode) {
+            Some(&key) => virtual_key_label(key),
+            None => label(scancode),
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use crate::settings::Settings;
+use amethyst::{
+    core::{timing::Time, SystemDesc},
+    ecs::{Read, ReadExpect, System, SystemData, World, Write},
+    input::is_close_requested,
+    shrev::{EventChannel, ReaderId},
+    winit::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
+};
+
+/// State of kiosk mode, see [`KioskSettings`](crate::settings::KioskSettings).
+#[derive(Default)]
+pub struct Kiosk {
+    /// Whether an operator unlocked exiting and the settings.
+    pub unlocked: bool,
+    /// Real time of the last key press or mouse input.
+    pub last_input: f64,
+    /// Real time at which the running demo ends.
+    pub demo_until: Option<f64>,
+}
+
+impl Kiosk {
+    /// Whether exiting and the settings are locked.
+    pub fn locked(&self, settings: &Settings) -> bool {
+        settings.kiosk.enabled && !self.unlocked
+    }
+
+    /// Whether nobody has touched the game for long enough to start a demo.
+    pub fn idle(&self, settings: &Settings, now: f64) -> bool {
+        settings.kiosk.enabled && now - self.last_input > settings.kiosk.idle_seconds
+    }
+}
+
+/// Whether `event` asks to close the window and that is allowed.
+pub fn exit_requested(world: &World, event: &Event) -> bool {
+    is_close_requested(event)
+        && !world
+            .read_resource::<Kiosk>()
+            .locked(&world.read_resource::<Settings>())
+}
+
+/// Tracks input for the idle timer and toggles the lock on Ctrl+Shift+K.
+pub struct KioskSystem {
+    reader_id: ReaderId<Event>,
+}
+
+pub struct KioskSystemDesc;
+
+impl<'a, 'b> SystemDesc<'a, 'b, KioskSystem> for KioskSystemDesc {
+    fn build(self, world: &mut World) -> KioskSystem {
+        <KioskSystem as System<'_>>::SystemData::setup(world);
+
+        let reader_id = world
+            .get_mut::<EventChannel<Event>>()
+            .unwrap()
+            .register_reader();
+
+        KioskSystem { reader_id }
+    }
+}
+
+impl<'s> System<'s> for KioskSystem {
+    type SystemData = (
+        Read<'s, EventChannel<Event>>,
+        ReadExpect<'s, Time>,
+        Read<'s, Settings>,
+        Write<'s, Kiosk>,
+    );
+
+    fn run(&mut self, (events, time, settings, mut kiosk): Self::SystemData) {
+        let now = time.absolute_real_time_seconds();
+        for event in events.read(&mut self.reader_id) {
+            let event = match event {
+                Event::WindowEvent { event, .. } => event,
+                _ => continue,
+            };
+            match event {
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode,
+                            modifiers,
+                            ..
+                        },
+                    ..
+                } => {
+                    kiosk.last_input = now;
+                    if settings.kiosk.enabled
+                        && *virtual_keycode == Some(VirtualKeyCode::K)
+                        && modifiers.ctrl
+                        && modifiers.shift
+                    {
+                        kiosk.unlocked = !kiosk.unlocked;
+                        log::info!(
+                            "Kiosk mode {}",
+                            if kiosk.unlocked { "unlocked" } else { "locked" }
+                        );
+                    }
+                }
+                WindowEvent::MouseInput { .. } | WindowEvent::MouseWheel { .. } => {
+                    kiosk.last_input = now;
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -1,18 +1,25 @@
-use crate::chart::ChartState;
+use crate::chart::{ChartState, LaserId, NoteKind, PlaySettings};
+use crate::error::RenderError;
+use crate::modifiers::{ActiveModifiers, Modifier};
+use crate::settings::Settings;
+use crate::stencil::{StencilAllocator, StencilLayer};
+use crate::theme::Theme;
+use amethyst::assets::{AssetStorage, Handle, Loader};
 use amethyst::core::{
     ecs::{
-        Component, DenseVecStorage, Entities, Join, Read, ReadExpect, ReadStorage, SystemData,
-        World,
+        Component, DenseVecStorage, Entities, Entity, Join, Read, ReadExpect, ReadStorage, System,
+        SystemData, World, WriteStorage,
     },
     math::{Matrix4, Point3, Vector3},
+    timing::Time,
     transform::{ParentHierarchy, Transform},
 };
 use amethyst::renderer::{
     bundle::{RenderOrder, RenderPlan, RenderPlugin, Target},
-    palette::rgb::LinSrgb,
+    loaders::load_from_srgba,
+    palette::{rgb::LinSrgb, Srgba},
     pass::validate_spirv,
     pipeline::{PipelineDescBuilder, PipelinesBuilder},
-    pod::VertexArgs,
     rendy::{
         command::{QueueId, RenderPassEncoder},
         factory::Factory,
@@ -20,21 +27,144 @@ use amethyst::renderer::{
             render::{PrepareResult, RenderGroup, RenderGroupDesc},
             GraphContext, NodeBuffer, NodeImage,
         },
-        hal::{device::Device, pass::Subpass, pso},
-        mesh::{AsVertex, Mesh, MeshBuilder, PosTex},
+        hal::{self, device::Device, pass::Subpass, pso},
+        mesh::{AsVertex, Color, Mesh, MeshBuilder, Model, PosTex, VertexFormat},
         shader::{ShaderSetBuilder, SpirvShader},
     },
-    submodules::{DynamicUniform, DynamicVertexBuffer, EnvironmentSub},
-    types::Backend,
+    submodules::{DynamicUniform, DynamicVertexBuffer, EnvironmentSub, TextureId, TextureSub},
+    types::{Backend, Texture},
 };
-use failure::Error;
 use glsl_layout::*;
+use serde::{Deserialize, Serialize};
 use std::iter;
 use std::marker::PhantomData;
 
 pub struct Laser {
+    pub id: LaserId,
     pub color: LinSrgb<f32>,
+    /// Color at the far end of the highway.
+    pub far_color: LinSrgb<f32>,
+    pub texture: Option<Handle<Texture>>,
+    /// Texture scroll speed in texture units per second.
+    pub scroll: f32,
     pub lanes: u16,
+    pub player: u8,
+    pub note_style: NoteStyle,
+    pub blend: LaserBlend,
+    /// Value in the lasers' stencil layer the laser marks its area with, which masks the notes of
+    /// lasers drawn after it.
+    pub stencil: u8,
+    /// The color fade in progress, if any.
+    pub animation: Option<ColorAnimation>,
+}
+
+/// A fade of the near and far color of a [`Laser`], started by
+/// [`LaserCommand::ColorTo`](crate::chart::LaserCommand::ColorTo).
+#[derive(Clone, Debug)]
+pub struct ColorAnimation {
+    pub from: (LinSrgb<f32>, LinSrgb<f32>),
+    pub to: (LinSrgb<f32>, LinSrgb<f32>),
+    /// Chart time of the start in seconds.
+    pub start: f32,
+    pub duration: f32,
+}
+
+impl ColorAnimation {
+    /// The near and far color at chart time `time`.
+    pub fn at(&self, time: f32) -> (LinSrgb<f32>, LinSrgb<f32>) {
+        let t = if self.duration > 0. {
+            ((time - self.start) / self.duration).max(0.).min(1.)
+        } else {
+            1.
+        };
+        let mix = |from: LinSrgb<f32>, to: LinSrgb<f32>| from + (to - from) * t;
+        (mix(self.from.0, self.to.0), mix(self.from.1, self.to.1))
+    }
+
+    fn finished(&self, time: f32) -> bool {
+        time >= self.start + self.duration
+    }
+}
+
+/// Advances the [`ColorAnimation`] of each laser by chart time, so that fades follow seeking and
+/// the time scale, and [`DrawLaser`] tints the laser with the result.
+#[derive(Default)]
+pub struct LaserAnimationSystem;
+
+impl<'s> System<'s> for LaserAnimationSystem {
+    type SystemData = (
+        ReadExpect<'s, Time>,
+        Read<'s, Option<PlaySettings>>,
+        WriteStorage<'s, Laser>,
+    );
+
+    fn run(&mut self, (time, settings, mut lasers): Self::SystemData) {
+        let now = match &*settings {
+            Some(settings) => (time.absolute_time_seconds() - settings.base_time) as f32,
+            None => return,
+        };
+        for laser in (&mut lasers).join() {
+            if let Some(animation) = &laser.animation {
+                let (color, far_color) = animation.at(now);
+                laser.color = color;
+                laser.far_color = far_color;
+                if animation.finished(now) {
+                    laser.animation = None;
+                }
+            }
+        }
+    }
+}
+
+/// Stencil value of lasers whose chart doesn't give one.
+pub const LASER_STENCIL: u8 = 1;
+/// Bits of the lasers' stencil layer, enough for the values charts may give lasers.
+const LASER_STENCIL_BITS: u32 = 4;
+/// The largest stencil value a chart may give a laser.
+pub const MAX_LASER_STENCIL: u8 = (1 << LASER_STENCIL_BITS) - 1;
+
+/// How a laser and its notes are blended with what is behind them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LaserBlend {
+    /// Adds the color, so overlapping lasers brighten each other.
+    Add,
+    /// Covers what is behind by the alpha of the texture.
+    Alpha,
+}
+
+impl Default for LaserBlend {
+    fn default() -> Self {
+        LaserBlend::Add
+    }
+}
+
+impl LaserBlend {
+    /// All modes, in the order of their pipelines.
+    const ALL: [LaserBlend; 2] = [LaserBlend::Add, LaserBlend::Alpha];
+
+    fn state(self) -> pso::BlendState {
+        match self {
+            LaserBlend::Add => pso::BlendState::ADD,
+            LaserBlend::Alpha => pso::BlendState::ALPHA,
+        }
+    }
+}
+
+/// Appearance of the notes on a laser.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NoteStyle {
+    pub color: LinSrgb<f32>,
+    /// Multiplier on the base note length along the highway.
+    pub height: f32,
+}
+
+impl Default for NoteStyle {
+    fn default() -> Self {
+        Self {
+            color: LinSrgb::new(0., 0., 0.),
+            height: 1.,
+        }
+    }
 }
 
 impl Component for Laser {
@@ -45,6 +175,29 @@ impl Component for Laser {
 pub struct LaserOptions {
     pub basis: Point3<f32>,
     pub judge_quad: Vec<Point3<f32>>,
+    /// World position of the camera, which lasers are sorted by distance from.
+    pub eye: Point3<f32>,
+    /// Size of the highway relative to the screen, see [`Chart::zoom`](crate::chart::Chart::zoom).
+    pub zoom: f32,
+}
+
+impl LaserOptions {
+    /// The slice of the judge quad and the basis for one of `players` side-by-side highways.
+    pub fn player_quad(&self, player: u8, players: u8) -> (Vec<Point3<f32>>, Point3<f32>) {
+        let left = player as f32 / players as f32;
+        let right = (player + 1) as f32 / players as f32;
+        let lerp = |a: Point3<f32>, b: Point3<f32>, t: f32| a + (b - a) * t;
+        let quad = &self.judge_quad;
+        let judge_quad = vec![
+            lerp(quad[0], quad[1], left),
+            lerp(quad[0], quad[1], right),
+            lerp(quad[3], quad[2], right),
+            lerp(quad[3], quad[2], left),
+        ];
+        let mut basis = self.basis;
+        basis.x = lerp(quad[3], quad[2], (left + right) / 2.).x;
+        (judge_quad, basis)
+    }
 }
 
 impl Default for LaserOptions {
@@ -52,6 +205,8 @@ impl Default for LaserOptions {
         Self {
             basis: Point3::new(0., 0., 0.),
             judge_quad: Vec::new(),
+            eye: Point3::new(0., 0., 0.),
+            zoom: 1.,
         }
     }
 }
@@ -61,16 +216,78 @@ struct LaserArgs {
     basis: vec3,
     pre_transform: mat4,
     post_transform: mat4,
+    /// Chart time in seconds, used to animate textures.
+    time: float,
+    /// Highway positions mapped to the near and far end, applied to instances in the vertex
+    /// shader so that scrolling only changes this uniform.
+    scroll: vec2,
+    /// Nonzero to smooth the edges of quads in the fragment shader.
+    edge_aa: float,
+}
+
+/// Per-instance data shared by lasers and notes.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, AsStd140)]
+#[repr(C, align(4))]
+struct LaserInstance {
+    model: mat4,
+    tint: vec4,
+    far_tint: vec4,
+    /// Texture scroll speed in x, the rest is reserved.
+    surface: vec4,
+}
+
+impl AsVertex for LaserInstance {
+    fn vertex() -> VertexFormat {
+        VertexFormat::new((
+            Model::vertex(),
+            Color::vertex(),
+            Color::vertex(),
+            Color::vertex(),
+        ))
+    }
+}
+
+impl LaserInstance {
+    fn new(model: Matrix4<f32>, tint: [f32; 4], far_tint: [f32; 4], scroll: f32) -> Self {
+        let model: [[f32; 4]; 4] = model.into();
+        Self {
+            model: model.into(),
+            tint: tint.into(),
+            far_tint: far_tint.into(),
+            surface: [scroll, 0., 0., 0.].into(),
+        }
+    }
 }
 
 pub struct Note {
     pub time: f32,
+    pub player: u8,
+    /// Number of lanes spanned by the chord this note is the leftmost of, if it is.
+    pub chord: Option<u32>,
+    pub kind: NoteKind,
+    /// Highway position where the body of the note ends, its own position if it has none.
+    pub end_position: f32,
+    /// Times of the ticks of a roll, see [`roll_ticks`](crate::judge::roll_ticks).
+    pub ticks: Vec<f32>,
+    /// Highway positions of the ticks.
+    pub tick_positions: Vec<f32>,
 }
 
 impl Component for Note {
     type Storage = DenseVecStorage<Self>;
 }
 
+/// Pulses per second of roll bodies and mines.
+const PULSE_RATE: f32 = 4.;
+/// With [`Modifier::Hidden`], notes start fading at this fraction of the visible length from the
+/// judge line, and are gone at [`HIDDEN_END`].
+const HIDDEN_START: f32 = 0.5;
+const HIDDEN_END: f32 = 0.3;
+/// Length of notes along the highway, before their style's height.
+const NOTE_LENGTH: f32 = 0.03;
+/// Length of the tick marks of rolls relative to notes.
+const TICK_MARK_LENGTH: f32 = 0.3;
+
 lazy_static::lazy_static! {
     static ref LASER_VERTEX: SpirvShader = SpirvShader::new(
         validate_spirv(include_bytes!("../compiled/vertex/laser.vert.spv")),
@@ -108,41 +325,59 @@ impl<B: Backend> RenderGroupDesc<B, World> for DrawLaserDesc<B> {
         _: &GraphContext<B>,
         factory: &mut Factory<B>,
         queue: QueueId,
-        _: &World,
+        world: &World,
         framebuffer_width: u32,
         framebuffer_height: u32,
         subpass: Subpass<B>,
         _: Vec<NodeBuffer>,
         _: Vec<NodeImage>,
-    ) -> Result<Box<dyn RenderGroup<B, World>>, Error> {
+    ) -> Result<Box<dyn RenderGroup<B, World>>, failure::Error> {
         let env = EnvironmentSub::new(
             factory,
             [
                 pso::ShaderStageFlags::VERTEX,
                 pso::ShaderStageFlags::FRAGMENT,
             ],
-        )?;
+        )
+        .map_err(|e| RenderError::Resources(e.to_string()))?;
         let laser_args = DynamicUniform::new(
             factory,
             pso::ShaderStageFlags::VERTEX | pso::ShaderStageFlags::FRAGMENT,
-        )?;
+        )
+        .map_err(|e| RenderError::Resources(e.to_string()))?;
         let note_args = DynamicUniform::new(
             factory,
             pso::ShaderStageFlags::VERTEX | pso::ShaderStageFlags::FRAGMENT,
-        )?;
+        )
+        .map_err(|e| RenderError::Resources(e.to_string()))?;
+        let textures =
+            TextureSub::new(factory).map_err(|e| RenderError::Resources(e.to_string()))?;
         let pipeline_layout = unsafe {
             factory.device().create_pipeline_layout(
-                [env.raw_layout(), laser_args.raw_layout()].iter().cloned(),
+                [
+                    env.raw_layout(),
+                    laser_args.raw_layout(),
+                    textures.raw_layout(),
+                ]
+                .iter()
+                .cloned(),
                 None as Option<(_, _)>,
             )
-        }?;
+        }
+        .map_err(|e| RenderError::Pipeline(e.to_string()))?;
 
         let vertex_desc = vec![
             (PosTex::vertex(), pso::VertexInputRate::Vertex),
-            (VertexArgs::vertex(), pso::VertexInputRate::Instance(1)),
+            (LaserInstance::vertex(), pso::VertexInputRate::Instance(1)),
         ];
 
-        let mut shaders = LASER_SHADERS.build(factory, Default::default())?;
+        let stencil = world
+            .fetch_mut::<StencilAllocator>()
+            .claim("lasers", LASER_STENCIL_BITS)?;
+
+        let mut shaders = LASER_SHADERS
+            .build(factory, Default::default())
+            .map_err(|e| RenderError::Shaders(e.to_string()))?;
 
         let stencil_face = pso::StencilFace {
             fun: pso::Comparison::Equal,
@@ -151,33 +386,45 @@ impl<B: Backend> RenderGroupDesc<B, World> for DrawLaserDesc<B> {
             op_pass: pso::StencilOp::Keep,
         };
 
-        let pipe_desc = PipelineDescBuilder::new()
-            .with_vertex_desc(&vertex_desc)
-            .with_shaders(shaders.raw()?)
-            .with_layout(&pipeline_layout)
-            .with_subpass(subpass)
-            .with_framebuffer_size(framebuffer_width, framebuffer_height)
-            .with_depth_stencil(pso::DepthStencilDesc {
-                depth: Some(pso::DepthTest {
-                    fun: pso::Comparison::Less,
-                    write: false,
-                }),
-                depth_bounds: false,
-                stencil: Some(pso::StencilTest {
-                    faces: pso::Sided::new(stencil_face),
-                    read_masks: pso::StencilValues::Static(pso::Sided::new(1)),
-                    write_masks: pso::StencilValues::Static(pso::Sided::new(1)),
-                    reference_values: pso::State::Dynamic,
-                }),
-            })
-            .with_blend_targets(vec![pso::ColorBlendDesc {
-                mask: pso::ColorMask::ALL,
-                blend: Some(pso::BlendState::ADD),
-            }]);
-
-        let mut pipelines = PipelinesBuilder::new()
-            .with_pipeline(pipe_desc)
-            .build(factory, None)?;
+        // One pipeline per blend mode, in the order of `LaserBlend::ALL`.
+        let mut builder = PipelinesBuilder::new();
+        for blend in LaserBlend::ALL.iter() {
+            builder = builder.with_pipeline(
+                PipelineDescBuilder::new()
+                    .with_vertex_desc(&vertex_desc)
+                    .with_shaders(
+                        shaders
+                            .raw()
+                            .map_err(|e| RenderError::Shaders(e.to_string()))?,
+                    )
+                    .with_layout(&pipeline_layout)
+                    .with_subpass(subpass)
+                    .with_framebuffer_size(framebuffer_width, framebuffer_height)
+                    .with_depth_stencil(pso::DepthStencilDesc {
+                        depth: Some(pso::DepthTest {
+                            fun: pso::Comparison::Less,
+                            write: false,
+                        }),
+                        depth_bounds: false,
+                        stencil: Some(pso::StencilTest {
+                            faces: pso::Sided::new(stencil_face),
+                            read_masks: pso::StencilValues::Static(pso::Sided::new(stencil.mask())),
+                            write_masks: pso::StencilValues::Static(pso::Sided::new(
+                                stencil.mask(),
+                            )),
+                            reference_values: pso::State::Dynamic,
+                        }),
+                    })
+                    .with_blend_targets(vec![pso::ColorBlendDesc {
+                        mask: pso::ColorMask::ALL,
+                        blend: Some(blend.state()),
+                    }]),
+            );
+        }
+
+        let pipelines = builder
+            .build(factory, None)
+            .map_err(|e| RenderError::Pipeline(e.to_string()))?;
 
         shaders.dispose(factory);
 
@@ -186,8 +433,8 @@ impl<B: Backend> RenderGroupDesc<B, World> for DrawLaserDesc<B> {
                 [
                     ([0., 0., 0.], [0., 0.]),
                     ([1., 0., 0.], [1., 0.]),
-                    ([1., 0., 1.], [1., 0.]),
-                    ([0., 0., 1.], [0., 0.]),
+                    ([1., 0., 1.], [1., 1.]),
+                    ([0., 0., 1.], [0., 1.]),
                 ]
                 .iter()
                 .cloned()
@@ -198,47 +445,358 @@ impl<B: Backend> RenderGroupDesc<B, World> for DrawLaserDesc<B> {
                 .collect::<Vec<_>>(),
             )
             .with_indices(&[0u32, 1, 2, 0, 2, 3][..])
-            .build(queue, factory)?;
+            .build(queue, factory)
+            .map_err(|e| RenderError::Mesh(e.to_string()))?;
+
+        let white_texture = world.read_resource::<Loader>().load_from_data(
+            load_from_srgba(Srgba::new(1., 1., 1., 1.)).into(),
+            (),
+            &world.read_resource::<AssetStorage<Texture>>(),
+        );
 
         Ok(Box::new(DrawLaser::<B> {
-            pipeline: pipelines.pop().unwrap(),
+            pipelines,
             pipeline_layout,
+            stencil,
             env,
-            laser_args,
-            note_args,
+            player_args: vec![(laser_args, note_args)],
+            textures,
+            white_texture,
+            white_texture_id: None,
             lasers: DynamicVertexBuffer::new(),
             notes: DynamicVertexBuffer::new(),
             instances: Vec::new(),
+            laser_textures: Vec::new(),
+            laser_players: Vec::new(),
+            laser_draws: Vec::new(),
             square_mesh: laser_mesh,
+            degenerate: false,
+            frames: Vec::new(),
         }))
     }
 }
 
-fn basis_to_points(arr: &Vec<Vector3<f32>>) -> Matrix4<f32> {
+/// Determinants and weights below this are treated as degenerate. The inputs are in view-space
+/// units, so this is far below anything that can be drawn meaningfully.
+const DEGENERATE_EPSILON: f32 = 1e-6;
+
+/// The projective transform mapping the origin, the unit axes and (1, 1, 1) to the five points in
+/// `arr`. Returns `None` if four of the points are coplanar, in which case no such transform
+/// exists.
+fn basis_to_points(arr: &[Vector3<f32>]) -> Option<Matrix4<f32>> {
     let m = Matrix4::from_iterator(
         (0..3)
             .flat_map(|i| (0..4).map(move |j| arr[j][i]))
             .chain(iter::repeat(1.).take(4)),
     )
     .transpose();
-    let v = m.try_inverse().unwrap() * arr[4].push(1.);
-    m * (Matrix4::from_iterator(
-        (0..4).flat_map(|i| (0..4).map(move |j| if i == j { v[i] } else { 0. })),
+    if m.determinant().abs() < DEGENERATE_EPSILON {
+        return None;
+    }
+    let v = m.try_inverse()? * arr[4].push(1.);
+    if v.iter().any(|x| x.abs() < DEGENERATE_EPSILON) {
+        return None;
+    }
+    Some(
+        m * (Matrix4::from_iterator(
+            (0..4).flat_map(|i| (0..4).map(move |j| if i == j { v[i] } else { 0. })),
+        )
+        .transpose()),
     )
-    .transpose())
+}
+
+/// The transform mapping the points in `source` to those in `target`, see [`basis_to_points`].
+fn points_to_points(source: &[Vector3<f32>], target: &[Vector3<f32>]) -> Option<Matrix4<f32>> {
+    Some(basis_to_points(target)? * basis_to_points(source)?.try_inverse()?)
+}
+
+/// The instances of a frame, built on the CPU by [`build_instances`].
+#[derive(Debug, Default)]
+pub struct FrameInstances {
+    lasers: Vec<LaserInstance>,
+    notes: Vec<LaserInstance>,
+    /// Start of the notes of each laser instance in `notes`, followed by their end.
+    instances: Vec<u32>,
+    /// Entity of each laser instance, to look up its texture.
+    laser_entities: Vec<Entity>,
+    laser_players: Vec<u8>,
+    laser_draws: Vec<(usize, LaserBlend, u8)>,
+}
+
+/// Builds the instances of the lasers and their visible notes, with `post_transforms` mapping
+/// the highway of each player to the screen. This is the CPU side of [`DrawLaser`]'s `prepare`,
+/// split out so that it can be benchmarked without a GPU.
+pub fn build_instances(world: &World, post_transforms: &[Matrix4<f32>]) -> FrameInstances {
+    let (
+        entities,
+        time,
+        theme,
+        settings,
+        user_settings,
+        options,
+        state,
+        modifiers,
+        lasers,
+        notes,
+        transforms,
+        hierarchy,
+    ) = <(
+        Entities,
+        ReadExpect<Time>,
+        ReadExpect<Theme>,
+        Read<Option<PlaySettings>>,
+        Read<Settings>,
+        ReadExpect<LaserOptions>,
+        Read<ChartState>,
+        Read<ActiveModifiers>,
+        ReadStorage<Laser>,
+        ReadStorage<Note>,
+        ReadStorage<Transform>,
+        ReadExpect<ParentHierarchy>,
+    )>::fetch(world);
+    let start_z = state.draw_window.start;
+    // Nothing is visible in an empty window, but the shader still divides by its length.
+    let end_z = state.draw_window.end.max(start_z + DEGENERATE_EPSILON);
+    let chart_time = settings
+        .as_ref()
+        .map_or(0., |s| (time.absolute_time_seconds() - s.base_time) as f32);
+    let mut frame = FrameInstances {
+        instances: vec![0],
+        ..Default::default()
+    };
+    let mut laser_depths = Vec::new();
+    let [glow_r, glow_g, glow_b] = theme.chord_glow;
+    let chord_glow = [glow_r, glow_g, glow_b, 1.];
+    let glow_enabled = theme.chord_glow != [0.; 3];
+    let pulse = if user_settings.accessibility.suppress_flashes {
+        1.
+    } else {
+        0.6 + 0.4 * (chart_time * PULSE_RATE * 2. * std::f32::consts::PI).cos()
+    };
+    let pulsing = |[r, g, b]: [f32; 3]| [r * pulse, g * pulse, b * pulse, 1.];
+    let roll_tint = pulsing(theme.roll_color);
+    let [tick_r, tick_g, tick_b] = theme.roll_tick_color;
+    let tick_tint = [tick_r, tick_g, tick_b, 1.];
+    let ticks_shown = theme.roll_tick_color != [0.; 3];
+    let mine_tint = pulsing(theme.mine_color);
+    let hidden = modifiers.contains(Modifier::Hidden);
+    for (e, l, t) in (&entities, &lasers, &transforms).join() {
+        // Lasers of a second player outlive a switch back to one player until the chart clears
+        // them, and have no highway to be drawn on.
+        let post_transform = match post_transforms.get(l.player as usize) {
+            Some(transform) => transform,
+            None => continue,
+        };
+        let (r, g, b) = l.color.into_components();
+        let (far_r, far_g, far_b) = l.far_color.into_components();
+        frame.lasers.push(LaserInstance::new(
+            *t.global_matrix(),
+            [r, g, b, 1.],
+            [far_r, far_g, far_b, 1.],
+            l.scroll,
+        ));
+        frame.laser_entities.push(e);
+        frame.laser_players.push(l.player);
+        // Overlapping lasers only blend right when drawn back to front.
+        let center = post_transform.transform_point(
+            &t.global_matrix()
+                .transform_point(&Point3::new(0.5, 0., 0.5)),
+        );
+        laser_depths.push((
+            (center - options.eye).norm(),
+            frame.laser_players.len() - 1,
+            l.blend,
+            l.stencil,
+        ));
+        let (note_r, note_g, note_b) = l.note_style.color.into_components();
+        let note_tint = [note_r, note_g, note_b, 1.];
+        let note_height =
+            Matrix4::new_nonuniform_scaling(&Vector3::new(1., 1., l.note_style.height));
+        // Lasers on their own BPM track have their own window, which is mapped onto the one
+        // the shader scrolls by.
+        let window = state.window_of(l.id);
+        let (window_start, window_end) = (
+            window.start,
+            window.end.max(window.start + DEGENERATE_EPSILON),
+        );
+        let remap = Matrix4::new_translation(&Vector3::new(0., 0., start_z))
+            * Matrix4::new_nonuniform_scaling(&Vector3::new(
+                1.,
+                1.,
+                (end_z - start_z) / (window_end - window_start),
+            ))
+            * Matrix4::new_translation(&Vector3::new(0., 0., -window_start));
+        // Moves the notes rather than the laser, so that it scales with the visible length.
+        let judge_offset = user_settings
+            .highway
+            .judge_line_offsets
+            .get(l.id.0 as usize)
+            .map_or(0., |offset| offset * (window_end - window_start));
+        let judge_shift = Matrix4::new_translation(&Vector3::new(0., 0., judge_offset));
+        let note_local = judge_shift * note_height;
+        // Notes are spawned ahead of the window and linger until judged, so only upload the
+        // ones overlapping it.
+        let half_len = NOTE_LENGTH * l.note_style.height / 2.;
+        let (near, far) = (
+            window_start - half_len - judge_offset,
+            window.end + half_len - judge_offset,
+        );
+        for (note, t, _) in (&notes, &transforms, hierarchy.all_children(e)).join() {
+            let z = t.translation().z;
+            if z > far || note.end_position < near {
+                continue;
+            }
+            let fade = if hidden {
+                let distance = (z + judge_offset - window_start) / (window_end - window_start);
+                ((distance - HIDDEN_END) / (HIDDEN_START - HIDDEN_END))
+                    .max(0.)
+                    .min(1.)
+            } else {
+                1.
+            };
+            if fade == 0. {
+                continue;
+            }
+            let faded = |tint: [f32; 4]| {
+                let [r, g, b, a] = tint;
+                [r * fade, g * fade, b * fade, a * fade]
+            };
+            if let NoteKind::Roll { .. } = note.kind {
+                // The head stops at the judge line and the body is consumed from there.
+                let head = z.max(window_start - judge_offset);
+                let length = (note.end_position - head).max(0.);
+                let body =
+                    Matrix4::new_nonuniform_scaling(&Vector3::new(1., 1., length / NOTE_LENGTH))
+                        .append_translation(&Vector3::new(0., 0., head - z + length / 2.));
+                frame.notes.push(LaserInstance::new(
+                    remap * t.global_matrix() * judge_shift * body,
+                    faded(roll_tint),
+                    faded(roll_tint),
+                    0.,
+                ));
+                let tick_mark =
+                    Matrix4::new_nonuniform_scaling(&Vector3::new(1., 1., TICK_MARK_LENGTH));
+                for &tick in note.tick_positions.iter().filter(|_| ticks_shown) {
+                    // Ticks already passed are consumed with the body.
+                    if tick <= head {
+                        continue;
+                    }
+                    frame.notes.push(LaserInstance::new(
+                        remap
+                            * t.global_matrix()
+                            * Matrix4::new_translation(&Vector3::new(0., 0., tick - z))
+                            * note_local
+                            * tick_mark,
+                        faded(tick_tint),
+                        faded(tick_tint),
+                        0.,
+                    ));
+                }
+                frame.notes.push(LaserInstance::new(
+                    remap
+                        * t.global_matrix()
+                        * Matrix4::new_translation(&Vector3::new(0., 0., head - z))
+                        * note_local,
+                    faded(note_tint),
+                    faded(note_tint),
+                    0.,
+                ));
+                continue;
+            }
+            if note.kind == NoteKind::Mine {
+                frame.notes.push(LaserInstance::new(
+                    remap * t.global_matrix() * note_local,
+                    faded(mine_tint),
+                    faded(mine_tint),
+                    0.,
+                ));
+                continue;
+            }
+            // A bar under the whole chord; blending is additive, so the notes stay brighter.
+            if let Some(span) = note.chord.filter(|_| glow_enabled) {
+                let span = Matrix4::new_nonuniform_scaling(&Vector3::new(span as f32, 1., 1.));
+                frame.notes.push(LaserInstance::new(
+                    remap * t.global_matrix() * span * note_local,
+                    faded(chord_glow),
+                    faded(chord_glow),
+                    0.,
+                ));
+            }
+            frame.notes.push(LaserInstance::new(
+                remap * t.global_matrix() * note_local,
+                faded(note_tint),
+                faded(note_tint),
+                0.,
+            ));
+        }
+        frame.instances.push(frame.notes.len() as u32);
+    }
+    laser_depths.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+    frame.laser_draws.extend(
+        laser_depths
+            .into_iter()
+            .map(|(_, i, blend, stencil)| (i, blend, stencil)),
+    );
+    frame
 }
 
 #[derive(Debug)]
 pub struct DrawLaser<B: Backend> {
-    pipeline: B::GraphicsPipeline,
+    /// Pipelines of each blend mode, in the order of `LaserBlend::ALL`.
+    pipelines: Vec<B::GraphicsPipeline>,
     pipeline_layout: B::PipelineLayout,
+    stencil: StencilLayer,
     env: EnvironmentSub<B>,
-    laser_args: DynamicUniform<B, LaserArgs>,
-    note_args: DynamicUniform<B, LaserArgs>,
-    lasers: DynamicVertexBuffer<B, VertexArgs>,
-    notes: DynamicVertexBuffer<B, VertexArgs>,
+    /// Laser and note uniforms of each player.
+    player_args: Vec<(DynamicUniform<B, LaserArgs>, DynamicUniform<B, LaserArgs>)>,
+    textures: TextureSub<B>,
+    white_texture: Handle<Texture>,
+    white_texture_id: Option<TextureId>,
+    lasers: DynamicVertexBuffer<B, LaserInstance>,
+    notes: DynamicVertexBuffer<B, LaserInstance>,
     instances: Vec<u32>,
+    /// Texture of each laser instance, or `None` if nothing is ready to be bound yet.
+    laser_textures: Vec<Option<TextureId>>,
+    /// Player of each laser instance.
+    laser_players: Vec<u8>,
+    /// Laser instances in the order they are drawn, farthest first, with their blend mode and
+    /// stencil value.
+    laser_draws: Vec<(usize, LaserBlend, u8)>,
     square_mesh: Mesh<B>,
+    /// Whether the highway was degenerate last frame, to warn only once.
+    degenerate: bool,
+    /// What was uploaded and recorded for each frame in flight.
+    frames: Vec<FrameCache>,
+}
+
+impl<B: Backend> DrawLaser<B> {
+    /// Records a frame without lasers, forgetting what was recorded for it before.
+    fn record_empty(&mut self, index: usize) -> PrepareResult {
+        self.instances.clear();
+        if let Some(frame) = self.frames.get_mut(index) {
+            frame.layout = None;
+        }
+        PrepareResult::DrawRecord
+    }
+}
+
+/// Everything the draw commands of a frame depend on besides buffer and uniform contents.
+#[derive(Debug, PartialEq)]
+struct DrawLayout {
+    instances: Vec<u32>,
+    laser_textures: Vec<Option<TextureId>>,
+    laser_players: Vec<u8>,
+    laser_draws: Vec<(usize, LaserBlend, u8)>,
+    white_texture: Option<TextureId>,
+}
+
+/// The instance data last written to a frame's buffers and the layout its commands were recorded
+/// with, so that unchanged frames skip both the upload and the command buffer rebuild.
+#[derive(Debug, Default)]
+struct FrameCache {
+    lasers: Vec<LaserInstance>,
+    notes: Vec<LaserInstance>,
+    layout: Option<DrawLayout>,
 }
 
 impl<B: Backend> RenderGroup<B, World> for DrawLaser<B> {
@@ -250,34 +808,26 @@ impl<B: Backend> RenderGroup<B, World> for DrawLaser<B> {
         _: Subpass<B>,
         world: &World,
     ) -> PrepareResult {
-        let (entities, options, state, lasers, notes, transforms, hierarchy) =
-            <(
-                Entities,
-                ReadExpect<LaserOptions>,
-                Read<ChartState>,
-                ReadStorage<Laser>,
-                ReadStorage<Note>,
-                ReadStorage<Transform>,
-                ReadExpect<ParentHierarchy>,
-            )>::fetch(world);
+        let (time, settings, user_settings, options, state, lasers) = <(
+            ReadExpect<Time>,
+            Read<Option<PlaySettings>>,
+            Read<Settings>,
+            ReadExpect<LaserOptions>,
+            Read<ChartState>,
+            ReadStorage<Laser>,
+        )>::fetch(world);
+        if options.judge_quad.len() < 4 {
+            // The camera hasn't been set up yet.
+            return self.record_empty(index);
+        }
         self.env.process(factory, index, world);
+        self.textures.maintain(factory, world);
 
-        let basis: [f32; 3] = options.basis.coords.into();
+        let players = settings.as_ref().map_or(1, |s| s.players.max(1));
         let start_z = state.draw_window.start;
-        let end_z = state.draw_window.end;
+        // Nothing is visible in an empty window, but the shader still divides by its length.
+        let end_z = state.draw_window.end.max(start_z + DEGENERATE_EPSILON);
         let cutoff = state.cutoff;
-        let note_len = 0.03;
-
-        let note_source: Vec<_> = [
-            [0., 0., start_z],
-            [1., 0., start_z],
-            [0., 1., start_z],
-            [0., 0., end_z],
-            [1., 1., end_z],
-        ]
-        .iter()
-        .map(|x| Vector3::from_column_slice(x))
-        .collect();
 
         let laser_source: Vec<_> = [
             [0., 0., 0.],
@@ -290,77 +840,163 @@ impl<B: Backend> RenderGroup<B, World> for DrawLaser<B> {
         .map(|x| Vector3::from_column_slice(x))
         .collect();
 
-        let split_inner = |x: Point3<f32>| options.basis.coords * cutoff + x.coords * (1. - cutoff);
-
-        let target: Vec<_> = [
-            options.judge_quad[0].coords,
-            options.judge_quad[1].coords,
-            options.judge_quad[3].coords,
-            split_inner(options.judge_quad[0]),
-            split_inner(options.judge_quad[2]),
-        ]
-        .to_vec();
-
         let identity: [[f32; 4]; 4] = Matrix4::identity().into();
-        let laser_post_transform: [[f32; 4]; 4] = (basis_to_points(&target)
-            * basis_to_points(&laser_source).try_inverse().unwrap())
-        .into();
-        let note_post_transform: [[f32; 4]; 4] = (basis_to_points(&target)
-            * basis_to_points(&note_source).try_inverse().unwrap())
-        .into();
         let note_pre_transform: [[f32; 4]; 4] =
             Matrix4::new_translation(&Vector3::new(0., 0., -0.5))
-                .append_nonuniform_scaling(&Vector3::new(1., 1., note_len))
+                .append_nonuniform_scaling(&Vector3::new(1., 1., NOTE_LENGTH))
                 .into();
 
-        let laser_args = LaserArgs {
-            basis: basis.into(),
-            pre_transform: identity.into(),
-            post_transform: laser_post_transform.into(),
+        let edge_aa = if user_settings.graphics.edge_antialiasing {
+            1.
+        } else {
+            0.
         };
+        let chart_time = settings
+            .as_ref()
+            .map_or(0., |s| (time.absolute_time_seconds() - s.base_time) as f32);
 
-        let note_args = LaserArgs {
-            basis: basis.into(),
-            pre_transform: note_pre_transform.into(),
-            post_transform: note_post_transform.into(),
-        };
-        self.laser_args.write(factory, index, laser_args.std140());
-        self.note_args.write(factory, index, note_args.std140());
-        let laser_vertex_args: Vec<_> = (&lasers, &transforms)
-            .join()
-            .map(|(l, t)| {
-                let (r, g, b) = l.color.into_components();
-                VertexArgs {
-                    tint: [r, g, b, 1.].into(),
-                    ..VertexArgs::from_object_data(t, None)
+        let mut post_transforms = Vec::new();
+        for player in 0..players {
+            let (judge_quad, basis_point) = options.player_quad(player, players);
+            let basis: [f32; 3] = basis_point.coords.into();
+            let split_inner =
+                |x: Point3<f32>| basis_point.coords * cutoff + x.coords * (1. - cutoff);
+
+            // The origin, the lane axis and the height axis go to the corners of the judge quad
+            // and the depth axis towards the basis, so a reversed quad mirrors the highway.
+            let target: Vec<_> = [
+                judge_quad[0].coords,
+                judge_quad[1].coords,
+                judge_quad[3].coords,
+                split_inner(judge_quad[0]),
+                split_inner(judge_quad[2]),
+            ]
+            .to_vec();
+
+            let post_transform: [[f32; 4]; 4] = match points_to_points(&laser_source, &target) {
+                Some(transform) => {
+                    post_transforms.push(transform);
+                    transform.into()
                 }
-            })
-            .collect();
-        self.instances.clear();
-        self.instances.push(0);
-        let mut note_vertex_args = Vec::new();
-        for (e, _) in (&entities, &lasers).join() {
-            note_vertex_args.extend((&notes, &transforms, hierarchy.all_children(e)).join().map(
-                |(_, t, _)| VertexArgs {
-                    tint: [0., 0., 0., 1.].into(),
-                    ..VertexArgs::from_object_data(t, None)
-                },
-            ));
-            self.instances.push(note_vertex_args.len() as u32);
+                None => {
+                    // E.g. an extreme FOV or a zero-area window during a resize.
+                    if !self.degenerate {
+                        log::warn!("The highway is degenerate, skipping lasers");
+                        self.degenerate = true;
+                    }
+                    return self.record_empty(index);
+                }
+            };
+
+            let laser_args = LaserArgs {
+                basis: basis.into(),
+                pre_transform: identity.into(),
+                post_transform: post_transform.into(),
+                time: chart_time,
+                scroll: [0., 1.].into(),
+                edge_aa,
+            };
+
+            let note_args = LaserArgs {
+                basis: basis.into(),
+                pre_transform: note_pre_transform.into(),
+                post_transform: post_transform.into(),
+                time: chart_time,
+                scroll: [start_z, end_z].into(),
+                edge_aa,
+            };
+            if self.player_args.len() <= player as usize {
+                let stages = pso::ShaderStageFlags::VERTEX | pso::ShaderStageFlags::FRAGMENT;
+                match (
+                    DynamicUniform::new(factory, stages),
+                    DynamicUniform::new(factory, stages),
+                ) {
+                    (Ok(laser), Ok(note)) => self.player_args.push((laser, note)),
+                    _ => return PrepareResult::DrawReuse,
+                }
+            }
+            let (laser_uniform, note_uniform) = &mut self.player_args[player as usize];
+            laser_uniform.write(factory, index, laser_args.std140());
+            note_uniform.write(factory, index, note_args.std140());
+        }
+
+        self.degenerate = false;
+
+        let mut textures_changed = false;
+        let white_texture = self
+            .textures
+            .insert(
+                factory,
+                world,
+                &self.white_texture,
+                hal::image::Layout::ShaderReadOnlyOptimal,
+            )
+            .map(|(id, changed)| {
+                textures_changed |= changed;
+                id
+            });
+        let built = build_instances(world, &post_transforms);
+        self.laser_textures.clear();
+        for &entity in &built.laser_entities {
+            let texture = lasers
+                .get(entity)
+                .and_then(|l| l.texture.as_ref())
+                .and_then(|handle| {
+                    self.textures.insert(
+                        factory,
+                        world,
+                        handle,
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    )
+                })
+                .map(|(id, changed)| {
+                    textures_changed |= changed;
+                    id
+                })
+                .or(white_texture);
+            self.laser_textures.push(texture);
+        }
+        self.instances = built.instances;
+        self.laser_players = built.laser_players;
+        self.laser_draws = built.laser_draws;
+        let (laser_vertex_args, note_vertex_args) = (built.lasers, built.notes);
+        self.white_texture_id = white_texture;
+        if self.frames.len() <= index {
+            self.frames.resize_with(index + 1, Default::default);
+        }
+        let frame = &mut self.frames[index];
+        if frame.lasers != laser_vertex_args {
+            self.lasers.write(
+                factory,
+                index,
+                std::cmp::max(laser_vertex_args.len() as u64, 1),
+                &[&laser_vertex_args],
+            );
+            frame.lasers = laser_vertex_args;
+        }
+        if frame.notes != note_vertex_args {
+            self.notes.write(
+                factory,
+                index,
+                std::cmp::max(note_vertex_args.len() as u64, 1),
+                &[&note_vertex_args],
+            );
+            frame.notes = note_vertex_args;
+        }
+        let layout = DrawLayout {
+            instances: self.instances.clone(),
+            laser_textures: self.laser_textures.clone(),
+            laser_players: self.laser_players.clone(),
+            laser_draws: self.laser_draws.clone(),
+            white_texture,
+        };
+        // Buffers only grow along with the layout, so equal layouts still bind the same buffers.
+        if !textures_changed && frame.layout.as_ref() == Some(&layout) {
+            PrepareResult::DrawReuse
+        } else {
+            frame.layout = Some(layout);
+            PrepareResult::DrawRecord
         }
-        self.lasers.write(
-            factory,
-            index,
-            std::cmp::max(laser_vertex_args.len() as u64, 1),
-            &[laser_vertex_args],
-        );
-        self.notes.write(
-            factory,
-            index,
-            std::cmp::max(note_vertex_args.len() as u64, 1),
-            &[note_vertex_args],
-        );
-        PrepareResult::DrawRecord
     }
 
     fn draw_inline(
@@ -370,23 +1006,42 @@ impl<B: Backend> RenderGroup<B, World> for DrawLaser<B> {
         _: Subpass<B>,
         _: &World,
     ) {
-        encoder.bind_graphics_pipeline(&self.pipeline);
-        self.env.bind(index, &self.pipeline_layout, 0, &mut encoder);
-        for (i, window) in self.instances.windows(2).enumerate() {
+        let white_texture = match self.white_texture_id {
+            Some(id) if self.instances.len() > 1 => id,
+            _ => return,
+        };
+        for &(i, blend, stencil) in &self.laser_draws {
+            let pipeline = LaserBlend::ALL.iter().position(|&b| b == blend).unwrap();
+            encoder.bind_graphics_pipeline(&self.pipelines[pipeline]);
+            self.env.bind(index, &self.pipeline_layout, 0, &mut encoder);
+            let window = &self.instances[i..i + 2];
+            let (laser_args, note_args) = &self.player_args[self.laser_players[i] as usize];
             unsafe {
-                encoder.set_stencil_reference(pso::Face::FRONT | pso::Face::BACK, 0);
+                encoder.set_stencil_reference(
+                    pso::Face::FRONT | pso::Face::BACK,
+                    self.stencil.reference(0),
+                );
             }
-            self.note_args
-                .bind(index, &self.pipeline_layout, 1, &mut encoder);
+            note_args.bind(index, &self.pipeline_layout, 1, &mut encoder);
+            self.textures
+                .bind(&self.pipeline_layout, 2, white_texture, &mut encoder);
             self.notes.bind(index, 1, 0, &mut encoder);
             self.square_mesh
                 .bind_and_draw(0, &[PosTex::vertex()], window[0]..window[1], &mut encoder)
                 .unwrap();
             unsafe {
-                encoder.set_stencil_reference(pso::Face::FRONT | pso::Face::BACK, 1);
+                encoder.set_stencil_reference(
+                    pso::Face::FRONT | pso::Face::BACK,
+                    self.stencil.reference(stencil.into()),
+                );
             }
-            self.laser_args
-                .bind(index, &self.pipeline_layout, 1, &mut encoder);
+            laser_args.bind(index, &self.pipeline_layout, 1, &mut encoder);
+            self.textures.bind(
+                &self.pipeline_layout,
+                2,
+                self.laser_textures[i].unwrap_or(white_texture),
+                &mut encoder,
+            );
             self.lasers.bind(index, 1, 0, &mut encoder);
             self.square_mesh
                 .bind_and_draw(0, &[PosTex::vertex()], i as u32..i as u32 + 1, &mut encoder)
@@ -396,7 +1051,9 @@ impl<B: Backend> RenderGroup<B, World> for DrawLaser<B> {
 
     fn dispose(self: Box<Self>, factory: &mut Factory<B>, _: &World) {
         unsafe {
-            factory.device().destroy_graphics_pipeline(self.pipeline);
+            for pipeline in self.pipelines {
+                factory.device().destroy_graphics_pipeline(pipeline);
+            }
             factory
                 .device()
                 .destroy_pipeline_layout(self.pipeline_layout);
@@ -404,6 +1061,8 @@ impl<B: Backend> RenderGroup<B, World> for DrawLaser<B> {
     }
 }
 
+/// Draws lasers and notes where the theme's `render_order.lasers` puts them, after transparent
+/// 3D content by default.
 #[derive(Debug)]
 pub struct RenderLaser;
 
@@ -412,14 +1071,78 @@ impl<B: Backend> RenderPlugin<B> for RenderLaser {
         &mut self,
         plan: &mut RenderPlan<B>,
         _: &mut Factory<B>,
-        _: &World,
+        world: &World,
     ) -> Result<(), amethyst::Error> {
+        let order: RenderOrder = world
+            .try_fetch::<Theme>()
+            .map_or(RenderOrder::AfterTransparent, |theme| {
+                theme.render_order.lasers.into()
+            });
         plan.extend_target(Target::default(), move |ctx| {
-            ctx.add(
-                RenderOrder::AfterTransparent,
-                DrawLaserDesc::<B>::new().builder(),
-            )
+            ctx.add(order, DrawLaserDesc::<B>::new().builder())
         });
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points(arr: &[[f32; 3]]) -> Vec<Vector3<f32>> {
+        arr.iter().map(|x| Vector3::from_column_slice(x)).collect()
+    }
+
+    /// The origin, `scale` along x, the unit y and z axes and (1, 1, 1).
+    fn scaled_basis(scale: f32) -> Vec<Vector3<f32>> {
+        points(&[
+            [0., 0., 0.],
+            [scale, 0., 0.],
+            [0., 1., 0.],
+            [0., 0., 1.],
+            [1., 1., 1.],
+        ])
+    }
+
+    #[test]
+    fn maps_points_to_themselves() {
+        let basis = scaled_basis(1.);
+        let m = points_to_points(&basis, &basis).unwrap();
+        let m = m / m[(3, 3)];
+        assert!((m - Matrix4::identity()).abs().max() < 1e-5, "{}", m);
+    }
+
+    #[test]
+    fn zero_basis_is_degenerate() {
+        assert_eq!(basis_to_points(&points(&[[0., 0., 0.]; 5])), None);
+    }
+
+    #[test]
+    fn collinear_basis_is_degenerate() {
+        let basis = points(&[
+            [0., 0., 0.],
+            [1., 1., 1.],
+            [2., 2., 2.],
+            [3., 3., 3.],
+            [4., 4., 4.],
+        ]);
+        assert_eq!(basis_to_points(&basis), None);
+    }
+
+    #[test]
+    fn fifth_point_on_a_face_is_degenerate() {
+        let mut basis = scaled_basis(1.);
+        basis[4] = Vector3::new(1., 1., 0.);
+        assert_eq!(basis_to_points(&basis), None);
+    }
+
+    #[test]
+    fn determinant_at_epsilon() {
+        // The determinant of the scaled basis is the scale.
+        assert_eq!(
+            basis_to_points(&scaled_basis(DEGENERATE_EPSILON / 2.)),
+            None
+        );
+        assert!(basis_to_points(&scaled_basis(DEGENERATE_EPSILON * 2.)).is_some());
+    }
+}
@@ -0,0 +1,1186 @@
+extern crate amethyst;
+
+use amethyst::{
+    core::{
+        math::{Matrix4, Point3},
+        timing::Time,
+        transform::Transform,
+        SystemBundle,
+    },
+    ecs::{
+        Component, DispatcherBuilder, Entities, Entity, Join, NullStorage, Read, ReadExpect,
+        ReadStorage, System, SystemData, Write,
+    },
+    input::is_key_down,
+    prelude::*,
+    renderer::{
+        bundle::{ImageOptions, OutputColor, RenderPlan, RenderPlugin, Target, TargetPlanOutputs},
+        camera::Projection,
+        rendy::hal::command::{ClearColor, ClearDepthStencil, ClearValue},
+        ActiveCamera, Backend, Camera, Factory, Kind,
+    },
+    shrev::EventChannel,
+    ui::{Anchor, FontHandle, LineMode, UiText},
+    window::{DisplayConfig, ScreenDimensions, Window, WindowBundle},
+    winit::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
+};
+
+pub mod judge;
+pub mod judge_harness;
+pub mod judge_sound;
+pub mod key_config;
+pub mod key_markers;
+pub mod key_stats;
+pub mod keycap;
+pub mod kiosk;
+pub mod laser;
+use challenge::{Challenge, Leaderboard, LeaderboardEntry};
+use chart::{
+    BpmCommand, Chart, ChartState, LaserCommand, LaserId, Note, NoteKind, PlaySettings, ScrollMode,
+    Timed,
+};
+use chart_file::{ChartFile, CHART_FILE};
+use cli::DirectLaunch;
+use combo_break::ComboBreak;
+use console::Console;
+use course::{CourseProgress, CourseResultState, StageResult};
+use diagnostics::ErrorEvent;
+use error::IrisError;
+use gpu::GpuInfo;
+use judge::{Assisted, Autoplay, Judgement, KeyMappings};
+use kiosk::Kiosk;
+use laser::LaserOptions;
+use modifiers::ActiveModifiers;
+use paths::Paths;
+use pause::{PauseState, Retrying};
+use profile::{suggested_offset, HistoryEntry, Profile, BEST_PACEMAKER};
+use quality::AdaptiveQuality;
+use replay::{PlaybackControls, Replay, ReplayPlayback};
+use score::{Pacemaker, Score, Scores};
+use settings::{GraphicsSettings, HighwayConfig, Settings};
+use song_select::SongSelectState;
+use stencil::StencilAllocator;
+use ui::percent_transform;
+use vfs::{FileSystem, Vfs};
+use viewport::Viewport;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub mod assets;
+pub mod audio;
+pub mod billboard;
+pub mod bloom;
+pub mod bms;
+pub mod bundle;
+pub mod challenge;
+pub mod chart;
+pub mod chart_file;
+pub mod chart_speed;
+pub mod cli;
+pub mod combo_break;
+pub mod console;
+pub mod course;
+pub mod crash;
+pub mod diagnostics;
+#[cfg(feature = "download")]
+pub mod download;
+pub mod error;
+pub mod geometry;
+pub mod gpu;
+pub mod hit_error;
+pub mod hud;
+pub mod import;
+pub mod library;
+pub mod logging;
+pub mod metronome;
+pub mod migrate;
+pub mod modifiers;
+#[cfg(feature = "onset")]
+pub mod onset;
+pub mod options;
+pub mod pack;
+pub mod paths;
+pub mod pause;
+pub mod profile;
+pub mod quality;
+#[cfg(feature = "remote")]
+pub mod remote;
+pub mod replay;
+pub mod save_queue;
+pub mod score;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+pub mod settings;
+pub mod song_select;
+pub mod speed_preview;
+pub mod stencil;
+pub mod stream_overlay;
+pub mod theme;
+pub mod thumbnail;
+#[cfg(feature = "tournament")]
+pub mod tournament;
+pub mod ui;
+pub mod vfs;
+pub mod viewport;
+pub mod wizard;
+
+/// Marks the camera the highway is seen through. Other cameras, such as those of menus, don't move
+/// the highway.
+#[derive(Default)]
+pub struct GameplayCamera;
+
+impl Component for GameplayCamera {
+    type Storage = NullStorage<Self>;
+}
+
+/// Computes the [`LaserOptions`] from the [`GameplayCamera`], placed in the [`Viewport`] and scaled
+/// by the zoom of the chart. Without a gameplay camera, such as between stages, the options are
+/// kept as they are.
+pub struct LaserFovSystem {
+    /// The camera the options were computed from, so that they are computed again when another
+    /// camera takes its place, even with the same matrices.
+    last_camera: Option<Entity>,
+    last_matrix: Matrix4<f32>,
+    last_view: Matrix4<f32>,
+    last_highway: Option<HighwayConfig>,
+    last_zoom: f32,
+    last_viewport: Viewport,
+}
+impl LaserFovSystem {
+    pub fn new() -> Self {
+        Self {
+            last_camera: None,
+            last_matrix: Matrix4::identity(),
+            last_view: Matrix4::identity(),
+            last_highway: None,
+            last_zoom: 1.,
+            last_viewport: Viewport::default(),
+        }
+    }
+}
+impl Default for LaserFovSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<'s> System<'s> for LaserFovSystem {
+    type SystemData = (
+        Entities<'s>,
+        ReadStorage<'s, Camera>,
+        ReadStorage<'s, GameplayCamera>,
+        ReadStorage<'s, Transform>,
+        Read<'s, Viewport>,
+        ReadExpect<'s, Time>,
+        Read<'s, Option<Chart>>,
+        Read<'s, Option<PlaySettings>>,
+        Read<'s, Settings>,
+        Write<'s, LaserOptions>,
+        Write<'s, EventChannel<ErrorEvent>>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            cameras,
+            gameplay_cameras,
+            transforms,
+            viewport,
+            time,
+            chart,
+            settings,
+            user_settings,
+            mut options,
+            mut errors,
+        ): Self::SystemData,
+    ) {
+        let highway = &user_settings.highway;
+        // Should there be several, such as for a frame while stages change, any one of them does.
+        let (entity, camera, transform, _) =
+            match (&entities, &cameras, &transforms, &gameplay_cameras)
+                .join()
+                .next()
+            {
+                Some(camera) => camera,
+                None => {
+                    self.last_camera = None;
+                    return;
+                }
+            };
+        let zoom = match (&*chart, &*settings) {
+            _ if user_settings.accessibility.reduced_motion => 1.,
+            (Some(chart), Some(settings)) => {
+                chart.zoom_at((time.absolute_time_seconds() - settings.base_time) as f32)
+            }
+            _ => 1.,
+        };
+        let proj = camera.as_matrix();
+        // The camera's global matrix maps view space back into world space.
+        let view_inv = transform.global_matrix();
+        if self.last_camera != Some(entity)
+            || proj != &self.last_matrix
+            || view_inv != &self.last_view
+            || self.last_highway.as_ref() != Some(highway)
+            || zoom != self.last_zoom
+            || *viewport != self.last_viewport
+        {
+            let perspective_inv = match proj.try_inverse() {
+                Some(inverse) => inverse,
+                None => {
+                    errors.single_write(ErrorEvent::new(
+                        "The camera projection is degenerate, keeping the previous judge quad",
+                    ));
+                    return;
+                }
+            };
+            let reverse_point = |x, y, target_z| {
+                let near = perspective_inv.transform_point(&Point3::new(x, y, 0.));
+                let near_far = perspective_inv.transform_point(&Point3::new(x, y, 1.)) - near;
+                let unit = near_far / near_far.z;
+                view_inv.transform_point(&(near + (target_z - near.z) * unit))
+            };
+            // Zooming scales the screen positions around the center, keeping the depth, so the
+            // highway and everything placed on the judge quad scale together. Reversing swaps the
+            // rows of the quad and moves the basis to the bottom; the corners keep their meaning
+            // in highway coordinates, so notes stay where their keys judge them.
+            let flip = if highway.reverse { -1. } else { 1. };
+            let judge_quad: Vec<_> = [(-1., 1.), (1., 1.), (1., -1.), (-1., -1.)]
+                .iter()
+                .map(|&(x, y)| {
+                    let (x, y) = viewport.to_screen(x * zoom, y * flip * zoom);
+                    reverse_point(x, y, -highway.judge_depth)
+                })
+                .collect();
+            let (x, y) = viewport.to_screen(0., -flip * zoom);
+            let basis = reverse_point(x, y, -highway.basis_depth);
+            let eye = view_inv.transform_point(&Point3::origin());
+            *options = LaserOptions {
+                judge_quad,
+                basis,
+                eye,
+                zoom,
+            };
+            self.last_camera = Some(entity);
+            self.last_matrix = proj.clone();
+            self.last_view = view_inv.clone();
+            self.last_highway = Some(highway.clone());
+            self.last_zoom = zoom;
+            self.last_viewport = viewport.clone();
+        }
+    }
+}
+
+const OFFSET_FONT_SIZE: f32 = 20.;
+/// Seconds before a play ended by [sudden death](modifiers::Modifier::SuddenDeath) goes back to
+/// song select.
+const FAILED_SECONDS: f64 = 2.;
+
+#[derive(Default)]
+pub struct MainStage {
+    /// Real time at which the chart was completely judged.
+    finished_at: Option<f64>,
+    /// Real time at which every player had failed with sudden death.
+    failed_at: Option<f64>,
+    /// Real time at which the play started.
+    started_at: f64,
+    /// Controls of the replay being watched, if any.
+    playback: Option<PlaybackControls>,
+    /// The player's own key bindings while a replay plays with those it was recorded with.
+    live_key_maps: Option<KeyMappings>,
+    /// Whether the offset suggestion was considered for the finished play.
+    offset_checked: bool,
+    /// Text of the offset suggestion shown after the play.
+    offset_text: Option<Entity>,
+    /// The suggested offset until it is applied.
+    suggested_offset: Option<f32>,
+    /// The camera of the stage, deleted when it stops.
+    camera: Option<Entity>,
+    /// [`chart_file::song_chart_hash`] of the chart playing, or `None` for the demo chart.
+    chart_hash: Option<u64>,
+}
+
+impl MainStage {
+    fn initialize_camera(&mut self, world: &mut World, proj: Projection) {
+        world.register::<GameplayCamera>();
+        let camera = world
+            .create_entity()
+            .with(Camera::from(proj))
+            .with(Transform::default())
+            .with(GameplayCamera)
+            .build();
+        // Rendered through even if another camera joins first.
+        world.insert(ActiveCamera {
+            entity: Some(camera),
+        });
+        self.camera = Some(camera);
+    }
+
+    fn initialize_chart(&mut self, world: &mut World) {
+        world.register::<laser::Note>();
+        world.register::<laser::Laser>();
+        let now = world.fetch::<Time>().absolute_time_seconds();
+        let (speed, offset, players, scroll) = {
+            let settings = world.read_resource::<Settings>();
+            let gameplay = &settings.gameplay;
+            (
+                gameplay.speed,
+                gameplay.offset,
+                if gameplay.versus { 2 } else { 1 },
+                if gameplay.constant_scroll {
+                    ScrollMode::Constant
+                } else {
+                    ScrollMode::Chart
+                },
+            )
+        };
+        // Song select already recalls the speed, but courses and challenges pick their songs.
+        let speed = match &*world.read_resource::<Option<library::Song>>() {
+            Some(song) if !world.read_resource::<Settings>().gameplay.normalize_speed => world
+                .read_resource::<Profile>()
+                .load_chart_speeds()
+                .recall(song)
+                .unwrap_or(speed),
+            _ => speed,
+        };
+        world.insert(Some(PlaySettings {
+            speed,
+            base_time: now,
+            offset,
+            norm_threshold: 0.1,
+            players,
+            scroll,
+        }));
+        let (mut chart, chart_hash) = load_chart(world);
+        self.chart_hash = chart_hash;
+        let (active, seed) = match &*world.read_resource::<Option<Challenge>>() {
+            Some(challenge) => (challenge.modifiers.clone(), challenge.seed),
+            // Demos show the chart as it is.
+            None if world.read_resource::<Kiosk>().demo_until.is_some() => (Vec::new(), 0),
+            None => (
+                world.read_resource::<Settings>().gameplay.modifiers.clone(),
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_nanos() as u64),
+            ),
+        };
+        modifiers::apply(&mut chart, &active, seed);
+        if !active.is_empty() {
+            log::info!("Modifiers: {}", modifiers::icons(&active));
+        }
+        world.insert(ActiveModifiers(active));
+        world.insert(Some(chart));
+        world.write_resource::<ChartState>().invalidate_positions();
+    }
+
+    #[cfg(feature = "scripting")]
+    fn initialize_scripts(&mut self, world: &mut World) {
+        let names = world.read_resource::<Settings>().gameplay.scripts.clone();
+        let root = world.read_resource::<Paths>().data.clone();
+        let scripts = {
+            let mut errors = world.write_resource::<EventChannel<ErrorEvent>>();
+            let scripts = scripting::Scripts::load(&root, &names, &mut errors);
+            if let Some(chart) = &mut *world.write_resource::<Option<Chart>>() {
+                scripts.transform_chart(chart, &mut errors);
+            }
+            scripts
+        };
+        world.insert(scripts);
+    }
+
+    fn initialize_pacemaker(&mut self, world: &mut World) {
+        let path = world.read_resource::<Settings>().gameplay.pacemaker.clone();
+        let target = path
+            .map(|path| {
+                let profile = world.read_resource::<Profile>();
+                if path == BEST_PACEMAKER {
+                    profile.best_score_path(self.chart_hash)
+                } else {
+                    profile.dir.join(path)
+                }
+            })
+            .and_then(|path| migrate::load::<Score>(&path).ok());
+        if let Some(target) = &target {
+            if target.windows != world.read_resource::<Settings>().gameplay.judge_windows {
+                log::info!("The pacemaker was judged with different timing windows");
+            }
+        }
+        let total_notes = world
+            .read_resource::<Option<Chart>>()
+            .as_ref()
+            .map_or(0, Chart::judged_notes);
+        world.insert(target.map(|target| Pacemaker::new(&target, total_notes)));
+    }
+
+    fn save_personal_best(&mut self, world: &World) -> Result<(), IrisError> {
+        if world.read_resource::<Settings>().gameplay.versus || world.read_resource::<Assisted>().0
+        {
+            return Ok(());
+        }
+        let scores = world.fetch::<Scores>();
+        let score = match scores.players.get(0) {
+            Some(score) => score,
+            None => return Ok(()),
+        };
+        let total_notes = world
+            .fetch::<Option<Chart>>()
+            .as_ref()
+            .map_or(0, Chart::judged_notes);
+        // Course stages only count towards the course record.
+        if world.read_resource::<Option<CourseProgress>>().is_some() {
+            return Ok(());
+        }
+        let profile = world.read_resource::<Profile>();
+        if let Some(challenge) = &*world.read_resource::<Option<Challenge>>() {
+            let path = challenge.leaderboard_path(&profile.dir);
+            let mut leaderboard: Leaderboard = migrate::load(&path).unwrap_or_default();
+            let song = world
+                .read_resource::<Option<library::Song>>()
+                .as_ref()
+                .map(|song| song.path.clone())
+                .unwrap_or_default();
+            let rank = leaderboard.insert(LeaderboardEntry {
+                song,
+                score: score.value(total_notes),
+                max_combo: score.max_combo,
+                modifiers: score.modifiers.clone(),
+            });
+            if let Some(rank) = rank {
+                log::info!(
+                    "Daily challenge rank #{} [{}]",
+                    rank + 1,
+                    modifiers::icons(&score.modifiers)
+                );
+                save_queue::save(path, &leaderboard)?;
+            }
+            return Ok(());
+        }
+        let path = profile.best_score_path(self.chart_hash);
+        let best = migrate::load::<Score>(&path).ok();
+        if best.map_or(true, |best| {
+            score.value(total_notes) > best.value(total_notes)
+        }) {
+            save_queue::save(path, score)?;
+            // Keep the replay of the best play to compare with others.
+            save_queue::copy(
+                &profile.replays_dir().join("latest.ron"),
+                profile.best_replay_path(self.chart_hash),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The first player's play, if anything was judged or it was retried.
+    fn history_entry(&self, world: &World, retried: bool) -> Option<HistoryEntry> {
+        let scores = world.read_resource::<Scores>();
+        let nothing = Score::default();
+        let score = scores.players.get(0).unwrap_or(&nothing);
+        if score.records.is_empty() && !retried {
+            return None;
+        }
+        let total_notes = world
+            .read_resource::<Option<Chart>>()
+            .as_ref()
+            .map_or(0, Chart::judged_notes);
+        let song = world
+            .read_resource::<Option<library::Song>>()
+            .as_ref()
+            .map(|song| song.path.clone())
+            .unwrap_or_default();
+        let mut entry = HistoryEntry::now(song, score.value(total_notes), score.max_combo);
+        entry.cleared = score.cleared(total_notes);
+        entry.retried = retried;
+        entry.seconds =
+            (world.read_resource::<Time>().absolute_real_time_seconds() - self.started_at) as f32;
+        let hits: Vec<_> = score
+            .records
+            .iter()
+            .filter(|record| record.judgement != Judgement::Miss)
+            .filter_map(|record| record.offset)
+            .collect();
+        if !hits.is_empty() && !world.read_resource::<Assisted>().0 {
+            let offset = world
+                .read_resource::<Option<PlaySettings>>()
+                .as_ref()
+                .map_or(0., |settings| settings.offset);
+            entry.hits = hits.len() as u32;
+            entry.raw_offset = Some(hits.iter().sum::<f32>() / hits.len() as f32 + offset);
+        }
+        Some(entry)
+    }
+
+    /// Suggests an offset after the play if the hits of the recent plays, this one included, lean
+    /// early or late. Enter applies it.
+    fn show_offset_suggestion(&mut self, world: &mut World) {
+        let mut plays = world.read_resource::<Profile>().load_history();
+        plays.extend(self.history_entry(world, false));
+        let current = world.read_resource::<Settings>().gameplay.offset;
+        let offset = match suggested_offset(&plays, current) {
+            Some(offset) => offset,
+            None => return,
+        };
+        let late = current - offset;
+        let text = format!(
+            "Your recent hits average {:.0} ms {}.\n\
+             Press Enter to change the offset from {:+.0} ms to {:+.0} ms.",
+            late.abs() * 1000.,
+            if late > 0. { "late" } else { "early" },
+            current * 1000.,
+            offset * 1000.
+        );
+        let font = world.read_resource::<InterFont>().0.clone();
+        let font_size = {
+            let settings = world.read_resource::<Settings>();
+            let dimensions = world.read_resource::<ScreenDimensions>();
+            OFFSET_FONT_SIZE * settings.ui.scale_factor(&dimensions)
+        };
+        let mut ui_text = UiText::new(font, text, [1.; 4], font_size);
+        ui_text.line_mode = LineMode::Wrap;
+        self.offset_text = Some(
+            world
+                .create_entity()
+                .with(percent_transform(
+                    "OffsetSuggestion",
+                    Anchor::BottomMiddle,
+                    0.,
+                    0.1,
+                    0.6,
+                    0.1,
+                ))
+                .with(ui_text)
+                .build(),
+        );
+        self.suggested_offset = Some(offset);
+    }
+
+    /// Applies the rate and starting time of a chart launched from the command line.
+    fn initialize_direct_launch(&mut self, world: &mut World) {
+        let (rate, start) = match &*world.read_resource::<Option<DirectLaunch>>() {
+            Some(launch) => (launch.rate, launch.seek),
+            None => return,
+        };
+        if let Some(start) = start {
+            seek(world, start);
+        }
+        if let Some(rate) = rate {
+            world.write_resource::<Time>().set_time_scale(rate);
+        }
+        if start.is_some() || rate.is_some() {
+            world.insert(Assisted(true));
+        }
+    }
+
+    /// Judges with the offset and key bindings of the replay being watched, if any.
+    fn initialize_playback(&mut self, world: &mut World) {
+        let (offset, key_maps) = match &*world.read_resource::<Option<ReplayPlayback>>() {
+            Some(playback) => (playback.offset, playback.key_maps()),
+            None => return,
+        };
+        if let Some(settings) = &mut *world.write_resource::<Option<PlaySettings>>() {
+            settings.offset = offset;
+        }
+        // Replays from before key bindings were recorded play with the current ones.
+        let players = world.read_resource::<KeyMappings>().0.len();
+        if let Some(key_maps) = key_maps.filter(|maps| maps.len() == players) {
+            let live = std::mem::replace(
+                &mut *world.write_resource::<KeyMappings>(),
+                KeyMappings(key_maps),
+            );
+            self.live_key_maps = Some(live);
+        }
+        let mut controls = PlaybackControls::default();
+        controls.start(world);
+        self.playback = Some(controls);
+    }
+
+    /// Starts the stage with the gauge carried over from the previous one.
+    fn initialize_course_gauge(&mut self, world: &mut World) {
+        let gauge = match &*world.read_resource::<Option<CourseProgress>>() {
+            Some(progress) => progress.gauge,
+            None => return,
+        };
+        world.write_resource::<Scores>().player_mut(0).gauge = gauge;
+    }
+
+    /// Moves on to the next stage of the course once the chart has been judged completely or the
+    /// gauge has run out.
+    fn advance_course(&mut self, world: &mut World) -> SimpleTrans {
+        let total_notes = world
+            .read_resource::<Option<Chart>>()
+            .as_ref()
+            .map_or(0, Chart::judged_notes);
+        let (result, gauge) = {
+            let scores = world.read_resource::<Scores>();
+            let score = match scores.players.get(0) {
+                Some(score) => score,
+                None => return Trans::None,
+            };
+            if score.records.len() < total_notes && score.gauge > 0. {
+                return Trans::None;
+            }
+            let title = world
+                .read_resource::<Option<library::Song>>()
+                .as_ref()
+                .map(|song| song.meta.title.clone())
+                .unwrap_or_default();
+            let result = StageResult {
+                title,
+                score: score.value(total_notes),
+                max_combo: score.max_combo,
+            };
+            (result, score.gauge)
+        };
+        let assisted = world.read_resource::<Assisted>().0;
+        let next = match &mut *world.write_resource::<Option<CourseProgress>>() {
+            Some(progress) => {
+                progress.assisted |= assisted;
+                progress.finish_stage(result, gauge).cloned()
+            }
+            None => return Trans::None,
+        };
+        match next {
+            Some(song) => {
+                world.insert(Some(song));
+                Trans::Switch(Box::new(MainStage::default()))
+            }
+            None => Trans::Switch(Box::new(CourseResultState::default())),
+        }
+    }
+}
+
+/// Whether the first player's notes have all been judged, or sudden death ended their play.
+fn chart_finished(world: &World) -> bool {
+    let total_notes = world
+        .read_resource::<Option<Chart>>()
+        .as_ref()
+        .map_or(0, Chart::judged_notes);
+    world
+        .read_resource::<Scores>()
+        .players
+        .get(0)
+        .map_or(false, |score| {
+            score.failed || score.records.len() >= total_notes
+        })
+}
+
+/// Remembers the speed the chart was played at, unless every chart uses the same speed.
+fn remember_speed(world: &World) -> Result<(), IrisError> {
+    if world.read_resource::<Settings>().gameplay.normalize_speed {
+        return Ok(());
+    }
+    let song = world.read_resource::<Option<library::Song>>();
+    let play_settings = world.read_resource::<Option<PlaySettings>>();
+    if let (Some(song), Some(play_settings)) = (&*song, &*play_settings) {
+        let profile = world.read_resource::<Profile>();
+        let mut speeds = profile.load_chart_speeds();
+        speeds.remember(song, play_settings.speed);
+        profile.save_chart_speeds(&speeds)?;
+    }
+    Ok(())
+}
+
+/// Whether sudden death has ended the play of every player.
+fn play_failed(world: &World) -> bool {
+    let players = world
+        .read_resource::<Option<PlaySettings>>()
+        .as_ref()
+        .map_or(1, |settings| settings.players.max(1));
+    let scores = world.read_resource::<Scores>();
+    (0..players as usize).all(|player| scores.players.get(player).map_or(false, |s| s.failed))
+}
+
+/// The native chart of the selected song and its [`chart_file::song_chart_hash`]. Songs without
+/// one play a built-in demo chart.
+fn load_chart(world: &World) -> (Chart, Option<u64>) {
+    let fs = FileSystem::new(
+        world
+            .read_resource::<Settings>()
+            .library
+            .root(&world.read_resource::<Paths>()),
+    );
+    let path = world
+        .read_resource::<Option<library::Song>>()
+        .as_ref()
+        .map(|song| song.path.join(CHART_FILE))
+        .filter(|path| fs.is_file(path));
+    if let Some(path) = path {
+        match ChartFile::load(&fs, &path) {
+            Ok(chart) => {
+                let hash = fs.read(&path).ok().map(|bytes| crash::chart_hash(&bytes));
+                if let Some(hash) = hash {
+                    crash::set_context("chart.txt", format!("{}\n{:016x}", path.display(), hash));
+                }
+                return (chart, hash);
+            }
+            Err(e) => world
+                .write_resource::<EventChannel<ErrorEvent>>()
+                .single_write(ErrorEvent::new(format!("{}: {}", path.display(), e))),
+        }
+    }
+    crash::set_context("chart.txt", "built-in demo chart".into());
+    let chart = Chart {
+        notes: (0..32)
+            .flat_map(|i| {
+                vec![
+                    Timed {
+                        time: 0.075 * (2 * i) as f32 + 1.0,
+                        inner: Note {
+                            laser: LaserId(0),
+                            lane: 1,
+                            kind: NoteKind::Tap,
+                        },
+                    },
+                    Timed {
+                        time: 0.075 * (2 * i + 1) as f32 + 1.0,
+                        inner: Note {
+                            laser: LaserId(0),
+                            lane: 2,
+                            kind: NoteKind::Tap,
+                        },
+                    },
+                ]
+            })
+            .collect(),
+        bpm: vec![Timed {
+            time: 0.0,
+            inner: BpmCommand {
+                bpm: 200.,
+                position: 0.0,
+            },
+        }],
+        laser_bpm: Default::default(),
+        lasers: vec![Timed {
+            time: 0.0,
+            inner: (
+                LaserId(0),
+                LaserCommand::Enter {
+                    y: 0.1,
+                    lanes: 4,
+                    color: (0., 0.1, 0.8).into(),
+                    surface: Default::default(),
+                    note_style: None,
+                },
+            ),
+        }],
+        sections: vec![
+            Timed {
+                time: 0.0,
+                inner: "Intro".into(),
+            },
+            Timed {
+                time: 1.0,
+                inner: "Stream".into(),
+            },
+        ],
+        zoom: Vec::new(),
+        default_bpm: 200.0,
+    };
+    (chart, None)
+}
+
+/// Deletes the spawned lasers and notes and makes the chart start over from `time`.
+fn clear_highway(world: &mut World, time: f32) {
+    {
+        let entities = world.entities();
+        let lasers = world.read_storage::<laser::Laser>();
+        let notes = world.read_storage::<laser::Note>();
+        for (entity, _) in (&*entities, &lasers).join() {
+            entities.delete(entity).unwrap();
+        }
+        for (entity, _) in (&*entities, &notes).join() {
+            entities.delete(entity).unwrap();
+        }
+    }
+    world.insert(ChartState::starting_at(time));
+}
+
+/// Removes what is left of the highway so that the next state starts from a clean slate.
+fn clear_play(world: &mut World) {
+    clear_highway(world, 0.);
+    world.insert(None::<PlaySettings>);
+    world.insert(None::<ComboBreak>);
+    world.insert(Scores::default());
+    world.insert(ActiveModifiers::default());
+    world.insert(Replay::default());
+    crash::clear_context("chart.txt");
+}
+
+/// Moves the play to chart time `time`.
+fn seek(world: &mut World, time: f32) {
+    let now = world.read_resource::<Time>().absolute_time_seconds();
+    if let Some(settings) = &mut *world.write_resource::<Option<PlaySettings>>() {
+        settings.base_time = now - time as f64;
+    }
+    clear_highway(world, time);
+}
+
+impl SimpleState for MainStage {
+    fn on_start(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        let proj = Projection::perspective(4.0 / 3.0, 90.0, 0.01, 100.0);
+        self.initialize_camera(world, proj);
+        self.initialize_chart(world);
+        #[cfg(feature = "scripting")]
+        self.initialize_scripts(world);
+        self.initialize_pacemaker(world);
+        self.initialize_course_gauge(world);
+        self.initialize_playback(world);
+        let autoplay = world.read_resource::<Autoplay>().0;
+        world.insert(Assisted(autoplay));
+        self.initialize_direct_launch(world);
+        self.started_at = world.read_resource::<Time>().absolute_real_time_seconds();
+    }
+
+    fn update(
+        &mut self,
+        StateData { world, .. }: &mut StateData<'_, GameData<'_, '_>>,
+    ) -> SimpleTrans {
+        let now = world.read_resource::<Time>().absolute_real_time_seconds();
+        if let Some(demo_until) = world.read_resource::<Kiosk>().demo_until {
+            if now > demo_until || chart_finished(world) {
+                return Trans::Switch(Box::new(SongSelectState::default()));
+            }
+            return Trans::None;
+        }
+        if world.read_resource::<Option<CourseProgress>>().is_some() {
+            return self.advance_course(world);
+        }
+        if world.read_resource::<Option<DirectLaunch>>().is_some() && chart_finished(world) {
+            return Trans::Quit;
+        }
+        if play_failed(world) {
+            let failed_at = *self.failed_at.get_or_insert_with(|| {
+                log::info!("Failed with sudden death");
+                now
+            });
+            if now - failed_at > FAILED_SECONDS {
+                return Trans::Switch(Box::new(SongSelectState::default()));
+            }
+            return Trans::None;
+        }
+        if !self.offset_checked && self.playback.is_none() && chart_finished(world) {
+            self.offset_checked = true;
+            if !world.read_resource::<Settings>().kiosk.enabled {
+                self.show_offset_suggestion(world);
+            }
+        }
+        // Unattended machines go back to song select on their own after a play.
+        let settings = world.read_resource::<Settings>();
+        if settings.kiosk.enabled && chart_finished(world) {
+            let finished_at = *self.finished_at.get_or_insert(now);
+            if now - finished_at > settings.kiosk.results_seconds {
+                return Trans::Switch(Box::new(SongSelectState::default()));
+            }
+        }
+        Trans::None
+    }
+
+    fn handle_event(
+        &mut self,
+        StateData { world, .. }: StateData<'_, GameData<'_, '_>>,
+        event: StateEvent,
+    ) -> SimpleTrans {
+        let event = match event {
+            StateEvent::Window(event) => event,
+            _ => return Trans::None,
+        };
+        if kiosk::exit_requested(world, &event) {
+            return Trans::Quit;
+        }
+        // Any key ends a demo.
+        if let Event::WindowEvent {
+            event:
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            ..
+                        },
+                    ..
+                },
+            ..
+        } = event
+        {
+            if world.read_resource::<Kiosk>().demo_until.is_some() {
+                return Trans::Switch(Box::new(SongSelectState::default()));
+            }
+        }
+        if let Some(controls) = &mut self.playback {
+            controls.handle_event(world, &event);
+        }
+        if is_key_down(&event, VirtualKeyCode::Escape) && !world.read_resource::<Console>().open {
+            return Trans::Push(Box::new(PauseState::default()));
+        }
+        if is_key_down(&event, VirtualKeyCode::Return) {
+            if let Some(offset) = self.suggested_offset.take() {
+                world.write_resource::<Settings>().gameplay.offset = offset;
+                log::info!("Offset set to {:+.0} ms", offset * 1000.);
+                if let Some(text) = self.offset_text {
+                    if let Some(text) = world.write_storage::<UiText>().get_mut(text) {
+                        text.text = format!("Offset set to {:+.0} ms.", offset * 1000.);
+                    }
+                }
+            }
+        }
+        Trans::None
+    }
+
+    fn on_stop(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        if let Some(text) = self.offset_text.take() {
+            world.delete_entity(text).unwrap();
+        }
+        if let Some(camera) = self.camera.take() {
+            if world.read_resource::<ActiveCamera>().entity == Some(camera) {
+                world.insert(ActiveCamera::default());
+            }
+            world.delete_entity(camera).unwrap();
+        }
+        // Read before autoplay is turned off below.
+        let assisted = world.read_resource::<Assisted>().0;
+        // A play started from song select after leaving a launched chart is a normal one.
+        let launch = world.write_resource::<Option<DirectLaunch>>().take();
+        if launch.as_ref().map_or(false, |launch| launch.autoplay) {
+            world.insert(Autoplay(false));
+        }
+        if world.write_resource::<Kiosk>().demo_until.take().is_some() {
+            // Demos are not the player's plays.
+            world.insert(Autoplay(false));
+            clear_play(world);
+            return;
+        }
+        if let Some(mut controls) = self.playback.take() {
+            // Watching a replay is not a play either.
+            controls.stop(world);
+            if let Some(live) = self.live_key_maps.take() {
+                world.insert(live);
+            }
+            world.insert(None::<ReplayPlayback>);
+            clear_play(world);
+            return;
+        }
+        let profile = world.read_resource::<Profile>().clone();
+        let dir = profile.replays_dir();
+        let replay = {
+            let mut replay = world.fetch::<Replay>().clone();
+            replay.song = world
+                .read_resource::<Option<library::Song>>()
+                .as_ref()
+                .map(|song| song.path.clone());
+            replay.key_maps = world.read_resource::<KeyMappings>().0.clone();
+            replay
+        };
+        if let Err(e) = save_queue::save(dir.join("latest.ron"), &replay) {
+            log::warn!("Failed to save replay: {}", e);
+        }
+        if let Some(path) = launch.and_then(|launch| launch.record) {
+            if let Err(e) = save_queue::save(&path, &replay) {
+                log::warn!("Failed to save replay to {}: {}", path.display(), e);
+            }
+        }
+        let retried = world
+            .try_fetch::<Retrying>()
+            .map_or(false, |retrying| retrying.0);
+        world.insert(Retrying(false));
+        if assisted {
+            log::info!("Assisted play, not saved as a score");
+            finish_play(world);
+            return;
+        }
+        if let Some(entry) = self.history_entry(world, retried) {
+            if let Err(e) = profile.record_play(entry) {
+                log::warn!("Failed to save play history: {}", e);
+            }
+        }
+        let records: Vec<_> = world
+            .read_resource::<Scores>()
+            .players
+            .iter()
+            .flat_map(|score| score.records.iter().cloned())
+            .collect();
+        if let Err(e) = profile.record_key_stats(&records) {
+            log::warn!("Failed to save key statistics: {}", e);
+        }
+        if let Err(e) = self.save_personal_best(world) {
+            log::warn!("Failed to save score: {}", e);
+        }
+        finish_play(world);
+    }
+}
+
+/// Saves the speed of the chart and the settings after a play, and clears it.
+fn finish_play(world: &mut World) {
+    if let Err(e) = remember_speed(world) {
+        log::warn!("Failed to save the speed of the chart: {}", e);
+    }
+    if let Err(e) = save_settings(world) {
+        log::warn!("Failed to save settings: {}", e);
+    }
+    clear_play(world);
+}
+
+fn save_settings(world: &World) -> Result<(), IrisError> {
+    let geometry = geometry::capture(&world.read_resource::<Window>());
+    let mut settings = world.write_resource::<Settings>();
+    // Graphics lowered for the play that just ended are not the user's choice.
+    world
+        .write_resource::<AdaptiveQuality>()
+        .restore(&mut settings.graphics);
+    // Keep the windowed geometry to return to when leaving fullscreen.
+    if !settings.graphics.fullscreen {
+        settings.window = geometry;
+    }
+    save_queue::save(world.read_resource::<Profile>().settings_path(), &*settings)?;
+    crash::snapshot_settings(&settings);
+    Ok(())
+}
+
+pub struct InterFont(pub FontHandle);
+
+#[derive(Default, Debug)]
+pub struct RenderToWindowWithStencil {
+    dirty: bool,
+    clear: Option<ClearColor>,
+    depth_clear: Option<ClearDepthStencil>,
+    config: Option<DisplayConfig>,
+    dimensions: Option<ScreenDimensions>,
+    graphics: Option<GraphicsSettings>,
+    first_run: bool,
+}
+
+impl RenderToWindowWithStencil {
+    /// Create RenderToWindow plugin with [`WindowBundle`] using specified config.
+    pub fn from_config(display_config: DisplayConfig) -> Self {
+        Self {
+            config: Some(display_config),
+            ..Default::default()
+        }
+    }
+
+    /// Clear window with specified color every frame.
+    pub fn with_clear(mut self, clear: impl Into<ClearColor>) -> Self {
+        self.clear = Some(clear.into());
+        self
+    }
+
+    /// Lowers the graphics settings to what the GPU supports when the window is first planned,
+    /// for profiles that have no saved settings yet.
+    pub fn with_safe_defaults(mut self, first_run: bool) -> Self {
+        self.first_run = first_run;
+        self
+    }
+}
+
+impl<B: Backend> RenderPlugin<B> for RenderToWindowWithStencil {
+    fn on_build<'a, 'b>(
+        &mut self,
+        world: &mut World,
+        builder: &mut DispatcherBuilder<'a, 'b>,
+    ) -> Result<(), amethyst::error::Error> {
+        if let Some(config) = self.config.take() {
+            WindowBundle::from_config(config).build(world, builder)?;
+        }
+        world.insert(None::<GpuInfo>);
+        world.insert(StencilAllocator::default());
+
+        Ok(())
+    }
+
+    #[allow(clippy::map_clone)]
+    fn should_rebuild(&mut self, world: &World) -> bool {
+        let new_dimensions = world.try_fetch::<ScreenDimensions>();
+        use std::ops::Deref;
+        if self.dimensions.as_ref() != new_dimensions.as_ref().map(|d| d.deref()) {
+            self.dirty = true;
+            self.dimensions = new_dimensions.map(|d| d.deref().clone());
+            return false;
+        }
+        let graphics = world.try_fetch::<Settings>().map(|s| s.graphics.clone());
+        if self.graphics != graphics {
+            self.graphics = graphics;
+            self.dirty = true;
+        }
+        self.dirty
+    }
+
+    fn on_plan(
+        &mut self,
+        plan: &mut RenderPlan<B>,
+        factory: &mut Factory<B>,
+        world: &World,
+    ) -> Result<(), amethyst::error::Error> {
+        self.dirty = false;
+
+        let info = GpuInfo::probe(factory);
+        crash::set_context("gpu.txt", format!("{:#?}", info));
+        if let (true, Some(mut settings)) = (self.first_run, world.try_fetch_mut::<Settings>()) {
+            self.first_run = false;
+            let dimensions = self.dimensions.as_ref().unwrap();
+            info.apply_safe_defaults(
+                &mut settings.graphics,
+                (dimensions.width(), dimensions.height()),
+            );
+            // Planned with the new settings right away, so they don't trigger a rebuild.
+            self.graphics = Some(settings.graphics.clone());
+        }
+
+        let window = <ReadExpect<'_, Window>>::fetch(world);
+        let surface = factory.create_surface(&window);
+        let dimensions = self.dimensions.as_ref().unwrap();
+        let window_kind = Kind::D2(dimensions.width() as u32, dimensions.height() as u32, 1, 1);
+        let offscreen = bloom::offscreen_enabled(world);
+        let scene_kind = if offscreen {
+            let (width, height) = bloom::scene_size(world);
+            Kind::D2(width, height, 1, 1)
+        } else {
+            window_kind
+        };
+
+        let format = info.depth_stencil_format.ok_or_else(|| {
+            amethyst::error::Error::from_string("None of the stencil formats are supported")
+        })?;
+        *world.write_resource::<Option<GpuInfo>>() = Some(info);
+
+        let depth_options = ImageOptions {
+            kind: scene_kind,
+            levels: 1,
+            format,
+            clear: Some(ClearValue::DepthStencil(ClearDepthStencil(1.0, 0))),
+        };
+
+        if offscreen {
+            // Render the scene offscreen and let the bloom plugin present it.
+            plan.add_root(bloom::PRESENT);
+            plan.define_pass(
+                Target::Main,
+                TargetPlanOutputs {
+                    colors: vec![OutputColor::Image(ImageOptions {
+                        kind: scene_kind,
+                        levels: 1,
+                        format: bloom::HDR_FORMAT,
+                        clear: self.clear.map(ClearValue::Color),
+                    })],
+                    depth: Some(depth_options),
+                },
+            )?;
+            plan.define_pass(
+                bloom::PRESENT,
+                TargetPlanOutputs {
+                    colors: vec![OutputColor::Surface(surface, None)],
+                    depth: None,
+                },
+            )?;
+        } else {
+            plan.add_root(Target::Main);
+            plan.define_pass(
+                Target::Main,
+                TargetPlanOutputs {
+                    colors: vec![OutputColor::Surface(
+                        surface,
+                        self.clear.map(ClearValue::Color),
+                    )],
+                    depth: Some(depth_options),
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use crate::pack::PackIndex;
+use crate::vfs::{FileSystem, Vfs};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Name of the metadata file that marks a song directory.
+pub const SONG_FILE: &str = "song.ron";
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SongMeta {
+    pub title: String,
+    pub artist: String,
+    pub level: u32,
+    /// Jacket image relative to the song directory.
+    pub jacket: Option<String>,
+    /// Image of the densest part of the chart relative to the song directory, rendered on
+    /// import.
+    pub thumbnail: Option<String>,
+    /// The chart this song was imported from.
+    pub source: Option<PathBuf>,
+    pub bpm: Option<BpmRange>,
+    /// Seconds to add to the note times to line them up with the beats detected in the audio,
+    /// suggested on import with the `onset` feature. Only informational; it is never applied.
+    pub suggested_offset: Option<f32>,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct BpmRange {
+    /// The BPM the chart starts with, which the scroll speed is relative to.
+    pub base: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Song {
+    pub meta: SongMeta,
+    /// Directory of the song relative to the library root, which may run through a zip archive.
+    pub path: PathBuf,
+}
+
+impl Song {
+    /// The song in `dir` outside the library, such as a chart launched from the command line.
+    /// Without readable metadata, the song is titled after its directory.
+    pub fn open(dir: PathBuf) -> Self {
+        let meta_path = dir.join(SONG_FILE);
+        let meta = std::fs::read(&meta_path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| ron::de::from_bytes(&bytes).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| {
+                log::warn!("Failed to read {}: {}", meta_path.display(), e);
+                SongMeta {
+                    title: dir
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                    ..Default::default()
+                }
+            });
+        Self { meta, path: dir }
+    }
+
+    /// The directory containing the song directory, usually the pack it came from.
+    pub fn folder(&self) -> String {
+        self.path
+            .parent()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+}
+
+/// How songs are grouped under headers in song select.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GroupBy {
+    Folder,
+    Level,
+    Artist,
+}
+
+impl GroupBy {
+    pub fn next(self) -> Self {
+        match self {
+            GroupBy::Folder => GroupBy::Level,
+            GroupBy::Level => GroupBy::Artist,
+            GroupBy::Artist => GroupBy::Folder,
+        }
+    }
+
+    /// The header a song is listed under. Headers sort in display order.
+    pub fn key(self, song: &Song) -> String {
+        match self {
+            GroupBy::Folder => song.folder(),
+            GroupBy::Level => format!("Level {:02}", song.meta.level),
+            GroupBy::Artist => song.meta.artist.clone(),
+        }
+    }
+}
+
+/// All songs found under the library root.
+#[derive(Default)]
+pub struct Library {
+    pub songs: Vec<Song>,
+}
+
+impl Library {
+    /// Finds every directory with a [`SONG_FILE`] below `root`, including those inside zip
+    /// archives, leaving out disabled packs.
+    pub fn scan(root: &Path) -> Self {
+        let mut songs = Vec::new();
+        scan_dir(&FileSystem::new(root), Path::new(""), &mut songs);
+        let packs = PackIndex::open(root);
+        songs.retain(|song| packs.is_enabled(&song.path));
+        Self { songs }
+    }
+
+    /// Indices into `songs` by header, each group sorted by title.
+    pub fn groups(&self, group_by: GroupBy) -> BTreeMap<String, Vec<usize>> {
+        let mut groups = BTreeMap::<_, Vec<_>>::new();
+        for (i, song) in self.songs.iter().enumerate() {
+            groups.entry(group_by.key(song)).or_default().push(i);
+        }
+        for group in groups.values_mut() {
+            group.sort_by(|&a, &b| self.songs[a].meta.title.cmp(&self.songs[b].meta.title));
+        }
+        groups
+    }
+}
+
+fn scan_dir(fs: &FileSystem, dir: &Path, songs: &mut Vec<Song>) {
+    let entries = match fs.read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!("Failed to scan {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    for entry in entries {
+        let hidden = entry
+            .path
+            .file_name()
+            .map_or(false, |name| name.to_string_lossy().starts_with('.'));
+        // Hidden directories include packs being extracted.
+        if !entry.is_dir || hidden {
+            continue;
+        }
+        let meta_path = entry.path.join(SONG_FILE);
+        if !fs.is_file(&meta_path) {
+            scan_dir(fs, &entry.path, songs);
+            continue;
+        }
+        let meta = fs
+            .read(&meta_path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| ron::de::from_bytes(&bytes).map_err(|e| e.to_string()));
+        match meta {
+            Ok(meta) => songs.push(Song {
+                meta,
+                path: entry.path,
+            }),
+            Err(e) => log::warn!("Failed to read {}: {}", meta_path.display(), e),
+        }
+    }
+}
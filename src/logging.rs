@@ -0,0 +1,101 @@
+use crate::crash;
+use crate::error::IrisError;
+use crate::settings::LogSettings;
+use log::LevelFilter;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `path` with `.n` appended, the name of the `n`th older log file.
+fn rotated(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Shifts the previous log files by one, dropping the oldest, so that each run starts a new file.
+fn rotate(path: &Path, keep_files: u32) -> std::io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    if keep_files == 0 {
+        return std::fs::remove_file(path);
+    }
+    for n in (1..keep_files).rev() {
+        let from = rotated(path, n);
+        if from.exists() {
+            std::fs::rename(from, rotated(path, n + 1))?;
+        }
+    }
+    std::fs::rename(path, rotated(path, 1))
+}
+
+/// Starts logging to the console and, if configured, to a file below `root`. The last lines are
+/// also kept for crash reports.
+///
+/// Levels that don't parse are reported once logging is up and fall back to `info`.
+pub fn init(root: &Path, settings: &LogSettings) -> Result<(), IrisError> {
+    let mut invalid = Vec::new();
+    let mut parse = |level: &str| {
+        level.parse().unwrap_or_else(|_| {
+            invalid.push(level.to_string());
+            LevelFilter::Info
+        })
+    };
+    let mut dispatch = fern::Dispatch::new().level(parse(&settings.level));
+    for (module, level) in &settings.modules {
+        dispatch = dispatch.level_for(module.clone(), parse(level));
+    }
+
+    let console = fern::Dispatch::new()
+        .format(|out, message, record| {
+            out.finish(format_args!(
+                "[{}][{}] {}",
+                record.level(),
+                record.target(),
+                message
+            ))
+        })
+        .chain(std::io::stdout());
+    dispatch = dispatch
+        .chain(console)
+        .chain(fern::Output::call(crash::record_log));
+
+    if let Some(file) = &settings.file {
+        let path = root.join(file);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        rotate(&path, settings.keep_files)?;
+        let mut file = fern::Dispatch::new();
+        file = if settings.json {
+            file.format(|out, message, record| {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0., |d| d.as_secs_f64());
+                let line = serde_json::json!({
+                    "time": timestamp,
+                    "level": record.level().to_string(),
+                    "target": record.target(),
+                    "message": message.to_string(),
+                });
+                out.finish(format_args!("{}", line))
+            })
+        } else {
+            file.format(|out, message, record| {
+                out.finish(format_args!(
+                    "[{}][{}] {}",
+                    record.level(),
+                    record.target(),
+                    message
+                ))
+            })
+        };
+        dispatch = dispatch.chain(file.chain(fern::log_file(path)?));
+    }
+
+    dispatch.apply()?;
+    for level in invalid {
+        log::warn!("Unknown log level `{}`, using info", level);
+    }
+    Ok(())
+}
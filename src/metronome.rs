@@ -0,0 +1,143 @@
+use crate::audio::{AudioOutput, Channel};
+use crate::chart::{timing::position_for_time, Chart, PlaySettings};
+use crate::judge_sound::{load_sound, Sound};
+use crate::paths::Paths;
+use crate::settings::Settings;
+use crate::theme::Theme;
+use crate::ui::percent_transform;
+use crate::vfs::FileSystem;
+use amethyst::{
+    core::{timing::Time, SystemDesc},
+    ecs::{Entities, Entity, Read, ReadExpect, System, SystemData, World, WriteStorage},
+    ui::{Anchor, UiImage, UiTransform},
+};
+use rodio::{source::SineWave, Source};
+use std::time::Duration;
+
+/// Pitch of the click played when the theme has no metronome sound.
+const CLICK_HZ: u32 = 1760;
+const CLICK_DURATION: Duration = Duration::from_millis(30);
+const CLICK_VOLUME: f32 = 0.4;
+/// Fraction of a beat the beat lamp stays lit for.
+const LAMP_BEATS: f32 = 0.25;
+pub(crate) const LAMP_COLOR: [f32; 3] = [1., 0.9, 0.2];
+/// Brightness of the lamp fading over the whole beat with flashes suppressed.
+const STEADY_LAMP: f32 = 0.5;
+
+/// A short synthesized click, the metronome sound of themes without one.
+pub(crate) fn click() -> impl Source<Item = f32> + Send {
+    SineWave::new(CLICK_HZ)
+        .take_duration(CLICK_DURATION)
+        .amplify(CLICK_VOLUME)
+}
+
+/// Brightness of a beat lamp `position` beats into the beat, which only fades out gently with
+/// flashes suppressed.
+pub(crate) fn lamp_brightness(position: f32, suppress_flashes: bool) -> f32 {
+    let fraction = position.fract();
+    if suppress_flashes {
+        STEADY_LAMP * (1. - fraction)
+    } else {
+        (1. - fraction / LAMP_BEATS).max(0.)
+    }
+}
+
+/// Ticks on every beat of the main BPM track in [silent practice] and flashes a lamp below the
+/// BPM display, so that the chart can be followed without its music.
+///
+/// [silent practice]: crate::settings::GameplaySettings::silent_practice
+pub struct MetronomeSystem {
+    /// The theme's metronome sound; a synthesized click if unset.
+    sound: Option<Sound>,
+    /// The last beat ticked, reset when time goes backwards so that seeking doesn't tick.
+    last_beat: Option<i64>,
+    lamp: Option<Entity>,
+}
+
+pub struct MetronomeSystemDesc;
+
+impl<'a, 'b> SystemDesc<'a, 'b, MetronomeSystem> for MetronomeSystemDesc {
+    fn build(self, world: &mut World) -> MetronomeSystem {
+        <MetronomeSystem as System<'_>>::SystemData::setup(world);
+
+        let theme = world.read_resource::<Theme>();
+        let fs = FileSystem::new(&world.read_resource::<Paths>().resources);
+        MetronomeSystem {
+            sound: load_sound(&fs, &theme.metronome_sound),
+            last_beat: None,
+            lamp: None,
+        }
+    }
+}
+
+impl<'s> System<'s> for MetronomeSystem {
+    type SystemData = (
+        Entities<'s>,
+        ReadExpect<'s, Time>,
+        Read<'s, Option<Chart>>,
+        Read<'s, Option<PlaySettings>>,
+        Read<'s, Settings>,
+        Read<'s, Option<AudioOutput>>,
+        WriteStorage<'s, UiTransform>,
+        WriteStorage<'s, UiImage>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            time,
+            chart,
+            play_settings,
+            settings,
+            output,
+            mut transforms,
+            mut images,
+        ): Self::SystemData,
+    ) {
+        let lamp = *self.lamp.get_or_insert_with(|| {
+            let entity = entities.create();
+            transforms
+                .insert(
+                    entity,
+                    percent_transform("BeatLamp", Anchor::TopMiddle, 0., -0.15, 0.06, 0.015),
+                )
+                .unwrap();
+            images.insert(entity, UiImage::SolidColor([0.; 4])).unwrap();
+            entity
+        });
+        let position = match (&*chart, &*play_settings) {
+            (Some(chart), Some(play_settings))
+                if settings.gameplay.silent_practice && !chart.bpm.is_empty() =>
+            {
+                let now = (time.absolute_time_seconds() - play_settings.base_time) as f32;
+                Some(position_for_time(&chart.bpm, now))
+            }
+            _ => None,
+        };
+
+        let beat = position.map(|position| position.floor() as i64);
+        let ticked = match (beat, self.last_beat) {
+            (Some(beat), Some(last)) => beat > last && beat >= 0,
+            _ => false,
+        };
+        self.last_beat = beat;
+        if let (true, Some(output)) = (ticked, &*output) {
+            match &self.sound {
+                Some(sound) => output.play(Channel::Effect, sound.clone()),
+                None => output.play(Channel::Effect, click()),
+            }
+        }
+
+        let brightness = match position {
+            Some(position) if position >= 0. => {
+                lamp_brightness(position, settings.accessibility.suppress_flashes)
+            }
+            _ => 0.,
+        };
+        let [r, g, b] = LAMP_COLOR;
+        if let Some(image) = images.get_mut(lamp) {
+            *image = UiImage::SolidColor([r, g, b, brightness]);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use crate::error::IrisError;
+use crate::save_queue;
+use amethyst::config::Config;
+use serde::{de::DeserializeOwned, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Files that record the version of their format so that older ones can be upgraded on load.
+///
+/// Renamed fields are handled with serde aliases and new fields with defaults; migrations are for
+/// changes in meaning, such as a value moving to different units.
+pub trait Versioned: Config + Default + Serialize + DeserializeOwned {
+    /// Upgrades from each older version to the next, starting with files written before versions
+    /// were recorded. The current version is the number of migrations.
+    const MIGRATIONS: &'static [fn(&mut Self)];
+
+    fn version_mut(&mut self) -> &mut u32;
+
+    fn current_version() -> u32 {
+        Self::MIGRATIONS.len() as u32
+    }
+}
+
+/// The version of files without one, for `#[serde(default = "...")]` on the version field.
+pub fn unversioned() -> u32 {
+    0
+}
+
+/// A migration for versions that only added fields or changed nothing that needs converting.
+pub fn no_changes<T>(_: &mut T) {}
+
+/// Where the original of a file upgraded from `version` is kept.
+fn backup_path(path: &Path, version: u32) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!(".v{}.bak", version));
+    path.with_file_name(name)
+}
+
+/// Upgrades `config`, loaded from `path`, to the current version. Returns the version it had if
+/// it was upgraded. Files from a newer version are loaded as far as they can be.
+fn upgrade<T: Versioned>(config: &mut T, path: &Path) -> Option<u32> {
+    let version = *config.version_mut();
+    let current = T::current_version();
+    if version > current {
+        log::warn!(
+            "{} is from a newer version of the game, some of it may be ignored",
+            path.display()
+        );
+    }
+    if version >= current {
+        return None;
+    }
+    for migration in &T::MIGRATIONS[version as usize..] {
+        migration(config);
+    }
+    *config.version_mut() = current;
+    Some(version)
+}
+
+/// Loads `path` and upgrades it to the current version, saving the upgraded file in its place and
+/// a backup of the original next to it.
+pub fn load<T: Versioned>(path: &Path) -> Result<T, IrisError> {
+    let mut config: T = save_queue::load(path)?;
+    if let Some(version) = upgrade(&mut config, path) {
+        let backup = backup_path(path, version);
+        save_queue::copy(path, &backup)?;
+        save_queue::save(path, &config)?;
+        log::info!(
+            "Upgraded {} to version {}, the original is kept as {}",
+            path.display(),
+            T::current_version(),
+            backup.display()
+        );
+    }
+    Ok(config)
+}
+
+/// [`load`], falling back to the default like [`Config::load`].
+pub fn load_or_default<T: Versioned>(path: &Path) -> T {
+    load(path).unwrap_or_else(|e| {
+        log::warn!("Failed to load {}: {}", path.display(), e);
+        T::default()
+    })
+}
+
+/// [`load_or_default`] for files in the resources directory, which are only upgraded in memory:
+/// the game never writes to its resources.
+pub fn load_resource_or_default<T: Versioned>(path: &Path) -> T {
+    let mut config = save_queue::load::<T>(path).unwrap_or_else(|e| {
+        log::warn!("Failed to load {}: {}", path.display(), e);
+        T::default()
+    });
+    upgrade(&mut config, path);
+    config
+}
@@ -0,0 +1,144 @@
+use crate::chart::{Chart, LaserCommand, LaserId};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Modifiers of a play. Some rearrange the notes of the chart before it is played, the others
+/// make it harder and are looked up in [`ActiveModifiers`] while playing.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Modifier {
+    /// Reverses the lanes of every laser.
+    Mirror,
+    /// Permutes the lanes of every laser, using the seed of the play.
+    Shuffle,
+    /// Notes fade out as they approach the judge line.
+    Hidden,
+    /// The first miss ends the play.
+    SuddenDeath,
+    /// Hits outside the perfect window are misses.
+    PerfectOnly,
+}
+
+impl Modifier {
+    pub fn name(self) -> &'static str {
+        match self {
+            Modifier::Mirror => "Mirror",
+            Modifier::Shuffle => "Shuffle",
+            Modifier::Hidden => "Hidden",
+            Modifier::SuddenDeath => "Sudden death",
+            Modifier::PerfectOnly => "Perfect only",
+        }
+    }
+
+    /// Short tag standing for the modifier in the HUD and on leaderboards.
+    pub fn icon(self) -> &'static str {
+        match self {
+            Modifier::Mirror => "MIR",
+            Modifier::Shuffle => "SHF",
+            Modifier::Hidden => "HID",
+            Modifier::SuddenDeath => "SD",
+            Modifier::PerfectOnly => "PFO",
+        }
+    }
+
+    /// Factor the score is multiplied by. Rearranging the notes doesn't make a chart harder.
+    pub fn multiplier(self) -> f64 {
+        match self {
+            Modifier::Mirror | Modifier::Shuffle => 1.,
+            Modifier::Hidden => 1.06,
+            Modifier::SuddenDeath => 1.1,
+            Modifier::PerfectOnly => 1.2,
+        }
+    }
+}
+
+/// The product of the multipliers of `modifiers`.
+pub fn multiplier(modifiers: &[Modifier]) -> f64 {
+    modifiers.iter().map(|m| m.multiplier()).product()
+}
+
+/// The icons of `modifiers`, separated by spaces.
+pub fn icons(modifiers: &[Modifier]) -> String {
+    let icons: Vec<_> = modifiers.iter().map(|m| m.icon()).collect();
+    icons.join(" ")
+}
+
+/// The modifiers of the play, from the [daily challenge] or the [gameplay settings]. The judge,
+/// score and render systems query it rather than the sources, so that it stays the same for the
+/// whole play.
+///
+/// [daily challenge]: crate::challenge::Challenge
+/// [gameplay settings]: crate::settings::GameplaySettings::modifiers
+#[derive(Clone, Debug, Default)]
+pub struct ActiveModifiers(pub Vec<Modifier>);
+
+impl ActiveModifiers {
+    pub fn contains(&self, modifier: Modifier) -> bool {
+        self.0.contains(&modifier)
+    }
+}
+
+/// SplitMix64, so that seeded plays are reproducible on every platform and build.
+pub struct SeedRng(u64);
+
+impl SeedRng {
+    pub fn new(seed: u64) -> Self {
+        SeedRng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`. `n` must not be zero.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// Applies the modifiers that rearrange notes in order to the notes of `chart`.
+pub fn apply(chart: &mut Chart, modifiers: &[Modifier], seed: u64) {
+    let mut lanes = BTreeMap::<LaserId, u32>::new();
+    for command in &chart.lasers {
+        if let LaserCommand::Enter { lanes: count, .. } = &command.1 {
+            lanes.entry(command.0).or_insert(u32::from(*count));
+        }
+    }
+    let mut rng = SeedRng::new(seed);
+    for modifier in modifiers {
+        match modifier {
+            Modifier::Mirror | Modifier::Shuffle => {}
+            // The others leave the notes alone.
+            Modifier::Hidden | Modifier::SuddenDeath | Modifier::PerfectOnly => continue,
+        }
+        let permutations: BTreeMap<LaserId, Vec<u32>> = lanes
+            .iter()
+            .map(|(&laser, &count)| {
+                let mut permutation: Vec<u32> = (0..count).collect();
+                match modifier {
+                    Modifier::Mirror => permutation.reverse(),
+                    Modifier::Shuffle => {
+                        for i in (1..permutation.len()).rev() {
+                            permutation.swap(i, rng.below(i + 1));
+                        }
+                    }
+                    Modifier::Hidden | Modifier::SuddenDeath | Modifier::PerfectOnly => {
+                        unreachable!()
+                    }
+                }
+                (laser, permutation)
+            })
+            .collect();
+        for note in &mut chart.notes {
+            if let Some(&lane) = permutations
+                .get(&note.laser)
+                .and_then(|p| p.get(note.lane as usize))
+            {
+                note.inner.lane = lane;
+            }
+        }
+    }
+}
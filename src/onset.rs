@@ -0,0 +1,139 @@
+//! Onset detection on song audio, enabled with the `onset` feature, for estimating how far the
+//! beats of the audio are from the chart's beat grid.
+//!
+//! Onsets are peaks of the spectral flux: the summed increase of the magnitude spectrum from one
+//! frame to the next, which rises sharply on drum hits and note attacks.
+use crate::error::AudioError;
+use crate::vfs::Vfs;
+use rodio::{Decoder, Source};
+use rustfft::num_complex::Complex;
+use rustfft::FFTplanner;
+use std::io::Cursor;
+use std::path::Path;
+
+/// Samples per analysis frame.
+const FRAME_SIZE: usize = 1024;
+/// Samples between the starts of consecutive frames.
+const HOP_SIZE: usize = 512;
+/// Only the start of the song is analyzed, which is where the offset is decided.
+const MAX_SECONDS: u32 = 60;
+/// Frames on each side of a frame whose flux it is compared against for peak picking.
+const PEAK_RADIUS: usize = 8;
+/// How far a peak must rise above the median of its neighborhood, relative to the mean flux.
+const PEAK_THRESHOLD: f32 = 0.5;
+
+/// An onset at `time` seconds from the start of the audio.
+#[derive(Copy, Clone, Debug)]
+pub struct Onset {
+    pub time: f32,
+    /// The flux at the onset, for weighting.
+    pub strength: f32,
+}
+
+/// Up to [`MAX_SECONDS`] of the audio at `path`, mixed down to mono, and its sample rate.
+fn decode_mono(fs: &dyn Vfs, path: &Path) -> Result<(Vec<f32>, u32), AudioError> {
+    let decode_error = |message: String| AudioError::Decode {
+        path: path.to_owned(),
+        message,
+    };
+    let bytes = fs.read(path).map_err(|e| decode_error(e.to_string()))?;
+    let decoder = Decoder::new(Cursor::new(bytes)).map_err(|e| decode_error(e.to_string()))?;
+    let channels = decoder.channels().max(1) as usize;
+    let rate = decoder.sample_rate();
+    let interleaved: Vec<i16> = decoder
+        .take((rate * MAX_SECONDS) as usize * channels)
+        .collect();
+    let samples = interleaved
+        .chunks(channels)
+        .map(|frame| frame.iter().map(|&s| f32::from(s)).sum::<f32>() / channels as f32 / 32768.)
+        .collect();
+    Ok((samples, rate))
+}
+
+/// Spectral flux of each frame of `samples`.
+fn spectral_flux(samples: &[f32]) -> Vec<f32> {
+    let fft = FFTplanner::new(false).plan_fft(FRAME_SIZE);
+    // A Hann window keeps the frame edges from smearing the spectrum.
+    let window: Vec<f32> = (0..FRAME_SIZE)
+        .map(|i| 0.5 - 0.5 * (2. * std::f32::consts::PI * i as f32 / FRAME_SIZE as f32).cos())
+        .collect();
+    let mut previous = vec![0.; FRAME_SIZE / 2];
+    let mut output = vec![Complex::new(0., 0.); FRAME_SIZE];
+    let mut flux = Vec::new();
+    let mut start = 0;
+    while start + FRAME_SIZE <= samples.len() {
+        let mut input: Vec<_> = samples[start..start + FRAME_SIZE]
+            .iter()
+            .zip(&window)
+            .map(|(s, w)| Complex::new(s * w, 0.))
+            .collect();
+        fft.process(&mut input, &mut output);
+        let mut sum = 0.;
+        for (bin, previous) in output[..FRAME_SIZE / 2].iter().zip(&mut previous) {
+            // Log compression keeps loud bass from drowning out everything else.
+            let magnitude = (1. + bin.norm()).ln();
+            sum += (magnitude - *previous).max(0.);
+            *previous = magnitude;
+        }
+        flux.push(sum);
+        start += HOP_SIZE;
+    }
+    flux
+}
+
+/// Frames of `flux` that are the largest in their neighborhood and clearly above its median.
+fn pick_peaks(flux: &[f32], frame_seconds: f32) -> Vec<Onset> {
+    let mean = flux.iter().sum::<f32>() / flux.len().max(1) as f32;
+    let mut onsets = Vec::new();
+    for i in 0..flux.len() {
+        let neighborhood =
+            &flux[i.saturating_sub(PEAK_RADIUS)..(i + PEAK_RADIUS + 1).min(flux.len())];
+        let mut sorted = neighborhood.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let median = sorted[sorted.len() / 2];
+        let is_max = neighborhood.iter().all(|&f| f <= flux[i]);
+        if is_max && flux[i] > median + PEAK_THRESHOLD * mean {
+            onsets.push(Onset {
+                time: i as f32 * frame_seconds,
+                strength: flux[i],
+            });
+        }
+    }
+    onsets
+}
+
+/// The onsets in the first [`MAX_SECONDS`] of the audio at `path`.
+pub fn detect(fs: &dyn Vfs, path: &Path) -> Result<Vec<Onset>, AudioError> {
+    let (samples, rate) = decode_mono(fs, path)?;
+    let flux = spectral_flux(&samples);
+    Ok(pick_peaks(&flux, HOP_SIZE as f32 / rate as f32))
+}
+
+/// Seconds by which the beats in `onsets` lag a grid of `bpm` starting at zero, between minus and
+/// plus half a beat. `None` without onsets.
+///
+/// The phase is the strength-weighted circular mean of the onsets on the beat, so that off-beat
+/// notes and the odd missed peak don't pull it much.
+pub fn beat_offset(onsets: &[Onset], bpm: f32) -> Option<f32> {
+    if onsets.is_empty() || bpm <= 0. {
+        return None;
+    }
+    let period = 60. / bpm;
+    let (sin, cos) = onsets.iter().fold((0_f32, 0_f32), |(sin, cos), onset| {
+        let angle = 2. * std::f32::consts::PI * onset.time / period;
+        (
+            sin + onset.strength * angle.sin(),
+            cos + onset.strength * angle.cos(),
+        )
+    });
+    Some(sin.atan2(cos) / (2. * std::f32::consts::PI) * period)
+}
+
+/// The first beat of the audio: the first onset, moved onto the beat phase found by
+/// [`beat_offset`].
+pub fn first_beat(onsets: &[Onset], bpm: f32) -> Option<f32> {
+    let offset = beat_offset(onsets, bpm)?;
+    let period = 60. / bpm;
+    let first = onsets[0].time;
+    Some(offset + ((first - offset) / period).round() * period)
+}
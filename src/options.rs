@@ -0,0 +1,280 @@
+use crate::kiosk::Kiosk;
+use crate::quality::AdaptiveQuality;
+use crate::settings::{GraphicsSettings, Settings};
+use crate::ui::percent_transform;
+use crate::InterFont;
+use amethyst::{
+    core::SystemDesc,
+    ecs::{Entities, Entity, Read, ReadExpect, System, SystemData, World, Write, WriteStorage},
+    shrev::{EventChannel, ReaderId},
+    ui::{Anchor, UiText, UiTransform},
+    window::{ScreenDimensions, Window},
+    winit::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
+};
+
+/// Font size of menu text in logical pixels.
+const MENU_FONT_SIZE: f32 = 24.;
+const MENU_KEY: VirtualKeyCode = VirtualKeyCode::F10;
+const RESOLUTION_SCALE_STEP: f32 = 0.25;
+const MIN_RESOLUTION_SCALE: f32 = 0.25;
+const MAX_RESOLUTION_SCALE: f32 = 2.;
+const VOLUME_STEP: f32 = 0.1;
+
+#[derive(Copy, Clone)]
+enum MenuOption {
+    Fullscreen,
+    ResolutionScale,
+    Bloom,
+    AdaptiveQuality,
+    EdgeAntialiasing,
+    MusicVolume,
+    KeysoundVolume,
+    EffectVolume,
+    JudgeVolume,
+}
+
+const OPTIONS: [MenuOption; 9] = [
+    MenuOption::Fullscreen,
+    MenuOption::ResolutionScale,
+    MenuOption::Bloom,
+    MenuOption::AdaptiveQuality,
+    MenuOption::EdgeAntialiasing,
+    MenuOption::MusicVolume,
+    MenuOption::KeysoundVolume,
+    MenuOption::EffectVolume,
+    MenuOption::JudgeVolume,
+];
+
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "On"
+    } else {
+        "Off"
+    }
+}
+
+impl MenuOption {
+    fn label(self, settings: &Settings) -> String {
+        let graphics = &settings.graphics;
+        let audio = &settings.audio;
+        match self {
+            MenuOption::Fullscreen => format!("Fullscreen: {}", on_off(graphics.fullscreen)),
+            MenuOption::ResolutionScale => {
+                format!("Resolution scale: {:.0}%", graphics.resolution_scale * 100.)
+            }
+            MenuOption::Bloom => format!("Bloom: {}", on_off(graphics.bloom_intensity > 0.)),
+            MenuOption::AdaptiveQuality => {
+                format!("Adaptive quality: {}", on_off(graphics.adaptive_quality))
+            }
+            MenuOption::EdgeAntialiasing => {
+                format!("Edge smoothing: {}", on_off(graphics.edge_antialiasing))
+            }
+            MenuOption::MusicVolume => format!("Music volume: {:.0}%", audio.music_volume * 100.),
+            MenuOption::KeysoundVolume => {
+                format!("Keysound volume: {:.0}%", audio.keysound_volume * 100.)
+            }
+            MenuOption::EffectVolume => {
+                format!("Effect volume: {:.0}%", audio.effect_volume * 100.)
+            }
+            MenuOption::JudgeVolume => format!("Judge volume: {:.0}%", audio.judge_volume * 100.),
+        }
+    }
+}
+
+fn step_volume(volume: &mut f32, direction: f32) {
+    *volume = (*volume + direction * VOLUME_STEP).max(0.).min(1.);
+}
+
+/// An overlay toggled with F10 to change the graphics and audio settings while playing. It can't
+/// be opened while kiosk mode is locked.
+///
+/// Changes are written to [`Settings`], which makes the window plugin rebuild the render graph and
+/// the mixer pick up the volumes; fullscreen is applied to the window directly.
+pub struct OptionsMenuSystem {
+    reader_id: ReaderId<Event>,
+    /// Text of each option while the menu is open.
+    rows: Vec<Entity>,
+    selected: usize,
+    /// Intensity to restore when bloom is switched back on.
+    bloom_intensity: f32,
+    /// Whether the window is currently fullscreen.
+    fullscreen: bool,
+}
+
+pub struct OptionsMenuSystemDesc;
+
+impl<'a, 'b> SystemDesc<'a, 'b, OptionsMenuSystem> for OptionsMenuSystemDesc {
+    fn build(self, world: &mut World) -> OptionsMenuSystem {
+        <OptionsMenuSystem as System<'_>>::SystemData::setup(world);
+
+        let reader_id = world
+            .get_mut::<EventChannel<Event>>()
+            .unwrap()
+            .register_reader();
+        let bloom_intensity = world.read_resource::<Settings>().graphics.bloom_intensity;
+
+        OptionsMenuSystem {
+            reader_id,
+            rows: Vec::new(),
+            selected: 0,
+            bloom_intensity: if bloom_intensity > 0. {
+                bloom_intensity
+            } else {
+                GraphicsSettings::default().bloom_intensity
+            },
+            fullscreen: false,
+        }
+    }
+}
+
+impl OptionsMenuSystem {
+    fn adjust(&mut self, settings: &mut Settings, quality: &mut AdaptiveQuality, direction: f32) {
+        let graphics = &mut settings.graphics;
+        let audio = &mut settings.audio;
+        if let MenuOption::ResolutionScale | MenuOption::Bloom = OPTIONS[self.selected] {
+            quality.forget();
+        }
+        match OPTIONS[self.selected] {
+            MenuOption::Fullscreen => graphics.fullscreen = !graphics.fullscreen,
+            MenuOption::ResolutionScale => {
+                graphics.resolution_scale = (graphics.resolution_scale
+                    + direction * RESOLUTION_SCALE_STEP)
+                    .max(MIN_RESOLUTION_SCALE)
+                    .min(MAX_RESOLUTION_SCALE)
+            }
+            MenuOption::Bloom => {
+                if graphics.bloom_intensity > 0. {
+                    self.bloom_intensity = graphics.bloom_intensity;
+                    graphics.bloom_intensity = 0.;
+                } else {
+                    graphics.bloom_intensity = self.bloom_intensity;
+                }
+            }
+            MenuOption::AdaptiveQuality => graphics.adaptive_quality = !graphics.adaptive_quality,
+            MenuOption::EdgeAntialiasing => {
+                graphics.edge_antialiasing = !graphics.edge_antialiasing
+            }
+            MenuOption::MusicVolume => step_volume(&mut audio.music_volume, direction),
+            MenuOption::KeysoundVolume => step_volume(&mut audio.keysound_volume, direction),
+            MenuOption::EffectVolume => step_volume(&mut audio.effect_volume, direction),
+            MenuOption::JudgeVolume => step_volume(&mut audio.judge_volume, direction),
+        }
+    }
+}
+
+impl<'s> System<'s> for OptionsMenuSystem {
+    type SystemData = (
+        Entities<'s>,
+        Read<'s, EventChannel<Event>>,
+        Write<'s, Settings>,
+        Write<'s, AdaptiveQuality>,
+        Read<'s, Kiosk>,
+        ReadExpect<'s, Window>,
+        ReadExpect<'s, ScreenDimensions>,
+        Option<ReadExpect<'s, InterFont>>,
+        WriteStorage<'s, UiText>,
+        WriteStorage<'s, UiTransform>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            events,
+            mut settings,
+            mut quality,
+            kiosk,
+            window,
+            dimensions,
+            inter_font,
+            mut texts,
+            mut transforms,
+        ): Self::SystemData,
+    ) {
+        let mut open = !self.rows.is_empty();
+        for event in events.read(&mut self.reader_id) {
+            let key = match event {
+                Event::WindowEvent {
+                    event:
+                        WindowEvent::KeyboardInput {
+                            input:
+                                KeyboardInput {
+                                    state: ElementState::Pressed,
+                                    virtual_keycode: Some(key),
+                                    ..
+                                },
+                            ..
+                        },
+                    ..
+                } => *key,
+                _ => continue,
+            };
+            match key {
+                MENU_KEY if kiosk.locked(&settings) => {}
+                MENU_KEY => open = !open,
+                _ if !open => {}
+                VirtualKeyCode::Up => {
+                    self.selected = (self.selected + OPTIONS.len() - 1) % OPTIONS.len()
+                }
+                VirtualKeyCode::Down => self.selected = (self.selected + 1) % OPTIONS.len(),
+                VirtualKeyCode::Left => self.adjust(&mut settings, &mut quality, -1.),
+                VirtualKeyCode::Right | VirtualKeyCode::Return => {
+                    self.adjust(&mut settings, &mut quality, 1.)
+                }
+                _ => {}
+            }
+        }
+
+        if settings.graphics.fullscreen != self.fullscreen {
+            self.fullscreen = settings.graphics.fullscreen;
+            window.set_fullscreen(if self.fullscreen {
+                Some(window.get_current_monitor())
+            } else {
+                None
+            });
+        }
+
+        // The font is only available once the loading state has finished.
+        let inter_font = match (open, &inter_font) {
+            (true, Some(inter_font)) => inter_font,
+            _ => {
+                for row in self.rows.drain(..) {
+                    entities.delete(row).unwrap();
+                }
+                return;
+            }
+        };
+        let font_size = MENU_FONT_SIZE * settings.ui.scale_factor(&dimensions);
+        while self.rows.len() < OPTIONS.len() {
+            let entity = entities.create();
+            texts
+                .insert(
+                    entity,
+                    UiText::new(inter_font.0.clone(), String::new(), [1.; 4], font_size),
+                )
+                .unwrap();
+            let mut transform = percent_transform(
+                "MenuOption",
+                Anchor::Middle,
+                0.,
+                0.15 - 0.06 * self.rows.len() as f32,
+                0.5,
+                0.05,
+            );
+            transform.local_z = 10.;
+            transforms.insert(entity, transform).unwrap();
+            self.rows.push(entity);
+        }
+        for (i, (&row, option)) in self.rows.iter().zip(OPTIONS.iter()).enumerate() {
+            if let Some(text) = texts.get_mut(row) {
+                text.text = option.label(&settings);
+                text.color = if i == self.selected {
+                    [1., 0.8, 0., 1.]
+                } else {
+                    [1.; 4]
+                };
+                text.font_size = font_size;
+            }
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use crate::error::PackError;
+use crate::kiosk;
+use crate::library::Library;
+use crate::paths::Paths;
+use crate::save_queue;
+use crate::settings::Settings;
+use crate::song_select::SongSelectState;
+use crate::ui::percent_transform;
+use crate::InterFont;
+use amethyst::{
+    config::ConfigError,
+    ecs::Entity,
+    input::is_key_down,
+    prelude::*,
+    ui::{Anchor, LineMode, UiText},
+    window::ScreenDimensions,
+    winit::{Event, VirtualKeyCode, WindowEvent},
+};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zip::ZipArchive;
+
+/// File in the library root listing the installed packs.
+const PACKS_FILE: &str = "packs.ron";
+/// Font size of the pack list in logical pixels.
+const PACK_FONT_SIZE: f32 = 28.;
+
+/// A zip archive of songs installed into its own directory of the library.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Pack {
+    /// Directory of the pack below the library root, named after the archive.
+    pub name: String,
+    /// File name of the archive the pack was installed from.
+    pub archive: String,
+    /// Seconds since the Unix epoch.
+    pub installed_at: u64,
+    pub songs: usize,
+    /// Songs of disabled packs stay on disk but are left out of song select.
+    pub enabled: bool,
+}
+
+/// The packs installed into a library, sorted by name.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PackIndex {
+    pub packs: Vec<Pack>,
+}
+
+impl PackIndex {
+    pub fn open(library_root: &Path) -> Self {
+        let path = library_root.join(PACKS_FILE);
+        // Through the save queue, so that packs added a moment ago are listed.
+        save_queue::load(&path).unwrap_or_else(|e| {
+            if path.is_file() {
+                log::warn!("Failed to load {}: {}", path.display(), e);
+            }
+            Self::default()
+        })
+    }
+
+    pub fn save(&self, library_root: &Path) -> Result<(), ConfigError> {
+        save_queue::save(library_root.join(PACKS_FILE), self)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Pack> {
+        self.packs.iter().find(|pack| pack.name == name)
+    }
+
+    /// Whether the song at `path`, relative to the library root, is in no pack or in an enabled
+    /// one.
+    pub fn is_enabled(&self, path: &Path) -> bool {
+        !self
+            .packs
+            .iter()
+            .any(|pack| !pack.enabled && path.starts_with(&pack.name))
+    }
+
+    /// Registers `pack`, replacing an earlier install of the same name.
+    pub fn add(&mut self, pack: Pack) {
+        self.packs.retain(|p| p.name != pack.name);
+        self.packs.push(pack);
+        self.packs.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    /// Deletes the songs of the pack at `index` and unregisters it.
+    pub fn uninstall(&mut self, library_root: &Path, index: usize) -> io::Result<Pack> {
+        let directory = library_root.join(&self.packs[index].name);
+        if directory.exists() {
+            fs::remove_dir_all(directory)?;
+        }
+        Ok(self.packs.remove(index))
+    }
+}
+
+/// Extracts the songs in the zip `archive` into a pack directory below `library_root`, named
+/// after the archive. An existing directory of that name is only replaced if `replace` is set,
+/// as it may hold songs imported some other way.
+///
+/// The archive is extracted into a hidden directory first, so that a failed install leaves the
+/// library as it was. The pack still has to be added to the [`PackIndex`].
+pub fn extract(library_root: &Path, archive: &Path, replace: bool) -> Result<Pack, PackError> {
+    let name = archive
+        .file_stem()
+        .map_or_else(|| "Pack".into(), |stem| stem.to_string_lossy().into_owned());
+    let destination = library_root.join(&name);
+    if destination.exists() && !replace {
+        return Err(PackError::NotManaged(name));
+    }
+    let staging = library_root.join(format!(".{}.partial", name));
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    let songs =
+        unpack(archive, &staging).and_then(|()| match Library::scan(&staging).songs.len() {
+            0 => Err(PackError::NoSongs),
+            songs => Ok(songs),
+        });
+    let songs = match songs {
+        Ok(songs) => songs,
+        Err(e) => {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+    };
+    if destination.exists() {
+        fs::remove_dir_all(&destination)?;
+    }
+    fs::rename(&staging, &destination)?;
+    Ok(Pack {
+        name,
+        archive: archive
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        installed_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        songs,
+        enabled: true,
+    })
+}
+
+fn unpack(archive: &Path, destination: &Path) -> Result<(), PackError> {
+    let mut zip = ZipArchive::new(BufReader::new(File::open(archive)?))?;
+    for i in 0..zip.len() {
+        let mut file = zip.by_index(i)?;
+        // Drops absolute paths and `..` so that every entry stays inside the pack.
+        let path = destination.join(file.sanitized_name());
+        if file.name().ends_with('/') {
+            fs::create_dir_all(&path)?;
+        } else {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            io::copy(&mut file, &mut File::create(&path)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Lists the installed chart packs. Up and down select, Space enables or disables a pack and
+/// Delete, pressed twice, uninstalls it. Zip archives dropped onto the window are installed in
+/// the background. Escape returns to song select, which rescans the library.
+#[derive(Default)]
+pub struct PackManagerState {
+    library_root: PathBuf,
+    index: PackIndex,
+    selected: usize,
+    /// Archives to install once the state starts.
+    queued: Vec<PathBuf>,
+    /// Archives being extracted, by file name.
+    installs: Vec<(String, Receiver<Result<Pack, PackError>>)>,
+    /// Outcome of the last install or change.
+    status: String,
+    confirm_uninstall: bool,
+    text: Option<Entity>,
+}
+
+impl PackManagerState {
+    /// Opens the pack list and installs `archive`.
+    pub fn installing(archive: PathBuf) -> Self {
+        Self {
+            queued: vec![archive],
+            ..Self::default()
+        }
+    }
+
+    fn install(&mut self, archive: PathBuf) {
+        let name = archive
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        // Only packs installed before are replaced; a folder of the same name is left alone.
+        let replace = archive.file_stem().map_or(false, |stem| {
+            self.index.get(&stem.to_string_lossy()).is_some()
+        });
+        let library_root = self.library_root.clone();
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            let _ = sender.send(extract(&library_root, &archive, replace));
+        });
+        self.installs.push((name, receiver));
+    }
+
+    fn save(&mut self) {
+        if let Err(e) = self.index.save(&self.library_root) {
+            log::warn!("Failed to save the pack list: {}", e);
+            self.status = format!("Failed to save the pack list: {}", e);
+        }
+    }
+
+    fn label(&self) -> String {
+        let mut text = String::from("Packs\n\n");
+        for (i, pack) in self.index.packs.iter().enumerate() {
+            let marker = if i == self.selected { "> " } else { "  " };
+            let enabled = if pack.enabled { "[x]" } else { "[ ]" };
+            text += &format!(
+                "{}{} {}  {} songs\n",
+                marker, enabled, pack.name, pack.songs
+            );
+        }
+        if self.index.packs.is_empty() {
+            text += "Drop a zip archive of songs onto the window to install it\n";
+        }
+        for (name, _) in &self.installs {
+            text += &format!("\nInstalling {}", name);
+        }
+        if !self.status.is_empty() {
+            text += &format!("\n{}", self.status);
+        }
+        match self.index.packs.get(self.selected) {
+            Some(pack) if self.confirm_uninstall => {
+                text += &format!("\nPress Delete again to uninstall {}", pack.name)
+            }
+            _ => text += "\nSpace: enable/disable  Delete: uninstall  Escape: back",
+        }
+        text
+    }
+
+    fn refresh(&self, world: &World) {
+        if let Some(text) = self.text {
+            if let Some(text) = world.write_storage::<UiText>().get_mut(text) {
+                text.text = self.label();
+            }
+        }
+    }
+}
+
+impl SimpleState for PackManagerState {
+    fn on_start(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        self.library_root = world
+            .read_resource::<Settings>()
+            .library
+            .root(&world.read_resource::<Paths>());
+        self.index = PackIndex::open(&self.library_root);
+        for archive in std::mem::replace(&mut self.queued, Vec::new()) {
+            self.install(archive);
+        }
+
+        let font = world.read_resource::<InterFont>().0.clone();
+        let font_size = {
+            let settings = world.read_resource::<Settings>();
+            let dimensions = world.read_resource::<ScreenDimensions>();
+            PACK_FONT_SIZE * settings.ui.scale_factor(&dimensions)
+        };
+        let transform = percent_transform("Packs", Anchor::Middle, 0., 0., 0.6, 0.8);
+        let mut ui_text = UiText::new(font, self.label(), [1.; 4], font_size);
+        ui_text.line_mode = LineMode::Wrap;
+        self.text = Some(world.create_entity().with(transform).with(ui_text).build());
+    }
+
+    fn on_stop(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        if let Some(text) = self.text.take() {
+            world.delete_entity(text).unwrap();
+        }
+    }
+
+    fn handle_event(
+        &mut self,
+        StateData { world, .. }: StateData<'_, GameData<'_, '_>>,
+        event: StateEvent,
+    ) -> SimpleTrans {
+        let event = match event {
+            StateEvent::Window(event) => event,
+            _ => return Trans::None,
+        };
+        if kiosk::exit_requested(world, &event) {
+            return Trans::Quit;
+        }
+        let confirming = self.confirm_uninstall;
+        self.confirm_uninstall = false;
+        if is_key_down(&event, VirtualKeyCode::Up) {
+            self.selected = self.selected.saturating_sub(1);
+        } else if is_key_down(&event, VirtualKeyCode::Down) {
+            self.selected = (self.selected + 1).min(self.index.packs.len().saturating_sub(1));
+        } else if is_key_down(&event, VirtualKeyCode::Space) {
+            if let Some(pack) = self.index.packs.get_mut(self.selected) {
+                pack.enabled = !pack.enabled;
+                self.status.clear();
+                self.save();
+            }
+        } else if is_key_down(&event, VirtualKeyCode::Delete) {
+            if self.selected < self.index.packs.len() {
+                if confirming {
+                    match self.index.uninstall(&self.library_root, self.selected) {
+                        Ok(pack) => {
+                            self.status = format!("Uninstalled {}", pack.name);
+                            self.selected =
+                                self.selected.min(self.index.packs.len().saturating_sub(1));
+                            self.save();
+                        }
+                        Err(e) => {
+                            log::warn!("Failed to uninstall a pack: {}", e);
+                            self.status = format!("Failed to uninstall: {}", e);
+                        }
+                    }
+                } else {
+                    self.confirm_uninstall = true;
+                }
+            }
+        } else if is_key_down(&event, VirtualKeyCode::Escape) {
+            return Trans::Switch(Box::new(SongSelectState::default()));
+        } else if let Event::WindowEvent {
+            event: WindowEvent::DroppedFile(path),
+            ..
+        } = event
+        {
+            self.install(path);
+        }
+        self.refresh(world);
+        Trans::None
+    }
+
+    fn update(&mut self, data: &mut StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
+        let mut finished = Vec::new();
+        self.installs
+            .retain(|(name, receiver)| match receiver.try_recv() {
+                Ok(result) => {
+                    finished.push((name.clone(), result));
+                    false
+                }
+                Err(TryRecvError::Empty) => true,
+                Err(TryRecvError::Disconnected) => {
+                    finished.push((name.clone(), Err(PackError::Crashed)));
+                    false
+                }
+            });
+        if finished.is_empty() {
+            return Trans::None;
+        }
+        for (name, result) in finished {
+            match result {
+                Ok(pack) => {
+                    self.status = format!("Installed {} with {} songs", pack.name, pack.songs);
+                    self.index.add(pack);
+                    self.save();
+                }
+                Err(e) => {
+                    log::warn!("Failed to install {}: {}", name, e);
+                    self.status = format!("Failed to install {}: {}", name, e);
+                }
+            }
+        }
+        self.refresh(data.world);
+        Trans::None
+    }
+}
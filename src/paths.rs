@@ -0,0 +1,59 @@
+use crate::profile::PROFILES_DIR;
+use amethyst::utils::application_root_dir;
+use directories::ProjectDirs;
+use std::io;
+use std::path::PathBuf;
+
+/// Marker file beside the executable that keeps every file beside it.
+pub const PORTABLE_MARKER: &str = "portable";
+
+/// The directories the game reads and writes its files in. Everything persisted goes through
+/// this resource.
+///
+/// In portable mode, everything is kept beside the executable. Otherwise the platform's standard
+/// directories are used, such as the XDG base directories on Linux.
+#[derive(Clone, Debug)]
+pub struct Paths {
+    /// Files shipped with the game, which are never written: the default settings and key
+    /// bindings, the theme and the assets.
+    pub resources: PathBuf,
+    /// Settings and key bindings of the profiles and the profile opened at startup.
+    pub config: PathBuf,
+    /// Scores, replays and statistics of the profiles, courses, scripts, play bundles and the
+    /// default song library.
+    pub data: PathBuf,
+    /// Logs, crash reports and the token of remote control.
+    pub cache: PathBuf,
+}
+
+impl Paths {
+    /// Picks the portable layout if `portable` is requested on the command line, the
+    /// [`PORTABLE_MARKER`] exists, or profiles from before the standard directories are beside
+    /// the executable; the standard directories otherwise.
+    pub fn new(portable: bool) -> io::Result<Self> {
+        let root = application_root_dir()?;
+        let resources = root.join("resources");
+        let portable =
+            portable || root.join(PORTABLE_MARKER).is_file() || root.join(PROFILES_DIR).is_dir();
+        // Without a home directory there are no standard directories to use.
+        let dirs = if portable {
+            None
+        } else {
+            ProjectDirs::from("", "", "iris")
+        };
+        Ok(match dirs {
+            Some(dirs) => Self {
+                resources,
+                config: dirs.config_dir().into(),
+                data: dirs.data_dir().into(),
+                cache: dirs.cache_dir().into(),
+            },
+            None => Self {
+                resources,
+                config: root.clone(),
+                data: root.clone(),
+                cache: root,
+            },
+        })
+    }
+}
@@ -0,0 +1,130 @@
+use crate::course::CourseProgress;
+use crate::key_config::KeyConfigState;
+use crate::kiosk::{self, Kiosk};
+use crate::settings::Settings;
+use crate::song_select::SongSelectState;
+use crate::ui::percent_transform;
+use crate::{InterFont, MainStage};
+use amethyst::{
+    core::timing::Time,
+    ecs::Entity,
+    input::is_key_down,
+    prelude::*,
+    ui::{Anchor, LineMode, UiText},
+    window::ScreenDimensions,
+    winit::VirtualKeyCode,
+};
+
+/// Font size of the pause menu in logical pixels.
+const PAUSE_FONT_SIZE: f32 = 32.;
+const PAUSE_TEXT: &str = "Paused\n\nEscape: resume  R: retry  K: key bindings  Q: quit";
+
+/// Whether the play is paused. Key presses are neither judged nor recorded while it is.
+#[derive(Default)]
+pub struct Paused(pub bool);
+
+/// Set when the play is abandoned to start the same chart over, so that the play history can
+/// count retries.
+#[derive(Default)]
+pub struct Retrying(pub bool);
+
+/// Pushed over the play with Escape. Chart time stands still until Escape resumes; R starts the
+/// chart over, K opens the key bindings and Q returns to song select. Course stages can't be
+/// retried.
+#[derive(Default)]
+pub struct PauseState {
+    /// The playback rate to restore on resume, which the console may have changed.
+    time_scale: f32,
+    text: Option<Entity>,
+}
+
+impl SimpleState for PauseState {
+    fn on_start(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        {
+            let mut time = world.write_resource::<Time>();
+            self.time_scale = time.time_scale();
+            time.set_time_scale(0.);
+        }
+        world.insert(Paused(true));
+
+        let font = world.read_resource::<InterFont>().0.clone();
+        let font_size = {
+            let settings = world.read_resource::<Settings>();
+            let dimensions = world.read_resource::<ScreenDimensions>();
+            PAUSE_FONT_SIZE * settings.ui.scale_factor(&dimensions)
+        };
+        let transform = percent_transform("Pause", Anchor::Middle, 0., 0., 0.6, 0.4);
+        let mut ui_text = UiText::new(font, PAUSE_TEXT.into(), [1.; 4], font_size);
+        ui_text.line_mode = LineMode::Wrap;
+        self.text = Some(world.create_entity().with(transform).with(ui_text).build());
+    }
+
+    fn on_stop(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        if let Some(text) = self.text.take() {
+            world.delete_entity(text).unwrap();
+        }
+        world
+            .write_resource::<Time>()
+            .set_time_scale(self.time_scale);
+        world.insert(Paused(false));
+    }
+
+    /// Hides the menu under the key bindings.
+    fn on_pause(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        if let Some(text) = self.text {
+            if let Some(text) = world.write_storage::<UiText>().get_mut(text) {
+                text.text.clear();
+            }
+        }
+    }
+
+    fn on_resume(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        if let Some(text) = self.text {
+            if let Some(text) = world.write_storage::<UiText>().get_mut(text) {
+                text.text = PAUSE_TEXT.into();
+            }
+        }
+    }
+
+    fn handle_event(
+        &mut self,
+        StateData { world, .. }: StateData<'_, GameData<'_, '_>>,
+        event: StateEvent,
+    ) -> SimpleTrans {
+        let event = match event {
+            StateEvent::Window(event) => event,
+            _ => return Trans::None,
+        };
+        if kiosk::exit_requested(world, &event) {
+            return Trans::Quit;
+        }
+        if is_key_down(&event, VirtualKeyCode::Escape) {
+            Trans::Pop
+        } else if is_key_down(&event, VirtualKeyCode::K) {
+            let locked = world
+                .read_resource::<Kiosk>()
+                .locked(&world.read_resource::<Settings>());
+            if locked {
+                Trans::None
+            } else {
+                Trans::Push(Box::new(KeyConfigState::default()))
+            }
+        } else if is_key_down(&event, VirtualKeyCode::R) {
+            if world.read_resource::<Option<CourseProgress>>().is_some() {
+                return Trans::None;
+            }
+            world.insert(Retrying(true));
+            Trans::Sequence(vec![
+                Trans::Pop,
+                Trans::Switch(Box::new(MainStage::default())),
+            ])
+        } else if is_key_down(&event, VirtualKeyCode::Q) {
+            Trans::Sequence(vec![
+                Trans::Pop,
+                Trans::Switch(Box::new(SongSelectState::default())),
+            ])
+        } else {
+            Trans::None
+        }
+    }
+}
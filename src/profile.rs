@@ -0,0 +1,628 @@
+use crate::chart_speed::ChartSpeeds;
+use crate::diagnostics::ErrorEvent;
+use crate::error::IrisError;
+use crate::judge::{KeyMappings, ScancodeMap};
+use crate::key_stats::{self, KeyStats};
+use crate::kiosk;
+use crate::migrate::{self, Versioned};
+use crate::paths::Paths;
+use crate::save_queue;
+use crate::score::NoteRecord;
+use crate::settings::Settings;
+use crate::song_select::SongSelectState;
+use crate::ui::percent_transform;
+use crate::InterFont;
+use amethyst::{
+    config::Config,
+    ecs::Entity,
+    input::is_key_down,
+    prelude::*,
+    shrev::EventChannel,
+    ui::{Anchor, LineMode, UiText},
+    window::ScreenDimensions,
+    winit::{Event, VirtualKeyCode, WindowEvent},
+};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Directory below the config and data directories holding one directory per profile.
+pub(crate) const PROFILES_DIR: &str = "profiles";
+const INDEX_FILE: &str = "profiles.ron";
+const DEFAULT_PROFILE: &str = "Default";
+/// Font size of the profile list in logical pixels.
+const PROFILE_FONT_SIZE: f32 = 28.;
+/// Font size of the keys in the statistics in logical pixels.
+const HEATMAP_FONT_SIZE: f32 = 14.;
+/// Charts listed as most retried.
+const LISTED_RETRIED: usize = 5;
+/// Plays kept in the history; older ones are dropped.
+const HISTORY_SIZE: usize = 1000;
+/// Plays the offset suggestion looks back on.
+const OFFSET_PLAYS: usize = 10;
+/// Hits needed over those plays before an offset is suggested.
+const MIN_OFFSET_HITS: u32 = 200;
+/// Offset changes in seconds too small to be worth suggesting.
+const MIN_OFFSET_CHANGE: f32 = 0.005;
+/// The [`pacemaker`] that races against the personal best of the chart being played.
+///
+/// [`pacemaker`]: crate::settings::GameplaySettings::pacemaker
+pub const BEST_PACEMAKER: &str = "best";
+
+/// Remembers the profile to open at startup.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct ProfileIndex {
+    last: Option<String>,
+}
+
+/// The local player whose settings, key bindings, scores and history are in use.
+///
+/// Everything a player owns is stored in their profile directories, one for the settings and key
+/// bindings among the [config](Paths::config) and one for the rest among the [data](Paths::data).
+/// Files missing there fall back to the defaults in the resources directory.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub name: String,
+    /// Scores, replays and statistics.
+    pub dir: PathBuf,
+    /// Settings and key bindings.
+    pub config_dir: PathBuf,
+}
+
+impl Profile {
+    pub fn open(paths: &Paths, name: &str) -> Self {
+        Self {
+            name: name.into(),
+            dir: paths.data.join(PROFILES_DIR).join(name),
+            config_dir: paths.config.join(PROFILES_DIR).join(name),
+        }
+    }
+
+    /// The profile used last, or the default one.
+    pub fn last_used(paths: &Paths) -> Self {
+        let index = ProfileIndex::load(paths.config.join(PROFILES_DIR).join(INDEX_FILE));
+        Self::open(
+            paths,
+            index.last.as_ref().map_or(DEFAULT_PROFILE, String::as_str),
+        )
+    }
+
+    /// Names of every profile on disk, sorted.
+    pub fn list(paths: &Paths) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(paths.config.join(PROFILES_DIR))
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .filter(|entry| entry.path().is_dir())
+                    .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        if !names.iter().any(|name| name == DEFAULT_PROFILE) {
+            names.push(DEFAULT_PROFILE.into());
+        }
+        names.sort();
+        names
+    }
+
+    /// Creates the profile directories and makes this the profile opened at startup.
+    pub fn make_current(&self) -> Result<(), IrisError> {
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::create_dir_all(&self.config_dir)?;
+        let index = ProfileIndex {
+            last: Some(self.name.clone()),
+        };
+        save_queue::save(self.config_dir.parent().unwrap().join(INDEX_FILE), &index)?;
+        Ok(())
+    }
+
+    /// Loads the file of this profile if it exists, or the default from the resources directory.
+    fn load_with_fallback<T: Versioned>(&self, resources: &Path, file: &str) -> T {
+        let path = self.config_dir.join(file);
+        if path.is_file() {
+            migrate::load_or_default(&path)
+        } else {
+            migrate::load_resource_or_default(&resources.join(file))
+        }
+    }
+
+    pub fn load_settings(&self, resources: &Path) -> Settings {
+        self.load_with_fallback(resources, "settings.ron")
+    }
+
+    pub fn settings_path(&self) -> PathBuf {
+        self.config_dir.join("settings.ron")
+    }
+
+    /// The key bindings for `settings`, one map per player. Mistakes in the maps are logged.
+    pub fn load_scancode_maps(&self, resources: &Path, settings: &Settings) -> Vec<ScancodeMap> {
+        scancode_files(settings)
+            .iter()
+            .map(|file| {
+                let map: ScancodeMap = self.load_with_fallback(resources, file);
+                for warning in map.validate() {
+                    log::warn!("{}: {}", file, warning);
+                }
+                map
+            })
+            .collect()
+    }
+
+    /// Saves the key bindings for `settings` into the profile, overriding the defaults.
+    pub fn save_scancode_maps(
+        &self,
+        settings: &Settings,
+        maps: &[ScancodeMap],
+    ) -> Result<(), IrisError> {
+        for (file, map) in scancode_files(settings).iter().zip(maps) {
+            save_queue::save(self.config_dir.join(file), map)?;
+        }
+        Ok(())
+    }
+
+    pub fn scores_dir(&self) -> PathBuf {
+        self.dir.join("scores")
+    }
+
+    pub fn replays_dir(&self) -> PathBuf {
+        self.dir.join("replays")
+    }
+
+    /// The personal best of the chart with the [`chart_hash`], so that the bests of different
+    /// charts never mix. The built-in demo chart, without a hash, has its own.
+    ///
+    /// [`chart_hash`]: crate::chart_file::song_chart_hash
+    pub fn best_score_path(&self, chart_hash: Option<u64>) -> PathBuf {
+        self.scores_dir().join("best").join(best_file(chart_hash))
+    }
+
+    /// The replay of the play of [`best_score_path`](Self::best_score_path).
+    pub fn best_replay_path(&self, chart_hash: Option<u64>) -> PathBuf {
+        self.replays_dir().join("best").join(best_file(chart_hash))
+    }
+
+    fn history_path(&self) -> PathBuf {
+        self.dir.join("history.ron")
+    }
+
+    /// Every play of this profile, oldest first.
+    pub fn load_history(&self) -> Vec<HistoryEntry> {
+        migrate::load::<History>(&self.history_path())
+            .map(|history| history.plays)
+            .unwrap_or_default()
+    }
+
+    /// Appends a finished play to the history.
+    pub fn record_play(&self, entry: HistoryEntry) -> Result<(), IrisError> {
+        let path = self.history_path();
+        let mut history: History = migrate::load(&path).unwrap_or_default();
+        history.plays.push(entry);
+        let excess = history.plays.len().saturating_sub(HISTORY_SIZE);
+        history.plays.drain(..excess);
+        save_queue::save(path, &history)?;
+        Ok(())
+    }
+
+    fn key_stats_path(&self) -> PathBuf {
+        self.dir.join("key_stats.ron")
+    }
+
+    pub fn load_key_stats(&self) -> KeyStats {
+        migrate::load(&self.key_stats_path()).unwrap_or_default()
+    }
+
+    /// Adds the judgements of a play to the statistics by key.
+    pub fn record_key_stats(&self, records: &[NoteRecord]) -> Result<(), IrisError> {
+        let mut stats = self.load_key_stats();
+        stats.add(records);
+        save_queue::save(self.key_stats_path(), &stats)?;
+        Ok(())
+    }
+
+    fn chart_speeds_path(&self) -> PathBuf {
+        self.dir.join("chart_speeds.ron")
+    }
+
+    pub fn load_chart_speeds(&self) -> ChartSpeeds {
+        migrate::load(&self.chart_speeds_path()).unwrap_or_default()
+    }
+
+    pub fn save_chart_speeds(&self, speeds: &ChartSpeeds) -> Result<(), IrisError> {
+        save_queue::save(self.chart_speeds_path(), speeds)?;
+        Ok(())
+    }
+}
+
+fn best_file(chart_hash: Option<u64>) -> String {
+    match chart_hash {
+        Some(hash) => format!("{:016x}.ron", hash),
+        None => "demo.ron".into(),
+    }
+}
+
+/// Files of the key bindings, one per player.
+fn scancode_files(settings: &Settings) -> &'static [&'static str] {
+    if settings.gameplay.versus {
+        &["scancode_1p.ron", "scancode_2p.ron"]
+    } else {
+        &["scancode.ron"]
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub song: PathBuf,
+    pub score: u32,
+    pub max_combo: u32,
+    /// Whether the chart was cleared, see [`Score::cleared`](crate::score::Score::cleared).
+    #[serde(default)]
+    pub cleared: bool,
+    /// Whether the play was abandoned to start the chart over.
+    #[serde(default)]
+    pub retried: bool,
+    /// Seconds spent playing.
+    #[serde(default)]
+    pub seconds: f32,
+    /// Notes hit, not counting misses.
+    #[serde(default)]
+    pub hits: u32,
+    /// Mean of the note time minus the key press time of the hits, before the offset setting
+    /// was added, in seconds. `None` for autoplay.
+    #[serde(default)]
+    pub raw_offset: Option<f32>,
+}
+
+impl HistoryEntry {
+    pub fn now(song: PathBuf, score: u32, max_combo: u32) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            song,
+            score,
+            max_combo,
+            cleared: false,
+            retried: false,
+            seconds: 0.,
+            hits: 0,
+            raw_offset: None,
+        }
+    }
+}
+
+/// How often a chart was retried and how long it took to clear.
+#[derive(Clone, Debug)]
+pub struct ChartRetries {
+    pub song: PathBuf,
+    pub plays: u32,
+    pub retries: u32,
+    /// Seconds played up to and including the first clear, or `None` if it is not cleared yet.
+    pub time_to_first_clear: Option<f32>,
+}
+
+/// The charts of `plays` by retry count, most retried first.
+pub fn most_retried(plays: &[HistoryEntry]) -> Vec<ChartRetries> {
+    let mut charts: Vec<ChartRetries> = Vec::new();
+    // Seconds played so far, for charts not cleared yet.
+    let mut played: Vec<f32> = Vec::new();
+    for play in plays {
+        let i = match charts.iter().position(|c| c.song == play.song) {
+            Some(i) => i,
+            None => {
+                charts.push(ChartRetries {
+                    song: play.song.clone(),
+                    plays: 0,
+                    retries: 0,
+                    time_to_first_clear: None,
+                });
+                played.push(0.);
+                charts.len() - 1
+            }
+        };
+        let chart = &mut charts[i];
+        chart.plays += 1;
+        if play.retried {
+            chart.retries += 1;
+        }
+        if chart.time_to_first_clear.is_none() {
+            played[i] += play.seconds;
+            if play.cleared {
+                chart.time_to_first_clear = Some(played[i]);
+            }
+        }
+    }
+    charts.retain(|c| c.retries > 0);
+    charts.sort_by(|a, b| b.retries.cmp(&a.retries));
+    charts
+}
+
+/// The [`offset`] that centers the hits of the last plays, the latest last, on the notes. `None`
+/// without enough hits to tell or if it is close to `current`.
+///
+/// [`offset`]: crate::settings::GameplaySettings::offset
+pub fn suggested_offset(plays: &[HistoryEntry], current: f32) -> Option<f32> {
+    let (mut sum, mut hits) = (0., 0);
+    for (raw_offset, play_hits) in plays
+        .iter()
+        .rev()
+        .filter_map(|play| Some((play.raw_offset?, play.hits)))
+        .take(OFFSET_PLAYS)
+    {
+        sum += raw_offset * play_hits as f32;
+        hits += play_hits;
+    }
+    if hits < MIN_OFFSET_HITS {
+        return None;
+    }
+    let offset = sum / hits as f32;
+    if (offset - current).abs() < MIN_OFFSET_CHANGE {
+        None
+    } else {
+        Some(offset)
+    }
+}
+
+/// Every play of a profile, oldest first.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+struct History {
+    /// Format version, see [`Versioned`].
+    #[serde(default = "migrate::unversioned")]
+    version: u32,
+    plays: Vec<HistoryEntry>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            version: Self::current_version(),
+            plays: Vec::new(),
+        }
+    }
+}
+
+impl Versioned for History {
+    const MIGRATIONS: &'static [fn(&mut Self)] = &[migrate::no_changes];
+
+    fn version_mut(&mut self) -> &mut u32 {
+        &mut self.version
+    }
+}
+
+/// Lists the profiles to switch to. Up and down select, Enter switches and F2 types the name of a
+/// new profile. Escape returns to song select without switching. Tab shows the statistics of the
+/// current profile instead.
+///
+/// Switching saves the settings of the current profile and loads those of the new one. The
+/// number of players can't change while running, so a profile with a different versus setting
+/// keeps the current key bindings until restart.
+#[derive(Default)]
+pub struct ProfileSelectState {
+    names: Vec<String>,
+    selected: usize,
+    /// The name being typed for a new profile.
+    new_name: Option<String>,
+    text: Option<Entity>,
+    /// The entities of the statistics while they are shown.
+    stats: Option<Vec<Entity>>,
+    most_retried: Vec<ChartRetries>,
+}
+
+impl ProfileSelectState {
+    fn label(&self, current: &str) -> String {
+        let mut text = String::from("Profiles\n\n");
+        for (i, name) in self.names.iter().enumerate() {
+            let marker = if i == self.selected { "> " } else { "  " };
+            let current = if name == current { " (current)" } else { "" };
+            text += &format!("{}{}{}\n", marker, name, current);
+        }
+        match &self.new_name {
+            Some(name) => text += &format!("\nNew profile: {}_", name),
+            None => text += "\nEnter: switch  F2: new profile  Tab: statistics  Escape: back",
+        }
+        text
+    }
+
+    fn stats_label(&self, profile: &Profile) -> String {
+        let mut text = format!("Statistics of {}\n\nMost retried\n", profile.name);
+        if self.most_retried.is_empty() {
+            text += "  No retries yet\n";
+        }
+        for chart in self.most_retried.iter().take(LISTED_RETRIED) {
+            let cleared = match chart.time_to_first_clear {
+                Some(seconds) => format!(
+                    "cleared after {}:{:02}",
+                    seconds as u32 / 60,
+                    seconds as u32 % 60
+                ),
+                None => "not cleared".into(),
+            };
+            text += &format!(
+                "  {}: {} retries in {} plays, {}\n",
+                chart.song.display(),
+                chart.retries,
+                chart.plays,
+                cleared
+            );
+        }
+        text += "\nAccuracy by key, attributed to the key nearest to each note\n\n\
+                 Tab: profiles  Escape: back";
+        text
+    }
+
+    /// Aligns the text to the top while the statistics are shown, to keep it clear of the
+    /// heatmap.
+    fn align_text(&self, world: &World) {
+        if let Some(text) = self.text {
+            if let Some(text) = world.write_storage::<UiText>().get_mut(text) {
+                text.align = if self.stats.is_some() {
+                    Anchor::TopMiddle
+                } else {
+                    Anchor::Middle
+                };
+            }
+        }
+    }
+
+    fn show_stats(&mut self, world: &mut World) {
+        let profile = world.read_resource::<Profile>().clone();
+        let resources = world.read_resource::<Paths>().resources.clone();
+        let map = profile
+            .load_scancode_maps(&resources, &world.read_resource::<Settings>())
+            .into_iter()
+            .next()
+            .unwrap_or_default();
+        let font = world.read_resource::<InterFont>().0.clone();
+        let font_size = {
+            let settings = world.read_resource::<Settings>();
+            let dimensions = world.read_resource::<ScreenDimensions>();
+            HEATMAP_FONT_SIZE * settings.ui.scale_factor(&dimensions)
+        };
+        let stats = profile.load_key_stats();
+        self.stats = Some(key_stats::show_heatmap(
+            world, &stats, &map, font, font_size,
+        ));
+        self.most_retried = most_retried(&profile.load_history());
+        self.align_text(world);
+    }
+
+    fn hide_stats(&mut self, world: &mut World) {
+        if let Some(entities) = self.stats.take() {
+            world.delete_entities(&entities).unwrap();
+        }
+        self.align_text(world);
+    }
+
+    fn switch(&self, world: &mut World, name: &str) {
+        if let Err(e) = crate::save_settings(world) {
+            log::warn!("Failed to save settings: {}", e);
+        }
+        let paths = world.read_resource::<Paths>().clone();
+        let profile = Profile::open(&paths, name);
+        let resources = paths.resources;
+        let settings = profile.load_settings(&resources);
+        let maps = profile.load_scancode_maps(&resources, &settings);
+        let players = world.read_resource::<KeyMappings>().0.len();
+        if maps.len() == players {
+            world.insert(KeyMappings::new(maps));
+        } else {
+            world
+                .write_resource::<EventChannel<ErrorEvent>>()
+                .single_write(ErrorEvent::new(
+                    "Restart to apply the versus setting of this profile",
+                ));
+        }
+        if let Err(e) = profile.make_current() {
+            log::warn!("Failed to save profile {}: {}", profile.name, e);
+        }
+        world.insert(settings);
+        world.insert(profile);
+    }
+}
+
+impl SimpleState for ProfileSelectState {
+    fn on_start(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        let current = world.read_resource::<Profile>().clone();
+        self.names = Profile::list(&world.read_resource::<Paths>());
+        self.selected = self
+            .names
+            .iter()
+            .position(|name| *name == current.name)
+            .unwrap_or(0);
+
+        let font = world.read_resource::<InterFont>().0.clone();
+        let font_size = {
+            let settings = world.read_resource::<Settings>();
+            let dimensions = world.read_resource::<ScreenDimensions>();
+            PROFILE_FONT_SIZE * settings.ui.scale_factor(&dimensions)
+        };
+        let transform = percent_transform("Profiles", Anchor::Middle, 0., 0., 0.6, 0.8);
+        let mut ui_text = UiText::new(font, self.label(&current.name), [1.; 4], font_size);
+        ui_text.line_mode = LineMode::Wrap;
+        self.text = Some(world.create_entity().with(transform).with(ui_text).build());
+    }
+
+    fn on_stop(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        if let Some(text) = self.text.take() {
+            world.delete_entity(text).unwrap();
+        }
+        self.hide_stats(world);
+    }
+
+    fn handle_event(
+        &mut self,
+        StateData { world, .. }: StateData<'_, GameData<'_, '_>>,
+        event: StateEvent,
+    ) -> SimpleTrans {
+        let event = match event {
+            StateEvent::Window(event) => event,
+            _ => return Trans::None,
+        };
+        if kiosk::exit_requested(world, &event) {
+            return Trans::Quit;
+        }
+        if let Some(name) = &mut self.new_name {
+            if is_key_down(&event, VirtualKeyCode::Return) {
+                let name = name.trim().to_string();
+                self.new_name = None;
+                if !name.is_empty() {
+                    self.switch(world, &name);
+                    return Trans::Switch(Box::new(SongSelectState::default()));
+                }
+            } else if is_key_down(&event, VirtualKeyCode::Escape) {
+                self.new_name = None;
+            } else if is_key_down(&event, VirtualKeyCode::Back) {
+                name.pop();
+            } else if let Event::WindowEvent {
+                event: WindowEvent::ReceivedCharacter(c),
+                ..
+            } = event
+            {
+                // Names become directory names, so keep to characters that are safe everywhere.
+                if c.is_alphanumeric() || c == ' ' || c == '-' || c == '_' {
+                    name.push(c);
+                }
+            }
+        } else if is_key_down(&event, VirtualKeyCode::Tab) {
+            if self.stats.is_some() {
+                self.hide_stats(world);
+            } else {
+                self.show_stats(world);
+            }
+        } else if self.stats.is_some() {
+            if is_key_down(&event, VirtualKeyCode::Escape) {
+                self.hide_stats(world);
+            }
+        } else if is_key_down(&event, VirtualKeyCode::Up) {
+            self.selected = self.selected.saturating_sub(1);
+        } else if is_key_down(&event, VirtualKeyCode::Down) {
+            self.selected = (self.selected + 1).min(self.names.len().saturating_sub(1));
+        } else if is_key_down(&event, VirtualKeyCode::F2) {
+            self.new_name = Some(String::new());
+        } else if is_key_down(&event, VirtualKeyCode::Return) {
+            let name = self.names[self.selected].clone();
+            if name != world.read_resource::<Profile>().name {
+                self.switch(world, &name);
+            }
+            return Trans::Switch(Box::new(SongSelectState::default()));
+        } else if is_key_down(&event, VirtualKeyCode::Escape) {
+            return Trans::Switch(Box::new(SongSelectState::default()));
+        }
+
+        let current = world.read_resource::<Profile>().clone();
+        let label = if self.stats.is_some() {
+            self.stats_label(&current)
+        } else {
+            self.label(&current.name)
+        };
+        if let Some(text) = self.text {
+            if let Some(text) = world.write_storage::<UiText>().get_mut(text) {
+                text.text = label;
+            }
+        }
+        Trans::None
+    }
+}
@@ -0,0 +1,117 @@
+use crate::chart::PlaySettings;
+use crate::settings::{GraphicsSettings, Settings};
+use amethyst::{
+    core::timing::Time,
+    ecs::{Read, ReadExpect, System, Write},
+};
+
+/// Seconds of frames looked at before deciding whether to lower the quality.
+const WINDOW_SECONDS: f32 = 2.;
+/// A frame is dropped when it takes this many times as long as the median frame of its window.
+const DROP_FACTOR: f32 = 1.5;
+/// Fraction of dropped frames in a window above which the quality is lowered.
+const MAX_DROP_FRACTION: f32 = 0.05;
+/// Resolution scales tried once bloom is off, in order.
+const SCALE_STEPS: [f32; 2] = [0.75, 0.5];
+
+/// The bloom intensity and resolution scale from before [`AdaptiveQualitySystem`] lowered them,
+/// if it did.
+#[derive(Default)]
+pub struct AdaptiveQuality {
+    original: Option<(f32, f32)>,
+}
+
+impl AdaptiveQuality {
+    /// Puts back the settings from before play, so that the reduced ones are neither kept in
+    /// menus nor saved.
+    pub fn restore(&mut self, graphics: &mut GraphicsSettings) {
+        if let Some((bloom_intensity, resolution_scale)) = self.original.take() {
+            graphics.bloom_intensity = bloom_intensity;
+            graphics.resolution_scale = resolution_scale;
+        }
+    }
+
+    /// Keeps the current settings when play ends, as the user has picked them.
+    pub fn forget(&mut self) {
+        self.original = None;
+    }
+}
+
+/// Lowers the graphics quality step by step while playing when frames are dropped repeatedly, as
+/// dropped frames ruin the timing, and restores it once play ends. Disabled by
+/// `graphics.adaptive_quality`.
+///
+/// Bloom is disabled first, then the resolution scale is lowered.
+#[derive(Default)]
+pub struct AdaptiveQualitySystem {
+    /// Real frame times of the current window.
+    frames: Vec<f32>,
+}
+
+impl AdaptiveQualitySystem {
+    /// Lowers `graphics` by one step, returning false if it is as low as it goes.
+    fn lower(graphics: &mut GraphicsSettings) -> bool {
+        if graphics.bloom_intensity > 0. {
+            graphics.bloom_intensity = 0.;
+            return true;
+        }
+        match SCALE_STEPS
+            .iter()
+            .find(|&&scale| scale < graphics.resolution_scale)
+        {
+            Some(&scale) => {
+                graphics.resolution_scale = scale;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<'s> System<'s> for AdaptiveQualitySystem {
+    type SystemData = (
+        ReadExpect<'s, Time>,
+        Read<'s, Option<PlaySettings>>,
+        Write<'s, Settings>,
+        Write<'s, AdaptiveQuality>,
+    );
+
+    fn run(&mut self, (time, play_settings, mut settings, mut quality): Self::SystemData) {
+        if play_settings.is_none() || !settings.graphics.adaptive_quality {
+            self.frames.clear();
+            quality.restore(&mut settings.graphics);
+            return;
+        }
+        self.frames.push(time.delta_real_seconds());
+        if self.frames.iter().sum::<f32>() < WINDOW_SECONDS {
+            return;
+        }
+
+        let mut sorted = self.frames.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let median = sorted[sorted.len() / 2];
+        let dropped = self
+            .frames
+            .iter()
+            .filter(|&&t| t > median * DROP_FACTOR)
+            .count();
+        let fraction = dropped as f32 / self.frames.len() as f32;
+        self.frames.clear();
+        if fraction <= MAX_DROP_FRACTION {
+            return;
+        }
+
+        let mut graphics = settings.graphics.clone();
+        if Self::lower(&mut graphics) {
+            log::info!(
+                "{:.0}% of frames dropped, lowering graphics quality",
+                fraction * 100.
+            );
+            let current = &settings.graphics;
+            quality
+                .original
+                .get_or_insert((current.bloom_intensity, current.resolution_scale));
+            settings.graphics = graphics;
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use crate::chart::{Chart, PlaySettings};
+use crate::console::Console;
+use crate::error::ConsoleError;
+use crate::paths::Paths;
+use crate::score::Scores;
+use crate::settings::Settings;
+use amethyst::{
+    core::{timing::Time, SystemDesc},
+    ecs::{LazyUpdate, Read, System, SystemData, World},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// File in the cache directory holding the token of the running game.
+pub const TOKEN_FILE: &str = "remote-token";
+/// The method presenting the token, which has to be the first request of a connection.
+const AUTH_METHOD: &str = "auth";
+/// Console commands that can be run remotely: those controlling playback and those only
+/// reading. The others, such as `export` and `bundle`, write files.
+const METHODS: &[&str] = &["load", "seek", "speed", "keys", "gpu"];
+
+// Error codes of the JSON-RPC 2.0 specification.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// A command that failed, such as seeking with no chart playing.
+const COMMAND_FAILED: i64 = -32000;
+/// The first request of a connection wasn't `auth` with the right token.
+const UNAUTHORIZED: i64 = -32001;
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Vec<Value>,
+    /// Absent for notifications, which get no response.
+    id: Option<Value>,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+type Reply = Result<Value, RpcError>;
+
+/// A request handed from a connection to the system, which runs it on the world.
+struct Call {
+    method: String,
+    params: Vec<String>,
+    reply: Sender<Reply>,
+}
+
+fn response(id: Value, reply: Reply) -> Value {
+    match reply {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(e) => json!({
+            "jsonrpc": "2.0",
+            "error": { "code": e.code, "message": e.message },
+            "id": id,
+        }),
+    }
+}
+
+/// A token of 128 random bits in hex, from the random keys of the standard library's hash maps.
+fn new_token() -> String {
+    (0..2)
+        .map(|_| format!("{:016x}", RandomState::new().build_hasher().finish()))
+        .collect()
+}
+
+/// Compares in time independent of where the two differ, so that the token can't be guessed byte
+/// by byte.
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Reads requests from a connection until it closes and writes back their responses.
+///
+/// The connection is closed on anything that isn't a JSON-RPC request, so that requests of other
+/// protocols, such as the HTTP requests any web page can send to a local port, go no further
+/// than their first line. The first request has to present the token.
+fn serve(stream: TcpStream, token: Arc<String>, calls: Sender<Call>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    let mut authenticated = false;
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = match serde_json::from_str::<Request>(&line) {
+            Ok(request) if request.jsonrpc == "2.0" => request,
+            Ok(_) => {
+                let error = RpcError {
+                    code: INVALID_REQUEST,
+                    message: "not a JSON-RPC 2.0 request".into(),
+                };
+                let _ = writeln!(writer, "{}", response(Value::Null, Err(error)));
+                return;
+            }
+            Err(e) => {
+                let error = RpcError {
+                    code: PARSE_ERROR,
+                    message: e.to_string(),
+                };
+                let _ = writeln!(writer, "{}", response(Value::Null, Err(error)));
+                return;
+            }
+        };
+        if !authenticated {
+            authenticated = request.method == AUTH_METHOD
+                && match request.params.as_slice() {
+                    [Value::String(given)] => same_token(given, &token),
+                    _ => false,
+                };
+            let reply = if authenticated {
+                Ok(Value::Bool(true))
+            } else {
+                Err(RpcError {
+                    code: UNAUTHORIZED,
+                    message: format!("the first request must be `{}` with the token", AUTH_METHOD),
+                })
+            };
+            let id = request.id.unwrap_or(Value::Null);
+            if writeln!(writer, "{}", response(id, reply)).is_err() || !authenticated {
+                return;
+            }
+            continue;
+        }
+        // Parameters are passed to the console as words, so numbers and strings both do.
+        let params = request
+            .params
+            .iter()
+            .map(|param| match param {
+                Value::String(param) => param.clone(),
+                param => param.to_string(),
+            })
+            .collect();
+        let (reply, receiver) = channel();
+        let call = Call {
+            method: request.method,
+            params,
+            reply,
+        };
+        // The game is shutting down.
+        if calls.send(call).is_err() {
+            return;
+        }
+        let reply = match receiver.recv() {
+            Ok(reply) => reply,
+            Err(_) => return,
+        };
+        let id = match request.id {
+            Some(id) => id,
+            None => continue,
+        };
+        if writeln!(writer, "{}", response(id, reply)).is_err() {
+            return;
+        }
+    }
+}
+
+/// The `score` method: the scores of the chart playing.
+fn score(world: &mut World) -> Reply {
+    let total_notes = match &*world.read_resource::<Option<Chart>>() {
+        Some(chart) => chart.judged_notes(),
+        None => {
+            return Err(RpcError {
+                code: COMMAND_FAILED,
+                message: ConsoleError::NotPlaying.to_string(),
+            })
+        }
+    };
+    let time = world
+        .read_resource::<Option<PlaySettings>>()
+        .as_ref()
+        .map(|play_settings| {
+            world.read_resource::<Time>().absolute_time_seconds() - play_settings.base_time
+        });
+    let players: Vec<_> = world
+        .read_resource::<Scores>()
+        .players
+        .iter()
+        .map(|score| {
+            json!({
+                "score": score.value(total_notes),
+                "combo": score.combo,
+                "max_combo": score.max_combo,
+                "gauge": score.gauge,
+                "judged": score.records.len(),
+                "cleared": score.cleared(total_notes),
+            })
+        })
+        .collect();
+    Ok(json!({
+        "time": time,
+        "total_notes": total_notes,
+        "players": players,
+    }))
+}
+
+/// Lets external tools, such as stream overlays and editor frontends, control a running game
+/// through a local socket, with the `remote` feature.
+///
+/// The socket listens on the [`port`] of the loopback address and takes one JSON-RPC 2.0 request
+/// per line. Each start of the game writes a new token to [`TOKEN_FILE`] in the cache directory,
+/// and the first request of a connection has to be `auth` with the token as its parameter;
+/// other connections are closed.
+///
+/// The [console] commands `load`, `seek`, `speed`, `keys` and `gpu` are methods taking the
+/// command's arguments as their parameters, and return the line the console would print. The
+/// `score` method returns the scores of the chart playing.
+///
+/// [`port`]: crate::settings::RemoteSettings::port
+/// [console]: crate::console::ConsoleSystem
+pub struct RemoteSystem {
+    /// Calls from the connections; none if the socket couldn't be opened.
+    calls: Option<Receiver<Call>>,
+}
+
+pub struct RemoteSystemDesc;
+
+impl<'a, 'b> SystemDesc<'a, 'b, RemoteSystem> for RemoteSystemDesc {
+    fn build(self, world: &mut World) -> RemoteSystem {
+        <RemoteSystem as System<'_>>::SystemData::setup(world);
+
+        let port = world.read_resource::<Settings>().remote.port;
+        let token_path = world.read_resource::<Paths>().cache.join(TOKEN_FILE);
+        let token = Arc::new(new_token());
+        let written = std::fs::create_dir_all(&world.read_resource::<Paths>().cache)
+            .and_then(|()| std::fs::write(&token_path, token.as_bytes()));
+        if let Err(e) = written {
+            log::warn!(
+                "Remote control is disabled, failed to write {}: {}",
+                token_path.display(),
+                e
+            );
+            return RemoteSystem { calls: None };
+        }
+        let listener = match TcpListener::bind((Ipv4Addr::LOCALHOST, port)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::warn!("Remote control is disabled: {}", e);
+                return RemoteSystem { calls: None };
+            }
+        };
+        log::info!("Listening for remote control on port {}", port);
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().filter_map(Result::ok) {
+                let calls = sender.clone();
+                let token = token.clone();
+                thread::spawn(move || serve(stream, token, calls));
+            }
+        });
+        RemoteSystem {
+            calls: Some(receiver),
+        }
+    }
+}
+
+impl<'s> System<'s> for RemoteSystem {
+    type SystemData = (Read<'s, LazyUpdate>, Read<'s, Console>);
+
+    fn run(&mut self, (lazy, console): Self::SystemData) {
+        let calls = match &self.calls {
+            Some(calls) => calls,
+            None => return,
+        };
+        for call in calls.try_iter() {
+            let command = match call.method.as_str() {
+                "score" => None,
+                method => match console
+                    .command(method)
+                    .filter(|_| METHODS.contains(&method))
+                {
+                    Some(command) => Some(command),
+                    None => {
+                        let _ = call.reply.send(Err(RpcError {
+                            code: METHOD_NOT_FOUND,
+                            message: format!("unknown method `{}`", call.method),
+                        }));
+                        continue;
+                    }
+                },
+            };
+            let Call { params, reply, .. } = call;
+            // Commands run at the end of the frame like those typed into the console.
+            let reply = Mutex::new(reply);
+            lazy.exec_mut(move |world| {
+                let result = match command {
+                    Some((usage, run)) => {
+                        let args: Vec<_> = params.iter().map(String::as_str).collect();
+                        match run(world, &args) {
+                            Ok(output) => {
+                                log::info!("Remote: {}", output);
+                                Ok(Value::String(output))
+                            }
+                            Err(ConsoleError::InvalidArguments) => Err(RpcError {
+                                code: INVALID_PARAMS,
+                                message: format!("usage: {}", usage),
+                            }),
+                            Err(e) => Err(RpcError {
+                                code: COMMAND_FAILED,
+                                message: e.to_string(),
+                            }),
+                        }
+                    }
+                    None => score(world),
+                };
+                let _ = reply.into_inner().unwrap().send(result);
+            });
+        }
+    }
+}
@@ -0,0 +1,405 @@
+use crate::chart::PlaySettings;
+use crate::pause::Paused;
+use crate::score::Scores;
+use crate::settings::Settings;
+use crate::ui::percent_transform;
+use crate::InterFont;
+use amethyst::{
+    core::{timing::Time, SystemDesc},
+    ecs::{Entity, Read, ReadExpect, System, SystemData, World, Write},
+    input::is_key_down,
+    prelude::*,
+    shrev::{EventChannel, ReaderId},
+    ui::{Anchor, UiText},
+    window::ScreenDimensions,
+    winit::{ElementState, Event, KeyboardInput, ScanCode, VirtualKeyCode, WindowEvent},
+};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Where the timestamps of a replay come from.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TimeSource {
+    /// The start of the frame that processed the event. Quantized to the frame interval.
+    Frame,
+    /// Frame timestamps corrected by the expected polling latency, see [`Replay::retime`].
+    Estimated,
+    /// Timestamps reported by the input device or OS.
+    Device,
+}
+
+impl Default for TimeSource {
+    fn default() -> Self {
+        TimeSource::Frame
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReplayInput {
+    /// Seconds since the start of the chart, without the play offset applied.
+    pub time: f64,
+    pub scancode: ScanCode,
+    pub pressed: bool,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Replay {
+    pub time_source: TimeSource,
+    /// The mean frame interval in seconds at the time of recording.
+    pub frame_interval: f64,
+    /// The offset the play was judged with.
+    pub offset: f32,
+    /// Inputs sorted by time.
+    pub inputs: Vec<ReplayInput>,
+    /// The song directory relative to the library root, or `None` for the built-in demo chart.
+    pub song: Option<PathBuf>,
+    /// Key positions of each player, as in [`KeyMappings`](crate::judge::KeyMappings).
+    pub key_maps: Vec<Vec<(ScanCode, (f32, f32))>>,
+}
+
+impl Replay {
+    /// Converts a replay recorded with frame timestamps into estimated input times.
+    ///
+    /// An input is observed at the first frame after it happened, so on average it occurred half
+    /// a frame earlier than recorded. Replays with other time sources are returned unchanged.
+    pub fn retime(mut self) -> Self {
+        if self.time_source == TimeSource::Frame {
+            let latency = self.frame_interval / 2.;
+            for input in &mut self.inputs {
+                input.time -= latency;
+            }
+            self.time_source = TimeSource::Estimated;
+        }
+        self
+    }
+}
+
+/// Records keyboard input into the [`Replay`] resource.
+///
+/// Winit does not expose device timestamps, so inputs are recorded with frame timestamps until
+/// input is read on a dedicated thread.
+pub struct ReplayRecordSystem {
+    reader_id: ReaderId<Event>,
+    frames: u32,
+}
+
+pub struct ReplayRecordSystemDesc;
+
+impl<'a, 'b> SystemDesc<'a, 'b, ReplayRecordSystem> for ReplayRecordSystemDesc {
+    fn build(self, world: &mut World) -> ReplayRecordSystem {
+        <ReplayRecordSystem as System<'_>>::SystemData::setup(world);
+
+        let reader_id = world
+            .get_mut::<EventChannel<Event>>()
+            .unwrap()
+            .register_reader();
+
+        ReplayRecordSystem {
+            reader_id,
+            frames: 0,
+        }
+    }
+}
+
+impl<'s> System<'s> for ReplayRecordSystem {
+    type SystemData = (
+        Read<'s, EventChannel<Event>>,
+        ReadExpect<'s, Time>,
+        Read<'s, Option<PlaySettings>>,
+        Read<'s, Paused>,
+        Read<'s, Option<ReplayPlayback>>,
+        Write<'s, Replay>,
+    );
+
+    fn run(&mut self, (events, time, settings, paused, playback, mut replay): Self::SystemData) {
+        let settings = match &*settings {
+            Some(settings) if !paused.0 && playback.is_none() => settings,
+            _ => {
+                // Drain events so they don't pile up until a chart starts or the play resumes.
+                events.read(&mut self.reader_id).for_each(drop);
+                return;
+            }
+        };
+        let now = time.absolute_time_seconds() - settings.base_time;
+        for event in events.read(&mut self.reader_id) {
+            if let Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                scancode, state, ..
+                            },
+                        ..
+                    },
+                ..
+            } = event
+            {
+                replay.inputs.push(ReplayInput {
+                    time: now,
+                    scancode: *scancode,
+                    pressed: *state == ElementState::Pressed,
+                });
+            }
+        }
+        self.frames += 1;
+        replay.frame_interval +=
+            (time.delta_real_seconds() as f64 - replay.frame_interval) / self.frames as f64;
+        replay.offset = settings.offset;
+        replay.time_source = TimeSource::Frame;
+    }
+}
+
+/// The inputs of one replay, fed by chart time.
+struct ReplayFeed {
+    replay: Replay,
+    /// Seconds added to the input times, so that the replay is judged with the offset it was
+    /// recorded with under the offset of the playback.
+    shift: f64,
+    /// Index of the next input to feed.
+    next: usize,
+    /// The inputs due in this frame.
+    due: Vec<ReplayInput>,
+}
+
+impl ReplayFeed {
+    fn new(replay: Replay, offset: f32) -> Self {
+        Self {
+            shift: (replay.offset - offset) as f64,
+            replay,
+            next: 0,
+            due: Vec::new(),
+        }
+    }
+
+    /// Moves the inputs up to chart time `now` into `due`, or skips the inputs before `now` if
+    /// `reposition` is set.
+    fn advance(&mut self, now: f64, reposition: bool) {
+        self.due.clear();
+        let (inputs, shift) = (&self.replay.inputs, self.shift);
+        if reposition {
+            self.next = inputs
+                .iter()
+                .position(|input| input.time + shift >= now)
+                .unwrap_or_else(|| inputs.len());
+        }
+        while let Some(input) = inputs
+            .get(self.next)
+            .filter(|input| input.time + shift <= now)
+        {
+            self.due.push(input.clone());
+            self.next += 1;
+        }
+    }
+}
+
+/// Feeds recorded [`Replay`]s to the judge systems in place of the keyboard.
+///
+/// Inputs are fed by chart time rather than wall-clock time, so the playback follows the time
+/// scale for pausing and speed changes, and a changed base time, as set by seeking, moves the feed
+/// to the new position.
+///
+/// A single replay feeds every player it was recorded with. To compare two plays of a chart, two
+/// single player replays can instead feed one player each on the highways of versus.
+pub struct ReplayPlayback {
+    /// Shown with the playback controls.
+    pub title: String,
+    /// The offset to judge with.
+    pub offset: f32,
+    feeds: Vec<ReplayFeed>,
+    /// The base time the feeds are positioned for.
+    base_time: f64,
+}
+
+impl ReplayPlayback {
+    pub fn new(replay: Replay) -> Self {
+        let offset = replay.offset;
+        Self {
+            title: "Replay".into(),
+            offset,
+            feeds: vec![ReplayFeed::new(replay, offset)],
+            base_time: std::f64::NAN,
+        }
+    }
+
+    /// Plays `left` as the first player and `right` as the second, judged with the offset of
+    /// `left`. Only the first key map of each replay is used.
+    pub fn compare(title: String, left: Replay, right: Replay) -> Self {
+        let offset = left.offset;
+        Self {
+            title,
+            offset,
+            feeds: vec![
+                ReplayFeed::new(left, offset),
+                ReplayFeed::new(right, offset),
+            ],
+            base_time: std::f64::NAN,
+        }
+    }
+
+    /// The song of the replays, see [`Replay::song`].
+    pub fn song(&self) -> Option<&PathBuf> {
+        self.feeds[0].replay.song.as_ref()
+    }
+
+    /// Key positions of each player, or `None` if a replay was recorded without them.
+    pub fn key_maps(&self) -> Option<Vec<Vec<(ScanCode, (f32, f32))>>> {
+        match &self.feeds[..] {
+            [feed] if !feed.replay.key_maps.is_empty() => Some(feed.replay.key_maps.clone()),
+            [_] => None,
+            feeds => feeds
+                .iter()
+                .map(|feed| feed.replay.key_maps.get(0).cloned())
+                .collect(),
+        }
+    }
+
+    /// The inputs of `player` due in this frame.
+    pub fn due(&self, player: u8) -> &[ReplayInput] {
+        match &self.feeds[..] {
+            [feed] => &feed.due,
+            feeds => feeds.get(player as usize).map_or(&[][..], |feed| &feed.due),
+        }
+    }
+
+    /// Moves the feeds to chart time `now`. If the base time changed, inputs before `now` are
+    /// skipped instead of fed.
+    fn advance(&mut self, base_time: f64, now: f64) {
+        let reposition = base_time != self.base_time;
+        self.base_time = base_time;
+        for feed in &mut self.feeds {
+            feed.advance(now, reposition);
+        }
+    }
+}
+
+/// Advances the [`ReplayPlayback`], if any.
+#[derive(Default)]
+pub struct ReplayPlaybackSystem;
+
+impl<'s> System<'s> for ReplayPlaybackSystem {
+    type SystemData = (
+        ReadExpect<'s, Time>,
+        Read<'s, Option<PlaySettings>>,
+        Write<'s, Option<ReplayPlayback>>,
+    );
+
+    fn run(&mut self, (time, settings, mut playback): Self::SystemData) {
+        if let (Some(settings), Some(playback)) = (&*settings, &mut *playback) {
+            let now = time.absolute_time_seconds() - settings.base_time;
+            playback.advance(settings.base_time, now);
+        }
+    }
+}
+
+/// Playback rates selectable while watching a replay.
+const PLAYBACK_SPEEDS: [f32; 7] = [0.25, 0.5, 0.75, 1., 1.25, 1.5, 2.];
+/// Seconds skipped by one seek.
+const SEEK_SECONDS: f32 = 5.;
+/// Font size of the playback status in logical pixels.
+const PLAYBACK_FONT_SIZE: f32 = 18.;
+
+/// Keys to control a [`ReplayPlayback`]: Space pauses, left and right seek and up and down change
+/// the playback rate. Seeking starts the score over, as notes before the new position are judged
+/// again or not at all.
+pub struct PlaybackControls {
+    /// Index into [`PLAYBACK_SPEEDS`].
+    speed: usize,
+    paused: bool,
+    text: Option<Entity>,
+}
+
+impl Default for PlaybackControls {
+    fn default() -> Self {
+        Self {
+            speed: 3,
+            paused: false,
+            text: None,
+        }
+    }
+}
+
+impl PlaybackControls {
+    fn label(&self, title: &str) -> String {
+        format!(
+            "{} {:.2}x{}\nSpace: pause  Left/Right: seek  Up/Down: speed",
+            title,
+            PLAYBACK_SPEEDS[self.speed],
+            if self.paused { ", paused" } else { "" }
+        )
+    }
+
+    fn title(world: &World) -> String {
+        world
+            .read_resource::<Option<ReplayPlayback>>()
+            .as_ref()
+            .map_or_else(String::new, |playback| playback.title.clone())
+    }
+
+    pub fn start(&mut self, world: &mut World) {
+        let font = world.read_resource::<InterFont>().0.clone();
+        let font_size = {
+            let settings = world.read_resource::<Settings>();
+            let dimensions = world.read_resource::<ScreenDimensions>();
+            PLAYBACK_FONT_SIZE * settings.ui.scale_factor(&dimensions)
+        };
+        let transform = percent_transform("ReplayPlayback", Anchor::TopLeft, 0.02, -0.02, 0.4, 0.1);
+        let mut text = UiText::new(font, self.label(&Self::title(world)), [1.; 4], font_size);
+        text.align = Anchor::TopLeft;
+        self.text = Some(world.create_entity().with(transform).with(text).build());
+        world.write_resource::<Time>().set_time_scale(1.);
+    }
+
+    pub fn stop(&mut self, world: &mut World) {
+        if let Some(text) = self.text.take() {
+            world.delete_entity(text).unwrap();
+        }
+        world.write_resource::<Time>().set_time_scale(1.);
+    }
+
+    fn apply_speed(&self, world: &mut World) {
+        let rate = if self.paused {
+            0.
+        } else {
+            PLAYBACK_SPEEDS[self.speed]
+        };
+        world.write_resource::<Time>().set_time_scale(rate);
+    }
+
+    fn seek_by(&self, world: &mut World, seconds: f32) {
+        let now = {
+            let time = world.read_resource::<Time>();
+            match &*world.read_resource::<Option<PlaySettings>>() {
+                Some(settings) => (time.absolute_time_seconds() - settings.base_time) as f32,
+                None => return,
+            }
+        };
+        crate::seek(world, (now + seconds).max(0.));
+        world.insert(Scores::default());
+    }
+
+    pub fn handle_event(&mut self, world: &mut World, event: &Event) {
+        if is_key_down(event, VirtualKeyCode::Space) {
+            self.paused = !self.paused;
+            self.apply_speed(world);
+        } else if is_key_down(event, VirtualKeyCode::Left) {
+            self.seek_by(world, -SEEK_SECONDS);
+        } else if is_key_down(event, VirtualKeyCode::Right) {
+            self.seek_by(world, SEEK_SECONDS);
+        } else if is_key_down(event, VirtualKeyCode::Up) {
+            self.speed = (self.speed + 1).min(PLAYBACK_SPEEDS.len() - 1);
+            self.apply_speed(world);
+        } else if is_key_down(event, VirtualKeyCode::Down) {
+            self.speed = self.speed.saturating_sub(1);
+            self.apply_speed(world);
+        } else {
+            return;
+        }
+        if let Some(text) = self.text {
+            let label = self.label(&Self::title(world));
+            if let Some(text) = world.write_storage::<UiText>().get_mut(text) {
+                text.text = label;
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Writes scores, replays, the play history and settings on a background thread, so that saving
+//! at the end of a play doesn't stall the frames leading into song select.
+//!
+//! Files are serialized when they are saved and their contents kept until they are written. Loads
+//! through [`load`], which [`migrate::load`](crate::migrate::load) also uses, read those contents
+//! instead of the file, so that a file saved a moment ago is never read back stale.
+use amethyst::config::{Config, ConfigError};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The contents of the files saved but not written yet, by path.
+type Pending = Arc<Mutex<HashMap<PathBuf, Arc<String>>>>;
+
+enum Job {
+    /// Writes the pending contents of the file.
+    Write(PathBuf),
+    /// Replies once everything queued before has been written.
+    Flush(Sender<()>),
+}
+
+struct Queue {
+    jobs: Mutex<Sender<Job>>,
+    pending: Pending,
+}
+
+lazy_static::lazy_static! {
+    static ref QUEUE: Queue = {
+        let (jobs, receiver) = channel();
+        let pending = Pending::default();
+        let writer_pending = pending.clone();
+        thread::spawn(move || run(receiver, writer_pending));
+        Queue {
+            jobs: Mutex::new(jobs),
+            pending,
+        }
+    };
+}
+
+/// Replaces the file in one step, so that a crash while writing never leaves it half written.
+fn write_file(path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let temp = path.with_extension("tmp");
+    std::fs::write(&temp, contents)?;
+    std::fs::rename(&temp, path)
+}
+
+fn run(jobs: Receiver<Job>, pending: Pending) {
+    while let Ok(job) = jobs.recv() {
+        // Files saved together, such as those at the end of a play, are written as one batch, and
+        // a file saved several times in it only once with its latest contents.
+        let mut written = HashSet::new();
+        let mut flushes = Vec::new();
+        for job in std::iter::once(job).chain(jobs.try_iter()) {
+            let path = match job {
+                Job::Write(path) => path,
+                Job::Flush(reply) => {
+                    flushes.push(reply);
+                    continue;
+                }
+            };
+            if !written.insert(path.clone()) {
+                continue;
+            }
+            let contents = match pending.lock().unwrap().get(&path) {
+                Some(contents) => contents.clone(),
+                None => continue,
+            };
+            if let Err(e) = write_file(&path, &contents) {
+                log::warn!("Failed to save {}: {}", path.display(), e);
+            }
+            // Contents saved again while writing wait for the next batch.
+            let mut pending = pending.lock().unwrap();
+            if pending
+                .get(&path)
+                .map_or(false, |latest| Arc::ptr_eq(latest, &contents))
+            {
+                pending.remove(&path);
+            }
+        }
+        for reply in flushes {
+            let _ = reply.send(());
+        }
+    }
+}
+
+fn enqueue(path: PathBuf, contents: Arc<String>) {
+    QUEUE.pending.lock().unwrap().insert(path.clone(), contents);
+    // The writer only stops with the process.
+    let _ = QUEUE.jobs.lock().unwrap().send(Job::Write(path));
+}
+
+/// Saves `value` to `path` in the background, creating its directory. Failures to write are
+/// logged.
+pub fn save<T: Serialize>(path: impl Into<PathBuf>, value: &T) -> Result<(), ConfigError> {
+    let contents =
+        ron::ser::to_string_pretty(value, Default::default()).map_err(ConfigError::Serializer)?;
+    enqueue(path.into(), Arc::new(contents));
+    Ok(())
+}
+
+/// Saves a copy of the file at `from` to `to` in the background, as it will be once the saves
+/// queued for it are written.
+pub fn copy(from: &Path, to: impl Into<PathBuf>) -> io::Result<()> {
+    let pending = QUEUE.pending.lock().unwrap().get(from).cloned();
+    let contents = match pending {
+        Some(contents) => contents,
+        None => Arc::new(std::fs::read_to_string(from)?),
+    };
+    enqueue(to.into(), contents);
+    Ok(())
+}
+
+/// Loads `path`, or what was last saved to it if that isn't written yet.
+pub fn load<T: Config + DeserializeOwned>(path: &Path) -> Result<T, ConfigError> {
+    let pending = QUEUE.pending.lock().unwrap().get(path).cloned();
+    match pending {
+        Some(contents) => ron::de::from_str(&contents).map_err(ConfigError::Parser),
+        None => T::load_no_fallback(path),
+    }
+}
+
+/// Waits until everything saved so far is written, before the game exits.
+pub fn flush() {
+    let (reply, done) = channel();
+    if QUEUE.jobs.lock().unwrap().send(Job::Flush(reply)).is_ok() {
+        let _ = done.recv();
+    }
+}
@@ -0,0 +1,337 @@
+use crate::chart::Chart;
+use crate::judge::{JudgeEvent, JudgeWindows, Judgement, MineEvent, TickEvent};
+use crate::migrate::{self, Versioned};
+use crate::modifiers::{self, ActiveModifiers, Modifier};
+use crate::settings::{RateWindows, Settings};
+use amethyst::{
+    core::{timing::Time, SystemDesc},
+    ecs::{Read, ReadExpect, System, SystemData, World, Write},
+    shrev::{EventChannel, ReaderId},
+    winit::ScanCode,
+};
+use serde::{Deserialize, Serialize};
+
+pub const MAX_SCORE: u32 = 10_000_000;
+/// Gauge lost on a miss, as a fraction of the full gauge.
+const MISS_DAMAGE: f32 = 0.02;
+/// Gauge lost when setting off a mine.
+const MINE_DAMAGE: f32 = 0.04;
+/// Gauge gained on each tick of a roll kept alive.
+const TICK_GAUGE: f32 = 0.002;
+/// Gauge needed at the end of the chart to clear it.
+pub const CLEAR_GAUGE: f32 = 0.7;
+
+/// The outcome of a single note.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NoteRecord {
+    pub time: f32,
+    pub judgement: Judgement,
+    pub offset: Option<f32>,
+    /// The key the judgement is attributed to, see [`JudgeEvent::key`].
+    #[serde(default)]
+    pub key: Option<ScanCode>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Score {
+    /// Format version, see [`Versioned`].
+    #[serde(default = "migrate::unversioned")]
+    pub version: u32,
+    /// Sum of judgement weights so far.
+    pub weight: f64,
+    pub combo: u32,
+    pub max_combo: u32,
+    /// Gauge in the range `0..=1`.
+    pub gauge: f32,
+    /// Records in judgement order.
+    pub records: Vec<NoteRecord>,
+    /// Number of mines set off.
+    pub mines_hit: u32,
+    /// Number of roll ticks passed while the roll was kept alive. They fill the gauge but don't
+    /// add to the score, so that scores stay comparable with those from before ticks.
+    pub ticks: u32,
+    /// The windows the play was judged with, which matter when comparing skewed plays. At
+    /// another rate with [`RateWindows::RealTime`], these are the windows in chart time.
+    pub windows: JudgeWindows,
+    /// The playback rate of the play.
+    pub rate: f32,
+    /// How the windows followed the rate.
+    pub rate_windows: RateWindows,
+    /// The miss forgiveness window the play used, if any. Its combos are not comparable to
+    /// those of plays without it.
+    pub miss_forgiveness: Option<f32>,
+    /// Whether the notes scrolled at a constant speed, which flattens the scroll gimmicks of the
+    /// chart.
+    pub constant_scroll: bool,
+    /// The modifiers of the play, which multiply the score.
+    pub modifiers: Vec<Modifier>,
+    /// Whether a miss ended the play with [`Modifier::SuddenDeath`]. Nothing is judged after it.
+    pub failed: bool,
+    /// Chart time of the last miss, for miss forgiveness.
+    #[serde(skip)]
+    last_miss: Option<f32>,
+}
+
+impl Default for Score {
+    fn default() -> Self {
+        Self {
+            version: Self::current_version(),
+            weight: 0.,
+            combo: 0,
+            max_combo: 0,
+            gauge: 0.,
+            records: Vec::new(),
+            mines_hit: 0,
+            ticks: 0,
+            windows: JudgeWindows::default(),
+            rate: 1.,
+            rate_windows: RateWindows::Scaled,
+            miss_forgiveness: None,
+            constant_scroll: false,
+            modifiers: Vec::new(),
+            failed: false,
+            last_miss: None,
+        }
+    }
+}
+
+impl Versioned for Score {
+    const MIGRATIONS: &'static [fn(&mut Self)] = &[migrate::no_changes];
+
+    fn version_mut(&mut self) -> &mut u32 {
+        &mut self.version
+    }
+}
+
+/// Scores of each player.
+#[derive(Default)]
+pub struct Scores {
+    pub players: Vec<Score>,
+}
+
+impl Scores {
+    pub fn player_mut(&mut self, player: u8) -> &mut Score {
+        let player = player as usize;
+        if self.players.len() <= player {
+            self.players.resize_with(player + 1, Default::default);
+        }
+        &mut self.players[player]
+    }
+}
+
+fn weight(judgement: Judgement) -> f64 {
+    match judgement {
+        Judgement::Perfect => 1.,
+        Judgement::Near => 0.5,
+        Judgement::Miss => 0.,
+    }
+}
+
+impl Score {
+    pub fn apply(&mut self, record: NoteRecord, total_notes: usize) {
+        let total_notes = total_notes.max(1) as f32;
+        self.weight += weight(record.judgement);
+        match record.judgement {
+            Judgement::Perfect | Judgement::Near => {
+                self.combo += 1;
+                self.max_combo = self.max_combo.max(self.combo);
+                self.gauge += weight(record.judgement) as f32 * 2. / total_notes;
+            }
+            Judgement::Miss => {
+                // With forgiveness, only a miss soon after another one breaks the combo.
+                let forgiven = self.miss_forgiveness.map_or(false, |window| {
+                    self.last_miss
+                        .map_or(true, |last| record.time - last > window)
+                });
+                if !forgiven {
+                    self.combo = 0;
+                }
+                self.last_miss = Some(record.time);
+                self.gauge -= MISS_DAMAGE;
+                if self.modifiers.contains(&Modifier::SuddenDeath) {
+                    self.failed = true;
+                    self.gauge = 0.;
+                }
+            }
+        }
+        self.gauge = self.gauge.max(0.).min(1.);
+        self.records.push(record);
+    }
+
+    pub fn hit_mine(&mut self) {
+        self.mines_hit += 1;
+        self.gauge = (self.gauge - MINE_DAMAGE).max(0.);
+    }
+
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+        self.gauge = (self.gauge + TICK_GAUGE).min(1.);
+    }
+
+    /// The score, multiplied by the modifiers, so that it can exceed [`MAX_SCORE`].
+    pub fn value(&self, total_notes: usize) -> u32 {
+        score_for_weight(self.weight, total_notes, &self.modifiers)
+    }
+
+    /// Whether every note was judged with at least [`CLEAR_GAUGE`] left.
+    pub fn cleared(&self, total_notes: usize) -> bool {
+        !self.failed && self.records.len() >= total_notes && self.gauge >= CLEAR_GAUGE
+    }
+}
+
+fn score_for_weight(weight: f64, total_notes: usize, modifiers: &[Modifier]) -> u32 {
+    (weight / total_notes.max(1) as f64 * MAX_SCORE as f64 * modifiers::multiplier(modifiers))
+        .round() as u32
+}
+
+/// A target play to compare against, such as the personal best.
+pub struct Pacemaker {
+    /// Weight and gauge of the target after each judgement.
+    progress: Vec<(f64, f32)>,
+    /// The modifiers of the target, which its score is multiplied by.
+    modifiers: Vec<Modifier>,
+}
+
+impl Pacemaker {
+    pub fn new(target: &Score, total_notes: usize) -> Self {
+        let mut replayed = Score::default();
+        let progress = target
+            .records
+            .iter()
+            .map(|record| {
+                replayed.apply(record.clone(), total_notes);
+                (replayed.weight, replayed.gauge)
+            })
+            .collect();
+        Self {
+            progress,
+            modifiers: target.modifiers.clone(),
+        }
+    }
+
+    fn at(&self, judged: usize) -> (f64, f32) {
+        judged
+            .min(self.progress.len())
+            .checked_sub(1)
+            .map_or((0., 0.), |i| self.progress[i])
+    }
+}
+
+/// The live comparison of the first player against the [`Pacemaker`].
+#[derive(Default)]
+pub struct Ghost {
+    /// Current score minus the target's score after the same number of notes.
+    pub difference: i64,
+    /// The target's gauge after the same number of notes.
+    pub gauge: f32,
+}
+
+pub struct ScoreSystem {
+    reader_id: ReaderId<JudgeEvent>,
+    mine_reader: ReaderId<MineEvent>,
+    tick_reader: ReaderId<TickEvent>,
+}
+
+pub struct ScoreSystemDesc;
+
+impl<'a, 'b> SystemDesc<'a, 'b, ScoreSystem> for ScoreSystemDesc {
+    fn build(self, world: &mut World) -> ScoreSystem {
+        <ScoreSystem as System<'_>>::SystemData::setup(world);
+
+        let reader_id = world
+            .get_mut::<EventChannel<JudgeEvent>>()
+            .unwrap()
+            .register_reader();
+        let mine_reader = world
+            .get_mut::<EventChannel<MineEvent>>()
+            .unwrap()
+            .register_reader();
+        let tick_reader = world
+            .get_mut::<EventChannel<TickEvent>>()
+            .unwrap()
+            .register_reader();
+
+        ScoreSystem {
+            reader_id,
+            mine_reader,
+            tick_reader,
+        }
+    }
+}
+
+impl<'s> System<'s> for ScoreSystem {
+    type SystemData = (
+        Read<'s, EventChannel<JudgeEvent>>,
+        Read<'s, EventChannel<MineEvent>>,
+        Read<'s, EventChannel<TickEvent>>,
+        Read<'s, Option<Chart>>,
+        Read<'s, Option<Pacemaker>>,
+        Read<'s, Settings>,
+        Read<'s, ActiveModifiers>,
+        ReadExpect<'s, Time>,
+        Write<'s, Scores>,
+        Write<'s, Ghost>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            events,
+            mine_events,
+            tick_events,
+            chart,
+            pacemaker,
+            settings,
+            modifiers,
+            time,
+            mut scores,
+            mut ghost,
+        ): Self::SystemData,
+    ) {
+        for event in mine_events.read(&mut self.mine_reader) {
+            scores.player_mut(event.player).hit_mine();
+        }
+        for event in tick_events.read(&mut self.tick_reader) {
+            let score = scores.player_mut(event.player);
+            if !score.failed {
+                score.tick();
+            }
+        }
+        let total_notes = chart.as_ref().map_or(0, Chart::judged_notes);
+        for event in events.read(&mut self.reader_id) {
+            let score = scores.player_mut(event.player);
+            if score.failed {
+                continue;
+            }
+            let rate = time.time_scale();
+            score.windows = settings.gameplay.windows(rate);
+            // The rate is zero while paused.
+            if rate > 0. {
+                score.rate = rate;
+            }
+            score.rate_windows = settings.gameplay.rate_windows;
+            score.miss_forgiveness = settings.gameplay.miss_forgiveness;
+            score.constant_scroll = settings.gameplay.constant_scroll;
+            score.modifiers.clone_from(&modifiers.0);
+            score.apply(
+                NoteRecord {
+                    time: event.time,
+                    judgement: event.judgement,
+                    offset: event.offset,
+                    key: event.key,
+                },
+                total_notes,
+            );
+            if event.player != 0 {
+                continue;
+            }
+            if let Some(pacemaker) = &*pacemaker {
+                let (target_weight, target_gauge) = pacemaker.at(score.records.len());
+                ghost.difference = score.value(total_notes) as i64
+                    - score_for_weight(target_weight, total_notes, &pacemaker.modifiers) as i64;
+                ghost.gauge = target_gauge;
+            }
+        }
+    }
+}
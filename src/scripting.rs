@@ -0,0 +1,191 @@
+//! Lua modifier scripts, enabled with the `scripting` feature.
+//!
+//! A script may define any of these globals:
+//!
+//! - `transform_chart(notes)` receives the notes as an array of `{ time, laser, lane }` tables
+//!   and returns the notes to play, or `nil` to keep them unchanged. Rolls also have a
+//!   `roll_end` time and mines are marked with `mine = true`.
+//! - `on_judge(event)` receives each judgement as `{ player, judgement, time, offset }`, where
+//!   `judgement` is `"Perfect"`, `"Near"` or `"Miss"`. Returning a number sets the player's gauge,
+//!   which is enough for e.g. sudden death.
+
+use crate::chart::{Chart, LaserId, Note, NoteKind, Timed};
+use crate::diagnostics::ErrorEvent;
+use crate::judge::JudgeEvent;
+use crate::score::Scores;
+use amethyst::{
+    core::SystemDesc,
+    ecs::{Read, System, SystemData, World, Write},
+    shrev::{EventChannel, ReaderId},
+};
+use rlua::{Function, Lua, Table};
+use std::path::Path;
+use std::sync::Mutex;
+
+struct Script {
+    name: String,
+    lua: Lua,
+}
+
+/// The scripts enabled in the settings, each in its own Lua state.
+#[derive(Default)]
+pub struct Scripts {
+    /// Lua states are not `Sync`; scripts only run from one system at a time anyway.
+    scripts: Mutex<Vec<Script>>,
+}
+
+impl Scripts {
+    /// Loads and runs the top level of each script, relative to `root`.
+    pub fn load(root: &Path, names: &[String], errors: &mut EventChannel<ErrorEvent>) -> Self {
+        let mut scripts = Vec::new();
+        for name in names {
+            let result = std::fs::read_to_string(root.join(name))
+                .map_err(|e| e.to_string())
+                .and_then(|source| {
+                    let lua = Lua::new();
+                    lua.context(|ctx| ctx.load(&source).set_name(name)?.exec())
+                        .map_err(|e| e.to_string())?;
+                    Ok(lua)
+                });
+            match result {
+                Ok(lua) => scripts.push(Script {
+                    name: name.clone(),
+                    lua,
+                }),
+                Err(e) => errors.single_write(ErrorEvent::new(format!("Script {}: {}", name, e))),
+            }
+        }
+        Self {
+            scripts: Mutex::new(scripts),
+        }
+    }
+
+    /// Runs `transform_chart` of each script in order on the chart's notes.
+    pub fn transform_chart(&self, chart: &mut Chart, errors: &mut EventChannel<ErrorEvent>) {
+        for script in self.scripts.lock().unwrap().iter() {
+            let notes = &chart.notes;
+            let result = script.lua.context(|ctx| {
+                let transform: Option<Function> = ctx.globals().get("transform_chart")?;
+                let transform = match transform {
+                    Some(transform) => transform,
+                    None => return Ok(None),
+                };
+                let table = ctx.create_table()?;
+                for (i, note) in notes.iter().enumerate() {
+                    let entry = ctx.create_table()?;
+                    entry.set("time", note.time)?;
+                    entry.set("laser", note.laser.0)?;
+                    entry.set("lane", note.lane)?;
+                    match note.kind {
+                        NoteKind::Tap => {}
+                        NoteKind::Roll { end } => entry.set("roll_end", end)?,
+                        NoteKind::Mine => entry.set("mine", true)?,
+                    }
+                    table.set(i + 1, entry)?;
+                }
+                let result: Option<Table> = transform.call(table)?;
+                result
+                    .map(|result| {
+                        result
+                            .sequence_values::<Table>()
+                            .map(|entry| {
+                                let entry = entry?;
+                                Ok(Timed {
+                                    time: entry.get("time")?,
+                                    inner: Note {
+                                        laser: LaserId(entry.get("laser")?),
+                                        lane: entry.get("lane")?,
+                                        kind: match (
+                                            entry.get("roll_end")?,
+                                            entry.get::<_, Option<bool>>("mine")?,
+                                        ) {
+                                            (_, Some(true)) => NoteKind::Mine,
+                                            (Some(end), _) => NoteKind::Roll { end },
+                                            _ => NoteKind::Tap,
+                                        },
+                                    },
+                                })
+                            })
+                            .collect::<rlua::Result<Vec<_>>>()
+                    })
+                    .transpose()
+            });
+            match result {
+                Ok(Some(mut notes)) => {
+                    let len = notes.len();
+                    notes.retain(|note| note.time.is_finite());
+                    if notes.len() != len {
+                        errors.single_write(ErrorEvent::new(format!(
+                            "Script {}: dropped notes with invalid times",
+                            script.name
+                        )));
+                    }
+                    notes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+                    chart.notes = notes;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    errors.single_write(ErrorEvent::new(format!("Script {}: {}", script.name, e)))
+                }
+            }
+        }
+    }
+}
+
+/// Passes judgements to the `on_judge` function of each script.
+pub struct ScriptJudgeSystem {
+    reader_id: ReaderId<JudgeEvent>,
+}
+
+pub struct ScriptJudgeSystemDesc;
+
+impl<'a, 'b> SystemDesc<'a, 'b, ScriptJudgeSystem> for ScriptJudgeSystemDesc {
+    fn build(self, world: &mut World) -> ScriptJudgeSystem {
+        <ScriptJudgeSystem as System<'_>>::SystemData::setup(world);
+
+        let reader_id = world
+            .get_mut::<EventChannel<JudgeEvent>>()
+            .unwrap()
+            .register_reader();
+
+        ScriptJudgeSystem { reader_id }
+    }
+}
+
+impl<'s> System<'s> for ScriptJudgeSystem {
+    type SystemData = (
+        Read<'s, EventChannel<JudgeEvent>>,
+        Read<'s, Scripts>,
+        Write<'s, Scores>,
+        Write<'s, EventChannel<ErrorEvent>>,
+    );
+
+    fn run(&mut self, (events, scripts, mut scores, mut errors): Self::SystemData) {
+        let scripts = scripts.scripts.lock().unwrap();
+        for event in events.read(&mut self.reader_id) {
+            for script in scripts.iter() {
+                let result = script.lua.context(|ctx| {
+                    let on_judge: Option<Function> = ctx.globals().get("on_judge")?;
+                    let on_judge = match on_judge {
+                        Some(on_judge) => on_judge,
+                        None => return Ok(None),
+                    };
+                    let table = ctx.create_table()?;
+                    table.set("player", event.player)?;
+                    table.set("judgement", format!("{:?}", event.judgement))?;
+                    table.set("time", event.time)?;
+                    table.set("offset", event.offset)?;
+                    on_judge.call::<_, Option<f32>>(table)
+                });
+                match result {
+                    Ok(Some(gauge)) => {
+                        scores.player_mut(event.player).gauge = gauge.max(0.).min(1.);
+                    }
+                    Ok(None) => {}
+                    Err(e) => errors
+                        .single_write(ErrorEvent::new(format!("Script {}: {}", script.name, e))),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,497 @@
+use crate::chart::timing::SnapGrid;
+use crate::judge::JudgeWindows;
+use crate::migrate::{self, Versioned};
+use crate::modifiers::Modifier;
+use crate::paths::Paths;
+use crate::profile::BEST_PACEMAKER;
+use amethyst::window::ScreenDimensions;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Format version, see [`Versioned`].
+    #[serde(default = "migrate::unversioned")]
+    pub version: u32,
+    pub graphics: GraphicsSettings,
+    pub ui: UiSettings,
+    pub accessibility: AccessibilitySettings,
+    pub gameplay: GameplaySettings,
+    pub library: LibrarySettings,
+    pub highway: HighwayConfig,
+    pub window: WindowSettings,
+    pub viewport: ViewportSettings,
+    pub audio: AudioSettings,
+    pub kiosk: KioskSettings,
+    pub log: LogSettings,
+    pub stream: StreamSettings,
+    pub download: DownloadSettings,
+    pub tournament: TournamentSettings,
+    pub remote: RemoteSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: Self::current_version(),
+            graphics: Default::default(),
+            ui: Default::default(),
+            accessibility: Default::default(),
+            gameplay: Default::default(),
+            library: Default::default(),
+            highway: Default::default(),
+            window: Default::default(),
+            viewport: Default::default(),
+            audio: Default::default(),
+            kiosk: Default::default(),
+            log: Default::default(),
+            stream: Default::default(),
+            download: Default::default(),
+            tournament: Default::default(),
+            remote: Default::default(),
+        }
+    }
+}
+
+impl Versioned for Settings {
+    const MIGRATIONS: &'static [fn(&mut Self)] = &[migrate::no_changes, pacemaker_per_chart];
+
+    fn version_mut(&mut self) -> &mut u32 {
+        &mut self.version
+    }
+}
+
+/// Version 2 keeps a personal best per chart, which replaced the single `scores/best.ron`.
+fn pacemaker_per_chart(settings: &mut Settings) {
+    let pacemaker = &mut settings.gameplay.pacemaker;
+    if pacemaker.as_ref().map(String::as_str) == Some("scores/best.ron") {
+        *pacemaker = Some(BEST_PACEMAKER.into());
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GraphicsSettings {
+    /// Strength of the glow around bright surfaces. Zero disables the bloom pass.
+    pub bloom_intensity: f32,
+    /// Luminance above which surfaces start to glow.
+    pub bloom_threshold: f32,
+    /// Size of the scene render target relative to the window.
+    pub resolution_scale: f32,
+    pub fullscreen: bool,
+    /// Lower bloom and the resolution scale while playing if frames are dropped repeatedly.
+    pub adaptive_quality: bool,
+    /// Smooth the edges of lasers and notes in their shader, independently of MSAA.
+    pub edge_antialiasing: bool,
+}
+
+impl Default for GraphicsSettings {
+    fn default() -> Self {
+        Self {
+            bloom_intensity: 0.6,
+            bloom_threshold: 0.8,
+            resolution_scale: 1.0,
+            fullscreen: false,
+            adaptive_quality: true,
+            edge_antialiasing: true,
+        }
+    }
+}
+
+/// Geometry of the highway, shared by the judge quad, note spawning and laser rendering.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HighwayConfig {
+    /// Distance from the camera to the judge plane in view space.
+    pub judge_depth: f32,
+    /// Distance from the camera to the vanishing basis of the lasers in view space.
+    pub basis_depth: f32,
+    /// Fraction of the way to the basis where the highway is cut off at default BPM.
+    pub cutoff: f32,
+    /// Upper bound of the cutoff when the BPM is higher than the default.
+    pub max_cutoff: f32,
+    /// How far notes are drawn past the judge line at their timing, indexed by laser id, as a
+    /// fraction of the visible highway. Positive values let players hit "above" the line. This
+    /// only moves the picture; judgement timing is unchanged.
+    pub judge_line_offsets: Vec<f32>,
+    /// Mirror the highway vertically, so that notes come from the bottom of the screen instead
+    /// of the top. Key positions keep playing the same lasers.
+    pub reverse: bool,
+}
+
+impl Default for HighwayConfig {
+    fn default() -> Self {
+        Self {
+            judge_depth: 1.,
+            basis_depth: 5.,
+            cutoff: 0.7,
+            max_cutoff: 0.95,
+            judge_line_offsets: Vec::new(),
+            reverse: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct UiSettings {
+    /// User preference multiplied on top of the display's DPI factor.
+    pub scale: f32,
+    /// Beats ahead of a BPM change at which the HUD announces it, or `None` to not announce.
+    pub bpm_warning_beats: Option<f32>,
+    /// Show a bar below the highway with the timing offset of each recent hit.
+    pub hit_error_bar: bool,
+    /// Draw judgement popups in world space above the judge line, following camera tilt and
+    /// zoom, instead of as UI text.
+    pub world_popups: bool,
+    /// Draw a faint marker on the judge quad at the position of each mapped key while it is
+    /// held, to show how the keyboard maps onto the playfield.
+    pub key_markers: bool,
+}
+
+impl Default for UiSettings {
+    fn default() -> Self {
+        Self {
+            scale: 1.0,
+            bpm_warning_beats: Some(4.),
+            hit_error_bar: true,
+            world_popups: false,
+            key_markers: true,
+        }
+    }
+}
+
+impl UiSettings {
+    /// The factor to convert logical UI pixel sizes into physical pixels.
+    pub fn scale_factor(&self, dimensions: &ScreenDimensions) -> f32 {
+        dimensions.hidpi_factor() as f32 * self.scale
+    }
+}
+
+/// Toning down charts and effects for players sensitive to motion or flashing light. These apply
+/// over what charts ask for.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessibilitySettings {
+    /// Keep the highway still: charts don't zoom it and judgement popups don't move.
+    pub reduced_motion: bool,
+    /// Slow down laser color changes to [`MIN_FADE_SECONDS`] and stop rolls and mines from
+    /// pulsing.
+    pub suppress_flashes: bool,
+}
+
+/// Shortest laser color fade with [`AccessibilitySettings::suppress_flashes`], which keeps color
+/// changes well below the three flashes per second considered safe.
+pub const MIN_FADE_SECONDS: f32 = 0.5;
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameplaySettings {
+    /// Seconds between a note appearing and its judgement at the chart's default BPM.
+    pub speed: f32,
+    /// Seconds added to the time of key presses to make up for the latency of the display and
+    /// input, measured by the calibration of the first-run wizard.
+    pub offset: f32,
+    /// Score file to race against, relative to the profile directory, or `best` for the personal
+    /// best of the chart being played.
+    pub pacemaker: Option<String>,
+    /// Replay to compare the replay of the personal best with, such as a downloaded top score.
+    /// Relative to the profile directory.
+    pub compare_replay: Option<String>,
+    /// Split the keyboard between two players with side-by-side highways.
+    pub versus: bool,
+    pub frame_pacing: FramePacing,
+    pub judge_windows: JudgeWindows,
+    /// How the windows follow a playback rate changed from the console.
+    pub rate_windows: RateWindows,
+    /// Ignore key presses that would take a note as an early miss, except those just outside the
+    /// near window.
+    pub note_lock: bool,
+    /// Lua modifier scripts relative to the data directory. Requires the `scripting` feature.
+    pub scripts: Vec<String>,
+    /// Replay the moments before a full combo was lost late in the chart after it ends.
+    pub combo_break_replay: bool,
+    /// Seconds of the burst window for stream charts: a miss keeps the combo unless another
+    /// miss came less than this long before it. It still scores as a miss and is recorded on
+    /// the score.
+    pub miss_forgiveness: Option<f32>,
+    /// Play without music or keysounds, with a metronome tick and a flash of the beat lamp on
+    /// every beat instead. For practicing without audio output or checking the visual sync of a
+    /// chart independent of the audio.
+    pub silent_practice: bool,
+    /// Scroll every note at the speed of the chart's default BPM, ignoring its BPM changes and
+    /// stops. Timing is unchanged, and scores are flagged since it defeats scroll gimmicks.
+    pub constant_scroll: bool,
+    /// Modifiers of plays outside the daily challenge, which picks its own. Those that make the
+    /// chart harder multiply the score.
+    pub modifiers: Vec<Modifier>,
+    /// Keep [`speed`](Self::speed) for every chart instead of recalling the one last used for the
+    /// chart or for charts of about the same BPM. As the speed is relative to the base BPM, notes
+    /// then scroll at the same on-screen velocity on every chart.
+    pub normalize_speed: bool,
+}
+
+/// The clock that chart time follows.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum FramePacing {
+    /// The start time of each rendered frame.
+    Render,
+    /// Experimental: the audio device's clock, to avoid judder when it drifts against the display.
+    Audio,
+}
+
+impl Default for FramePacing {
+    fn default() -> Self {
+        FramePacing::Render
+    }
+}
+
+/// How timing windows apply when the chart plays at another rate.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RateWindows {
+    /// The windows are in chart time, so they shrink in real time when playing faster.
+    Scaled,
+    /// The windows are in real time, so they stay as wide as at the normal rate.
+    RealTime,
+}
+
+impl Default for RateWindows {
+    fn default() -> Self {
+        RateWindows::Scaled
+    }
+}
+
+impl GameplaySettings {
+    /// The windows to judge chart time with at the playback `rate`. Rates of zero, while
+    /// paused, count as the normal rate.
+    pub fn windows(&self, rate: f32) -> JudgeWindows {
+        match self.rate_windows {
+            RateWindows::RealTime if rate > 0. => self.judge_windows.scaled(rate),
+            _ => self.judge_windows,
+        }
+    }
+}
+
+impl Default for GameplaySettings {
+    fn default() -> Self {
+        Self {
+            speed: 0.7,
+            offset: -0.05,
+            pacemaker: Some(BEST_PACEMAKER.into()),
+            compare_replay: Some("replays/compare.ron".into()),
+            versus: false,
+            frame_pacing: FramePacing::Render,
+            judge_windows: JudgeWindows::default(),
+            rate_windows: RateWindows::Scaled,
+            note_lock: false,
+            scripts: Vec::new(),
+            combo_break_replay: false,
+            miss_forgiveness: None,
+            silent_practice: false,
+            constant_scroll: false,
+            modifiers: Vec::new(),
+            normalize_speed: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioSettings {
+    /// Name of the output device. The default device is used if unset or not found.
+    pub device: Option<String>,
+    pub music_volume: f32,
+    pub keysound_volume: f32,
+    pub effect_volume: f32,
+    /// Volume of the judgement sounds from the theme.
+    pub judge_volume: f32,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            device: None,
+            music_volume: 1.0,
+            keysound_volume: 1.0,
+            effect_volume: 1.0,
+            judge_volume: 1.0,
+        }
+    }
+}
+
+/// Window geometry saved on exit, in physical pixels.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindowSettings {
+    pub dimensions: Option<(u32, u32)>,
+    /// Outer position in desktop coordinates.
+    pub position: Option<(i32, i32)>,
+    /// Name of the monitor the window was on.
+    pub monitor: Option<String>,
+}
+
+/// Where the highway is drawn in the window, for ultra-wide and vertical monitors.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ViewportSettings {
+    /// Width over height of the picture, such as 16:9 centered on an ultra-wide monitor with
+    /// black bars around it. `None` fills the window.
+    pub aspect: Option<f32>,
+    /// Turns the picture a quarter turn for a monitor turned on its side, with `aspect` being that
+    /// of the picture upright.
+    pub rotation: ViewportRotation,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ViewportRotation {
+    Upright,
+    Clockwise,
+    CounterClockwise,
+}
+
+impl Default for ViewportRotation {
+    fn default() -> Self {
+        ViewportRotation::Upright
+    }
+}
+
+/// Unattended operation on a cabinet or at events.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct KioskSettings {
+    /// Locks exiting and the settings behind Ctrl+Shift+K and plays demos when idle.
+    pub enabled: bool,
+    /// Seconds without input in song select before a demo starts.
+    pub idle_seconds: f64,
+    /// Length of a demo in seconds.
+    pub demo_seconds: f64,
+    /// Seconds a finished play or course result stays before returning to song select.
+    pub results_seconds: f64,
+}
+
+impl Default for KioskSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            idle_seconds: 60.,
+            demo_seconds: 45.,
+            results_seconds: 15.,
+        }
+    }
+}
+
+/// Where log messages go and how detailed they are, for reporting sync or rendering issues.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogSettings {
+    /// Level of modules without an entry in `modules`: off, error, warn, info, debug or trace.
+    pub level: String,
+    /// Levels of modules by path, overriding `level`.
+    pub modules: BTreeMap<String, String>,
+    /// Log file relative to the cache directory, or none to only log to the console.
+    pub file: Option<String>,
+    /// Logs of previous runs kept as `<file>.1`, `<file>.2` and so on.
+    pub keep_files: u32,
+    /// Write the log file as one JSON object per line.
+    pub json: bool,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        // The graphics stack is very chatty at info.
+        let quiet = [
+            "gfx_backend_vulkan",
+            "rendy_factory::factory",
+            "rendy_memory::allocator::dynamic",
+            "rendy_memory::allocator::linear",
+            "rendy_graph::node::render::pass",
+            "rendy_graph::node::present",
+            "rendy_graph::graph",
+            "rendy_wsi",
+        ];
+        Self {
+            level: "info".into(),
+            modules: quiet
+                .iter()
+                .map(|module| (module.to_string(), "warn".into()))
+                .collect(),
+            file: Some("iris.log".into()),
+            keep_files: 3,
+            json: false,
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LibrarySettings {
+    /// Directory of the song library. Unset uses `songs` in the [data directory](Paths::data).
+    pub songs_dir: Option<PathBuf>,
+    /// Moves the notes of imported charts that are slightly off this grid onto it. Unset keeps
+    /// them as written.
+    pub import_quantize: Option<SnapGrid>,
+}
+
+impl LibrarySettings {
+    /// The directory of the song library.
+    pub fn root(&self, paths: &Paths) -> PathBuf {
+        self.songs_dir
+            .clone()
+            .unwrap_or_else(|| paths.data.join("songs"))
+    }
+}
+
+/// Files with the live play data for the overlays of streaming software.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct StreamSettings {
+    pub enabled: bool,
+    /// The scores and last judgements of all players as JSON, relative to the data directory.
+    pub json_file: Option<String>,
+    /// The score, combo, accuracy and last judgement of the first player as lines of text,
+    /// relative to the data directory.
+    pub text_file: Option<String>,
+}
+
+impl Default for StreamSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            json_file: Some("overlay/play.json".into()),
+            text_file: Some("overlay/play.txt".into()),
+        }
+    }
+}
+
+/// Where song select's download screen finds songs, with the `download` feature.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DownloadSettings {
+    /// Base URL of the song repository, which serves its song list as `index.json`.
+    pub repository: Option<String>,
+}
+
+/// Play bundles for tournaments, with the `tournament` feature.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TournamentSettings {
+    /// The organizer's hex encoded Ed25519 signing key, which exported bundles are signed with.
+    /// Only set it on the machines the event is played on.
+    pub key: Option<String>,
+}
+
+/// The local control socket, with the `remote` feature.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct RemoteSettings {
+    /// Port on the loopback address to take JSON-RPC requests on.
+    pub port: u16,
+}
+
+impl Default for RemoteSettings {
+    fn default() -> Self {
+        Self { port: 7373 }
+    }
+}
@@ -0,0 +1,820 @@
+use crate::challenge::Challenge;
+use crate::chart_file;
+use crate::chart_speed::ChartSpeeds;
+use crate::course::{Course, CourseProgress};
+use crate::diagnostics::ErrorEvent;
+use crate::import::ImportQueue;
+use crate::judge::{Autoplay, KeyMappings};
+use crate::kiosk::{self, Kiosk};
+use crate::library::{GroupBy, Library, Song};
+use crate::modifiers::SeedRng;
+use crate::pack::PackManagerState;
+use crate::paths::Paths;
+use crate::profile::{Profile, ProfileSelectState};
+use crate::replay::{Replay, ReplayPlayback};
+use crate::save_queue;
+use crate::settings::Settings;
+use crate::speed_preview::{SpeedPreview, MAX_SPEED, MIN_SPEED, SPEED_STEP};
+use crate::ui::percent_transform;
+use crate::vfs::FileSystem;
+use crate::{InterFont, MainStage};
+use amethyst::{
+    assets::{AssetStorage, Handle, Loader},
+    config::Config,
+    core::timing::Time,
+    ecs::Entity,
+    input::is_key_down,
+    prelude::*,
+    renderer::{formats::texture::ImageFormat, Texture},
+    shrev::EventChannel,
+    ui::{Anchor, LineMode, UiImage, UiText, UiTransform},
+    window::ScreenDimensions,
+    winit::{Event, MouseScrollDelta, VirtualKeyCode, WindowEvent},
+};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Asset source name of the song library directory.
+const SONGS_SOURCE: &str = "songs";
+/// Font size of wheel entries in logical pixels.
+const WHEEL_FONT_SIZE: f32 = 28.;
+/// Rows drawn on either side of the selection.
+const VISIBLE_ROWS: i32 = 5;
+/// Height of a row as a fraction of the screen.
+const ROW_HEIGHT: f32 = 0.085;
+/// Horizontal displacement per squared row of distance, which bends the list into a wheel.
+const WHEEL_CURVE: f32 = 0.008;
+/// Exponential decay rate of the scrolling velocity per second.
+const FRICTION: f32 = 4.;
+/// Velocity in rows per second below which the wheel snaps to the nearest song.
+const MIN_VELOCITY: f32 = 1.;
+/// Rate of the exponential approach towards a snap target per second.
+const SNAP_RATE: f32 = 14.;
+/// Velocity in rows per second added by one line of mouse wheel movement.
+const WHEEL_IMPULSE: f32 = 8.;
+/// Import failures listed in the progress panel; the rest are only logged.
+const LISTED_FAILURES: usize = 5;
+
+enum WheelEntry {
+    Header(String),
+    Song(usize),
+    Course(usize),
+}
+
+struct WheelRow {
+    text: Entity,
+    jacket: Entity,
+}
+
+/// Song select with a kinetically scrolling wheel of songs grouped under headers.
+///
+/// Up and down step between songs, the mouse wheel flings the wheel, Tab changes the grouping and
+/// Enter starts the selected song. The selected song is inserted as an `Option<Song>` resource.
+/// D starts today's [`Challenge`] instead, which picks the song and modifiers itself. Courses are
+/// listed above the songs and start a [`CourseProgress`]. Minus and equals adjust the scroll speed,
+/// previewed next to the wheel and recalled for each song as described in [`chart_speed`], P
+/// switches profiles, R watches the last replay, C compares the
+/// replay of the personal best with another in versus, S toggles [silent practice] and M manages
+/// the installed packs. With the `download` feature, O opens the download screen.
+///
+/// In kiosk mode, a demo of a random song starts after a while without input.
+///
+/// Dropping a folder onto the window imports the BMS charts in it in the background; imported
+/// songs are added to the wheel as they finish. Leaving song select cancels the import. Dropping
+/// a zip archive installs it as a pack instead.
+///
+/// [silent practice]: crate::settings::GameplaySettings::silent_practice
+/// [`chart_speed`]: crate::chart_speed
+pub struct SongSelectState {
+    library_root: PathBuf,
+    library: Library,
+    courses: Vec<Course>,
+    group_by: GroupBy,
+    entries: Vec<WheelEntry>,
+    rows: Vec<WheelRow>,
+    /// Fractional index of the entry in the middle of the wheel.
+    position: f32,
+    /// Rows per second.
+    velocity: f32,
+    /// Entry the wheel is settling on.
+    target: Option<usize>,
+    /// Jackets by song index, loaded when first scrolled into view.
+    jackets: HashMap<usize, Handle<Texture>>,
+    /// Chart thumbnails by song index, loaded when first selected.
+    thumbnails: HashMap<usize, Handle<Texture>>,
+    /// Shows the thumbnail of the selected song.
+    thumbnail: Option<Entity>,
+    import: Option<ImportQueue>,
+    import_panel: Option<Entity>,
+    speed_preview: Option<SpeedPreview>,
+    chart_speeds: ChartSpeeds,
+    /// The song whose speed was last recalled.
+    speed_song: Option<PathBuf>,
+    /// Whether a speed was changed, so that the speeds are saved when leaving.
+    speeds_changed: bool,
+}
+
+impl Default for SongSelectState {
+    fn default() -> Self {
+        Self {
+            library_root: PathBuf::new(),
+            library: Library::default(),
+            courses: Vec::new(),
+            group_by: GroupBy::Folder,
+            entries: Vec::new(),
+            rows: Vec::new(),
+            position: 0.,
+            velocity: 0.,
+            target: None,
+            jackets: HashMap::new(),
+            thumbnails: HashMap::new(),
+            thumbnail: None,
+            import: None,
+            import_panel: None,
+            speed_preview: None,
+            chart_speeds: ChartSpeeds::default(),
+            speed_song: None,
+            speeds_changed: false,
+        }
+    }
+}
+
+impl SongSelectState {
+    fn rebuild_entries(&mut self) {
+        let selected = self.selected_song();
+        self.entries.clear();
+        if !self.courses.is_empty() {
+            self.entries.push(WheelEntry::Header("Courses".into()));
+            self.entries
+                .extend((0..self.courses.len()).map(WheelEntry::Course));
+        }
+        for (header, songs) in self.library.groups(self.group_by) {
+            self.entries.push(WheelEntry::Header(header));
+            self.entries.extend(songs.into_iter().map(WheelEntry::Song));
+        }
+        let index = self
+            .entries
+            .iter()
+            .position(|e| match e {
+                WheelEntry::Song(i) => selected.map_or(true, |s| *i == s),
+                WheelEntry::Header(_) | WheelEntry::Course(_) => false,
+            })
+            .unwrap_or(0);
+        self.position = index as f32;
+        self.velocity = 0.;
+        self.target = Some(index);
+    }
+
+    fn selected_song(&self) -> Option<usize> {
+        match self.entries.get(self.target?) {
+            Some(WheelEntry::Song(i)) => Some(*i),
+            _ => None,
+        }
+    }
+
+    fn selected_course(&self) -> Option<usize> {
+        match self.entries.get(self.target?) {
+            Some(WheelEntry::Course(i)) => Some(*i),
+            _ => None,
+        }
+    }
+
+    /// The song or course entry closest to `index` in the given direction, or `index` itself if
+    /// there is none.
+    fn selectable_entry(&self, index: usize, direction: i32) -> usize {
+        let mut i = index as i32;
+        while i >= 0 && (i as usize) < self.entries.len() {
+            if let WheelEntry::Song(_) | WheelEntry::Course(_) = self.entries[i as usize] {
+                return i as usize;
+            }
+            i += direction;
+        }
+        index
+    }
+
+    fn step(&mut self, direction: i32) {
+        let current = self
+            .target
+            .unwrap_or_else(|| self.position.round().max(0.) as usize);
+        let next = (current as i32 + direction).max(0) as usize;
+        self.target =
+            Some(self.selectable_entry(next.min(self.entries.len().saturating_sub(1)), direction));
+        self.velocity = 0.;
+    }
+
+    fn scroll(&mut self, delta: f32) {
+        self.target = None;
+        self.velocity += delta;
+    }
+
+    fn update_wheel(&mut self, delta_seconds: f32) {
+        let max = self.entries.len().saturating_sub(1) as f32;
+        match self.target {
+            Some(target) => {
+                let approach = 1. - (-SNAP_RATE * delta_seconds).exp();
+                self.position += (target as f32 - self.position) * approach;
+            }
+            None => {
+                self.position += self.velocity * delta_seconds;
+                self.velocity *= (-FRICTION * delta_seconds).exp();
+                if self.position <= 0. || self.position >= max {
+                    self.velocity = 0.;
+                }
+                self.position = self.position.max(0.).min(max);
+                if self.velocity.abs() < MIN_VELOCITY {
+                    self.velocity = 0.;
+                    let nearest = self.position.round() as usize;
+                    let direction = if self.position > nearest as f32 {
+                        1
+                    } else {
+                        -1
+                    };
+                    self.target = Some(self.selectable_entry(nearest, direction));
+                }
+            }
+        }
+    }
+
+    /// Adds newly imported songs to the wheel and updates the progress panel.
+    fn update_import(&mut self, world: &mut World) {
+        let import = match &mut self.import {
+            Some(import) => import,
+            None => return,
+        };
+        let imported = import.poll();
+        let mut text = if import.is_finished() {
+            let mut text = format!(
+                "Imported {} charts, {} failed",
+                import.total - import.failures.len() - import.skipped,
+                import.failures.len()
+            );
+            if import.skipped > 0 {
+                text += &format!(", {} cancelled", import.skipped);
+            }
+            text
+        } else {
+            format!("Importing {} of {} charts", import.done, import.total)
+        };
+        for (chart, error) in import.failures.iter().rev().take(LISTED_FAILURES) {
+            let name = chart.file_name().unwrap_or_default().to_string_lossy();
+            text += &format!("\n{}: {}", name, error);
+        }
+
+        if !imported.is_empty() {
+            for song in imported {
+                // Reimported songs replace the old entry so that indices stay valid.
+                match self.library.songs.iter().position(|s| s.path == song.path) {
+                    Some(i) => {
+                        self.library.songs[i] = song;
+                        self.jackets.remove(&i);
+                        self.thumbnails.remove(&i);
+                    }
+                    None => self.library.songs.push(song),
+                }
+            }
+            self.rebuild_entries();
+        }
+
+        let panel = match self.import_panel {
+            Some(panel) => panel,
+            None => {
+                let font = world.read_resource::<InterFont>().0.clone();
+                let font_size = {
+                    let settings = world.read_resource::<Settings>();
+                    let dimensions = world.read_resource::<ScreenDimensions>();
+                    WHEEL_FONT_SIZE * 0.6 * settings.ui.scale_factor(&dimensions)
+                };
+                let transform =
+                    percent_transform("ImportPanel", Anchor::BottomLeft, 0.02, 0.02, 0.4, 0.25);
+                let mut ui_text = UiText::new(font, String::new(), [1.; 4], font_size);
+                ui_text.line_mode = LineMode::Wrap;
+                ui_text.align = Anchor::BottomLeft;
+                let panel = world.create_entity().with(transform).with(ui_text).build();
+                self.import_panel = Some(panel);
+                panel
+            }
+        };
+        if let Some(ui_text) = world.write_storage::<UiText>().get_mut(panel) {
+            ui_text.text = text;
+        }
+    }
+
+    /// Starts autoplay on a random song once kiosk mode has been idle for long enough.
+    fn start_demo(&mut self, world: &mut World) -> Option<SimpleTrans> {
+        let now = world.read_resource::<Time>().absolute_real_time_seconds();
+        let demo_seconds = {
+            let settings = world.read_resource::<Settings>();
+            let kiosk = world.read_resource::<Kiosk>();
+            if !kiosk.idle(&settings, now) || self.library.songs.is_empty() {
+                return None;
+            }
+            settings.kiosk.demo_seconds
+        };
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        let song = &self.library.songs[SeedRng::new(seed).below(self.library.songs.len())];
+        log::info!("Starting demo of {}", song.meta.title);
+        world.insert(Some(song.clone()));
+        world.insert(None::<Challenge>);
+        world.insert(None::<CourseProgress>);
+        world.insert(Autoplay(true));
+        let mut kiosk = world.write_resource::<Kiosk>();
+        kiosk.demo_until = Some(now + demo_seconds);
+        // The next demo only starts after another idle period.
+        kiosk.last_input = now;
+        Some(Trans::Switch(Box::new(MainStage::default())))
+    }
+
+    /// Starts `playback` on the song of its replays, or reports that the song is missing.
+    fn watch(&self, world: &mut World, playback: ReplayPlayback) -> Option<SimpleTrans> {
+        let song = match playback.song() {
+            Some(path) => match self.library.songs.iter().find(|song| song.path == *path) {
+                Some(song) => Some(song.clone()),
+                None => {
+                    world
+                        .write_resource::<EventChannel<ErrorEvent>>()
+                        .single_write(ErrorEvent::new(format!(
+                            "The song of the replay, {}, is not in the library",
+                            path.display()
+                        )));
+                    return None;
+                }
+            },
+            None => None,
+        };
+        world.insert(song);
+        world.insert(None::<Challenge>);
+        world.insert(None::<CourseProgress>);
+        world.insert(Autoplay(false));
+        world.insert(Some(playback));
+        Some(Trans::Switch(Box::new(MainStage::default())))
+    }
+
+    /// Plays back the profile's last replay on the song it was recorded on.
+    fn watch_last_replay(&self, world: &mut World) -> Option<SimpleTrans> {
+        let path = world
+            .read_resource::<Profile>()
+            .replays_dir()
+            .join("latest.ron");
+        let replay = match save_queue::load::<Replay>(&path) {
+            Ok(replay) => replay,
+            Err(e) => {
+                log::warn!("Failed to load {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        self.watch(world, ReplayPlayback::new(replay))
+    }
+
+    /// Plays back the replay of the personal best on the left highway of versus and the
+    /// [`compare_replay`] on the right, on the same clock.
+    ///
+    /// [`compare_replay`]: crate::settings::GameplaySettings::compare_replay
+    fn compare_replays(&self, world: &mut World) -> Option<SimpleTrans> {
+        let (versus, name) = {
+            let settings = world.read_resource::<Settings>();
+            let gameplay = &settings.gameplay;
+            (gameplay.versus, gameplay.compare_replay.clone()?)
+        };
+        let error = |world: &mut World, message: String| {
+            world
+                .write_resource::<EventChannel<ErrorEvent>>()
+                .single_write(ErrorEvent::new(message));
+        };
+        // The highways of versus are set up at startup.
+        if !versus {
+            error(world, "Turn on versus to compare replays".into());
+            return None;
+        }
+        let load = |world: &mut World, path: &Path| match save_queue::load::<Replay>(path) {
+            Ok(replay) => Some(replay),
+            Err(e) => {
+                error(
+                    world,
+                    format!("Failed to load replay {}: {}", path.display(), e),
+                );
+                None
+            }
+        };
+        let profile = world.read_resource::<Profile>().clone();
+        let mut other = load(world, &profile.dir.join(&name))?;
+        // The personal best of the chart the other replay was recorded on.
+        let chart_hash = other.song.as_ref().and_then(|song| {
+            let root = world
+                .read_resource::<Settings>()
+                .library
+                .root(&world.read_resource::<Paths>());
+            chart_file::song_chart_hash(&FileSystem::new(root), song)
+        });
+        let mut best = load(world, &profile.best_replay_path(chart_hash))?;
+        if best.song != other.song {
+            error(world, "The replays are of different songs".into());
+            return None;
+        }
+        // Replays from before key bindings were recorded were played with the first player's.
+        let live = world.read_resource::<KeyMappings>().0.get(0).cloned();
+        for replay in [&mut best, &mut other].iter_mut() {
+            if replay.key_maps.is_empty() {
+                replay.key_maps.extend(live.clone());
+            }
+        }
+        let title = format!("Personal best vs. {}", name);
+        let playback = ReplayPlayback::compare(title, best, other);
+        self.watch(world, playback)
+    }
+
+    fn create_rows(&mut self, world: &mut World) {
+        let font = world.read_resource::<InterFont>().0.clone();
+        for _ in -VISIBLE_ROWS..=VISIBLE_ROWS {
+            let mut text_transform =
+                percent_transform("WheelText", Anchor::Middle, 0., 0., 0.4, ROW_HEIGHT);
+            text_transform.pivot = Anchor::MiddleLeft;
+            let text = world
+                .create_entity()
+                .with(text_transform)
+                .with(UiText::new(font.clone(), String::new(), [1.; 4], 0.))
+                .build();
+            let mut jacket_transform = percent_transform(
+                "WheelJacket",
+                Anchor::Middle,
+                0.,
+                0.,
+                0.06,
+                ROW_HEIGHT * 0.9,
+            );
+            jacket_transform.pivot = Anchor::MiddleRight;
+            let jacket = world.create_entity().with(jacket_transform).build();
+            self.rows.push(WheelRow { text, jacket });
+        }
+    }
+
+    fn layout_rows(&mut self, world: &World) {
+        let font_size = {
+            let settings = world.read_resource::<Settings>();
+            let dimensions = world.read_resource::<ScreenDimensions>();
+            WHEEL_FONT_SIZE * settings.ui.scale_factor(&dimensions)
+        };
+        let loader = world.read_resource::<Loader>();
+        let texture_storage = world.read_resource::<AssetStorage<Texture>>();
+        let mut texts = world.write_storage::<UiText>();
+        let mut transforms = world.write_storage::<UiTransform>();
+        let mut images = world.write_storage::<UiImage>();
+
+        let songs = &self.library.songs;
+        let courses = &self.courses;
+        let jackets = &mut self.jackets;
+        let center = self.position.round() as i32;
+        for (k, row) in self.rows.iter().enumerate() {
+            let index = center - VISIBLE_ROWS + k as i32;
+            let offset = index as f32 - self.position;
+            let x = 0.05 + WHEEL_CURVE * offset * offset;
+            let y = -offset * ROW_HEIGHT;
+            for &entity in &[row.text, row.jacket] {
+                if let Some(transform) = transforms.get_mut(entity) {
+                    transform.local_x = x;
+                    transform.local_y = y;
+                }
+            }
+
+            let entry = if index >= 0 {
+                self.entries.get(index as usize)
+            } else {
+                None
+            };
+            let (label, color, song) = match entry {
+                Some(WheelEntry::Header(header)) => (header.clone(), [0.6, 0.8, 1., 1.], None),
+                Some(WheelEntry::Course(i)) => {
+                    let color = if index == center {
+                        [1., 0.8, 0., 1.]
+                    } else {
+                        [1.; 4]
+                    };
+                    (courses[*i].name.clone(), color, None)
+                }
+                Some(WheelEntry::Song(i)) => {
+                    let song = &songs[*i];
+                    let color = if index == center {
+                        [1., 0.8, 0., 1.]
+                    } else {
+                        [1.; 4]
+                    };
+                    let label = format!("{} / {}", song.meta.title, song.meta.artist);
+                    (label, color, Some((*i, song)))
+                }
+                None => (String::new(), [1.; 4], None),
+            };
+            if let Some(text) = texts.get_mut(row.text) {
+                text.text = label;
+                text.color = color;
+                text.font_size = font_size;
+            }
+
+            let jacket = song.and_then(|(i, song): (usize, &Song)| {
+                let path = song.meta.jacket.as_ref()?;
+                let handle = jackets.entry(i).or_insert_with(|| {
+                    let path = song.path.join(path);
+                    loader.load_from(
+                        path.to_string_lossy().as_ref(),
+                        ImageFormat::default(),
+                        SONGS_SOURCE,
+                        (),
+                        &texture_storage,
+                    )
+                });
+                Some(handle.clone())
+            });
+            match jacket {
+                Some(handle) => {
+                    images.insert(row.jacket, UiImage::Texture(handle)).unwrap();
+                }
+                None => {
+                    images.remove(row.jacket);
+                }
+            }
+        }
+    }
+
+    fn create_thumbnail(&mut self, world: &mut World) {
+        let transform =
+            percent_transform("SongThumbnail", Anchor::TopRight, -0.1, -0.22, 0.12, 0.3);
+        self.thumbnail = Some(world.create_entity().with(transform).build());
+    }
+
+    /// Shows the [thumbnail](crate::thumbnail) of the selected song, if it has one.
+    /// Switches to the speed last used for the selected song, once when it is selected.
+    fn recall_speed(&mut self, world: &World) {
+        let song = match self.selected_song() {
+            Some(i) => &self.library.songs[i],
+            None => return,
+        };
+        if self.speed_song.as_ref() == Some(&song.path) {
+            return;
+        }
+        self.speed_song = Some(song.path.clone());
+        let mut settings = world.write_resource::<Settings>();
+        if settings.gameplay.normalize_speed {
+            return;
+        }
+        if let Some(speed) = self.chart_speeds.recall(song) {
+            settings.gameplay.speed = speed;
+        }
+    }
+
+    /// Changes the speed by `step` and remembers it for the selected song.
+    fn change_speed(&mut self, world: &World, step: f32) {
+        let mut settings = world.write_resource::<Settings>();
+        let gameplay = &mut settings.gameplay;
+        gameplay.speed = (gameplay.speed + step).max(MIN_SPEED).min(MAX_SPEED);
+        if gameplay.normalize_speed {
+            return;
+        }
+        if let Some(i) = self.selected_song() {
+            self.chart_speeds
+                .remember(&self.library.songs[i], gameplay.speed);
+            self.speeds_changed = true;
+        }
+    }
+
+    fn show_thumbnail(&mut self, world: &World) {
+        let entity = match self.thumbnail {
+            Some(entity) => entity,
+            None => return,
+        };
+        let songs = &self.library.songs;
+        let thumbnails = &mut self.thumbnails;
+        let handle = self.selected_song().and_then(|i| {
+            let song = &songs[i];
+            let path = song.meta.thumbnail.as_ref()?;
+            let handle = thumbnails.entry(i).or_insert_with(|| {
+                world.read_resource::<Loader>().load_from(
+                    song.path.join(path).to_string_lossy().as_ref(),
+                    ImageFormat::default(),
+                    SONGS_SOURCE,
+                    (),
+                    &world.read_resource::<AssetStorage<Texture>>(),
+                )
+            });
+            Some(handle.clone())
+        });
+        let mut images = world.write_storage::<UiImage>();
+        match handle {
+            Some(handle) => {
+                images.insert(entity, UiImage::Texture(handle)).unwrap();
+            }
+            None => {
+                images.remove(entity);
+            }
+        }
+    }
+}
+
+impl SimpleState for SongSelectState {
+    fn on_start(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        self.library_root = world
+            .read_resource::<Settings>()
+            .library
+            .root(&world.read_resource::<Paths>());
+        world
+            .write_resource::<Loader>()
+            .add_source(SONGS_SOURCE, FileSystem::new(&self.library_root));
+        self.library = Library::scan(&self.library_root);
+        self.courses = Course::load_all(&world.read_resource::<Paths>().data);
+        self.chart_speeds = world.read_resource::<Profile>().load_chart_speeds();
+        self.rebuild_entries();
+        self.create_rows(world);
+        self.create_thumbnail(world);
+        self.speed_preview = Some(SpeedPreview::new(world));
+    }
+
+    fn on_stop(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        for row in self.rows.drain(..) {
+            world.delete_entity(row.text).unwrap();
+            world.delete_entity(row.jacket).unwrap();
+        }
+        if let Some(import) = self.import.take() {
+            import.cancel();
+        }
+        if let Some(panel) = self.import_panel.take() {
+            world.delete_entity(panel).unwrap();
+        }
+        if let Some(preview) = self.speed_preview.take() {
+            preview.delete(world);
+        }
+        if let Some(thumbnail) = self.thumbnail.take() {
+            world.delete_entity(thumbnail).unwrap();
+        }
+        if self.speeds_changed {
+            self.speeds_changed = false;
+            let profile = world.read_resource::<Profile>();
+            if let Err(e) = profile.save_chart_speeds(&self.chart_speeds) {
+                log::warn!("Failed to save the speeds of the charts: {}", e);
+            }
+        }
+    }
+
+    fn handle_event(
+        &mut self,
+        StateData { world, .. }: StateData<'_, GameData<'_, '_>>,
+        event: StateEvent,
+    ) -> SimpleTrans {
+        let event = match event {
+            StateEvent::Window(event) => event,
+            _ => return Trans::None,
+        };
+        if kiosk::exit_requested(world, &event) {
+            return Trans::Quit;
+        }
+        if is_key_down(&event, VirtualKeyCode::Up) {
+            self.step(-1);
+        } else if is_key_down(&event, VirtualKeyCode::Down) {
+            self.step(1);
+        } else if is_key_down(&event, VirtualKeyCode::Minus) {
+            self.change_speed(world, -SPEED_STEP);
+        } else if is_key_down(&event, VirtualKeyCode::Equals) {
+            self.change_speed(world, SPEED_STEP);
+        } else if is_key_down(&event, VirtualKeyCode::P) {
+            let locked = world
+                .read_resource::<Kiosk>()
+                .locked(&world.read_resource::<Settings>());
+            if !locked {
+                return Trans::Switch(Box::new(ProfileSelectState::default()));
+            }
+        } else if is_key_down(&event, VirtualKeyCode::R) {
+            if let Some(trans) = self.watch_last_replay(world) {
+                return trans;
+            }
+        } else if is_key_down(&event, VirtualKeyCode::C) {
+            if let Some(trans) = self.compare_replays(world) {
+                return trans;
+            }
+        } else if is_key_down(&event, VirtualKeyCode::S) {
+            let silent = &mut world.write_resource::<Settings>().gameplay.silent_practice;
+            *silent = !*silent;
+            log::info!(
+                "Silent practice {}",
+                if *silent { "enabled" } else { "disabled" }
+            );
+        } else if is_key_down(&event, VirtualKeyCode::M) {
+            let locked = world
+                .read_resource::<Kiosk>()
+                .locked(&world.read_resource::<Settings>());
+            if !locked {
+                return Trans::Switch(Box::new(PackManagerState::default()));
+            }
+        } else if cfg!(feature = "download") && is_key_down(&event, VirtualKeyCode::O) {
+            #[cfg(feature = "download")]
+            {
+                let locked = world
+                    .read_resource::<Kiosk>()
+                    .locked(&world.read_resource::<Settings>());
+                if !locked {
+                    return Trans::Switch(Box::new(crate::download::DownloadState::default()));
+                }
+            }
+        } else if is_key_down(&event, VirtualKeyCode::Tab) {
+            self.group_by = self.group_by.next();
+            self.rebuild_entries();
+        } else if is_key_down(&event, VirtualKeyCode::Return) {
+            if let Some(song) = self.selected_song() {
+                world.insert(Some(self.library.songs[song].clone()));
+                world.insert(None::<Challenge>);
+                world.insert(None::<CourseProgress>);
+                return Trans::Switch(Box::new(MainStage::default()));
+            }
+            if let Some(course) = self.selected_course() {
+                let course = self.courses[course].clone();
+                match course.resolve(&self.library.songs) {
+                    Ok(songs) if !songs.is_empty() => {
+                        let progress = CourseProgress::new(course, songs);
+                        world.insert(Some(progress.song().clone()));
+                        world.insert(None::<Challenge>);
+                        world.insert(Some(progress));
+                        return Trans::Switch(Box::new(MainStage::default()));
+                    }
+                    Ok(_) => world
+                        .write_resource::<EventChannel<ErrorEvent>>()
+                        .single_write(ErrorEvent::new(format!(
+                            "Course {} has no songs",
+                            course.name
+                        ))),
+                    Err(missing) => world
+                        .write_resource::<EventChannel<ErrorEvent>>()
+                        .single_write(ErrorEvent::new(format!(
+                            "Course {}: {} is not in the library",
+                            course.name,
+                            missing.display()
+                        ))),
+                }
+            }
+        } else if is_key_down(&event, VirtualKeyCode::D) {
+            let challenge = Challenge::today();
+            if let Some(song) = challenge.pick(&self.library.songs) {
+                let modifiers: Vec<_> = challenge.modifiers.iter().map(|m| m.name()).collect();
+                log::info!(
+                    "Daily challenge: {} [{}]",
+                    song.meta.title,
+                    modifiers.join(", ")
+                );
+                world.insert(Some(song.clone()));
+                world.insert(Some(challenge));
+                world.insert(None::<CourseProgress>);
+                return Trans::Switch(Box::new(MainStage::default()));
+            }
+        } else if let Event::WindowEvent {
+            event: WindowEvent::MouseWheel { delta, .. },
+            ..
+        } = event
+        {
+            let lines = match delta {
+                MouseScrollDelta::LineDelta(_, y) => y,
+                MouseScrollDelta::PixelDelta(position) => position.y as f32 / 40.,
+            };
+            self.scroll(-lines * WHEEL_IMPULSE);
+        } else if let Event::WindowEvent {
+            event: WindowEvent::DroppedFile(path),
+            ..
+        } = event
+        {
+            if path
+                .extension()
+                .map_or(false, |ext| ext.eq_ignore_ascii_case("zip"))
+            {
+                return Trans::Switch(Box::new(PackManagerState::installing(path)));
+            } else if path.is_dir() {
+                if self.import.is_none() {
+                    let quantize = world.read_resource::<Settings>().library.import_quantize;
+                    match ImportQueue::new(self.library_root.clone(), quantize) {
+                        Ok(import) => self.import = Some(import),
+                        Err(e) => {
+                            world
+                                .write_resource::<EventChannel<ErrorEvent>>()
+                                .single_write(ErrorEvent::new(e.to_string()));
+                            return Trans::None;
+                        }
+                    }
+                }
+                self.import.as_mut().unwrap().add_pack(&path);
+            }
+        }
+        Trans::None
+    }
+
+    fn update(&mut self, data: &mut StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
+        if let Some(trans) = self.start_demo(data.world) {
+            return trans;
+        }
+        let delta_seconds = data.world.read_resource::<Time>().delta_seconds();
+        self.update_import(data.world);
+        self.update_wheel(delta_seconds);
+        self.layout_rows(data.world);
+        self.show_thumbnail(data.world);
+        self.recall_speed(data.world);
+        let song = self.selected_song().map(|i| &self.library.songs[i]);
+        if let Some(preview) = &mut self.speed_preview {
+            preview.update(data.world, song, delta_seconds);
+        }
+        Trans::None
+    }
+}
@@ -0,0 +1,137 @@
+use crate::chart::visible_time;
+use crate::library::Song;
+use crate::settings::Settings;
+use crate::ui::percent_transform;
+use crate::InterFont;
+use amethyst::{
+    ecs::Entity,
+    prelude::*,
+    ui::{Anchor, LineMode, UiImage, UiText, UiTransform},
+    window::ScreenDimensions,
+};
+
+/// Font size of the speed panel in logical pixels.
+const PREVIEW_FONT_SIZE: f32 = 20.;
+/// Notes scrolling down the preview lane at once.
+const PREVIEW_NOTES: usize = 4;
+/// Height of the preview lane as a fraction of the screen.
+const LANE_HEIGHT: f32 = 0.4;
+/// Change of [`GameplaySettings::speed`](crate::settings::GameplaySettings::speed) per key press.
+pub const SPEED_STEP: f32 = 0.05;
+pub const MIN_SPEED: f32 = 0.2;
+pub const MAX_SPEED: f32 = 3.;
+
+/// Shows how long notes of the selected song stay visible at the current speed, as milliseconds
+/// (the "green number") for the base, lowest and highest BPM, next to a lane where notes scroll at
+/// the base BPM.
+pub struct SpeedPreview {
+    text: Entity,
+    lane: Entity,
+    notes: Vec<Entity>,
+    /// Progress of the first note down the lane in `0..1`.
+    phase: f32,
+}
+
+impl SpeedPreview {
+    pub fn new(world: &mut World) -> Self {
+        let font = world.read_resource::<InterFont>().0.clone();
+        let text_transform =
+            percent_transform("SpeedText", Anchor::TopRight, -0.1, -0.05, 0.3, 0.15);
+        let mut ui_text = UiText::new(font, String::new(), [1.; 4], 0.);
+        ui_text.line_mode = LineMode::Wrap;
+        ui_text.align = Anchor::TopRight;
+        let text = world
+            .create_entity()
+            .with(text_transform)
+            .with(ui_text)
+            .build();
+
+        let mut lane_transform = percent_transform(
+            "SpeedLane",
+            Anchor::TopRight,
+            -0.04,
+            -0.05,
+            0.04,
+            LANE_HEIGHT,
+        );
+        lane_transform.local_z = 0.5;
+        let lane = world
+            .create_entity()
+            .with(lane_transform)
+            .with(UiImage::SolidColor([0.1, 0.1, 0.15, 0.8]))
+            .build();
+
+        let notes = (0..PREVIEW_NOTES)
+            .map(|_| {
+                let mut transform =
+                    percent_transform("SpeedNote", Anchor::TopRight, -0.06, 0., 0.036, 0.01);
+                transform.pivot = Anchor::Middle;
+                world
+                    .create_entity()
+                    .with(transform)
+                    .with(UiImage::SolidColor([0.2, 0.6, 1., 1.]))
+                    .build()
+            })
+            .collect();
+
+        Self {
+            text,
+            lane,
+            notes,
+            phase: 0.,
+        }
+    }
+
+    pub fn update(&mut self, world: &World, song: Option<&Song>, delta_seconds: f32) {
+        let settings = world.read_resource::<Settings>();
+        let speed = settings.gameplay.speed;
+        let bpm = song.and_then(|song| song.meta.bpm);
+        let base = bpm.map(|bpm| bpm.base);
+        let visible = |at: f32| visible_time(speed, at, base.unwrap_or(at), &settings.highway);
+        let label = match bpm {
+            Some(bpm) if bpm.min < bpm.max => format!(
+                "Speed {:.2}\nGreen {:.0} ({:.0}-{:.0} at BPM {:.0}-{:.0})",
+                speed,
+                visible(bpm.base) * 1000.,
+                visible(bpm.max) * 1000.,
+                visible(bpm.min) * 1000.,
+                bpm.min,
+                bpm.max,
+            ),
+            Some(bpm) => format!(
+                "Speed {:.2}\nGreen {:.0} at BPM {:.0}",
+                speed,
+                visible(bpm.base) * 1000.,
+                bpm.base
+            ),
+            None => format!("Speed {:.2}\nGreen {:.0}", speed, speed * 1000.),
+        };
+        let lane_time = bpm.map_or(speed, |bpm| visible(bpm.base));
+
+        let font_size = PREVIEW_FONT_SIZE
+            * settings
+                .ui
+                .scale_factor(&world.read_resource::<ScreenDimensions>());
+        if let Some(text) = world.write_storage::<UiText>().get_mut(self.text) {
+            text.text = label;
+            text.font_size = font_size;
+        }
+        self.phase = (self.phase + delta_seconds / lane_time.max(0.01)).fract();
+        let mut transforms = world.write_storage::<UiTransform>();
+        let lane_top = transforms.get(self.lane).map_or(0., |t| t.local_y);
+        for (i, &note) in self.notes.iter().enumerate() {
+            let progress = (self.phase + i as f32 / PREVIEW_NOTES as f32).fract();
+            if let Some(transform) = transforms.get_mut(note) {
+                transform.local_y = lane_top - progress * LANE_HEIGHT;
+            }
+        }
+    }
+
+    pub fn delete(self, world: &mut World) {
+        world.delete_entity(self.text).unwrap();
+        world.delete_entity(self.lane).unwrap();
+        for note in self.notes {
+            world.delete_entity(note).unwrap();
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use crate::error::RenderError;
+
+/// Bits of the stencil buffer; all supported depth/stencil formats have 8.
+const STENCIL_BITS: u32 = 8;
+
+/// Bits of the stencil buffer owned by one masked layer, such as the laser masks. A layer only
+/// reads and writes its own bits, so layers don't clobber each other.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StencilLayer {
+    shift: u32,
+    bits: u32,
+}
+
+impl StencilLayer {
+    /// The read and write mask of the layer.
+    pub fn mask(self) -> u32 {
+        ((1 << self.bits) - 1) << self.shift
+    }
+
+    /// The stencil reference of `value` in this layer. Values beyond its bits are cut off.
+    pub fn reference(self, value: u32) -> u32 {
+        (value << self.shift) & self.mask()
+    }
+}
+
+/// Hands out the bits of the stencil buffer to render groups as they are built.
+///
+/// Layers are claimed by name and keep their bits when the render graph is rebuilt, so claiming
+/// again with the same name returns the same layer.
+#[derive(Debug, Default)]
+pub struct StencilAllocator {
+    layers: Vec<(&'static str, StencilLayer)>,
+    used: u32,
+}
+
+impl StencilAllocator {
+    pub fn claim(&mut self, name: &'static str, bits: u32) -> Result<StencilLayer, RenderError> {
+        if let Some(&(_, layer)) = self.layers.iter().find(|(n, _)| *n == name) {
+            return Ok(layer);
+        }
+        if self.used + bits > STENCIL_BITS {
+            return Err(RenderError::StencilExhausted(name));
+        }
+        let layer = StencilLayer {
+            shift: self.used,
+            bits,
+        };
+        self.used += bits;
+        self.layers.push((name, layer));
+        Ok(layer)
+    }
+}
@@ -0,0 +1,149 @@
+use crate::chart::Chart;
+use crate::judge::{JudgeEvent, Judgement};
+use crate::paths::Paths;
+use crate::score::{Score, Scores};
+use crate::settings::Settings;
+use amethyst::{
+    core::SystemDesc,
+    ecs::{Read, ReadExpect, System, SystemData, World},
+    shrev::{EventChannel, ReaderId},
+};
+use serde_json::json;
+use std::io;
+use std::path::Path;
+
+/// The last judgement of a player and its offset in seconds.
+type LastJudgement = (Judgement, Option<f32>);
+
+/// Accuracy in percent over the notes judged so far.
+fn accuracy(score: &Score) -> f64 {
+    if score.records.is_empty() {
+        100.
+    } else {
+        score.weight / score.records.len() as f64 * 100.
+    }
+}
+
+/// Replaces the file in one step, so that an overlay never reads it half written.
+fn write_file(path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let temp = path.with_extension("tmp");
+    std::fs::write(&temp, contents)?;
+    std::fs::rename(&temp, path)
+}
+
+/// Publishes the play to files that streaming software reads for its overlays, rewritten on each
+/// judgement while the [stream overlay] is enabled.
+///
+/// The JSON file has the score, combo, max combo, accuracy and last judgement of each player. The
+/// text file has the same of the first player as lines for a text source.
+///
+/// [stream overlay]: crate::settings::StreamSettings
+pub struct StreamOverlaySystem {
+    reader_id: ReaderId<JudgeEvent>,
+    last: Vec<Option<LastJudgement>>,
+    /// Whether writing has failed, so that the warning isn't repeated on every judgement.
+    failed: bool,
+}
+
+pub struct StreamOverlaySystemDesc;
+
+impl<'a, 'b> SystemDesc<'a, 'b, StreamOverlaySystem> for StreamOverlaySystemDesc {
+    fn build(self, world: &mut World) -> StreamOverlaySystem {
+        <StreamOverlaySystem as System<'_>>::SystemData::setup(world);
+
+        let reader_id = world
+            .get_mut::<EventChannel<JudgeEvent>>()
+            .unwrap()
+            .register_reader();
+        StreamOverlaySystem {
+            reader_id,
+            last: Vec::new(),
+            failed: false,
+        }
+    }
+}
+
+impl StreamOverlaySystem {
+    fn write(&mut self, paths: &Paths, settings: &Settings, scores: &Scores, total_notes: usize) {
+        let players: Vec<_> = scores
+            .players
+            .iter()
+            .zip(self.last.iter().chain(std::iter::repeat(&None)))
+            .map(|(score, last)| (score, *last))
+            .collect();
+        let stream = &settings.stream;
+        if let Some(file) = &stream.json_file {
+            let json = json!({
+                "players": players
+                    .iter()
+                    .map(|(score, last)| {
+                        json!({
+                            "score": score.value(total_notes),
+                            "combo": score.combo,
+                            "max_combo": score.max_combo,
+                            "accuracy": accuracy(score),
+                            "judgement": last.map(|(judgement, _)| judgement.text()),
+                            "offset_ms": last.and_then(|(_, offset)| offset).map(|o| o * 1000.),
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            });
+            self.report(write_file(&paths.data.join(file), &json.to_string()));
+        }
+        if let (Some(file), Some((score, last))) = (&stream.text_file, players.first()) {
+            let mut text = format!(
+                "{:08}\n{} combo\n{:.2}%\n",
+                score.value(total_notes),
+                score.combo,
+                accuracy(score)
+            );
+            match last {
+                Some((judgement, Some(offset))) => {
+                    text += &format!("{} {:+.0} ms\n", judgement.text(), offset * 1000.)
+                }
+                Some((judgement, None)) => text += &format!("{}\n", judgement.text()),
+                None => text += "\n",
+            }
+            self.report(write_file(&paths.data.join(file), &text));
+        }
+    }
+
+    fn report(&mut self, result: io::Result<()>) {
+        if let Err(e) = result {
+            if !self.failed {
+                log::warn!("Failed to write the stream overlay: {}", e);
+            }
+            self.failed = true;
+        }
+    }
+}
+
+impl<'s> System<'s> for StreamOverlaySystem {
+    type SystemData = (
+        Read<'s, EventChannel<JudgeEvent>>,
+        Read<'s, Option<Chart>>,
+        Read<'s, Scores>,
+        Read<'s, Settings>,
+        ReadExpect<'s, Paths>,
+    );
+
+    fn run(&mut self, (events, chart, scores, settings, paths): Self::SystemData) {
+        let mut judged = false;
+        for event in events.read(&mut self.reader_id) {
+            let player = event.player as usize;
+            if self.last.len() <= player {
+                self.last.resize(player + 1, None);
+            }
+            self.last[player] = Some((event.judgement, event.offset));
+            judged = true;
+        }
+        if !judged || !settings.stream.enabled {
+            return;
+        }
+        let total_notes = chart.as_ref().map_or(0, Chart::judged_notes);
+        self.write(&paths, &settings, &scores, total_notes);
+    }
+}
@@ -0,0 +1,147 @@
+use crate::laser::LaserBlend;
+use crate::migrate::{self, Versioned};
+use amethyst::renderer::bundle::RenderOrder;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Theme {
+    /// Format version, see [`Versioned`].
+    #[serde(default = "migrate::unversioned")]
+    pub version: u32,
+    /// Factor applied to a laser's color at the far end of the highway when the chart doesn't
+    /// specify a far color.
+    pub laser_far_fade: f32,
+    /// Texture drawn over lasers without their own texture, relative to the resources directory.
+    pub laser_texture: Option<String>,
+    /// Scroll speed of the default laser texture in texture units per second.
+    pub laser_scroll: f32,
+    /// Blend mode of lasers that don't specify one.
+    pub laser_blend: LaserBlend,
+    /// Linear color added behind notes struck together on a laser, connecting them into one bar.
+    /// Black disables the glow.
+    pub chord_glow: [f32; 3],
+    /// Linear color of the body of rolls, which pulses to ask for repeated presses.
+    pub roll_color: [f32; 3],
+    /// Linear color of the marks on the body of rolls where they tick. Black hides the marks.
+    pub roll_tick_color: [f32; 3],
+    /// Linear color of mines, which pulse in the same rhythm as rolls.
+    pub mine_color: [f32; 3],
+    pub judge_sounds: JudgeSounds,
+    /// Sound ticked on every beat in silent practice, relative to the resources directory. Unset
+    /// plays a short synthesized click.
+    pub metronome_sound: Option<String>,
+    pub judge_images: JudgeImages,
+    /// Where the game's render plugins draw among the others, applied when the render graph is
+    /// planned.
+    pub render_order: RenderOrders,
+}
+
+/// [`RenderOrder`] of each render plugin of the game.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct RenderOrders {
+    pub lasers: PluginOrder,
+    /// World-space judgement popups.
+    pub popups: PluginOrder,
+}
+
+impl Default for RenderOrders {
+    fn default() -> Self {
+        Self {
+            lasers: PluginOrder::AfterTransparent,
+            popups: PluginOrder::AfterTransparent,
+        }
+    }
+}
+
+/// The stages of [`RenderOrder`], in drawing order. Plugins in the same stage draw in the order
+/// they were added.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PluginOrder {
+    BeforeOpaque,
+    Opaque,
+    AfterOpaque,
+    BeforeTransparent,
+    Transparent,
+    AfterTransparent,
+    Overlay,
+}
+
+impl From<PluginOrder> for RenderOrder {
+    fn from(order: PluginOrder) -> Self {
+        match order {
+            PluginOrder::BeforeOpaque => RenderOrder::BeforeOpaque,
+            PluginOrder::Opaque => RenderOrder::Opaque,
+            PluginOrder::AfterOpaque => RenderOrder::AfterOpaque,
+            PluginOrder::BeforeTransparent => RenderOrder::BeforeTransparent,
+            PluginOrder::Transparent => RenderOrder::Transparent,
+            PluginOrder::AfterTransparent => RenderOrder::AfterTransparent,
+            PluginOrder::Overlay => RenderOrder::Overlay,
+        }
+    }
+}
+
+/// Sound effects played on judgements, relative to the resources directory. Unset sounds are
+/// silent.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct JudgeSounds {
+    pub perfect: Option<String>,
+    pub near: Option<String>,
+    pub miss: Option<String>,
+    /// Played instead of the judgement sound when the combo reaches a multiple of
+    /// `combo_interval`.
+    pub combo: Option<String>,
+    pub combo_interval: u32,
+}
+
+/// Images of the judgements for popups drawn in world space, relative to the resources directory.
+/// They are drawn square. Unset images are drawn as plain quads in the judgement's color.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JudgeImages {
+    pub perfect: Option<String>,
+    pub near: Option<String>,
+    pub miss: Option<String>,
+}
+
+impl Default for JudgeSounds {
+    fn default() -> Self {
+        Self {
+            perfect: None,
+            near: None,
+            miss: None,
+            combo: None,
+            combo_interval: 100,
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            version: Self::current_version(),
+            laser_far_fade: 1.0,
+            laser_texture: None,
+            laser_scroll: 0.0,
+            laser_blend: LaserBlend::Add,
+            chord_glow: [0.25, 0.25, 0.3],
+            roll_color: [0.6, 0.35, 0.05],
+            roll_tick_color: [0.9, 0.6, 0.15],
+            mine_color: [0.9, 0.02, 0.02],
+            judge_sounds: JudgeSounds::default(),
+            metronome_sound: None,
+            judge_images: JudgeImages::default(),
+            render_order: RenderOrders::default(),
+        }
+    }
+}
+
+impl Versioned for Theme {
+    const MIGRATIONS: &'static [fn(&mut Self)] = &[migrate::no_changes];
+
+    fn version_mut(&mut self) -> &mut u32 {
+        &mut self.version
+    }
+}
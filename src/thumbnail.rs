@@ -0,0 +1,80 @@
+//! Static images of the densest part of a chart, rendered on import for song select.
+//!
+//! The notes are drawn on the CPU from the BMS chart as read on import, laid out by measure: the
+//! import runs on background threads without access to the renderer.
+use crate::bms::{BmsNote, LANE_CHANNELS};
+use image::{Rgba, RgbaImage};
+use std::io;
+use std::path::Path;
+
+/// File name of the thumbnail in the song directory.
+pub const THUMBNAIL_FILE: &str = "thumbnail.png";
+const WIDTH: u32 = 128;
+const HEIGHT: u32 = 256;
+/// Measures shown in a thumbnail.
+const WINDOW_MEASURES: f32 = 2.;
+/// Height of a note in pixels.
+const NOTE_HEIGHT: u32 = 4;
+const BACKGROUND: Rgba<u8> = Rgba([16, 16, 24, 255]);
+const DIVIDER: Rgba<u8> = Rgba([48, 48, 64, 255]);
+
+fn lane_color(lane: usize) -> Rgba<u8> {
+    match lane {
+        0 => Rgba([230, 60, 60, 255]),
+        lane if lane % 2 == 0 => Rgba([80, 150, 255, 255]),
+        _ => Rgba([235, 235, 235, 255]),
+    }
+}
+
+/// The start of the [`WINDOW_MEASURES`] with the most notes. `notes` must be sorted.
+fn densest_window(notes: &[BmsNote]) -> f32 {
+    let (mut best, mut best_count) = (0., 0);
+    let mut end = 0;
+    for (i, note) in notes.iter().enumerate() {
+        while end < notes.len() && notes[end].position < note.position + WINDOW_MEASURES {
+            end += 1;
+        }
+        if end - i > best_count {
+            best = note.position;
+            best_count = end - i;
+        }
+    }
+    best
+}
+
+/// Draws the densest part of `notes` as a highway seen from above, scrolling upwards.
+pub fn render(notes: &[BmsNote]) -> RgbaImage {
+    let mut image = RgbaImage::from_pixel(WIDTH, HEIGHT, BACKGROUND);
+    let lanes = LANE_CHANNELS.len() as u32;
+    let lane_width = WIDTH / lanes;
+    for lane in 1..lanes {
+        for y in 0..HEIGHT {
+            image.put_pixel(lane * lane_width, y, DIVIDER);
+        }
+    }
+    let start = densest_window(notes);
+    for note in notes {
+        let t = (note.position - start) / WINDOW_MEASURES;
+        if t < 0. || t >= 1. {
+            continue;
+        }
+        let bottom = HEIGHT - 1 - (t * (HEIGHT - NOTE_HEIGHT) as f32) as u32;
+        let left = note.lane as u32 * lane_width + 1;
+        for y in bottom + 1 - NOTE_HEIGHT..=bottom {
+            for x in left..left + lane_width - 1 {
+                image.put_pixel(x, y, lane_color(note.lane));
+            }
+        }
+    }
+    image
+}
+
+/// Renders the thumbnail of the notes of a BMS chart to `path`. Returns `false` without writing
+/// anything if the chart has no notes to show.
+pub fn write_bms_thumbnail(notes: &[BmsNote], path: &Path) -> io::Result<bool> {
+    if notes.is_empty() {
+        return Ok(false);
+    }
+    render(notes).save(path)?;
+    Ok(true)
+}
@@ -0,0 +1,320 @@
+//! Signed play bundles for tournaments, enabled with the `tournament` feature.
+//!
+//! A bundle holds everything needed to check a play: the chart as played, the hash of its file,
+//! the rules, the key bindings, the replay and the claimed results. It is signed with the
+//! organizer's Ed25519 signing key in `tournament.key`, set only on the machines the event is
+//! played on, so that changes after the export are caught. Checking a signature takes only the
+//! public key, which can't sign. The `iris-verify` tool checks the signature and judges the
+//! replay again with the [judge harness](crate::judge_harness), which shares the note picking,
+//! rolls and mines of the game's judge, to confirm the results.
+use crate::chart::{Chart, PlaySettings};
+use crate::chart_file::{ChartFile, CHART_FILE};
+use crate::console;
+use crate::error::{ConsoleError, TournamentError};
+use crate::judge::{JudgeWindows, Judgement, KeyMappings};
+use crate::judge_harness::{judge_chart, HarnessEvent, JudgeOptions, Press};
+use crate::library::Song;
+use crate::paths::Paths;
+use crate::replay::Replay;
+use crate::score::{NoteRecord, Score, Scores};
+use crate::settings::Settings;
+use crate::vfs::{FileSystem, Vfs};
+use amethyst::{core::math::Vector2, prelude::*, winit::ScanCode};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Format version of [`PlayBundle`].
+pub const BUNDLE_VERSION: u32 = 2;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlayerResult {
+    pub score: u32,
+    pub max_combo: u32,
+    /// Records in judgement order.
+    pub records: Vec<NoteRecord>,
+}
+
+impl PlayerResult {
+    fn new(score: &Score, total_notes: usize) -> Self {
+        Self {
+            score: score.value(total_notes),
+            max_combo: score.max_combo,
+            records: score.records.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PlayBundle {
+    pub version: u32,
+    /// Seconds since the Unix epoch.
+    pub exported_at: u64,
+    /// The song directory relative to the library root.
+    pub song: Option<PathBuf>,
+    /// [`chart_hash`] of the chart file, or `None` for the built-in demo chart.
+    pub chart_hash: Option<String>,
+    /// The chart as played, with modifiers applied.
+    pub chart: ChartFile,
+    pub windows: JudgeWindows,
+    pub note_lock: bool,
+    pub norm_threshold: f32,
+    pub miss_forgiveness: Option<f32>,
+    /// Whether the notes scrolled at a constant speed, which organizers may not allow.
+    #[serde(default)]
+    pub constant_scroll: bool,
+    /// Key positions of each player, as in [`KeyMappings`].
+    pub key_maps: Vec<Vec<(ScanCode, (f32, f32))>>,
+    pub replay: Replay,
+    pub players: Vec<PlayerResult>,
+}
+
+/// A note judged differently in the bundle and when judging the replay again. `None` means the
+/// note has no judgement on that side.
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    pub player: usize,
+    pub time: f32,
+    pub recorded: Option<Judgement>,
+    pub replayed: Option<Judgement>,
+}
+
+pub struct Verification {
+    /// The results of judging the replay again, by player.
+    pub replayed: Vec<PlayerResult>,
+    pub claimed: Vec<PlayerResult>,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl Verification {
+    pub fn is_consistent(&self) -> bool {
+        self.mismatches.is_empty()
+            && self
+                .replayed
+                .iter()
+                .zip(&self.claimed)
+                .all(|(r, c)| r.score == c.score && r.max_combo == c.max_combo)
+    }
+}
+
+/// Judgements of `records` sorted by note time.
+fn by_time(records: &[NoteRecord]) -> Vec<(f32, Judgement)> {
+    let mut judgements: Vec<_> = records.iter().map(|r| (r.time, r.judgement)).collect();
+    judgements.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    judgements
+}
+
+impl PlayBundle {
+    /// Judges the replay again, each player with the keys of their own map, and compares the
+    /// results with the claimed ones.
+    pub fn verify(self) -> Verification {
+        let chart: Chart = self.chart.into_chart();
+        let total_notes = chart.judged_notes();
+        let options = JudgeOptions {
+            windows: self.windows,
+            note_lock: self.note_lock,
+            norm_threshold: self.norm_threshold,
+        };
+        let replay = &self.replay;
+        let mut replayed = Vec::new();
+        let mut mismatches = Vec::new();
+        for (player, map) in self.key_maps.iter().enumerate() {
+            let presses: Vec<_> = replay
+                .inputs
+                .iter()
+                .filter(|input| input.pressed)
+                .filter_map(|input| {
+                    let i = map
+                        .binary_search_by_key(&input.scancode, |(s, _)| *s)
+                        .ok()?;
+                    let (_, (x, y)) = map[i];
+                    Some(Press {
+                        time: input.time as f32 + replay.offset,
+                        position: Vector2::new(x, y),
+                    })
+                })
+                .collect();
+            let mut score = Score::default();
+            score.windows = self.windows;
+            score.miss_forgiveness = self.miss_forgiveness;
+            score.constant_scroll = self.constant_scroll;
+            for event in judge_chart(&chart, &presses, &options) {
+                match event {
+                    HarnessEvent::Judged(judgement) => score.apply(
+                        NoteRecord {
+                            time: judgement.time,
+                            judgement: judgement.judgement,
+                            offset: judgement.offset,
+                            key: None,
+                        },
+                        total_notes,
+                    ),
+                    HarnessEvent::Mine { .. } => score.hit_mine(),
+                    HarnessEvent::Tick { .. } => score.tick(),
+                }
+            }
+            let result = PlayerResult::new(&score, total_notes);
+
+            let recorded = self
+                .players
+                .get(player)
+                .map_or_else(Vec::new, |claimed| by_time(&claimed.records));
+            let again = by_time(&result.records);
+            for i in 0..recorded.len().max(again.len()) {
+                let (r, a) = (recorded.get(i), again.get(i));
+                if r.map(|r| r.1) != a.map(|a| a.1) {
+                    mismatches.push(Mismatch {
+                        player,
+                        time: r.or(a).unwrap().0,
+                        recorded: r.map(|r| r.1),
+                        replayed: a.map(|a| a.1),
+                    });
+                }
+            }
+            replayed.push(result);
+        }
+        Verification {
+            replayed,
+            claimed: self.players,
+            mismatches,
+        }
+    }
+}
+
+/// A bundle with its signature, as written to disk.
+#[derive(Serialize, Deserialize)]
+pub struct SignedBundle {
+    pub bundle: PlayBundle,
+    /// Hex encoded Ed25519 signature of the bundle as JSON.
+    pub signature: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len() / 2)
+        .map(|i| u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok())
+        .collect()
+}
+
+/// The SHA-256 of a chart file in hex, which identifies the chart a bundle was played on.
+pub fn chart_hash(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+fn signing_key(key: &str) -> Result<SecretKey, TournamentError> {
+    from_hex(key)
+        .and_then(|bytes| SecretKey::from_bytes(&bytes).ok())
+        .ok_or(TournamentError::InvalidKey)
+}
+
+/// The hex encoded public key of the signing key `key`, which bundles signed with it are
+/// checked with.
+pub fn public_key(key: &str) -> Result<String, TournamentError> {
+    Ok(to_hex(PublicKey::from(&signing_key(key)?).as_bytes()))
+}
+
+impl SignedBundle {
+    /// Signs `bundle` with the hex encoded Ed25519 signing key `key`.
+    pub fn new(bundle: PlayBundle, key: &str) -> Result<Self, TournamentError> {
+        let secret = signing_key(key)?;
+        let public = PublicKey::from(&secret);
+        let keypair = Keypair { secret, public };
+        let signature = to_hex(&keypair.sign(&serde_json::to_vec(&bundle)?).to_bytes());
+        Ok(Self { bundle, signature })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, TournamentError> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), TournamentError> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Checks the signature with the organizer's hex encoded public key.
+    pub fn check_signature(&self, public_key: &str) -> Result<(), TournamentError> {
+        let public = from_hex(public_key)
+            .and_then(|bytes| PublicKey::from_bytes(&bytes).ok())
+            .ok_or(TournamentError::InvalidKey)?;
+        let signature = from_hex(&self.signature)
+            .and_then(|bytes| Signature::from_bytes(&bytes).ok())
+            .ok_or(TournamentError::BadSignature)?;
+        public
+            .verify(&serde_json::to_vec(&self.bundle)?, &signature)
+            .map_err(|_| TournamentError::BadSignature)
+    }
+}
+
+/// The [`chart_hash`] of the selected song's chart file in the library at `root`.
+fn song_chart_hash(root: &Path, song: &Song) -> Option<String> {
+    let fs = FileSystem::new(root);
+    let bytes = fs.read(&song.path.join(CHART_FILE)).ok()?;
+    Some(chart_hash(&bytes))
+}
+
+/// Console command `bundle <file>`: exports the current play, signed with `tournament.key`, to
+/// `file` relative to the data directory.
+pub fn export(world: &mut World, args: &[&str]) -> Result<String, ConsoleError> {
+    let paths = world.read_resource::<Paths>().clone();
+    let path = match args {
+        [path] => console::path_below(&paths.data, Path::new(path))?,
+        _ => return Err(ConsoleError::InvalidArguments),
+    };
+    let norm_threshold = world
+        .read_resource::<Option<PlaySettings>>()
+        .as_ref()
+        .map(|settings| settings.norm_threshold)
+        .ok_or(ConsoleError::NotPlaying)?;
+    let chart = world.read_resource::<Option<Chart>>();
+    let chart = chart.as_ref().ok_or(ConsoleError::NotPlaying)?;
+    let total_notes = chart.judged_notes();
+    let settings = world.read_resource::<Settings>();
+    let key = settings
+        .tournament
+        .key
+        .clone()
+        .ok_or(TournamentError::NoKey)?;
+    let song = world.read_resource::<Option<Song>>();
+    let gameplay = &settings.gameplay;
+    let bundle = PlayBundle {
+        version: BUNDLE_VERSION,
+        exported_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        song: song.as_ref().map(|song| song.path.clone()),
+        chart_hash: song
+            .as_ref()
+            .and_then(|song| song_chart_hash(&settings.library.root(&paths), song)),
+        chart: ChartFile::from_chart(chart),
+        // The windows in chart time, which differ at another rate with real-time windows.
+        windows: world
+            .read_resource::<Scores>()
+            .players
+            .get(0)
+            .map_or(gameplay.judge_windows, |score| score.windows),
+        note_lock: gameplay.note_lock,
+        norm_threshold,
+        miss_forgiveness: gameplay.miss_forgiveness,
+        constant_scroll: gameplay.constant_scroll,
+        key_maps: world.read_resource::<KeyMappings>().0.clone(),
+        replay: world.read_resource::<Replay>().clone(),
+        players: world
+            .read_resource::<Scores>()
+            .players
+            .iter()
+            .map(|score| PlayerResult::new(score, total_notes))
+            .collect(),
+    };
+    SignedBundle::new(bundle, &key)?.save(&path)?;
+    Ok(format!("Exported the play to {}", path.display()))
+}
@@ -0,0 +1,17 @@
+use amethyst::ui::{Anchor, ScaleMode, UiTransform};
+
+/// A transform whose position and size are fractions of the parent, or of the screen for
+/// top-level elements. It is pivoted at `anchor` and sits at depth 1; set `pivot` or `local_z`
+/// on the result for anything else.
+pub fn percent_transform(
+    id: &str,
+    anchor: Anchor,
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+) -> UiTransform {
+    let mut transform = UiTransform::new(id.into(), anchor, anchor, x, y, 1., width, height);
+    transform.scale_mode = ScaleMode::Percent;
+    transform
+}
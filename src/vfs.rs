@@ -0,0 +1,272 @@
+//! Read access to files that may be stored inside zip archives, so that songs and packs can be
+//! kept compressed.
+//!
+//! [`FileSystem`] is what the game reads songs, charts and sounds through: a path that runs
+//! through a `.zip` file, such as `Pack.zip/Song/chart.ron`, is read from inside the archive.
+use amethyst::assets::Source;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+use zip::ZipArchive;
+
+/// Extension of the archives that [`FileSystem`] reads as directories.
+const ARCHIVE_EXTENSION: &str = "zip";
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub path: PathBuf,
+    pub is_dir: bool,
+}
+
+/// A tree of files to read from.
+pub trait Vfs: Send + Sync {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Size of the file in bytes.
+    fn size(&self, path: &Path) -> io::Result<u64>;
+
+    /// Modification time in seconds since the Unix epoch.
+    fn modified(&self, path: &Path) -> io::Result<u64>;
+
+    /// Files and directories directly in `dir`, as paths that include `dir`.
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<DirEntry>>;
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.size(path).is_ok()
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found in archive", path.display()),
+    )
+}
+
+/// Name of the entry at `path` inside an archive, which always uses slashes.
+fn entry_name(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn is_archive(path: &Path) -> bool {
+    path.extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case(ARCHIVE_EXTENSION))
+        && path.is_file()
+}
+
+/// The files of one zip archive. Entries are read under a lock, as the archive reader is shared.
+pub struct ZipFs {
+    archive: Mutex<ZipArchive<BufReader<File>>>,
+    /// Index and size of the files by entry name. Names are sanitized, so they may differ from
+    /// the names stored in the archive.
+    files: BTreeMap<String, (usize, u64)>,
+    /// Modification time of the archive, which stands in for its entries'.
+    modified: u64,
+}
+
+impl ZipFs {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let modified = file
+            .metadata()?
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut archive = ZipArchive::new(BufReader::new(file))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut files = BTreeMap::new();
+        for i in 0..archive.len() {
+            let entry = archive
+                .by_index(i)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if !entry.name().ends_with('/') {
+                files.insert(entry_name(&entry.sanitized_name()), (i, entry.size()));
+            }
+        }
+        Ok(Self {
+            archive: Mutex::new(archive),
+            files,
+            modified,
+        })
+    }
+}
+
+impl Vfs for ZipFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let &(index, size) = self
+            .files
+            .get(&entry_name(path))
+            .ok_or_else(|| not_found(path))?;
+        let mut archive = self.archive.lock().unwrap();
+        let mut entry = archive
+            .by_index(index)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut bytes = Vec::with_capacity(size as usize);
+        entry.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        self.files
+            .get(&entry_name(path))
+            .map(|&(_, size)| size)
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn modified(&self, _path: &Path) -> io::Result<u64> {
+        Ok(self.modified)
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        let mut prefix = entry_name(dir);
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        // Directories are often not stored as entries of their own, so they are found from the
+        // files in them.
+        let mut children = BTreeMap::new();
+        for name in self.files.keys() {
+            if !name.starts_with(&prefix) {
+                continue;
+            }
+            let rest = &name[prefix.len()..];
+            match rest.find('/') {
+                Some(end) => children.insert(&rest[..end], true),
+                None => children.insert(rest, false),
+            };
+        }
+        if children.is_empty() && !prefix.is_empty() {
+            return Err(not_found(dir));
+        }
+        Ok(children
+            .into_iter()
+            .map(|(name, is_dir)| DirEntry {
+                path: dir.join(name),
+                is_dir,
+            })
+            .collect())
+    }
+}
+
+/// The files below `root` on disk, where zip archives read as directories.
+///
+/// Opened archives are kept until the file system is dropped.
+pub struct FileSystem {
+    root: PathBuf,
+    archives: Mutex<HashMap<PathBuf, Arc<ZipFs>>>,
+}
+
+impl FileSystem {
+    /// Paths are relative to `root`; an empty root takes them as they are.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            archives: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The archive that `path` runs through and the path inside it, or `None` for a plain file.
+    fn resolve(&self, path: &Path) -> io::Result<Option<(Arc<ZipFs>, PathBuf)>> {
+        let full = self.root.join(path);
+        let mut archive_path = PathBuf::new();
+        let mut components = full.components();
+        while let Some(component) = components.next() {
+            archive_path.push(component);
+            if !is_archive(&archive_path) {
+                continue;
+            }
+            let mut archives = self.archives.lock().unwrap();
+            let archive = match archives.get(&archive_path) {
+                Some(archive) => archive.clone(),
+                None => {
+                    let archive = Arc::new(ZipFs::open(&archive_path)?);
+                    archives.insert(archive_path, archive.clone());
+                    archive
+                }
+            };
+            return Ok(Some((archive, components.as_path().to_owned())));
+        }
+        Ok(None)
+    }
+}
+
+impl Vfs for FileSystem {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.resolve(path)? {
+            Some((archive, inner)) => archive.read(&inner),
+            None => std::fs::read(self.root.join(path)),
+        }
+    }
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        match self.resolve(path)? {
+            Some((archive, inner)) => archive.size(&inner),
+            None => {
+                let metadata = std::fs::metadata(self.root.join(path))?;
+                if metadata.is_file() {
+                    Ok(metadata.len())
+                } else {
+                    Err(io::Error::new(io::ErrorKind::Other, "not a file"))
+                }
+            }
+        }
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<u64> {
+        match self.resolve(path)? {
+            Some((archive, inner)) => archive.modified(&inner),
+            None => Ok(std::fs::metadata(self.root.join(path))?
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())),
+        }
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        if let Some((archive, inner)) = self.resolve(dir)? {
+            return Ok(archive
+                .read_dir(&inner)?
+                .into_iter()
+                .map(|entry| DirEntry {
+                    path: dir.join(entry.path.strip_prefix(&inner).unwrap()),
+                    is_dir: entry.is_dir,
+                })
+                .collect());
+        }
+        std::fs::read_dir(self.root.join(dir))?
+            .map(|entry| {
+                let entry = entry?;
+                let path = entry.path();
+                Ok(DirEntry {
+                    is_dir: path.is_dir() || is_archive(&path),
+                    path: dir.join(entry.file_name()),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Lets the asset loader load textures and other assets from inside archives.
+impl Source for FileSystem {
+    fn modified(&self, path: &str) -> Result<u64, amethyst::Error> {
+        Ok(Vfs::modified(self, Path::new(path))?)
+    }
+
+    fn load(&self, path: &str) -> Result<Vec<u8>, amethyst::Error> {
+        Ok(self.read(Path::new(path))?)
+    }
+}
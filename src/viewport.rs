@@ -0,0 +1,127 @@
+use crate::settings::{Settings, ViewportRotation, ViewportSettings};
+use crate::ui::percent_transform;
+use amethyst::{
+    ecs::{Entities, Entity, Read, ReadExpect, System, Write, WriteStorage},
+    ui::{Anchor, UiImage, UiTransform},
+    window::ScreenDimensions,
+};
+
+const BAR_COLOR: [f32; 4] = [0., 0., 0., 1.];
+
+/// Where the highway is drawn on the screen. [`LaserFovSystem`](crate::LaserFovSystem) places the
+/// judge quad and the basis through it; the default fills the window.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Viewport {
+    /// Half the width and height of the picture on the screen in normalized device coordinates,
+    /// so that 1 reaches the edge of the window.
+    pub half_extents: (f32, f32),
+    pub rotation: ViewportRotation,
+}
+
+impl Default for Viewport {
+    fn default() -> Self {
+        Self {
+            half_extents: (1., 1.),
+            rotation: ViewportRotation::Upright,
+        }
+    }
+}
+
+impl Viewport {
+    /// The largest centered picture of the configured aspect in a window of `width` by `height`.
+    pub fn fit(settings: &ViewportSettings, width: f32, height: f32) -> Self {
+        let half_extents = match settings.aspect {
+            Some(aspect) if aspect > 0. && width > 0. && height > 0. => {
+                // A quarter turn swaps the width and height of the picture on the screen.
+                let aspect = match settings.rotation {
+                    ViewportRotation::Upright => aspect,
+                    ViewportRotation::Clockwise | ViewportRotation::CounterClockwise => 1. / aspect,
+                };
+                let window_aspect = width / height;
+                if aspect > window_aspect {
+                    (1., window_aspect / aspect)
+                } else {
+                    (aspect / window_aspect, 1.)
+                }
+            }
+            _ => (1., 1.),
+        };
+        Self {
+            half_extents,
+            rotation: settings.rotation,
+        }
+    }
+
+    /// Where the point `(x, y)` of the picture, with the picture spanning `-1..=1` on both axes,
+    /// is on the screen in normalized device coordinates.
+    pub fn to_screen(&self, x: f32, y: f32) -> (f32, f32) {
+        // Device coordinates point down, so a clockwise turn takes right to down.
+        let (x, y) = match self.rotation {
+            ViewportRotation::Upright => (x, y),
+            ViewportRotation::Clockwise => (-y, x),
+            ViewportRotation::CounterClockwise => (y, -x),
+        };
+        (x * self.half_extents.0, y * self.half_extents.1)
+    }
+}
+
+/// Fits the [`Viewport`] to the window following the [viewport settings], and covers the rest of
+/// the window with black bars. The HUD and menus still use the whole window.
+///
+/// [viewport settings]: crate::settings::ViewportSettings
+#[derive(Default)]
+pub struct ViewportSystem {
+    /// The top, bottom, left and right bars.
+    bars: Vec<Entity>,
+}
+
+impl<'s> System<'s> for ViewportSystem {
+    type SystemData = (
+        Entities<'s>,
+        ReadExpect<'s, ScreenDimensions>,
+        Read<'s, Settings>,
+        Write<'s, Viewport>,
+        WriteStorage<'s, UiTransform>,
+        WriteStorage<'s, UiImage>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, dimensions, settings, mut viewport, mut transforms, mut images): Self::SystemData,
+    ) {
+        let fitted = Viewport::fit(&settings.viewport, dimensions.width(), dimensions.height());
+        if !self.bars.is_empty() && *viewport == fitted {
+            return;
+        }
+        if self.bars.is_empty() {
+            for (id, anchor) in &[
+                ("ViewportTop", Anchor::TopMiddle),
+                ("ViewportBottom", Anchor::BottomMiddle),
+                ("ViewportLeft", Anchor::MiddleLeft),
+                ("ViewportRight", Anchor::MiddleRight),
+            ] {
+                let bar = entities.create();
+                let mut transform = percent_transform(id, *anchor, 0., 0., 0., 0.);
+                // Below the HUD and menus.
+                transform.local_z = 0.;
+                transforms.insert(bar, transform).unwrap();
+                images.insert(bar, UiImage::SolidColor(BAR_COLOR)).unwrap();
+                self.bars.push(bar);
+            }
+        }
+        let (x, y) = fitted.half_extents;
+        let sizes = [
+            (1., (1. - y) / 2.),
+            (1., (1. - y) / 2.),
+            ((1. - x) / 2., 1.),
+            ((1. - x) / 2., 1.),
+        ];
+        for (&bar, &(width, height)) in self.bars.iter().zip(&sizes) {
+            if let Some(transform) = transforms.get_mut(bar) {
+                transform.width = width;
+                transform.height = height;
+            }
+        }
+        *viewport = fitted;
+    }
+}
@@ -0,0 +1,355 @@
+use crate::audio::{AudioOutput, Channel};
+use crate::key_config::KeyConfigState;
+use crate::kiosk;
+use crate::metronome::{self, LAMP_COLOR};
+use crate::paths::Paths;
+use crate::settings::Settings;
+use crate::song_select::SongSelectState;
+use crate::ui::percent_transform;
+use crate::InterFont;
+use amethyst::{
+    core::timing::Time,
+    ecs::Entity,
+    input::is_key_down,
+    prelude::*,
+    ui::{Anchor, LineMode, UiImage, UiText},
+    window::ScreenDimensions,
+    winit::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
+};
+use std::path::PathBuf;
+
+/// Font size of the wizard's text in logical pixels.
+const WIZARD_FONT_SIZE: f32 = 24.;
+/// Seconds between the clicks of the calibration, 100 BPM.
+const BEAT_SECONDS: f64 = 0.6;
+/// Seconds before the first click of the calibration.
+const LEAD_IN_SECONDS: f64 = 1.;
+/// Taps the calibration measures the offset from.
+const CALIBRATION_TAPS: usize = 16;
+
+/// Creates the text of a wizard step in the middle of the screen.
+fn create_text(world: &mut World, id: &str, text: String) -> Entity {
+    let font = world.read_resource::<InterFont>().0.clone();
+    let font_size = {
+        let settings = world.read_resource::<Settings>();
+        let dimensions = world.read_resource::<ScreenDimensions>();
+        WIZARD_FONT_SIZE * settings.ui.scale_factor(&dimensions)
+    };
+    let mut ui_text = UiText::new(font, text, [1.; 4], font_size);
+    ui_text.line_mode = LineMode::Wrap;
+    world
+        .create_entity()
+        .with(percent_transform(id, Anchor::Middle, 0., 0., 0.7, 0.6))
+        .with(ui_text)
+        .build()
+}
+
+fn set_text(world: &World, entity: Option<Entity>, text: String) {
+    if let Some(entity) = entity {
+        if let Some(ui_text) = world.write_storage::<UiText>().get_mut(entity) {
+            ui_text.text = text;
+        }
+    }
+}
+
+/// The steps of the first-run wizard in order.
+#[derive(Copy, Clone)]
+enum Step {
+    KeyBindings,
+    Calibration,
+    SongsFolder,
+}
+
+const STEPS: [Step; 3] = [Step::KeyBindings, Step::Calibration, Step::SongsFolder];
+
+/// Sets up a new profile in place of hand-editing its RON files: the key bindings, the input
+/// offset and the songs folder. Shown at startup when the profile has no settings yet.
+///
+/// Each step is a state pushed on top of this one, which moves on to the next step when it is
+/// popped, whether done or skipped with Escape. After the last step, the settings are saved to
+/// the profile, so that the wizard isn't shown again, and song select opens.
+#[derive(Default)]
+pub struct FirstRunState {
+    next: usize,
+}
+
+impl SimpleState for FirstRunState {
+    fn update(
+        &mut self,
+        StateData { world, .. }: &mut StateData<'_, GameData<'_, '_>>,
+    ) -> SimpleTrans {
+        let step = match STEPS.get(self.next) {
+            Some(&step) => step,
+            None => {
+                if let Err(e) = crate::save_settings(world) {
+                    log::warn!("Failed to save settings: {}", e);
+                }
+                return Trans::Switch(Box::new(SongSelectState::default()));
+            }
+        };
+        self.next += 1;
+        match step {
+            Step::KeyBindings => Trans::Push(Box::new(KeyConfigState::default())),
+            Step::Calibration => Trans::Push(Box::new(CalibrationState::default())),
+            Step::SongsFolder => Trans::Push(Box::new(SongsFolderState::default())),
+        }
+    }
+}
+
+/// Measures the [`offset`] by having the player tap any key along to a metronome. A lamp flashes
+/// with each click, so that players without audio output can tap along to the display instead.
+///
+/// The offset cancels the median distance of the taps from the clicks, so that a stray tap
+/// doesn't skew it. Enter keeps the measured offset, R measures again and Escape leaves the
+/// offset as it was.
+///
+/// [`offset`]: crate::settings::GameplaySettings::offset
+#[derive(Default)]
+pub struct CalibrationState {
+    /// Real time of the first click.
+    start: f64,
+    /// The last beat clicked, counted from the first click.
+    clicked: Option<i64>,
+    /// Seconds each tap was late for the nearest click.
+    taps: Vec<f64>,
+    text: Option<Entity>,
+    lamp: Option<Entity>,
+}
+
+impl CalibrationState {
+    fn restart(&mut self, world: &World) {
+        self.start = world.read_resource::<Time>().absolute_real_time_seconds() + LEAD_IN_SECONDS;
+        self.clicked = None;
+        self.taps.clear();
+    }
+
+    /// The measured offset once enough taps are in.
+    fn offset(&self) -> Option<f32> {
+        if self.taps.len() < CALIBRATION_TAPS {
+            return None;
+        }
+        let mut taps = self.taps.clone();
+        taps.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        Some(-taps[taps.len() / 2] as f32)
+    }
+
+    fn label(&self) -> String {
+        let mut text = String::from("Calibration\n\n");
+        match self.offset() {
+            Some(offset) => {
+                text += &format!(
+                    "Your taps were {:+.0} ms off the clicks.\n\n\
+                     Enter: keep  R: retry  Escape: skip",
+                    -offset * 1000.
+                );
+            }
+            None => {
+                text += &format!(
+                    "Tap any key on each click, or on each flash without sound.\n\n\
+                     {}/{} taps\n\nEscape: skip",
+                    self.taps.len(),
+                    CALIBRATION_TAPS
+                );
+            }
+        }
+        text
+    }
+}
+
+impl SimpleState for CalibrationState {
+    fn on_start(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        self.restart(world);
+        self.text = Some(create_text(world, "Calibration", self.label()));
+        self.lamp = Some(
+            world
+                .create_entity()
+                .with(percent_transform(
+                    "CalibrationLamp",
+                    Anchor::BottomMiddle,
+                    0.,
+                    0.15,
+                    0.1,
+                    0.02,
+                ))
+                .with(UiImage::SolidColor([0.; 4]))
+                .build(),
+        );
+    }
+
+    fn on_stop(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        if let Some(text) = self.text.take() {
+            world.delete_entity(text).unwrap();
+        }
+        if let Some(lamp) = self.lamp.take() {
+            world.delete_entity(lamp).unwrap();
+        }
+    }
+
+    fn update(
+        &mut self,
+        StateData { world, .. }: &mut StateData<'_, GameData<'_, '_>>,
+    ) -> SimpleTrans {
+        let now = world.read_resource::<Time>().absolute_real_time_seconds();
+        let position = ((now - self.start) / BEAT_SECONDS) as f32;
+        let measuring = self.offset().is_none() && position >= 0.;
+        let beat = position.floor() as i64;
+        if measuring && self.clicked != Some(beat) {
+            self.clicked = Some(beat);
+            if let Some(output) = &*world.read_resource::<Option<AudioOutput>>() {
+                output.play(Channel::Effect, metronome::click());
+            }
+        }
+        let brightness = if measuring {
+            let suppress_flashes = world
+                .read_resource::<Settings>()
+                .accessibility
+                .suppress_flashes;
+            metronome::lamp_brightness(position, suppress_flashes)
+        } else {
+            0.
+        };
+        if let Some(lamp) = self.lamp {
+            if let Some(image) = world.write_storage::<UiImage>().get_mut(lamp) {
+                let [r, g, b] = LAMP_COLOR;
+                *image = UiImage::SolidColor([r, g, b, brightness]);
+            }
+        }
+        Trans::None
+    }
+
+    fn handle_event(
+        &mut self,
+        StateData { world, .. }: StateData<'_, GameData<'_, '_>>,
+        event: StateEvent,
+    ) -> SimpleTrans {
+        let event = match event {
+            StateEvent::Window(event) => event,
+            _ => return Trans::None,
+        };
+        if kiosk::exit_requested(world, &event) {
+            return Trans::Quit;
+        }
+        if is_key_down(&event, VirtualKeyCode::Escape) {
+            return Trans::Pop;
+        }
+        if let Some(offset) = self.offset() {
+            if is_key_down(&event, VirtualKeyCode::Return) {
+                world.write_resource::<Settings>().gameplay.offset = offset;
+                log::info!("Calibrated offset to {:+.0} ms", offset * 1000.);
+                return Trans::Pop;
+            } else if is_key_down(&event, VirtualKeyCode::R) {
+                self.restart(world);
+            }
+        } else if let Event::WindowEvent {
+            event:
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            ..
+                        },
+                    ..
+                },
+            ..
+        } = event
+        {
+            let now = world.read_resource::<Time>().absolute_real_time_seconds();
+            let nearest = ((now - self.start) / BEAT_SECONDS).round();
+            if nearest >= 0. {
+                self.taps.push(now - self.start - nearest * BEAT_SECONDS);
+            }
+        }
+        set_text(world, self.text, self.label());
+        Trans::None
+    }
+}
+
+/// Asks for the directory of the [song library]. Enter uses the typed directory, creating it if
+/// needed, and Escape keeps the current one.
+///
+/// [song library]: crate::settings::LibrarySettings::songs_dir
+#[derive(Default)]
+pub struct SongsFolderState {
+    path: String,
+    /// Why the typed directory can't be used.
+    error: Option<String>,
+    text: Option<Entity>,
+}
+
+impl SongsFolderState {
+    fn label(&self) -> String {
+        let mut text = format!(
+            "Songs folder\n\nType the folder to keep your songs in.\n\n{}_\n\n",
+            self.path
+        );
+        if let Some(error) = &self.error {
+            text += &format!("{}\n\n", error);
+        }
+        text += "Enter: use this folder  Escape: skip";
+        text
+    }
+
+    /// Creates the typed directory and makes it the library. Returns `false` if it can't be used.
+    fn apply(&mut self, world: &World) -> bool {
+        let path = PathBuf::from(self.path.trim());
+        if let Err(e) = std::fs::create_dir_all(&path) {
+            self.error = Some(format!("Can't use {}: {}", path.display(), e));
+            return false;
+        }
+        let default = world.read_resource::<Paths>().data.join("songs");
+        world.write_resource::<Settings>().library.songs_dir =
+            Some(path).filter(|path| *path != default);
+        true
+    }
+}
+
+impl SimpleState for SongsFolderState {
+    fn on_start(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        self.path = world
+            .read_resource::<Settings>()
+            .library
+            .root(&world.read_resource::<Paths>())
+            .display()
+            .to_string();
+        self.text = Some(create_text(world, "SongsFolder", self.label()));
+    }
+
+    fn on_stop(&mut self, StateData { world, .. }: StateData<'_, GameData<'_, '_>>) {
+        if let Some(text) = self.text.take() {
+            world.delete_entity(text).unwrap();
+        }
+    }
+
+    fn handle_event(
+        &mut self,
+        StateData { world, .. }: StateData<'_, GameData<'_, '_>>,
+        event: StateEvent,
+    ) -> SimpleTrans {
+        let event = match event {
+            StateEvent::Window(event) => event,
+            _ => return Trans::None,
+        };
+        if kiosk::exit_requested(world, &event) {
+            return Trans::Quit;
+        }
+        if is_key_down(&event, VirtualKeyCode::Return) {
+            if !self.path.trim().is_empty() && self.apply(world) {
+                return Trans::Pop;
+            }
+        } else if is_key_down(&event, VirtualKeyCode::Escape) {
+            return Trans::Pop;
+        } else if is_key_down(&event, VirtualKeyCode::Back) {
+            self.path.pop();
+        } else if let Event::WindowEvent {
+            event: WindowEvent::ReceivedCharacter(c),
+            ..
+        } = event
+        {
+            if !c.is_control() {
+                self.path.push(c);
+            }
+        }
+        set_text(world, self.text, self.label());
+        Trans::None
+    }
+}